@@ -12,6 +12,12 @@ fn main() {
     let include = package_dir.join("deps/ltreesitter/include");
     let csrc = package_dir.join("deps/ltreesitter/csrc");
     let mut config = cc::Build::new();
+    // Luau's C API differs from the PUC-Lua and LuaJIT APIs that ltreesitter is written against.
+    // Our shims have to come before Luau's own headers in the include path.
+    if std::env::var_os("CARGO_FEATURE_LUAU").is_some() {
+        let luau = package_dir.join("csrc/luau");
+        config.include(&luau).file(luau.join("ltreesitter_luau.c"));
+    }
     if let Some(include) = std::env::var_os("DEP_LUA_INCLUDE") {
         config.include(include);
     }
@@ -19,6 +25,10 @@ fn main() {
         config.include(include);
     }
     println!("cargo:include={}", include.display());
+    // Lets us compile grammars for the same target at runtime.
+    if let Ok(target) = std::env::var("TARGET") {
+        println!("cargo:rustc-env=MLUA_TREE_SITTER_TARGET={}", target);
+    }
     config
         .warnings(true)
         .opt_level(2)
@@ -35,5 +45,6 @@ fn main() {
         .file(csrc.join("tree.c"))
         .file(csrc.join("tree_cursor.c"))
         .file(csrc.join("types.c"))
+        .file(package_dir.join("csrc/layout.c"))
         .compile("ltreesitter");
 }
@@ -0,0 +1,27 @@
+/* -*- coding: utf-8 -*-
+ * ------------------------------------------------------------------------------------------------
+ * Copyright © 2023, Douglas Creager.
+ * Licensed under the MIT license.
+ * Please see the LICENSE file in this distribution for license details.
+ * ------------------------------------------------------------------------------------------------
+ */
+
+/* Checks that the Rust mirrors of ltreesitter's structs (in src/ltreesitter.rs) match ltreesitter's
+ * own headers, so that a change to ltreesitter's layout fails the build instead of corrupting
+ * memory. */
+
+#include <stddef.h>
+
+#include <ltreesitter/types.h>
+
+_Static_assert(offsetof(ltreesitter_Parser, parser) == 0, "ltreesitter_Parser has changed");
+_Static_assert(offsetof(ltreesitter_Tree, tree) == 0, "ltreesitter_Tree has changed");
+_Static_assert(offsetof(ltreesitter_Tree, source) == sizeof(TSTree *),
+               "ltreesitter_Tree has changed");
+_Static_assert(offsetof(ltreesitter_SourceText, text) == sizeof(size_t),
+               "ltreesitter_SourceText has changed");
+
+/* The size of the userdata that ltreesitter allocates for a parser. */
+size_t mlua_tree_sitter_parser_size(void) {
+    return sizeof(ltreesitter_Parser);
+}
@@ -0,0 +1,11 @@
+/* -*- coding: utf-8 -*-
+ * ------------------------------------------------------------------------------------------------
+ * Copyright © 2023, Douglas Creager.
+ * Licensed under the MIT license.
+ * Please see the LICENSE file in this distribution for license details.
+ * ------------------------------------------------------------------------------------------------
+ */
+
+/* Luau declares its auxiliary library in lualib.h; ltreesitter includes lauxlib.h. */
+
+#include "ltreesitter_luau.h"
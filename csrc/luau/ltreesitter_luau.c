@@ -0,0 +1,106 @@
+/* -*- coding: utf-8 -*-
+ * ------------------------------------------------------------------------------------------------
+ * Copyright © 2023, Douglas Creager.
+ * Licensed under the MIT license.
+ * Please see the LICENSE file in this distribution for license details.
+ * ------------------------------------------------------------------------------------------------
+ */
+
+/* Implementations of the Lua 5.x functions that Luau doesn't provide. */
+
+#include "ltreesitter_luau.h"
+
+#include <tree_sitter/api.h>
+
+#include <ltreesitter/types.h>
+
+int ltreesitter_luau_ref(lua_State *L, int t) {
+    int ref;
+    if (t != LUA_REGISTRYINDEX) {
+        return luaL_error(L, "luaL_ref is only supported for the registry on Luau");
+    }
+    ref = lua_ref(L, -1);
+    lua_pop(L, 1);
+    return ref;
+}
+
+void *ltreesitter_luau_testudata(lua_State *L, int idx, const char *tname) {
+    int same;
+    void *p = lua_touserdata(L, idx);
+    if (p == NULL || !lua_getmetatable(L, idx)) {
+        return NULL;
+    }
+    luaL_getmetatable(L, tname);
+    same = lua_rawequal(L, -1, -2);
+    lua_pop(L, 2);
+    return same ? p : NULL;
+}
+
+void ltreesitter_luau_setfuncs(lua_State *L, const luaL_Reg *l, int nup) {
+    int i;
+    luaL_checkstack(L, nup, "too many upvalues");
+    for (; l->name != NULL; l++) {
+        for (i = 0; i < nup; i++) {
+            lua_pushvalue(L, -nup);
+        }
+        lua_pushcclosurek(L, l->func, l->name, nup, NULL);
+        lua_setfield(L, -(nup + 2), l->name);
+    }
+    lua_pop(L, nup);
+}
+
+static void destroy_parser(lua_State *L, void *userdata) {
+    (void)L;
+    ts_parser_delete(((ltreesitter_Parser *)userdata)->parser);
+}
+
+/* ltreesitter's copy of the tree's source is a separate userdata, which Luau frees itself. */
+static void destroy_tree(lua_State *L, void *userdata) {
+    (void)L;
+    ts_tree_delete(((ltreesitter_Tree *)userdata)->tree);
+}
+
+/* A cursor's userdata is laid out like a TSTreeCursor, as src/ltreesitter.rs also assumes. */
+static void destroy_tree_cursor(lua_State *L, void *userdata) {
+    (void)L;
+    ts_tree_cursor_delete((TSTreeCursor *)userdata);
+}
+
+static void destroy_query(lua_State *L, void *userdata) {
+    (void)L;
+    ts_query_delete(((ltreesitter_Query *)userdata)->query);
+}
+
+static const struct {
+    const char *metatable;
+    lua_Destructor destroy;
+} destructors[] = {
+    {"ltreesitter.Parser", destroy_parser},
+    {"ltreesitter.Tree", destroy_tree},
+    {"ltreesitter.TreeCursor", destroy_tree_cursor},
+    {"ltreesitter.Query", destroy_query},
+    /* The cursors of closed trees get this metatable from src/trees.rs. */
+    {"mlua_tree_sitter.ClosedTreeCursor", destroy_tree_cursor},
+};
+
+int ltreesitter_luau_setmetatable(lua_State *L, int idx) {
+    size_t i;
+    int same;
+    int tag;
+    idx = lua_absindex(L, idx);
+    if (lua_type(L, idx) == LUA_TUSERDATA) {
+        for (i = 0; i < sizeof(destructors) / sizeof(destructors[0]); i++) {
+            luaL_getmetatable(L, destructors[i].metatable);
+            same = lua_rawequal(L, -1, -2);
+            lua_pop(L, 1);
+            if (same) {
+                tag = LTREESITTER_LUAU_FIRST_TAG + (int)i;
+                lua_setuserdatadtor(L, tag, destructors[i].destroy);
+                lua_setuserdatatag(L, idx, tag);
+                break;
+            }
+        }
+    }
+    /* The parentheses keep our macro from replacing Luau's own function. */
+    return (lua_setmetatable)(L, idx);
+}
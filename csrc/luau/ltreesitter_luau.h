@@ -0,0 +1,82 @@
+/* -*- coding: utf-8 -*-
+ * ------------------------------------------------------------------------------------------------
+ * Copyright © 2023, Douglas Creager.
+ * Licensed under the MIT license.
+ * Please see the LICENSE file in this distribution for license details.
+ * ------------------------------------------------------------------------------------------------
+ */
+
+/* Lets the vendored ltreesitter sources compile against Luau's C API.
+ *
+ * Luau's C API is derived from Lua 5.1's, so we claim to be Lua 5.1, which makes ltreesitter use
+ * its own 5.1 compatibility code.  The remaining differences are bridged here: functions that
+ * Luau renamed or dropped, macros that take an extra debug name, and error functions that don't
+ * return a value. */
+
+#ifndef LTREESITTER_LUAU_H
+#define LTREESITTER_LUAU_H
+
+#include <lua.h>
+#include <lualib.h>
+
+#ifndef LUA_VERSION_NUM
+#define LUA_VERSION_NUM 501
+#endif
+
+/* Luau wants a debug name for every C function. */
+#undef lua_pushcfunction
+#define lua_pushcfunction(L, fn) lua_pushcclosurek(L, fn, #fn, 0, NULL)
+#undef lua_pushcclosure
+#define lua_pushcclosure(L, fn, n) lua_pushcclosurek(L, fn, #fn, n, NULL)
+
+/* Luau's error functions never return, and are declared as returning void, so they can't be used
+ * in ltreesitter's `return luaL_error(...)` statements as-is. */
+#undef luaL_error
+#define luaL_error(L, ...) (luaL_errorL(L, __VA_ARGS__), 0)
+#undef luaL_argerror
+#define luaL_argerror(L, arg, extramsg) (luaL_argerrorL(L, arg, extramsg), 0)
+#undef luaL_typeerror
+#define luaL_typeerror(L, arg, tname) (luaL_typeerrorL(L, arg, tname), 0)
+#define luaL_typerror luaL_typeerror
+#define lua_error(L) (lua_error(L), 0)
+
+#ifndef lua_rawlen
+#define lua_rawlen lua_objlen
+#endif
+#ifndef luaL_len
+#define luaL_len(L, idx) ((lua_Integer)lua_objlen(L, idx))
+#endif
+#ifndef luaL_checkint
+#define luaL_checkint(L, n) ((int)luaL_checkinteger(L, n))
+#endif
+#ifndef luaL_optint
+#define luaL_optint(L, n, d) ((int)luaL_optinteger(L, n, d))
+#endif
+
+/* Luau only has references into the registry. */
+#define luaL_ref(L, t) ltreesitter_luau_ref(L, t)
+#define luaL_unref(L, t, ref) lua_unref(L, ref)
+int ltreesitter_luau_ref(lua_State *L, int t);
+
+#define luaL_testudata(L, idx, tname) ltreesitter_luau_testudata(L, idx, tname)
+void *ltreesitter_luau_testudata(lua_State *L, int idx, const char *tname);
+
+#define luaL_setfuncs(L, l, nup) ltreesitter_luau_setfuncs(L, l, nup)
+void ltreesitter_luau_setfuncs(lua_State *L, const luaL_Reg *l, int nup);
+
+#ifndef luaL_newlib
+#define luaL_newlib(L, l) (lua_createtable(L, 0, 0), luaL_setfuncs(L, l, 0))
+#endif
+
+/* Luau never calls `__gc` metamethods, and a userdata's destructor can't call back into Lua, so it
+ * can't run ltreesitter's finalizers either.  Instead, when an object gets one of ltreesitter's
+ * metatables, we give it a userdata tag with a destructor that frees the object's tree-sitter data
+ * the same way that the type's finalizer would.  The tags start at LTREESITTER_LUAU_FIRST_TAG,
+ * which an embedder that uses those tags for its own userdata can override. */
+#ifndef LTREESITTER_LUAU_FIRST_TAG
+#define LTREESITTER_LUAU_FIRST_TAG 120
+#endif
+#define lua_setmetatable(L, idx) ltreesitter_luau_setmetatable(L, idx)
+int ltreesitter_luau_setmetatable(lua_State *L, int idx);
+
+#endif
@@ -0,0 +1,164 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Runs a Lua analysis script over a set of source files.
+//!
+//! ``` console
+//! $ cargo run --features mlua/lua54,mlua/vendored --example mts -- \
+//!     run script.lua src/**/*.py [--lang python] [--pack grammars/rust/pack.toml]
+//! ```
+//!
+//! Each file's language comes from `--lang`, or else from the file types of the language packs in
+//! the [language registry][mlua_tree_sitter::LanguageRegistry].  The registry starts out with the
+//! Python grammar that's linked into this binary; `--pack` loads more (which needs the
+//! `language-packs` feature).  Files are parsed with the registry's parser for their language.
+//!
+//! The script must define a global `analyze(tree, ctx)` function, which is called once for each
+//! file.  `ctx` is an `AnalysisContext` whose `path` and `language` fields describe the file.  If
+//! `analyze` returns a value, it's printed after the file's path.  Scripts can also report findings
+//! as they go via `require("ltreesitter_rs").emit("report", message)`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use mlua_tree_sitter::AnalysisContext;
+use mlua_tree_sitter::EmitChannels;
+use mlua_tree_sitter::LanguagePack;
+use mlua_tree_sitter::LanguageRegistry;
+use mlua_tree_sitter::Languages;
+use mlua_tree_sitter::Module;
+use mlua_tree_sitter::TSParser;
+use mlua_tree_sitter::WithSource;
+
+const USAGE: &str =
+    "usage: mts run <script.lua> <files>... [--lang <language>] [--pack <pack.toml>]...";
+
+const REGISTRY_PARSER: &str = r#"
+    local name = ...
+    local languages = require("ltreesitter_rs").languages
+    return languages:parser(name)
+"#;
+
+struct Args {
+    script: PathBuf,
+    files: Vec<PathBuf>,
+    language: Option<String>,
+    packs: Vec<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, anyhow::Error> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("run") => {}
+        _ => bail!(USAGE),
+    }
+    let script = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let mut files = Vec::new();
+    let mut language = None;
+    let mut packs = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--lang" {
+            language = Some(args.next().ok_or_else(|| anyhow!(USAGE))?);
+        } else if arg == "--pack" {
+            packs.push(PathBuf::from(args.next().ok_or_else(|| anyhow!(USAGE))?));
+        } else {
+            files.push(PathBuf::from(arg));
+        }
+    }
+    Ok(Args {
+        script: PathBuf::from(script),
+        files,
+        language,
+        packs,
+    })
+}
+
+#[cfg(feature = "language-packs")]
+fn load_pack(registry: &mut LanguageRegistry, path: &Path) -> Result<(), anyhow::Error> {
+    registry
+        .load_pack(path)
+        .with_context(|| format!("cannot load language pack {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "language-packs"))]
+fn load_pack(_registry: &mut LanguageRegistry, path: &Path) -> Result<(), anyhow::Error> {
+    bail!(
+        "cannot load language pack {}: mts was built without the language-packs feature",
+        path.display()
+    )
+}
+
+/// Returns the name of the language that a file should be parsed as.
+fn language_for(lua: &mlua::Lua, args: &Args, path: &Path) -> Result<String, anyhow::Error> {
+    if let Some(language) = &args.language {
+        return Ok(language.clone());
+    }
+    lua.with_language_registry(|registry| registry.for_path(path).map(|pack| pack.name.clone()))?
+        .ok_or_else(|| anyhow!("no language pack applies to {}", path.display()))
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = parse_args()?;
+
+    let lua = mlua::Lua::new();
+    lua.open_ltreesitter()?;
+    lua.register_language("python", tree_sitter_python::language())?;
+    lua.with_language_registry(|registry| {
+        registry.add(LanguagePack::new("python").with_file_type("py"));
+        args.packs
+            .iter()
+            .try_for_each(|pack| load_pack(registry, pack))
+    })??;
+    let registry_parser = lua
+        .load(REGISTRY_PARSER)
+        .set_name("registry_parser")
+        .into_function()?;
+    let mut parsers: HashMap<String, TSParser> = HashMap::new();
+
+    let reports = lua.emit_channel::<String>("report")?;
+    let script = std::fs::read_to_string(&args.script)
+        .with_context(|| format!("cannot read {}", args.script.display()))?;
+    lua.load(&script)
+        .set_name(args.script.to_string_lossy())
+        .exec()?;
+    let analyze: mlua::Function = lua
+        .globals()
+        .get("analyze")
+        .context("script does not define an analyze function")?;
+
+    for path in &args.files {
+        let language = language_for(&lua, &args, path)?;
+        if !parsers.contains_key(&language) {
+            let parser: TSParser = registry_parser
+                .call(language.as_str())
+                .with_context(|| format!("cannot create a parser for {}", language))?;
+            parsers.insert(language.clone(), parser);
+        }
+        let parser = parsers.get_mut(&language).expect("parser was just created");
+        let src = std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let tree = parser
+            .parse(&src, None)
+            .ok_or_else(|| anyhow!("cannot parse {}", path.display()))?;
+        let ctx = AnalysisContext::new()
+            .with_path(path.to_string_lossy())
+            .with_language(&language);
+        let result: mlua::Value = analyze.call((tree.with_source(&src), ctx))?;
+        for report in reports.try_iter() {
+            println!("{}: {}", path.display(), report);
+        }
+        match result {
+            mlua::Value::Nil => {}
+            mlua::Value::String(result) => println!("{}: {}", path.display(), result.to_str()?),
+            result => println!("{}: {:?}", path.display(), result),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Checks that the grammars of trees and parsers that cross the bridge use a supported ABI.
+//!
+//! Every grammar records the version of the tree-sitter ABI that it was generated for, and each
+//! tree-sitter runtime only supports a range of ABI versions.  This crate's runtime supports
+//! [`tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION`] through [`tree_sitter::LANGUAGE_VERSION`].  If
+//! the `ltreesitter` module advertises the range that its runtime supports (via its
+//! `MIN_COMPATIBLE_LANGUAGE_VERSION` and `LANGUAGE_VERSION` fields), we only accept grammars that
+//! both runtimes support.  Whenever a tree, parser, or language crosses the bridge in either
+//! direction, we check its grammar, and fail with an [`Error::LanguageVersionMismatch`] instead of
+//! letting the other side misinterpret its nodes.
+
+use std::ops::RangeInclusive;
+
+use mlua::Lua;
+use tree_sitter::Language;
+
+use crate::languages;
+use crate::ltreesitter;
+use crate::Error;
+
+/// The ABI versions that both tree-sitter runtimes support.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct SupportedVersions(RangeInclusive<usize>);
+
+fn rust_versions() -> RangeInclusive<usize> {
+    tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION
+}
+
+fn supported_versions(lua: &Lua) -> RangeInclusive<usize> {
+    lua.app_data_ref::<SupportedVersions>()
+        .map(|versions| versions.0.clone())
+        .unwrap_or_else(rust_versions)
+}
+
+/// Returns an error if a grammar's ABI version isn't supported by both tree-sitter runtimes.
+/// `name` is used in the error message if we can't find the name of the grammar.
+pub(crate) fn check_version(lua: &Lua, name: &str, version: usize) -> Result<(), mlua::Error> {
+    let supported = supported_versions(lua);
+    if supported.contains(&version) {
+        return Ok(());
+    }
+    Err(Error::LanguageVersionMismatch {
+        language: name.to_string(),
+        version,
+        min_version: *supported.start(),
+        max_version: *supported.end(),
+    }
+    .into())
+}
+
+/// Returns an error if a grammar's ABI version isn't supported by both tree-sitter runtimes.
+pub(crate) fn check_language(lua: &Lua, language: Language) -> Result<(), mlua::Error> {
+    let version = language.version();
+    if supported_versions(lua).contains(&version) {
+        return Ok(());
+    }
+    let name = languages::linked_languages(lua)
+        .into_iter()
+        .find(|(_, linked)| *linked == language)
+        .map(|(name, _)| name);
+    check_version(lua, name.as_deref().unwrap_or("(unknown)"), version)
+}
+
+/// Works out which ABI versions both tree-sitter runtimes support.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = ltreesitter::module(lua)?;
+    let rust = rust_versions();
+    let min = module
+        .get::<_, Option<usize>>("MIN_COMPATIBLE_LANGUAGE_VERSION")?
+        .unwrap_or(*rust.start());
+    let max = module
+        .get::<_, Option<usize>>("LANGUAGE_VERSION")?
+        .unwrap_or(*rust.end());
+    lua.set_app_data(SupportedVersions(
+        min.max(*rust.start())..=max.min(*rust.end()),
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_validate_grammar_versions() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let supported = supported_versions(&l);
+        assert!(supported.start() >= rust_versions().start());
+        assert!(supported.end() <= rust_versions().end());
+        check_language(&l, tree_sitter_python::language()).unwrap();
+
+        let err = check_version(&l, "ancient", 1).unwrap_err();
+        assert!(matches!(
+            Error::find(&err),
+            Some(Error::LanguageVersionMismatch { version: 1, .. })
+        ));
+
+        // Pretend that ltreesitter's runtime only supports grammars older than Python's.
+        let version = tree_sitter_python::language().version();
+        l.set_app_data(SupportedVersions(0..=version - 1));
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap_err();
+        let code = b"x = 1\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let err = l
+            .globals()
+            .set("parsed", parsed.with_source(code))
+            .unwrap_err();
+        assert!(matches!(
+            Error::find(&err),
+            Some(Error::LanguageVersionMismatch { max_version, .. }) if *max_version == version - 1
+        ));
+    }
+}
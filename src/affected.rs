@@ -0,0 +1,141 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Works out which patterns of a query need to be rerun after an edit.
+//!
+//! [`affected_patterns`] compares an edited tree with the tree that was reparsed from it, and
+//! returns the patterns whose matches might have changed: any pattern that matches within one of
+//! the changed ranges of either tree, plus every [non-local][crate::PatternMetadata::non_local]
+//! pattern, whose matches can be affected by changes outside of their own ranges.  The result is
+//! conservative; every pattern whose matches did change is included, but some patterns whose
+//! matches didn't might be included too.
+//!
+//! Predicates in patterns are evaluated against the new source for both trees, since the old tree
+//! has already been edited to use the new tree's byte offsets.
+//!
+//! In Lua, `require("ltreesitter_rs").affected_patterns(old_tree, new_tree, query)` does the same,
+//! and returns a list of 1-based pattern indexes.  `query` can be an ltreesitter query or the
+//! source of one.
+
+use std::collections::BTreeSet;
+
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+use tree_sitter::Tree;
+
+use crate::patterns;
+use crate::TreeWithSource;
+
+/// Returns the indexes of the patterns in `query` whose matches might differ between `old_tree`,
+/// which must have been edited to match `new_src`, and `new_tree`, which was parsed from
+/// `new_src`.
+pub fn affected_patterns(
+    query: &Query,
+    old_tree: &Tree,
+    new_tree: &Tree,
+    new_src: &[u8],
+) -> Vec<usize> {
+    let changed = old_tree.changed_ranges(new_tree).collect::<Vec<_>>();
+    if changed.is_empty() {
+        return Vec::new();
+    }
+    let mut affected = (0..query.pattern_count())
+        .filter(|index| query.is_pattern_non_local(*index))
+        .collect::<BTreeSet<_>>();
+    let mut cursor = QueryCursor::new();
+    for range in &changed {
+        cursor.set_byte_range(range.start_byte..range.end_byte);
+        for tree in [old_tree, new_tree] {
+            for query_match in cursor.matches(query, tree.root_node(), new_src) {
+                affected.insert(query_match.pattern_index);
+            }
+        }
+    }
+    affected.into_iter().collect()
+}
+
+/// Adds `affected_patterns` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let affected = lua.create_function(
+        |lua, (old_tree, new_tree, query): (TreeWithSource, TreeWithSource, Value)| {
+            let affected = |query: &Query| {
+                affected_patterns(query, &old_tree.tree, &new_tree.tree, new_tree.src)
+            };
+            let affected = match query {
+                Value::String(source) => affected(
+                    &Query::new(new_tree.tree.language(), source.to_str()?)
+                        .map_err(mlua::Error::external)?,
+                ),
+                query => patterns::with_compiled(lua, &query, |query, _| Ok(affected(query)))?,
+            };
+            Ok(affected
+                .into_iter()
+                .map(|index| index + 1)
+                .collect::<Vec<_>>())
+        },
+    )?;
+    crate::companion_module(lua)?.set("affected_patterns", affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+    use tree_sitter::InputEdit;
+    use tree_sitter::Point;
+
+    const QUERY: &str = r#"
+        (function_definition) @function
+        (integer) @number
+        (string) @string
+        ((expression_statement) @a . (expression_statement) @b)
+    "#;
+
+    #[test]
+    fn can_find_affected_patterns() {
+        let old_src = b"def a(): pass\nx = 1\n";
+        let new_src = b"def a(): pass\nx = \"s\"\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let mut old_tree = parser.parse(old_src, None).unwrap();
+        old_tree.edit(&InputEdit {
+            start_byte: 18,
+            old_end_byte: 19,
+            new_end_byte: 21,
+            start_position: Point::new(1, 4),
+            old_end_position: Point::new(1, 5),
+            new_end_position: Point::new(1, 7),
+        });
+        let new_tree = parser.parse(new_src, Some(&old_tree)).unwrap();
+        let query = Query::new(tree_sitter_python::language(), QUERY).unwrap();
+        assert_eq!(
+            vec![1, 2, 3],
+            affected_patterns(&query, &old_tree, &new_tree, new_src)
+        );
+        let unchanged = parser.parse(new_src, Some(&new_tree)).unwrap();
+        assert!(affected_patterns(&query, &new_tree, &unchanged, new_src).is_empty());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("old", old_tree.with_source(new_src))
+            .unwrap();
+        l.globals()
+            .set("new", new_tree.with_source(new_src))
+            .unwrap();
+        l.check(
+            r#"
+              local affected_patterns = require("ltreesitter_rs").affected_patterns
+              local affected = affected_patterns(old, new, "(integer) @n (string) @s")
+              assert(#affected == 2 and affected[1] == 1 and affected[2] == 2)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Parses large documents without blocking the thread that runs Lua.
+//!
+//! With the `async` feature, [`parse_in_background`] hands a parse to a shared pool of worker
+//! threads, and returns a future that resolves to the tree.  [`parse_into_lua`] then pushes the
+//! tree into Lua once it's ready, sharing the source buffer (a [`SharedSource`]) with the Lua tree
+//! instead of copying it.  Like [`LuaAnalysisService`][crate::LuaAnalysisService], the futures
+//! don't depend on any particular async runtime.
+//!
+//! Lua code gets the same thing via mlua's async support: `ltreesitter_rs.parse_async(language,
+//! source)` (where `language` is a language or a parser) yields the current coroutine until the
+//! parse finishes.  The host has to call into Lua with one of mlua's async methods (like
+//! [`Function::call_async`][mlua::Function::call_async]) for that to work.
+//!
+//! The pool has one worker per available CPU, and is started the first time that it's needed.
+
+use std::cell::RefCell;
+use std::num::NonZeroUsize;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Language;
+use tree_sitter::Parser;
+use tree_sitter::Tree;
+
+use crate::abi;
+use crate::service;
+use crate::AnalysisFuture;
+use crate::SharedSource;
+use crate::TSLanguage;
+use crate::TreeWithOwnedSource;
+
+type ParseJob = Box<dyn FnOnce() + Send>;
+
+static POOL: OnceLock<Mutex<mpsc::Sender<ParseJob>>> = OnceLock::new();
+
+fn pool() -> &'static Mutex<mpsc::Sender<ParseJob>> {
+    POOL.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<ParseJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name("ltreesitter-parse".to_string())
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+                .expect("Could not spawn parse worker");
+        }
+        Mutex::new(sender)
+    })
+}
+
+thread_local! {
+    static PARSER: RefCell<Parser> = RefCell::new(Parser::new());
+}
+
+fn parse(language: Language, src: &[u8]) -> Result<Tree, mlua::Error> {
+    PARSER.with(|parser| {
+        let mut parser = parser.borrow_mut();
+        parser
+            .set_language(language)
+            .map_err(mlua::Error::external)?;
+        parser
+            .parse(src, None)
+            .ok_or_else(|| mlua::Error::RuntimeError("parse did not finish".to_string()))
+    })
+}
+
+fn worker_failed() -> mlua::Error {
+    mlua::Error::RuntimeError("background parse failed".to_string())
+}
+
+/// Parses `src` on a background thread, and returns a future that resolves to its tree.
+pub fn parse_in_background(language: Language, src: SharedSource) -> AnalysisFuture<Tree> {
+    let (completion, future) = service::completion(worker_failed);
+    // If the worker panics, the completion is dropped, which resolves the future to an error.
+    let job: ParseJob = Box::new(move || completion.complete(parse(language, &src)));
+    let _ = pool().lock().unwrap().send(job);
+    future
+}
+
+/// Parses `src` on a background thread, and then pushes the tree into Lua.  The Lua tree reads its
+/// source from the same [`SharedSource`] that the background parse used, so it isn't copied.
+/// (Converting some other buffer into a `SharedSource` copies it once, before the parse.)
+pub async fn parse_into_lua<'lua, S>(
+    lua: &'lua Lua,
+    language: Language,
+    src: S,
+) -> Result<Value<'lua>, mlua::Error>
+where
+    S: Into<SharedSource>,
+{
+    // Check the grammar before doing the work of parsing with it.
+    abi::check_language(lua, language)?;
+    let src = src.into();
+    let tree = parse_in_background(language, src.clone()).await?;
+    TreeWithOwnedSource::new(tree, src).into_lua(lua)
+}
+
+/// Adds `parse_async` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let parse_async =
+        lua.create_async_function(|lua, (language, src): (TSLanguage, mlua::String)| {
+            let src = SharedSource::new(src.as_bytes());
+            parse_into_lua(lua, language.0, src)
+        })?;
+    crate::companion_module(lua)?.set("parse_async", parse_async)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::block_on;
+    use crate::Module;
+
+    #[test]
+    fn can_parse_in_the_background() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        let code = SharedSource::from("def double(x): return x * 2\n");
+        let parsed = block_on(parse_into_lua(
+            &l,
+            tree_sitter_python::language(),
+            code.clone(),
+        ))
+        .unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        assert_eq!(
+            "module",
+            l.load("return parsed:root():type()")
+                .eval::<String>()
+                .unwrap()
+        );
+        let shared: crate::TreeWithSource = l.globals().get("parsed").unwrap();
+        assert_eq!(code.as_ptr(), shared.src.as_ptr());
+        drop(shared);
+
+        let describe: mlua::Function = l
+            .load(
+                r#"
+                  return function(source)
+                    local parser = require("ltreesitter").require("python")
+                    local parsed = require("ltreesitter_rs").parse_async(parser, source)
+                    return parsed:root():child(0):type()
+                  end
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let kind: String = block_on(describe.call_async("class A: pass\n")).unwrap();
+        assert_eq!("class_definition", kind);
+    }
+}
@@ -0,0 +1,126 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+
+use mlua::FromLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Node;
+use tree_sitter::Tree;
+
+use crate::abi;
+use crate::limits;
+use crate::ltreesitter;
+use crate::stores;
+use crate::trees;
+use crate::Anchor;
+use crate::LuaNode;
+
+/// A read-only view of a tree that is owned by Lua.
+///
+/// Converting an ltreesitter tree into a [`TreeWithSource`][crate::TreeWithSource] copies the
+/// underlying tree-sitter tree, since the Rust bindings want to own the tree that they wrap.  A
+/// `TreeRef` borrows Lua's tree instead, so converting one is cheap, which is what you want when
+/// Rust code only needs to look at a tree for the duration of a call.  The `TreeRef` keeps the Lua
+/// tree alive, and Lua code can't close the tree while the `TreeRef` is live.
+pub struct TreeRef<'lua> {
+    // Lua owns the tree, so we must never free it.
+    tree: ManuallyDrop<Tree>,
+    pub src: &'lua [u8],
+    anchor: Anchor<'lua>,
+}
+
+impl<'lua> TreeRef<'lua> {
+    /// Returns the borrowed tree.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Wraps a node of this tree so that it can be converted into an ltreesitter node.
+    pub fn lua_node<'t>(&'t self, node: Node<'t>) -> LuaNode<'t> {
+        LuaNode::anchored(node, self.anchor.clone())
+    }
+}
+
+impl Deref for TreeRef<'_> {
+    type Target = Tree;
+    fn deref(&self) -> &Self::Target {
+        &self.tree
+    }
+}
+
+impl<'lua> FromLua<'lua> for TreeRef<'lua> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let ltreesitter_tree = ltreesitter::as_tree(lua, &value)?.ok_or_else(|| {
+            mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "TreeRef",
+                message: Some("expected an ltreesitter tree".to_string()),
+            }
+        })?;
+        let ts_tree = unsafe { (*ltreesitter_tree).tree };
+        if ts_tree.is_null() {
+            return Err(trees::closed_error());
+        }
+        abi::check_language(
+            lua,
+            unsafe { ltreesitter::root_node(ltreesitter_tree) }.language(),
+        )?;
+        trees::check_generation(lua, &value)?;
+        limits::check_depth(lua, unsafe { ltreesitter::root_node(ltreesitter_tree) })?;
+        let src = stores::source(lua, &value)?
+            .ok_or_else(|| stores::not_in_memory(value.type_name(), "TreeRef"))?;
+        Ok(TreeRef {
+            tree: ManuallyDrop::new(unsafe { Tree::from_raw(ts_tree) }),
+            src,
+            anchor: Anchor::new(lua, value, ts_tree),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::IntoLua;
+
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_borrow_lua_trees() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let function_name = l
+            .create_function(|lua, tree: TreeRef| {
+                let definition = tree.root_node().child(0).unwrap();
+                let name = definition.child_by_field_name("name").unwrap();
+                let name_text = name.utf8_text(tree.src).unwrap().to_string();
+                Ok((name_text, tree.lua_node(name).into_lua(lua)?))
+            })
+            .unwrap();
+        l.globals().set("function_name", function_name).unwrap();
+        l.check(
+            r#"
+              local name, node = function_name(parsed)
+              assert(name == "double" and node:source() == "double")
+            "#,
+        );
+
+        let tree: TreeRef = l.call(r#" return parsed "#);
+        assert_eq!("module", tree.root_node().kind());
+        assert!(l.load(r#" parsed:close() "#).exec().is_err());
+        drop(tree);
+        l.check(r#" parsed:close() "#);
+    }
+}
@@ -0,0 +1,285 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Bounds the time that an analysis pass can spend parsing, querying, and traversing trees.
+//!
+//! Interactive hosts need bounded latency, not best-effort completion.  A host creates a [`Budget`]
+//! with a total time limit, and optionally limits for each [`Phase`] of the analysis, and passes it
+//! into the operations that might take a long time.  Instead of running to completion, those
+//! operations stop once the budget runs out, and return a [`Budgeted`] value that holds whatever
+//! they managed to produce, along with a marker saying that the budget was exceeded.  Clones of a
+//! budget share the same clock.
+//!
+//! Budgets can be pushed into Lua, where they have the following methods:
+//!
+//! - `budget:matches(tree, query)` returns a packed match buffer of the matches of `query` (the
+//!   source of a query) that were found before the query phase ran out, and whether it ran out.
+//! - `budget:run(phase, fn, ...)` calls `fn`, charging the time it takes to `phase`.
+//! - `budget:check(phase)` returns whether there's time left for `phase`, including the time spent
+//!   so far by a `run` call that's still in progress.  Lua traversals should call this regularly.
+//! - `budget:remaining_ms(phase)` returns the time that's left for `phase`.
+//!
+//! Phases are named `"parse"`, `"query"`, and `"traversal"`.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use mlua::Value;
+use tree_sitter::Node;
+use tree_sitter::Parser;
+use tree_sitter::Query;
+use tree_sitter::Tree;
+
+use crate::threads::Shared;
+use crate::MatchBuffer;
+use crate::TreeWithSource;
+
+/// One of the phases of an analysis pass.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Phase {
+    Parse,
+    Query,
+    Traversal,
+}
+
+impl Phase {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl<'lua> FromLua<'lua> for Phase {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let name = String::from_lua(value, lua)?;
+        match name.as_str() {
+            "parse" => Ok(Phase::Parse),
+            "query" => Ok(Phase::Query),
+            "traversal" => Ok(Phase::Traversal),
+            _ => Err(mlua::Error::RuntimeError(format!(
+                "unknown budget phase {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// The result of an operation that was bounded by a [`Budget`].
+#[derive(Clone, Debug)]
+pub struct Budgeted<T> {
+    /// Whatever the operation produced before it finished, or before the budget ran out.
+    pub value: T,
+    /// Whether the budget ran out before the operation finished.
+    pub exceeded: bool,
+}
+
+struct BudgetState {
+    start: Instant,
+    total: Duration,
+    limits: [Option<Duration>; 3],
+    spent: [Duration; 3],
+    active: Option<(Phase, Instant)>,
+}
+
+/// A time budget for an analysis pass.
+#[derive(Clone)]
+pub struct Budget {
+    state: Shared<BudgetState>,
+}
+
+impl Budget {
+    /// Creates a new budget, whose clock starts right away.
+    pub fn new(total: Duration) -> Budget {
+        Budget {
+            state: Shared::new(BudgetState {
+                start: Instant::now(),
+                total,
+                limits: [None; 3],
+                spent: [Duration::ZERO; 3],
+                active: None,
+            }),
+        }
+    }
+
+    /// Limits the time that can be spent in one phase.
+    pub fn with_phase_limit(self, phase: Phase, limit: Duration) -> Budget {
+        self.state.borrow_mut().limits[phase.index()] = Some(limit);
+        self
+    }
+
+    /// Returns the time that has been spent in a phase.
+    pub fn spent(&self, phase: Phase) -> Duration {
+        let state = self.state.borrow();
+        let mut spent = state.spent[phase.index()];
+        if let Some((active, since)) = state.active {
+            if active == phase {
+                spent += since.elapsed();
+            }
+        }
+        spent
+    }
+
+    /// Returns the time that's left for a phase: the smaller of what's left of the total budget
+    /// and what's left of the phase's own limit.
+    pub fn remaining(&self, phase: Phase) -> Duration {
+        let (start, total, limit) = {
+            let state = self.state.borrow();
+            (state.start, state.total, state.limits[phase.index()])
+        };
+        let remaining = total.saturating_sub(start.elapsed());
+        match limit {
+            Some(limit) => remaining.min(limit.saturating_sub(self.spent(phase))),
+            None => remaining,
+        }
+    }
+
+    /// Returns whether there's no time left for a phase.
+    pub fn is_exhausted(&self, phase: Phase) -> bool {
+        self.remaining(phase).is_zero()
+    }
+
+    /// Calls `f`, charging the time that it takes to a phase.
+    pub fn run<R, F: FnOnce() -> R>(&self, phase: Phase, f: F) -> R {
+        let previous = self
+            .state
+            .borrow_mut()
+            .active
+            .replace((phase, Instant::now()));
+        let result = f();
+        let mut state = self.state.borrow_mut();
+        if let Some((phase, since)) = state.active.take() {
+            state.spent[phase.index()] += since.elapsed();
+        }
+        state.active = previous.map(|(phase, _)| (phase, Instant::now()));
+        result
+    }
+
+    /// Parses `src` with `parser`, giving up if the parse phase runs out.  If it does, the parser
+    /// is reset, so that the next parse starts from scratch.  Returns an error if the parser
+    /// doesn't have a language, since it would give up for that reason instead.
+    pub fn parse(
+        &self,
+        parser: &mut Parser,
+        src: &[u8],
+        old_tree: Option<&Tree>,
+    ) -> Result<Budgeted<Option<Tree>>, mlua::Error> {
+        if parser.language().is_none() {
+            return Err(mlua::Error::RuntimeError(
+                "cannot parse without a language".to_string(),
+            ));
+        }
+        let remaining = self.remaining(Phase::Parse);
+        if remaining.is_zero() {
+            return Ok(Budgeted {
+                value: None,
+                exceeded: true,
+            });
+        }
+        let timeout = parser.timeout_micros();
+        parser.set_timeout_micros((remaining.as_micros() as u64).max(1));
+        let tree = self.run(Phase::Parse, || parser.parse(src, old_tree));
+        parser.set_timeout_micros(timeout);
+        // With a language set, the parser only gives up when it times out.
+        let exceeded = tree.is_none();
+        if exceeded {
+            parser.reset();
+        }
+        Ok(Budgeted {
+            value: tree,
+            exceeded,
+        })
+    }
+
+    /// Collects the matches of `query` over `node`, stopping if the query phase runs out.
+    pub fn matches(&self, query: &Query, node: Node, src: &[u8]) -> Budgeted<MatchBuffer> {
+        let (buffer, complete) = self.run(Phase::Query, || {
+            MatchBuffer::new_while(query, node, src, || !self.is_exhausted(Phase::Query))
+        });
+        Budgeted {
+            value: buffer,
+            exceeded: !complete,
+        }
+    }
+}
+
+impl UserData for Budget {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "matches",
+            |_, budget, (tree, query): (TreeWithSource, String)| {
+                let query =
+                    Query::new(tree.tree.language(), &query).map_err(mlua::Error::external)?;
+                let result = budget.matches(&query, tree.tree.root_node(), tree.src);
+                Ok((result.value, result.exceeded))
+            },
+        );
+        methods.add_method(
+            "run",
+            |_, budget, (phase, f, args): (Phase, Function, MultiValue)| {
+                budget.run(phase, || f.call::<_, MultiValue>(args))
+            },
+        );
+        methods.add_method("check", |_, budget, phase: Phase| {
+            Ok(!budget.is_exhausted(phase))
+        });
+        methods.add_method("remaining_ms", |_, budget, phase: Phase| {
+            Ok(budget.remaining(phase).as_secs_f64() * 1000.0)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_stop_when_budget_runs_out() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let query = Query::new(tree_sitter_python::language(), "(identifier) @id").unwrap();
+
+        let budget =
+            Budget::new(Duration::from_secs(60)).with_phase_limit(Phase::Query, Duration::ZERO);
+        let parsed = budget.parse(&mut parser, code, None).unwrap();
+        assert!(!parsed.exceeded);
+        let parsed = parsed.value.unwrap();
+        let matches = budget.matches(&query, parsed.root_node(), code);
+        assert!(matches.exceeded);
+        assert!(matches.value.is_empty());
+
+        let budget = Budget::new(Duration::ZERO);
+        assert!(budget.parse(&mut parser, code, None).unwrap().exceeded);
+        let mut no_language = tree_sitter::Parser::new();
+        assert!(budget.parse(&mut no_language, code, None).is_err());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let budget = Budget::new(Duration::from_secs(60))
+            .with_phase_limit(Phase::Traversal, Duration::from_secs(30));
+        l.globals().set("budget", budget.clone()).unwrap();
+        l.check(
+            r#"
+              local matches, exceeded = budget:matches(parsed, "(identifier) @id")
+              assert(#matches == 3 and not exceeded)
+              local checked = budget:run("traversal", function(x)
+                return budget:check("traversal") and x
+              end, 7)
+              assert(checked == 7)
+              assert(budget:remaining_ms("traversal") <= 30000)
+            "#,
+        );
+    }
+}
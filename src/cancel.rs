@@ -0,0 +1,180 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Cancellation that propagates between Lua scripts and the Rust operations that they drive.
+//!
+//! A [`CancellationToken`] is a flag that can be shared across threads.  Every
+//! [`AnalysisContext`] has one, so a Lua script can check `ctx:cancelled()` to see whether the
+//! host has given up on it, and can call `ctx:cancel()` to give up itself.  Long-running Rust
+//! operations accept a token too, and stop promptly once it's cancelled, returning whatever they
+//! had produced so far:
+//!
+//! - [`DirectoryStream::with_cancellation`][crate::DirectoryStream::with_cancellation] stops
+//!   walking and parsing, and waits for its worker threads to exit.
+//! - [`MatchBuffer::new_cancellable`][crate::MatchBuffer::new_cancellable] stops collecting
+//!   matches.  In Lua, `packed_matches(tree, query, ctx)` does the same, and returns whether it
+//!   finished as a second result.
+//!
+//! Cancelling a token also cancels all of its [children][CancellationToken::child], but not its
+//! parent, so a script can cancel the operations that it started without cancelling itself.
+//!
+//! In Lua, tokens have `cancel()`, `cancelled()`, and `child()` methods, and `ctx.cancellation`
+//! returns the token of an analysis context.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use mlua::AnyUserData;
+use mlua::UserData;
+use mlua::UserDataMethods;
+
+use crate::AnalysisContext;
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    parent: Option<CancellationToken>,
+}
+
+/// A flag that tells an operation that it should stop.  Clones of a token share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    /// Creates a new token that hasn't been cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Creates a new token that is cancelled whenever this one is, but that can also be cancelled
+    /// on its own.
+    pub fn child(&self) -> CancellationToken {
+        CancellationToken {
+            state: Arc::new(TokenState {
+                cancelled: AtomicBool::new(false),
+                parent: Some(self.clone()),
+            }),
+        }
+    }
+
+    /// Cancels this token, and all of its children.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether this token, or any of its ancestors, has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        let mut token = self;
+        loop {
+            if token.state.cancelled.load(Ordering::SeqCst) {
+                return true;
+            }
+            match &token.state.parent {
+                Some(parent) => token = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &CancellationToken) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl UserData for CancellationToken {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("cancel", |_, token, ()| {
+            token.cancel();
+            Ok(())
+        });
+        methods.add_method("cancelled", |_, token, ()| Ok(token.is_cancelled()));
+        methods.add_method("child", |_, token, ()| Ok(token.child()));
+    }
+}
+
+/// Returns the cancellation token of a Lua value, which can be a token or an analysis context.
+pub(crate) fn token_from(udata: &AnyUserData) -> Result<CancellationToken, mlua::Error> {
+    if let Ok(ctx) = udata.borrow::<AnalysisContext>() {
+        return Ok(ctx.cancellation.clone());
+    }
+    Ok(udata.borrow::<CancellationToken>()?.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::DirectoryStream;
+    use crate::Module;
+    use crate::WithSource;
+    use mlua::Function;
+    use mlua::Lua;
+    use std::path::Path;
+
+    #[test]
+    fn can_cancel_from_lua() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        child.cancel();
+        assert!(child.is_cancelled() && !parent.is_cancelled());
+        let grandchild = parent.child().child();
+        parent.cancel();
+        assert!(grandchild.is_cancelled());
+
+        let dir =
+            std::env::temp_dir().join(format!("mlua-tree-sitter-cancel-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            std::fs::write(dir.join(format!("{}.py", name)), "x = 1\n").unwrap();
+        }
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let ctx = AnalysisContext::new();
+        let token = ctx.cancellation.clone();
+        l.globals().set("ctx", ctx).unwrap();
+        let callback: Function = l
+            .load(
+                r#"
+                  return function(path, tree)
+                    assert(not ctx:cancelled())
+                    ctx:cancel()
+                  end
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let python = |path: &Path| (path.extension()? == "py").then(tree_sitter_python::language);
+        let stats = DirectoryStream::new(&dir, python)
+            .with_cancellation(token.clone())
+            .run(callback)
+            .unwrap();
+        assert_eq!(1, stats.delivered);
+        assert!(stats.cancelled);
+        assert!(token.is_cancelled());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let code = b"x = 1\ny = 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local packed_matches = require("ltreesitter_rs").packed_matches
+              assert(ctx:cancelled() and ctx.cancellation:cancelled())
+              local buffer, complete = packed_matches(parsed, "(integer) @number", ctx)
+              assert(#buffer == 0 and not complete)
+              local fresh = packed_matches(parsed, "(integer) @number")
+              assert(#fresh == 2)
+            "#,
+        );
+    }
+}
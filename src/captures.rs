@@ -0,0 +1,195 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Maps the named captures of a query match into typed Rust structs.
+//!
+//! Implement [`FromCaptures`] for a struct (usually via the
+//! [`from_captures!`][crate::from_captures] macro), and then use [`typed_matches`] to run a query
+//! and get back a value of that struct for each match, instead of looking up each capture by name.
+
+use tree_sitter::Node;
+use tree_sitter::Query;
+use tree_sitter::QueryCapture;
+use tree_sitter::QueryCursor;
+use tree_sitter::QueryMatch;
+
+use crate::TSNode;
+
+/// The named captures of a single query match.
+pub struct Captures<'a, 'tree> {
+    query: &'a Query,
+    pattern_index: usize,
+    captures: &'a [QueryCapture<'tree>],
+}
+
+impl<'a, 'tree> Captures<'a, 'tree> {
+    /// Wraps the captures of a query match.
+    pub fn new(query: &'a Query, query_match: &'a QueryMatch<'_, 'tree>) -> Captures<'a, 'tree> {
+        Captures {
+            query,
+            pattern_index: query_match.pattern_index,
+            captures: query_match.captures,
+        }
+    }
+
+    /// Returns the index of the pattern that produced this match.
+    pub fn pattern_index(&self) -> usize {
+        self.pattern_index
+    }
+
+    /// Returns all of the nodes that were captured with the given name.
+    pub fn all(&self, name: &str) -> Vec<TSNode<'tree>> {
+        let index = match self.query.capture_index_for_name(name) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        self.captures
+            .iter()
+            .filter(|capture| capture.index == index)
+            .map(|capture| TSNode::new(capture.node))
+            .collect()
+    }
+
+    /// Returns the first node that was captured with the given name, if there is one.
+    pub fn get(&self, name: &str) -> Option<TSNode<'tree>> {
+        let index = self.query.capture_index_for_name(name)?;
+        self.captures
+            .iter()
+            .find(|capture| capture.index == index)
+            .map(|capture| TSNode::new(capture.node))
+    }
+
+    /// Returns the first node that was captured with the given name, or an error if there isn't
+    /// one.
+    pub fn require(&self, name: &str) -> Result<TSNode<'tree>, mlua::Error> {
+        self.get(name).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!(
+                "pattern {} has no capture named {}",
+                self.pattern_index, name
+            ))
+        })
+    }
+}
+
+/// A type that can be built from the named captures of a query match.
+pub trait FromCaptures<'tree>: Sized {
+    fn from_captures(captures: &Captures<'_, 'tree>) -> Result<Self, mlua::Error>;
+}
+
+/// A type that can be the field of a struct built by the
+/// [`from_captures!`][crate::from_captures] macro.  [`TSNode`] fields are required, `Option`
+/// fields are optional, and `Vec` fields collect every node with the capture's name.
+pub trait CaptureField<'tree>: Sized {
+    fn from_capture(captures: &Captures<'_, 'tree>, name: &str) -> Result<Self, mlua::Error>;
+}
+
+impl<'tree> CaptureField<'tree> for TSNode<'tree> {
+    fn from_capture(captures: &Captures<'_, 'tree>, name: &str) -> Result<Self, mlua::Error> {
+        captures.require(name)
+    }
+}
+
+impl<'tree> CaptureField<'tree> for Option<TSNode<'tree>> {
+    fn from_capture(captures: &Captures<'_, 'tree>, name: &str) -> Result<Self, mlua::Error> {
+        Ok(captures.get(name))
+    }
+}
+
+impl<'tree> CaptureField<'tree> for Vec<TSNode<'tree>> {
+    fn from_capture(captures: &Captures<'_, 'tree>, name: &str) -> Result<Self, mlua::Error> {
+        Ok(captures.all(name))
+    }
+}
+
+/// Defines a struct and implements [`FromCaptures`] for it.  Each field is filled in from the
+/// capture with the same name as the field.
+///
+/// ```
+/// use mlua_tree_sitter::from_captures;
+/// use mlua_tree_sitter::TSNode;
+///
+/// from_captures! {
+///     struct FnDef<'tree> {
+///         name: TSNode<'tree>,
+///         body: Option<TSNode<'tree>>,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! from_captures {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident<$tree:lifetime> {
+            $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $type:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name<$tree> {
+            $($(#[$field_attr])* $field_vis $field: $type),*
+        }
+
+        impl<$tree> $crate::FromCaptures<$tree> for $name<$tree> {
+            fn from_captures(
+                captures: &$crate::Captures<'_, $tree>,
+            ) -> ::std::result::Result<Self, ::mlua::Error> {
+                Ok($name {
+                    $($field: $crate::CaptureField::from_capture(captures, stringify!($field))?),*
+                })
+            }
+        }
+    };
+}
+
+/// Executes a query against a node, returning a typed value for each match.
+pub fn typed_matches<'tree, T: FromCaptures<'tree>>(
+    query: &Query,
+    node: Node<'tree>,
+    src: &[u8],
+) -> Result<Vec<T>, mlua::Error> {
+    let mut cursor = QueryCursor::new();
+    cursor
+        .matches(query, node, src)
+        .map(|query_match| T::from_captures(&Captures::new(query, &query_match)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    from_captures! {
+        struct FnDef<'tree> {
+            name: TSNode<'tree>,
+            params: Vec<TSNode<'tree>>,
+            decorator: Option<TSNode<'tree>>,
+        }
+    }
+
+    #[test]
+    fn can_map_captures_into_structs() {
+        let code = b"def double(x): return x * 2\n@cache\ndef negate(a): return -a\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let query = Query::new(
+            tree_sitter_python::language(),
+            r#"
+              (function_definition
+                name: (identifier) @name
+                parameters: (parameters (identifier) @params))
+            "#,
+        )
+        .unwrap();
+        let defs: Vec<FnDef> = typed_matches(&query, parsed.root_node(), code).unwrap();
+        let names = defs
+            .iter()
+            .map(|def| def.name.utf8_text(code).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["double", "negate"], names);
+        assert!(defs.iter().all(|def| def.params.len() == 1));
+        assert!(defs.iter().all(|def| def.decorator.is_none()));
+    }
+}
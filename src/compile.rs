@@ -0,0 +1,200 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Compiles grammars from source at runtime.  Requires the `grammar-compile` feature.
+//!
+//! Point a [`GrammarCompiler`] at a checked-out grammar repository (one with a generated
+//! `src/parser.c`, and optionally a `src/scanner.c` or `src/scanner.cc`), and it will build a
+//! shared library using the system's C compiler, and return a [`LanguagePack`] that loads it.
+//! Any `queries/*.scm` files in the repository are added to the pack, keyed by file stem.  The
+//! library is only rebuilt when it's older than the grammar's sources.
+//!
+//! In Lua, `require("ltreesitter_rs").languages:compile_grammar(repo_dir)` compiles a grammar
+//! and adds it to the language registry, so that `languages:parser(name)` can load it.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::read_query_file;
+use crate::LanguagePack;
+
+/// Compiles grammar repositories into shared libraries.
+pub struct GrammarCompiler {
+    out_dir: PathBuf,
+}
+
+impl Default for GrammarCompiler {
+    fn default() -> GrammarCompiler {
+        GrammarCompiler::new(std::env::temp_dir().join("mlua-tree-sitter-grammars"))
+    }
+}
+
+impl GrammarCompiler {
+    /// Creates a new compiler that puts the libraries it builds into `out_dir`.
+    pub fn new<P: Into<PathBuf>>(out_dir: P) -> GrammarCompiler {
+        GrammarCompiler {
+            out_dir: out_dir.into(),
+        }
+    }
+
+    /// Compiles the grammar in `repo`.  If `name` isn't given, it's derived from the repository's
+    /// directory name, so that `tree-sitter-python` becomes `python`.
+    pub fn compile<P: AsRef<Path>>(
+        &self,
+        repo: P,
+        name: Option<&str>,
+    ) -> Result<LanguagePack, mlua::Error> {
+        let repo = repo.as_ref();
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => grammar_name(repo)?,
+        };
+        let src_dir = repo.join("src");
+        let parser = src_dir.join("parser.c");
+        if !parser.is_file() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "{} is not a grammar repository: missing {}",
+                repo.display(),
+                parser.display()
+            )));
+        }
+        let c_scanner = src_dir.join("scanner.c");
+        let cpp_scanner = src_dir.join("scanner.cc");
+        let scanner = [c_scanner, cpp_scanner]
+            .into_iter()
+            .find(|path| path.is_file());
+
+        std::fs::create_dir_all(&self.out_dir).map_err(mlua::Error::external)?;
+        let library = self
+            .out_dir
+            .join(format!("{}{}", name, std::env::consts::DLL_SUFFIX));
+        let sources = std::iter::once(&parser).chain(scanner.as_ref());
+        if needs_rebuild(&library, sources)? {
+            build(&src_dir, &parser, scanner.as_deref(), &library)?;
+        }
+
+        let mut pack = LanguagePack::new(name).with_library(library);
+        if let Ok(entries) = std::fs::read_dir(repo.join("queries")) {
+            for entry in entries {
+                let path = entry.map_err(mlua::Error::external)?.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("scm") {
+                    continue;
+                }
+                if let Some(kind) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    let kind = kind.to_string();
+                    pack = pack.with_query(kind, read_query_file(&path)?);
+                }
+            }
+        }
+        Ok(pack)
+    }
+}
+
+fn grammar_name(repo: &Path) -> Result<String, mlua::Error> {
+    let dir_name = repo
+        .canonicalize()
+        .ok()
+        .and_then(|repo| repo.file_name()?.to_str().map(str::to_string))
+        .ok_or_else(|| {
+            mlua::Error::RuntimeError(format!(
+                "cannot determine grammar name of {}",
+                repo.display()
+            ))
+        })?;
+    let name = dir_name.strip_prefix("tree-sitter-").unwrap_or(&dir_name);
+    Ok(name.replace('-', "_"))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn needs_rebuild<'a>(
+    library: &Path,
+    sources: impl Iterator<Item = &'a PathBuf>,
+) -> Result<bool, mlua::Error> {
+    let library_modified = match modified(library) {
+        Some(library_modified) => library_modified,
+        None => return Ok(true),
+    };
+    for source in sources {
+        if modified(source).map_or(true, |source_modified| source_modified > library_modified) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn build(
+    src_dir: &Path,
+    parser: &Path,
+    scanner: Option<&Path>,
+    library: &Path,
+) -> Result<(), mlua::Error> {
+    let is_cpp = scanner.map_or(false, |scanner| scanner.extension() == Some("cc".as_ref()));
+    let target = env!("MLUA_TREE_SITTER_TARGET");
+    let compiler = cc::Build::new()
+        .cpp(is_cpp)
+        .target(target)
+        .host(target)
+        .opt_level(2)
+        .cargo_metadata(false)
+        .warnings(false)
+        .try_get_compiler()
+        .map_err(mlua::Error::external)?;
+    let mut command = compiler.to_command();
+    if compiler.is_like_msvc() {
+        command.arg("-LD").arg("-utf-8");
+        command.arg(format!("-I{}", src_dir.display()));
+        command.arg(format!("-Fe{}", library.display()));
+        command.arg(parser);
+        command.args(scanner);
+    } else {
+        command.arg("-shared").arg("-fPIC").arg("-fno-exceptions");
+        command.arg("-I").arg(src_dir);
+        command.arg("-o").arg(library);
+        command.arg("-xc").arg(parser);
+        if let Some(scanner) = scanner {
+            command
+                .arg(if is_cpp { "-xc++" } else { "-xc" })
+                .arg(scanner);
+        }
+    }
+    let output = command.output().map_err(mlua::Error::external)?;
+    if !output.status.success() {
+        return Err(mlua::Error::RuntimeError(format!(
+            "failed to compile {}:\n{}",
+            parser.display(),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_reject_directories_that_are_not_grammars() {
+        let repo =
+            std::env::temp_dir().join(format!("tree-sitter-not-a-grammar-{}", std::process::id()));
+        std::fs::create_dir_all(&repo).unwrap();
+        assert_eq!(
+            format!("not_a_grammar_{}", std::process::id()),
+            grammar_name(&repo).unwrap()
+        );
+        let err = GrammarCompiler::default()
+            .compile(&repo, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing"), "unexpected error: {}", err);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A harness that checks that the Lua-facing API behaves the same way in every Lua environment
+//! that a host creates.
+//!
+//! The conformance suite is data: each [`ConformanceCase`] is a Lua chunk, together with the
+//! values that it should return.  [`conformance`] returns the built-in suite, which exercises the
+//! core API, and Lua code can get the same cases from `require("ltreesitter_rs").conformance()`,
+//! as a list of `{name, chunk, expected, requires_language}` tables.  That lets alternative
+//! backends (like a pure-Rust implementation of the ltreesitter userdata, or one running on Luau)
+//! check that they behave the same way as the C ltreesitter library, without depending on this
+//! crate's test code.
+//!
+//! Each chunk is called with the name of a grammar (or `nil`), and its results are converted to
+//! strings with Lua's `tostring`.  A case passes if it doesn't raise an error, and if those
+//! strings match its expected outputs.  Cases that parse something are marked as requiring a
+//! language, and are skipped when there isn't one.
+//!
+//! A [`ConformanceHarness`] runs a suite, each case in a fresh Lua environment, for each of its
+//! _flavors_.  A flavor is a named function that creates a Lua environment; the harness loads the
+//! `ltreesitter` module into it, and registers the harness's grammars.  Downstream crates can add
+//! their own cases, so that they can check their own scripts the same way.
+//!
+//! mlua links in exactly one Lua implementation per build, so the default flavor uses whichever
+//! one that is, and the report names it (via `_VERSION`).  To compare several Lua versions, build
+//! and run the harness once per mlua feature (for instance, in a CI matrix); to compare several
+//! configurations of the same Lua (like a sandboxed one), add a flavor for each.
+
+use std::fmt::Display;
+
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use tree_sitter::Language;
+
+use crate::Module;
+
+/// One Lua chunk of a conformance suite.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConformanceCase {
+    pub name: String,
+    pub chunk: String,
+    /// The results that the chunk should return, after being passed through `tostring`.  If this
+    /// is `None`, the chunk only has to run without raising an error.
+    pub expected: Option<Vec<String>>,
+    /// Whether the chunk needs the name of a grammar to parse with.
+    pub requires_language: bool,
+}
+
+impl ConformanceCase {
+    pub fn new<N: Into<String>, C: Into<String>>(name: N, chunk: C) -> ConformanceCase {
+        ConformanceCase {
+            name: name.into(),
+            chunk: chunk.into(),
+            expected: None,
+            requires_language: false,
+        }
+    }
+
+    /// Sets the results that the chunk should return.
+    pub fn expecting<I, S>(mut self, expected: I) -> ConformanceCase
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.expected = Some(expected.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Marks the chunk as needing the name of a grammar.
+    pub fn requiring_language(mut self) -> ConformanceCase {
+        self.requires_language = true;
+        self
+    }
+
+    fn to_lua<'lua>(&self, lua: &'lua Lua) -> Result<Table<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        table.set("name", self.name.as_str())?;
+        table.set("chunk", self.chunk.as_str())?;
+        table.set("expected", self.expected.clone())?;
+        table.set("requires_language", self.requires_language)?;
+        Ok(table)
+    }
+}
+
+struct BuiltinCase {
+    name: &'static str,
+    chunk: &'static str,
+    expected: &'static [&'static str],
+    requires_language: bool,
+}
+
+const BUILTIN_CASES: &[BuiltinCase] = &[
+    BuiltinCase {
+        name: "companion module",
+        chunk: r#"
+          local ltreesitter_rs = require("ltreesitter_rs")
+          return type(ltreesitter_rs.api_version), require("ltreesitter_rs.v1") == ltreesitter_rs.v1
+        "#,
+        expected: &["number", "true"],
+        requires_language: false,
+    },
+    BuiltinCase {
+        name: "range arithmetic",
+        chunk: r#"
+          local ranges = require("ltreesitter_rs").ranges
+          local a = { start_byte = 0, end_byte = 4 }
+          local b = { start_byte = 2, end_byte = 6 }
+          return ranges.overlap(a, b),
+            ranges.hull(a, b).end_byte,
+            tostring(ranges.intersection(a, { start_byte = 4, end_byte = 5 }))
+        "#,
+        expected: &["true", "6", "nil"],
+        requires_language: false,
+    },
+    BuiltinCase {
+        name: "parse and inspect",
+        chunk: r#"
+          local language = ...
+          local ltreesitter_rs = require("ltreesitter_rs")
+          local tree = require("ltreesitter").require(language):parse_string("")
+          local root = tree:root()
+          return ltreesitter_rs.is_tree(tree),
+            ltreesitter_rs.is_node(tree),
+            ltreesitter_rs.is_node(root),
+            root:start_byte(),
+            root:end_byte()
+        "#,
+        expected: &["true", "false", "true", "0", "0"],
+        requires_language: true,
+    },
+    BuiltinCase {
+        name: "close trees",
+        chunk: r#"
+          local language = ...
+          local tree = require("ltreesitter").require(language):parse_string("")
+          tree:close()
+          return (pcall(function() return tree:root() end))
+        "#,
+        expected: &["false"],
+        requires_language: true,
+    },
+];
+
+/// Returns the built-in conformance suite.
+pub fn conformance() -> Vec<ConformanceCase> {
+    BUILTIN_CASES
+        .iter()
+        .map(|case| {
+            let result = ConformanceCase::new(case.name, case.chunk)
+                .expecting(case.expected.iter().copied());
+            if case.requires_language {
+                result.requiring_language()
+            } else {
+                result
+            }
+        })
+        .collect()
+}
+
+/// Adds `conformance` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = crate::companion_module(lua)?;
+    module.set(
+        "conformance",
+        lua.create_function(|lua, ()| {
+            conformance()
+                .iter()
+                .map(|case| case.to_lua(lua))
+                .collect::<Result<Vec<_>, _>>()
+        })?,
+    )
+}
+
+/// A check that failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConformanceFailure {
+    /// The flavor that the check failed in, together with its Lua version.
+    pub flavor: String,
+    pub case: String,
+    pub message: String,
+}
+
+/// The results of running a conformance suite.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConformanceReport {
+    /// The number of checks (cases times flavors) that passed.
+    pub passed: usize,
+    /// The number of checks that were skipped, because they need a language and the harness
+    /// doesn't have one.
+    pub skipped: usize,
+    pub failures: Vec<ConformanceFailure>,
+}
+
+impl ConformanceReport {
+    /// Returns whether every check passed.
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl Display for ConformanceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} passed, {} skipped, {} failed",
+            self.passed,
+            self.skipped,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "[{}] {}: {}",
+                failure.flavor, failure.case, failure.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type Flavor = Box<dyn Fn() -> Result<Lua, mlua::Error>>;
+
+/// Runs a conformance suite against one or more flavors of Lua environment.
+pub struct ConformanceHarness {
+    flavors: Vec<(String, Flavor)>,
+    languages: Vec<(String, Language)>,
+    cases: Vec<ConformanceCase>,
+}
+
+impl Default for ConformanceHarness {
+    fn default() -> ConformanceHarness {
+        ConformanceHarness::new()
+    }
+}
+
+impl ConformanceHarness {
+    /// Creates a harness with the built-in suite, and a single `default` flavor, which creates
+    /// environments with [`Lua::new`].
+    pub fn new() -> ConformanceHarness {
+        ConformanceHarness {
+            flavors: vec![("default".to_string(), Box::new(|| Ok(Lua::new())))],
+            languages: Vec::new(),
+            cases: conformance(),
+        }
+    }
+
+    /// Adds a flavor.  `create` should return a Lua environment that doesn't have the
+    /// `ltreesitter` module loaded yet.
+    pub fn with_flavor<N, F>(mut self, name: N, create: F) -> ConformanceHarness
+    where
+        N: Into<String>,
+        F: Fn() -> Result<Lua, mlua::Error> + 'static,
+    {
+        self.flavors.push((name.into(), Box::new(create)));
+        self
+    }
+
+    /// Registers a grammar in every environment that the harness creates.
+    pub fn with_language<N: Into<String>>(
+        mut self,
+        name: N,
+        language: Language,
+    ) -> ConformanceHarness {
+        self.languages.push((name.into(), language));
+        self
+    }
+
+    /// Adds a case to the suite.
+    pub fn with_case(mut self, case: ConformanceCase) -> ConformanceHarness {
+        self.cases.push(case);
+        self
+    }
+
+    /// Runs every case in every flavor.
+    pub fn run(&self) -> ConformanceReport {
+        let mut report = ConformanceReport::default();
+        for (flavor, create) in &self.flavors {
+            for case in &self.cases {
+                if case.requires_language && self.languages.is_empty() {
+                    report.skipped += 1;
+                    continue;
+                }
+                let (label, result) = match create() {
+                    Ok(lua) => (flavor_label(flavor, &lua), self.run_case(&lua, case)),
+                    Err(err) => (flavor.clone(), Err(err)),
+                };
+                match result {
+                    Ok(()) => report.passed += 1,
+                    Err(err) => report.failures.push(ConformanceFailure {
+                        flavor: label,
+                        case: case.name.clone(),
+                        message: err.to_string(),
+                    }),
+                }
+            }
+        }
+        report
+    }
+
+    fn run_case(&self, lua: &Lua, case: &ConformanceCase) -> Result<(), mlua::Error> {
+        lua.open_ltreesitter()?;
+        for (name, language) in &self.languages {
+            lua.register_language(name, *language)?;
+        }
+        let language = self.languages.first().map(|(name, _)| name.as_str());
+        let results: MultiValue = lua.load(&case.chunk).set_name(&case.name).call(language)?;
+        let expected = match &case.expected {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let tostring: mlua::Function = lua.globals().get("tostring")?;
+        let actual = results
+            .into_iter()
+            .map(|value| tostring.call::<_, String>(value))
+            .collect::<Result<Vec<_>, _>>()?;
+        if &actual != expected {
+            return Err(mlua::Error::RuntimeError(format!(
+                "expected {:?}, got {:?}",
+                expected, actual
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn flavor_label(flavor: &str, lua: &Lua) -> String {
+    match lua.globals().get::<_, String>("_VERSION") {
+        Ok(version) => format!("{} ({})", flavor, version),
+        Err(_) => flavor.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+
+    #[test]
+    fn can_run_conformance_suite() {
+        let report = ConformanceHarness::new()
+            .with_language("python", tree_sitter_python::language())
+            .with_flavor("second", || Ok(Lua::new()))
+            .with_case(
+                ConformanceCase::new(
+                    "python modules",
+                    r#"
+                      local language = ...
+                      local tree = require("ltreesitter").require(language):parse_string("x = 1")
+                      return tree:root():type()
+                    "#,
+                )
+                .expecting(["module"])
+                .requiring_language(),
+            )
+            .run();
+        assert!(report.is_success(), "{}", report);
+        assert_eq!(2 * (BUILTIN_CASES.len() + 1), report.passed);
+
+        let report = ConformanceHarness::new()
+            .with_case(ConformanceCase::new("broken", "error('nope')"))
+            .with_case(ConformanceCase::new("wrong", "return 1, 2").expecting(["1"]))
+            .run();
+        assert_eq!(2, report.skipped);
+        assert_eq!(2, report.failures.len());
+        assert_eq!("broken", report.failures[0].case);
+        assert_eq!("wrong", report.failures[1].case);
+        assert!(report.failures[0].flavor.starts_with("default (Lua"));
+    }
+
+    #[test]
+    fn can_load_conformance_suite_from_lua() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let count: usize = l.call(
+            r#"
+              local cases = require("ltreesitter_rs").conformance()
+              for _, case in ipairs(cases) do
+                assert(type(case.chunk) == "string" and type(case.expected) == "table")
+                if not case.requires_language then
+                  local results = { (loadstring or load)(case.chunk)() }
+                  for i, expected in ipairs(case.expected) do
+                    assert(tostring(results[i]) == expected, case.name)
+                  end
+                end
+              end
+              return #cases
+            "#,
+        );
+        assert_eq!(conformance().len(), count);
+    }
+}
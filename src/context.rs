@@ -0,0 +1,508 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Structured configuration that a host passes to its scripts for each analysis run.
+//!
+//! An [`AnalysisContext`] holds metadata about the file being analyzed and a table of
+//! configuration values.  It's `Send`, so you can pass it as one of the arguments of an
+//! [`AnalysisJob`][crate::AnalysisJob].  In Lua, it's a userdata with `path` and `language`
+//! fields, and methods for reading configuration values:
+//!
+//! - `ctx:get(key, [default])` returns the value of `key`, or `default` if it isn't set.
+//! - `ctx:get_string(key)`, `ctx:get_bool(key)`, `ctx:get_integer(key)`, and
+//!   `ctx:get_number(key)` return `nil` if `key` isn't set, and raise an error if it has the
+//!   wrong type.
+//! - `ctx:config()` returns a copy of the whole configuration table.
+//! - `ctx:helper(name)` returns the host function that was registered in `ltreesitter_rs` with
+//!   the given name.
+//! - `ctx:cancelled()` returns whether the run has been cancelled, and `ctx:cancel()` cancels it.
+//!   (See [`CancellationToken`].)
+
+use std::collections::BTreeMap;
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Table;
+use mlua::TablePairs;
+use mlua::TableSequence;
+use mlua::UserData;
+use mlua::UserDataFields;
+use mlua::UserDataMethods;
+use mlua::Value;
+
+use crate::limits;
+use crate::CancellationToken;
+
+/// A configuration value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    Bool(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    List(Vec<ConfigValue>),
+    Table(BTreeMap<String, ConfigValue>),
+}
+
+impl ConfigValue {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ConfigValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            ConfigValue::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a number.  Integers are converted into numbers.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            ConfigValue::Integer(value) => Some(*value as f64),
+            ConfigValue::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ConfigValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[ConfigValue]> {
+        match self {
+            ConfigValue::List(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&BTreeMap<String, ConfigValue>> {
+        match self {
+            ConfigValue::Table(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            ConfigValue::Bool(_) => "boolean",
+            ConfigValue::Integer(_) => "integer",
+            ConfigValue::Number(_) => "number",
+            ConfigValue::String(_) => "string",
+            ConfigValue::List(_) => "list",
+            ConfigValue::Table(_) => "table",
+        }
+    }
+}
+
+impl From<bool> for ConfigValue {
+    fn from(value: bool) -> ConfigValue {
+        ConfigValue::Bool(value)
+    }
+}
+
+impl From<i64> for ConfigValue {
+    fn from(value: i64) -> ConfigValue {
+        ConfigValue::Integer(value)
+    }
+}
+
+impl From<f64> for ConfigValue {
+    fn from(value: f64) -> ConfigValue {
+        ConfigValue::Number(value)
+    }
+}
+
+impl From<&str> for ConfigValue {
+    fn from(value: &str) -> ConfigValue {
+        ConfigValue::String(value.to_string())
+    }
+}
+
+impl From<String> for ConfigValue {
+    fn from(value: String) -> ConfigValue {
+        ConfigValue::String(value)
+    }
+}
+
+impl<T: Into<ConfigValue>> From<Vec<T>> for ConfigValue {
+    fn from(value: Vec<T>) -> ConfigValue {
+        ConfigValue::List(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<'lua> IntoLua<'lua> for ConfigValue {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        // Nested lists and tables are converted with an explicit stack, so that deeply nested
+        // values can't overflow the Rust stack.
+        enum Frame<'lua> {
+            List(Table<'lua>, std::vec::IntoIter<ConfigValue>),
+            Table(
+                Table<'lua>,
+                std::collections::btree_map::IntoIter<String, ConfigValue>,
+                Option<String>,
+            ),
+        }
+        let mut stack: Vec<Frame> = Vec::new();
+        let mut next = self;
+        loop {
+            let mut done = match next {
+                ConfigValue::Bool(value) => Some(value.into_lua(lua)?),
+                ConfigValue::Integer(value) => Some(value.into_lua(lua)?),
+                ConfigValue::Number(value) => Some(value.into_lua(lua)?),
+                ConfigValue::String(value) => Some(value.into_lua(lua)?),
+                ConfigValue::List(values) => {
+                    limits::check_table_depth(lua, stack.len() + 1)?;
+                    stack.push(Frame::List(lua.create_table()?, values.into_iter()));
+                    None
+                }
+                ConfigValue::Table(values) => {
+                    limits::check_table_depth(lua, stack.len() + 1)?;
+                    stack.push(Frame::Table(lua.create_table()?, values.into_iter(), None));
+                    None
+                }
+            };
+            loop {
+                let frame = match stack.last_mut() {
+                    Some(frame) => frame,
+                    None => return Ok(done.expect("converted value is missing")),
+                };
+                let child = match frame {
+                    Frame::List(table, values) => {
+                        if let Some(value) = done.take() {
+                            table.raw_push(value)?;
+                        }
+                        values.next()
+                    }
+                    Frame::Table(table, values, key) => {
+                        if let Some(value) = done.take() {
+                            table.raw_set(key.take().unwrap_or_default(), value)?;
+                        }
+                        values.next().map(|(child_key, value)| {
+                            *key = Some(child_key);
+                            value
+                        })
+                    }
+                };
+                match child {
+                    Some(child) => {
+                        next = child;
+                        break;
+                    }
+                    None => {
+                        done = Some(match stack.pop() {
+                            Some(Frame::List(table, _)) | Some(Frame::Table(table, _, _)) => {
+                                Value::Table(table)
+                            }
+                            None => unreachable!(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for ConfigValue {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        // Nested tables are converted with an explicit stack, so that deeply nested tables can't
+        // overflow the Rust stack.  We keep track of the tables that we're in the middle of
+        // converting, so that we can report cycles instead of looping forever.
+        enum Frame<'lua> {
+            List(
+                *const std::ffi::c_void,
+                Vec<ConfigValue>,
+                TableSequence<'lua, Value<'lua>>,
+            ),
+            Table(
+                *const std::ffi::c_void,
+                BTreeMap<String, ConfigValue>,
+                TablePairs<'lua, String, Value<'lua>>,
+                Option<String>,
+            ),
+        }
+        impl Frame<'_> {
+            fn pointer(&self) -> *const std::ffi::c_void {
+                match self {
+                    Frame::List(pointer, _, _) | Frame::Table(pointer, _, _, _) => *pointer,
+                }
+            }
+        }
+        let mut stack: Vec<Frame> = Vec::new();
+        let mut next = value;
+        loop {
+            let mut done = match next {
+                Value::Boolean(value) => Some(ConfigValue::Bool(value)),
+                Value::Integer(value) => Some(ConfigValue::Integer(value)),
+                Value::Number(value) => Some(ConfigValue::Number(value)),
+                Value::String(value) => Some(ConfigValue::String(value.to_str()?.to_string())),
+                Value::Table(table) => {
+                    let pointer = table.to_pointer();
+                    if stack.iter().any(|frame| frame.pointer() == pointer) {
+                        return Err(mlua::Error::FromLuaConversionError {
+                            from: "table",
+                            to: "ConfigValue",
+                            message: Some("table contains a cycle".to_string()),
+                        });
+                    }
+                    limits::check_table_depth(lua, stack.len() + 1)?;
+                    // Tables with only consecutive integer keys starting at 1 are lists.
+                    let length = table.raw_len();
+                    if length > 0 && table.clone().pairs::<Value, Value>().count() == length {
+                        stack.push(Frame::List(pointer, Vec::new(), table.sequence_values()));
+                    } else {
+                        stack.push(Frame::Table(pointer, BTreeMap::new(), table.pairs(), None));
+                    }
+                    None
+                }
+                value => {
+                    return Err(mlua::Error::FromLuaConversionError {
+                        from: value.type_name(),
+                        to: "ConfigValue",
+                        message: None,
+                    })
+                }
+            };
+            loop {
+                let frame = match stack.last_mut() {
+                    Some(frame) => frame,
+                    None => return Ok(done.expect("converted value is missing")),
+                };
+                let child = match frame {
+                    Frame::List(_, values, items) => {
+                        values.extend(done.take());
+                        items.next().transpose()?
+                    }
+                    Frame::Table(_, values, entries, key) => {
+                        if let Some(value) = done.take() {
+                            values.insert(key.take().unwrap_or_default(), value);
+                        }
+                        match entries.next().transpose()? {
+                            Some((child_key, value)) => {
+                                *key = Some(child_key);
+                                Some(value)
+                            }
+                            None => None,
+                        }
+                    }
+                };
+                match child {
+                    Some(child) => {
+                        next = child;
+                        break;
+                    }
+                    None => {
+                        done = Some(match stack.pop() {
+                            Some(Frame::List(_, values, _)) => ConfigValue::List(values),
+                            Some(Frame::Table(_, values, _, _)) => ConfigValue::Table(values),
+                            None => unreachable!(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Configuration and metadata for a single analysis run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnalysisContext {
+    pub path: Option<String>,
+    pub language: Option<String>,
+    pub config: BTreeMap<String, ConfigValue>,
+    pub cancellation: CancellationToken,
+}
+
+impl AnalysisContext {
+    /// Creates a new, empty analysis context.
+    pub fn new() -> AnalysisContext {
+        AnalysisContext::default()
+    }
+
+    /// Sets the path of the file being analyzed.
+    pub fn with_path<S: Into<String>>(mut self, path: S) -> AnalysisContext {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Sets the language of the file being analyzed.
+    pub fn with_language<S: Into<String>>(mut self, language: S) -> AnalysisContext {
+        self.language = Some(language.into());
+        self
+    }
+
+    /// Sets a configuration value.
+    pub fn with_config<K: Into<String>, V: Into<ConfigValue>>(
+        mut self,
+        key: K,
+        value: V,
+    ) -> AnalysisContext {
+        self.config.insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets the token that cancels this analysis run.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> AnalysisContext {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Returns a configuration value.
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.config.get(key)
+    }
+
+    fn get_typed<T>(
+        &self,
+        key: &str,
+        expected: &str,
+        convert: impl FnOnce(&ConfigValue) -> Option<T>,
+    ) -> Result<Option<T>, mlua::Error> {
+        let value = match self.config.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        convert(value).map(Some).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!(
+                "config value {} is a {}, not a {}",
+                key,
+                value.type_name(),
+                expected
+            ))
+        })
+    }
+}
+
+impl std::fmt::Display for AnalysisContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AnalysisContext(")?;
+        if let Some(path) = &self.path {
+            write!(f, "{}", path)?;
+        }
+        if let Some(language) = &self.language {
+            write!(f, " [{}]", language)?;
+        }
+        write!(f, ", {} config values)", self.config.len())
+    }
+}
+
+impl UserData for AnalysisContext {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("path", |_, ctx| Ok(ctx.path.clone()));
+        fields.add_field_method_get("language", |_, ctx| Ok(ctx.language.clone()));
+        fields.add_field_method_get("cancellation", |_, ctx| Ok(ctx.cancellation.clone()));
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(mlua::MetaMethod::ToString, |_, ctx, ()| Ok(ctx.to_string()));
+        methods.add_method("get", |lua, ctx, (key, default): (String, Value)| match ctx
+            .config
+            .get(&key)
+        {
+            Some(value) => value.clone().into_lua(lua),
+            None => Ok(default),
+        });
+        methods.add_method("get_string", |_, ctx, key: String| {
+            ctx.get_typed(&key, "string", |value| value.as_str().map(str::to_string))
+        });
+        methods.add_method("get_bool", |_, ctx, key: String| {
+            ctx.get_typed(&key, "boolean", ConfigValue::as_bool)
+        });
+        methods.add_method("get_integer", |_, ctx, key: String| {
+            ctx.get_typed(&key, "integer", ConfigValue::as_integer)
+        });
+        methods.add_method("get_number", |_, ctx, key: String| {
+            ctx.get_typed(&key, "number", ConfigValue::as_number)
+        });
+        methods.add_method("config", |lua, ctx, ()| {
+            ConfigValue::Table(ctx.config.clone()).into_lua(lua)
+        });
+        methods.add_method("helper", |lua, _, name: String| {
+            let helper: Option<mlua::Function> =
+                crate::companion_module(lua)?.get(name.as_str())?;
+            helper.ok_or_else(|| mlua::Error::RuntimeError(format!("no helper named {}", name)))
+        });
+        methods.add_method("cancelled", |_, ctx, ()| {
+            Ok(ctx.cancellation.is_cancelled())
+        });
+        methods.add_method("cancel", |_, ctx, ()| {
+            ctx.cancellation.cancel();
+            Ok(())
+        });
+    }
+}
+
+impl<'lua> FromLua<'lua> for AnalysisContext {
+    fn from_lua(value: Value<'lua>, _lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        match value {
+            Value::UserData(userdata) => Ok(userdata.borrow::<AnalysisContext>()?.clone()),
+            value => Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "AnalysisContext",
+                message: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::HostFunctions;
+    use crate::Module;
+    use crate::TSNode;
+    use crate::WithSource;
+
+    #[test]
+    fn can_read_context_from_lua() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_function("kind_of", |_, node: TSNode| Ok(node.kind().to_string()))
+            .unwrap();
+        let ctx = AnalysisContext::new()
+            .with_path("src/double.py")
+            .with_language("python")
+            .with_config("max_depth", 3_i64)
+            .with_config("strict", true)
+            .with_config("ignore", vec!["tests", "docs"]);
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("ctx", ctx.clone()).unwrap();
+        l.check(
+            r#"
+              assert(ctx.path == "src/double.py")
+              assert(ctx.language == "python")
+              assert(ctx:get_integer("max_depth") == 3)
+              assert(ctx:get_number("max_depth") == 3.0)
+              assert(ctx:get_bool("strict") == true)
+              assert(ctx:get_string("missing") == nil)
+              assert(ctx:get("missing", "default") == "default")
+              assert(ctx:get("ignore")[2] == "docs")
+              assert(not pcall(ctx.get_string, ctx, "strict"))
+              assert(ctx:config().max_depth == 3)
+              assert(ctx:helper("kind_of")(parsed:root()) == "module")
+            "#,
+        );
+        let returned: AnalysisContext = l.call(r#" return ctx "#);
+        assert_eq!(ctx, returned);
+        let config: ConfigValue = l.call(r#" return { names = { "a", "b" }, depth = 2 } "#);
+        assert_eq!(
+            Some(&ConfigValue::from(vec!["a", "b"])),
+            config.as_table().unwrap().get("names")
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A debugging aid that tracks every copy of a tree-sitter tree that this crate makes.
+//!
+//! tree-sitter's `ts_tree_copy` is cheap, but not free, and it's easy to end up copying each tree
+//! many more times than you meant to.  While tracking is enabled, this crate records each copy,
+//! along with where it came from: the place in Rust code that asked for the copy, and if the copy
+//! was made while converting a Lua value, the line of Lua code that was running.  A
+//! [`TreeCopyReport`] lists the totals for each of those sites, most frequent first.
+//!
+//! Copies can happen on any thread (for instance, in the Lua states of a
+//! [`ScriptPool`][crate::ScriptPool]), so tracking is process-wide, rather than per Lua
+//! environment.  [`ConversionInstrumentation`][crate::ConversionInstrumentation] gives cheaper,
+//! per-environment counts.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::panic::Location;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use mlua::Lua;
+use tree_sitter::Tree;
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static SITES: Mutex<BTreeMap<(String, Option<String>), u64>> = Mutex::new(BTreeMap::new());
+
+/// A place that copied trees.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeCopySite {
+    /// The Rust source location that asked for the copy.
+    pub location: String,
+    /// The Lua source location that was running when the copy was made, if any.
+    pub lua_location: Option<String>,
+    /// The number of copies made at this site.
+    pub count: u64,
+}
+
+/// The tree copies that were made while tracking was enabled.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TreeCopyReport {
+    /// The total number of copies.
+    pub total: u64,
+    /// The sites that made copies, with the most frequent first.
+    pub sites: Vec<TreeCopySite>,
+}
+
+impl Display for TreeCopyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} tree copies", self.total)?;
+        for site in &self.sites {
+            write!(f, "{:>8}  {}", site.count, site.location)?;
+            if let Some(lua_location) = &site.lua_location {
+                write!(f, " (from Lua at {})", lua_location)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts tracking tree copies, discarding any that were tracked before.
+pub fn enable_tree_copy_tracking() {
+    SITES.lock().unwrap().clear();
+    TRACKING.store(true, Ordering::SeqCst);
+}
+
+/// Returns the tree copies that have been tracked so far.
+pub fn tree_copy_report() -> TreeCopyReport {
+    let sites = SITES.lock().unwrap();
+    let mut report = TreeCopyReport::default();
+    for ((location, lua_location), count) in sites.iter() {
+        report.total += count;
+        report.sites.push(TreeCopySite {
+            location: location.clone(),
+            lua_location: lua_location.clone(),
+            count: *count,
+        });
+    }
+    report.sites.sort_by(|a, b| b.count.cmp(&a.count));
+    report
+}
+
+/// Stops tracking tree copies, and returns the copies that were tracked.
+pub fn disable_tree_copy_tracking() -> TreeCopyReport {
+    TRACKING.store(false, Ordering::SeqCst);
+    let report = tree_copy_report();
+    SITES.lock().unwrap().clear();
+    report
+}
+
+/// Copies a tree, recording the copy (attributed to the caller) if tracking is enabled.
+#[track_caller]
+pub(crate) fn copy_tree(tree: &Tree) -> Tree {
+    record(Location::caller(), None);
+    tree.clone()
+}
+
+/// Copies a raw tree that a Lua value owns, recording the copy (attributed to the caller and to
+/// the Lua code that's running) if tracking is enabled.
+///
+/// # Safety
+///
+/// `tree` must be a valid tree.
+#[track_caller]
+pub(crate) unsafe fn copy_raw_tree(
+    lua: &Lua,
+    tree: *const tree_sitter::ffi::TSTree,
+) -> *mut tree_sitter::ffi::TSTree {
+    crate::metrics::record_tree_copy(lua);
+    record(Location::caller(), Some(lua));
+    tree_sitter::ffi::ts_tree_copy(tree)
+}
+
+fn record(location: &Location, lua: Option<&Lua>) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let lua_location = lua.and_then(lua_location);
+    let location = format!("{}:{}", location.file(), location.line());
+    *SITES
+        .lock()
+        .unwrap()
+        .entry((location, lua_location))
+        .or_default() += 1;
+}
+
+/// Returns the innermost Lua source line on the call stack.
+fn lua_location(lua: &Lua) -> Option<String> {
+    let mut level = 0;
+    while let Some(debug) = lua.inspect_stack(level) {
+        let line = debug.curr_line();
+        if line > 0 {
+            let source = debug.source();
+            let short_src = source.short_src.as_deref().unwrap_or("?");
+            return Some(format!("{}:{}", short_src, line));
+        }
+        level += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::SharedTree;
+    use crate::TreeWithSource;
+    use crate::WithSource;
+
+    #[test]
+    fn can_track_tree_copies() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("parsed", parsed.clone().with_source(code))
+            .unwrap();
+        let convert = l
+            .create_function(|_, tree: TreeWithSource| Ok(tree.tree.root_node().kind_id()))
+            .unwrap();
+        l.globals().set("convert", convert).unwrap();
+        let shared = SharedTree::new(parsed, &code[..]);
+
+        enable_tree_copy_tracking();
+        l.load("for _ = 1, 4 do convert(parsed) end")
+            .set_name("copy storm")
+            .exec()
+            .unwrap();
+        let _ = shared.tree();
+        let report = disable_tree_copy_tracking();
+
+        let lua_site = report
+            .sites
+            .iter()
+            .find(|site| site.lua_location.as_deref() == Some("[string \"copy storm\"]:1"))
+            .unwrap();
+        assert_eq!(4, lua_site.count);
+        let rust_site = report
+            .sites
+            .iter()
+            .find(|site| site.location.starts_with(file!()))
+            .unwrap();
+        assert_eq!(1, rust_site.count);
+        assert!(report.total >= 5);
+        assert!(report
+            .to_string()
+            .contains("(from Lua at [string \"copy storm\"]:1)"));
+        assert_eq!(TreeCopyReport::default(), tree_copy_report());
+    }
+}
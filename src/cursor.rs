@@ -0,0 +1,174 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::ops::Deref;
+use std::ops::DerefMut;
+
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+
+use crate::ltreesitter;
+use crate::recording;
+use crate::trees;
+use crate::Anchor;
+
+/// A wrapper around a [`tree_sitter::TreeCursor`].  This only exists to get around Rust's orphan
+/// rules, so that we can implement the [`mlua::FromLua`] trait.
+///
+/// Converting an ltreesitter tree cursor into a `TSTreeCursor` makes a copy of the cursor, so
+/// moving the Rust cursor does not affect the position of the Lua cursor, and vice versa.  The
+/// Rust cursor keeps the Lua cursor (and therefore its tree) alive, so that it remains valid even
+/// if Lua code drops its last reference to the tree.
+pub struct TSTreeCursor<'tree>(pub tree_sitter::TreeCursor<'tree>, Anchor<'tree>);
+
+impl<'tree> TSTreeCursor<'tree> {
+    /// Wraps a cursor that doesn't belong to a Lua tree.
+    pub fn new(cursor: tree_sitter::TreeCursor<'tree>) -> TSTreeCursor<'tree> {
+        TSTreeCursor(cursor, Anchor::default())
+    }
+}
+
+impl<'tree> Deref for TSTreeCursor<'tree> {
+    type Target = tree_sitter::TreeCursor<'tree>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'tree> DerefMut for TSTreeCursor<'tree> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+// We can only implement this for the 'lua lifetime, to express that the returned Rust value is
+// only valid while the Lua interpreter is live.
+impl<'lua> mlua::FromLua<'lua> for TSTreeCursor<'lua> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let ltreesitter_cursor = match ltreesitter::as_tree_cursor(lua, &value)? {
+            Some(ltreesitter_cursor) => ltreesitter_cursor,
+            None if trees::is_closed_object(lua, &value)? => return Err(trees::closed_error()),
+            None => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "TSTreeCursor",
+                    message: Some("expected an ltreesitter tree cursor".to_string()),
+                })
+            }
+        };
+        let tree = trees::check_open(lua, &value)?;
+        let ts_tree = unsafe { (*ltreesitter_cursor).cursor.tree };
+        trees::check_generation(lua, &value)?;
+        let cursor = TSTreeCursor(
+            unsafe {
+                let cursor = tree_sitter::ffi::ts_tree_cursor_copy(&(*ltreesitter_cursor).cursor);
+                tree_sitter::TreeCursor::from_raw(cursor)
+            },
+            Anchor::new(lua, value, ts_tree as *const _),
+        );
+        if recording::is_recording(lua) {
+            let input = recording::hash_node(&unsafe {
+                let node =
+                    tree_sitter::ffi::ts_tree_cursor_current_node(&(*ltreesitter_cursor).cursor);
+                tree_sitter::Node::from_raw(node)
+            });
+            let output = recording::hash_node(&cursor.node());
+            let replay = tree
+                .as_ref()
+                .and_then(|tree| recording::tree_index(lua, tree))
+                .map(|tree| recording::ReplayStep::CursorFromLua {
+                    tree,
+                    path: recording::node_path(cursor.node()),
+                });
+            recording::record(lua, "cursor_from_lua", input, output, replay);
+        }
+        Ok(cursor)
+    }
+}
+
+/// Adds methods to ltreesitter's tree cursors for the fast navigation functions that ltreesitter
+/// doesn't expose itself:
+///
+/// - `cursor:goto_first_child_for_byte(byte)`
+/// - `cursor:goto_first_child_for_point(point)`, where `point` is a `{ row = ..., column = ... }`
+///   table
+///
+/// Both move the cursor to the first child that extends beyond the given position, and return the
+/// index of that child, or `nil` if there is no such child.
+pub(crate) fn install_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    let methods = ltreesitter::methods(lua, ltreesitter::TREE_CURSOR_METATABLE)?;
+
+    let goto_first_child_for_byte = lua.create_function(|lua, (cursor, byte): (Value, u32)| {
+        let ltreesitter_cursor = ltreesitter::tree_cursor_ptr(lua, cursor)?;
+        let index = unsafe {
+            tree_sitter::ffi::ts_tree_cursor_goto_first_child_for_byte(
+                &mut (*ltreesitter_cursor).cursor,
+                byte,
+            )
+        };
+        Ok((index >= 0).then_some(index))
+    })?;
+    methods.raw_set("goto_first_child_for_byte", goto_first_child_for_byte)?;
+
+    let goto_first_child_for_point =
+        lua.create_function(|lua, (cursor, point): (Value, Table)| {
+            let ltreesitter_cursor = ltreesitter::tree_cursor_ptr(lua, cursor)?;
+            let point = tree_sitter::ffi::TSPoint {
+                row: point.get("row")?,
+                column: point.get("column")?,
+            };
+            let index = unsafe {
+                tree_sitter::ffi::ts_tree_cursor_goto_first_child_for_point(
+                    &mut (*ltreesitter_cursor).cursor,
+                    point,
+                )
+            };
+            Ok((index >= 0).then_some(index))
+        })?;
+    methods.raw_set("goto_first_child_for_point", goto_first_child_for_point)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_jump_to_child_for_position() {
+        let code = br#"
+          def double(x):
+              return x * 2
+        "#;
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local cursor = parsed:root():create_cursor()
+              assert(cursor:goto_first_child_for_byte(15) == 0, "expected first child")
+              local node = cursor:current_node()
+              assert(node:type() == "function_definition", "expected function definition")
+
+              cursor = parsed:root():create_cursor()
+              local index = cursor:goto_first_child_for_point({ row = 2, column = 14 })
+              assert(index == 0, "expected first child")
+              assert(cursor:goto_first_child_for_byte(10000) == nil, "expected no child")
+            "#,
+        );
+
+        let mut cursor: TSTreeCursor = l.call(r#" return parsed:root():create_cursor() "#);
+        assert_eq!(Some(0), cursor.goto_first_child_for_byte(15));
+        assert_eq!("function_definition", cursor.node().kind());
+    }
+}
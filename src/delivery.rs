@@ -0,0 +1,159 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Delivers large result sets to Lua in chunks, so that scripts stay within their memory limits.
+//!
+//! If the host limits a Lua environment's memory, building one huge table of results can abort a
+//! script partway through.  [`ChunkedDelivery::deliver_chunked`] instead passes the results to a
+//! Lua consumer function a chunk at a time, and lets Lua collect each chunk before building the
+//! next.  Chunks start small and grow while there's plenty of memory to spare; they shrink again
+//! whenever the remaining memory gets close to the limit.
+//!
+//! Set the limit via [`ChunkedDelivery::set_delivery_memory_limit`], so that the bridge knows what
+//! it is.  Lua code can deliver the matches in a packed match buffer via
+//! `buffer:deliver(consumer)`.  In either case, a consumer can return `false` to stop the delivery
+//! early.
+
+use mlua::Function;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+
+const INITIAL_CHUNK_SIZE: usize = 64;
+const MAX_CHUNK_SIZE: usize = 4096;
+
+#[derive(Clone, Copy)]
+struct DeliveryLimit(usize);
+
+/// What happened during a chunked delivery.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DeliveryStats {
+    /// The number of results that were passed to the consumer.
+    pub delivered: usize,
+    /// The number of times that the consumer was called.
+    pub chunks: usize,
+    /// Whether the consumer stopped the delivery before every result was delivered.
+    pub stopped: bool,
+}
+
+/// An extension trait that lets you deliver large result sets to Lua in chunks.
+pub trait ChunkedDelivery {
+    /// Limits the memory that this Lua environment can use, via [`Lua::set_memory_limit`], and
+    /// records the limit so that chunked deliveries can stay within it.  A limit of 0 means no
+    /// limit.
+    fn set_delivery_memory_limit(&self, limit: usize) -> Result<(), mlua::Error>;
+
+    /// Passes `items` to `consumer` in chunks.  Each chunk is a Lua sequence.  Stops early if an
+    /// item can't be created, or if the consumer returns `false`.
+    fn deliver_chunked<'lua, V, I>(
+        &'lua self,
+        items: I,
+        consumer: &Function<'lua>,
+    ) -> Result<DeliveryStats, mlua::Error>
+    where
+        V: IntoLua<'lua>,
+        I: IntoIterator<Item = Result<V, mlua::Error>>;
+}
+
+impl ChunkedDelivery for Lua {
+    fn set_delivery_memory_limit(&self, limit: usize) -> Result<(), mlua::Error> {
+        self.set_memory_limit(limit)?;
+        if limit == 0 {
+            self.remove_app_data::<DeliveryLimit>();
+        } else {
+            self.set_app_data(DeliveryLimit(limit));
+        }
+        Ok(())
+    }
+
+    fn deliver_chunked<'lua, V, I>(
+        &'lua self,
+        items: I,
+        consumer: &Function<'lua>,
+    ) -> Result<DeliveryStats, mlua::Error>
+    where
+        V: IntoLua<'lua>,
+        I: IntoIterator<Item = Result<V, mlua::Error>>,
+    {
+        let limit = self.app_data_ref::<DeliveryLimit>().map(|limit| limit.0);
+        // Flush early once less than a quarter of the limit remains.
+        let low_memory = || match limit {
+            Some(limit) => self.used_memory() + limit / 4 > limit,
+            None => false,
+        };
+
+        let mut stats = DeliveryStats::default();
+        let mut chunk_size = INITIAL_CHUNK_SIZE;
+        let mut chunk = self.create_table()?;
+        let mut len = 0;
+        let mut items = items.into_iter().peekable();
+        while let Some(item) = items.next() {
+            chunk.raw_set(len + 1, item?)?;
+            len += 1;
+            let pressured = low_memory();
+            if len < chunk_size && !pressured && items.peek().is_some() {
+                continue;
+            }
+
+            let keep_going = consumer.call::<_, Value>(chunk)?;
+            stats.delivered += len;
+            stats.chunks += 1;
+            if let Value::Boolean(false) = keep_going {
+                stats.stopped = items.peek().is_some();
+                return Ok(stats);
+            }
+            self.gc_collect()?;
+            chunk_size = if pressured || low_memory() {
+                (chunk_size / 2).max(1)
+            } else {
+                (chunk_size * 2).min(MAX_CHUNK_SIZE)
+            };
+            chunk = self.create_table()?;
+            len = 0;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+
+    #[test]
+    fn can_deliver_in_chunks() {
+        let l = Lua::new();
+        l.set_delivery_memory_limit(4 * 1024 * 1024).unwrap();
+        let consumer: Function = l.call(
+            r#"
+              total = 0
+              return function(chunk)
+                for _, item in ipairs(chunk) do
+                  total = total + #item.text
+                end
+              end
+            "#,
+        );
+        let items = (0..10000).map(|i| {
+            let table = l.create_table()?;
+            table.set("text", "x".repeat(i % 100 + 1))?;
+            Ok(table)
+        });
+        let stats = l.deliver_chunked(items, &consumer).unwrap();
+        assert_eq!(10000, stats.delivered);
+        assert!(stats.chunks > 1);
+        assert_eq!(505000, l.globals().get::<_, usize>("total").unwrap());
+
+        let stop: Function = l.call("return function(chunk) return false end");
+        let stats = l
+            .deliver_chunked((0..1000).map(Ok::<_, mlua::Error>), &stop)
+            .unwrap();
+        assert_eq!(
+            (INITIAL_CHUNK_SIZE, 1, true),
+            (stats.delivered, stats.chunks, stats.stopped)
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Renders query matches as Mermaid or Graphviz diagrams, for documenting and debugging queries.
+//!
+//! The diagram has a box for each match, linked to a box for each of its captures, which are in
+//! turn linked to a box for each of the source ranges that they capture.  Captures of the same
+//! range share a range box, so it's easy to see where patterns overlap.
+//!
+//! In Lua, `require("ltreesitter_rs").render_matches(buffer, format, tree)` renders a packed match
+//! buffer, where `format` is `"mermaid"` or `"dot"`.  `tree` is optional; if given, each range box
+//! includes an excerpt of its text.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+use mlua::AnyUserData;
+use mlua::Lua;
+use mlua::Value;
+
+use crate::display::excerpt;
+use crate::pretty;
+use crate::MatchBuffer;
+
+/// The diagram languages that matches can be rendered in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiagramFormat {
+    Mermaid,
+    Dot,
+}
+
+impl DiagramFormat {
+    fn node(self, diagram: &mut String, id: &str, label: &str) {
+        let _ = match self {
+            DiagramFormat::Mermaid => {
+                writeln!(diagram, "  {}[\"{}\"]", id, label.replace('"', "#quot;"))
+            }
+            DiagramFormat::Dot => writeln!(
+                diagram,
+                "  {} [label=\"{}\"];",
+                id,
+                label.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        };
+    }
+
+    fn edge(self, diagram: &mut String, from: &str, to: &str) {
+        let _ = match self {
+            DiagramFormat::Mermaid => writeln!(diagram, "  {} --> {}", from, to),
+            DiagramFormat::Dot => writeln!(diagram, "  {} -> {};", from, to),
+        };
+    }
+}
+
+/// Renders the matches in a packed match buffer as a diagram.  If `src` is given, each source
+/// range includes an excerpt of its text.
+pub fn render_matches(buffer: &MatchBuffer, src: Option<&[u8]>, format: DiagramFormat) -> String {
+    let mut diagram = String::new();
+    diagram.push_str(match format {
+        DiagramFormat::Mermaid => "graph LR\n",
+        DiagramFormat::Dot => "digraph matches {\n  rankdir=LR;\n",
+    });
+    let mut ranges: BTreeMap<(usize, usize), String> = BTreeMap::new();
+    for match_index in 0..buffer.len() {
+        let match_id = format!("m{}", match_index);
+        let label = format!(
+            "match {} (pattern {})",
+            match_index,
+            buffer.pattern_index(match_index)
+        );
+        format.node(&mut diagram, &match_id, &label);
+        for (capture_index, capture) in buffer.captures(match_index).iter().enumerate() {
+            let capture_id = format!("m{}c{}", match_index, capture_index);
+            let label = format!("@{}: {}", buffer.capture_name(capture), capture.kind);
+            format.node(&mut diagram, &capture_id, &label);
+            format.edge(&mut diagram, &match_id, &capture_id);
+            let next_id = format!("r{}", ranges.len());
+            let range_id = ranges
+                .entry((capture.start_byte, capture.end_byte))
+                .or_insert(next_id);
+            format.edge(&mut diagram, &capture_id, range_id);
+        }
+    }
+    for ((start, end), range_id) in &ranges {
+        let range: Range<usize> = *start..*end;
+        let mut label = format!("{}..{}", start, end);
+        if let Some(text) = src.and_then(|src| src.get(range)) {
+            label.push(' ');
+            label.push_str(&excerpt(text));
+        }
+        format.node(&mut diagram, range_id, &label);
+    }
+    if format == DiagramFormat::Dot {
+        diagram.push_str("}\n");
+    }
+    diagram
+}
+
+/// Adds `render_matches` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let render = lua.create_function(
+        |lua, (buffer, format, tree): (AnyUserData, String, Value)| {
+            let format = match format.as_str() {
+                "mermaid" => DiagramFormat::Mermaid,
+                "dot" => DiagramFormat::Dot,
+                _ => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "unknown diagram format {}",
+                        format
+                    )))
+                }
+            };
+            let src = match tree {
+                Value::Nil => None,
+                tree => pretty::node_and_source(lua, tree)?.1,
+            };
+            let buffer = buffer.borrow::<MatchBuffer>()?;
+            Ok(render_matches(&buffer, src, format))
+        },
+    )?;
+    crate::companion_module(lua)?.set("render_matches", render)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+    use tree_sitter::Query;
+
+    #[test]
+    fn can_render_match_diagrams() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let query = Query::new(
+            tree_sitter_python::language(),
+            "(function_definition name: (identifier) @name) (identifier) @id",
+        )
+        .unwrap();
+        let buffer = MatchBuffer::new(&query, parsed.root_node(), code);
+
+        let mermaid = render_matches(&buffer, Some(code), DiagramFormat::Mermaid);
+        assert!(mermaid.starts_with("graph LR\n"));
+        assert!(mermaid.contains(" (pattern 0)\"]\n"));
+        assert!(mermaid.contains("[\"@name: identifier\"]\n"));
+        assert!(mermaid.contains("  r0[\"4..10 #quot;double#quot;\"]\n"));
+        // The function name is captured by both patterns, and they share a range box.
+        assert_eq!(2, mermaid.matches("--> r0\n").count());
+
+        let dot = render_matches(&buffer, Some(code), DiagramFormat::Dot);
+        assert!(dot.starts_with("digraph matches {\n"));
+        assert!(dot.contains("  r0 [label=\"4..10 \\\"double\\\"\"];\n"));
+        assert!(dot.ends_with("}\n"));
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local buffer = ltreesitter_rs.packed_matches(parsed, "(integer) @number")
+              local diagram = ltreesitter_rs.render_matches(buffer, "dot", parsed)
+              assert(diagram:find('r0 [label="26..27 \\"2\\""];', 1, true))
+              assert(ltreesitter_rs.render_matches(buffer, "mermaid"):find("m0c0 --> r0", 1, true))
+            "#,
+        );
+    }
+}
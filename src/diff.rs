@@ -0,0 +1,266 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Compares the structure of two syntax trees.
+//!
+//! [`diff_trees`] compares two trees — before and after an edit, or two versions of a file — and
+//! returns the subtrees that were added, removed, or changed.  Unlike
+//! [`Tree::changed_ranges`][tree_sitter::Tree::changed_ranges], the trees don't have to be related
+//! by an edit; they're compared by their contents.  Two subtrees are the same if they have the same
+//! kind and the same source code.  The children of two nodes of the same kind are lined up by
+//! finding their longest common subsequence, and children that are left over are compared
+//! pairwise, so a change is reported at the deepest subtree that contains it.  Changes to
+//! whitespace between nodes aren't reported.
+//!
+//! In Lua, `require("ltreesitter_rs").diff(old, new)` (where each is a tree or a node) returns a
+//! list of changes, in document order.  Each change is a table with these fields:
+//!
+//! - `change`: `"added"`, `"removed"`, or `"changed"`
+//! - `kind`: the kind of the new node, or of the old node for a removal
+//! - `old_kind`: the kind of the old node, for a change
+//! - `old` and `new`: the ranges of the old and new nodes, in the same shape as a
+//!   [`TSRange`][crate::TSRange], for the nodes that the change has
+
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Node;
+
+use crate::limits;
+use crate::pretty;
+use crate::TSRange;
+use crate::TreeWithSource;
+
+/// A difference between two syntax trees.
+#[derive(Clone, Copy, Debug)]
+pub enum TreeChange<'a> {
+    /// A subtree that's only in the new tree.
+    Added(Node<'a>),
+    /// A subtree that's only in the old tree.
+    Removed(Node<'a>),
+    /// A subtree of the old tree that was replaced by a subtree of the new tree.
+    Changed(Node<'a>, Node<'a>),
+}
+
+impl<'lua> IntoLua<'lua> for TreeChange<'_> {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        let (change, old, new) = match self {
+            TreeChange::Added(new) => ("added", None, Some(new)),
+            TreeChange::Removed(old) => ("removed", Some(old), None),
+            TreeChange::Changed(old, new) => {
+                table.set("old_kind", old.kind())?;
+                ("changed", Some(old), Some(new))
+            }
+        };
+        table.set("change", change)?;
+        table.set("kind", new.or(old).map(|node| node.kind()))?;
+        table.set("old", old.map(|node| TSRange(node.range())))?;
+        table.set("new", new.map(|node| TSRange(node.range())))?;
+        Ok(Value::Table(table))
+    }
+}
+
+/// Returns the differences between the tree rooted at `old`, whose source code is `old_src`, and
+/// the tree rooted at `new`, whose source code is `new_src`.
+pub fn diff_trees<'a>(
+    old: Node<'a>,
+    old_src: &[u8],
+    new: Node<'a>,
+    new_src: &[u8],
+) -> Vec<TreeChange<'a>> {
+    let mut differ = Differ {
+        old_src,
+        new_src,
+        changes: Vec::new(),
+    };
+    differ.diff_nodes(old, new);
+    differ.changes
+}
+
+impl TreeWithSource<'_> {
+    /// Returns the differences between this tree and `new`.
+    pub fn diff<'a>(&'a self, new: &'a TreeWithSource) -> Vec<TreeChange<'a>> {
+        diff_trees(
+            self.tree.root_node(),
+            self.src,
+            new.tree.root_node(),
+            new.src,
+        )
+    }
+}
+
+struct Differ<'s, 'a> {
+    old_src: &'s [u8],
+    new_src: &'s [u8],
+    changes: Vec<TreeChange<'a>>,
+}
+
+impl<'a> Differ<'_, 'a> {
+    fn same(&self, old: Node, new: Node) -> bool {
+        if old.kind_id() != new.kind_id() || old.has_error() != new.has_error() {
+            return false;
+        }
+        match (
+            self.old_src.get(old.byte_range()),
+            self.new_src.get(new.byte_range()),
+        ) {
+            (Some(old_text), Some(new_text)) => old_text == new_text,
+            _ => false,
+        }
+    }
+
+    fn diff_nodes(&mut self, old: Node<'a>, new: Node<'a>) {
+        if self.same(old, new) {
+            return;
+        }
+        if old.kind_id() != new.kind_id() || old.child_count() == 0 || new.child_count() == 0 {
+            self.changes.push(TreeChange::Changed(old, new));
+            return;
+        }
+        self.diff_children(old, new);
+    }
+
+    fn diff_children(&mut self, old: Node<'a>, new: Node<'a>) {
+        let old_children = old.children(&mut old.walk()).collect::<Vec<_>>();
+        let new_children = new.children(&mut new.walk()).collect::<Vec<_>>();
+
+        // Most edits only touch a few children, so skip past the ones at either end that are
+        // unchanged before lining up the rest.
+        let mut start = 0;
+        while start < old_children.len()
+            && start < new_children.len()
+            && self.same(old_children[start], new_children[start])
+        {
+            start += 1;
+        }
+        let (mut old_end, mut new_end) = (old_children.len(), new_children.len());
+        while old_end > start
+            && new_end > start
+            && self.same(old_children[old_end - 1], new_children[new_end - 1])
+        {
+            old_end -= 1;
+            new_end -= 1;
+        }
+        let old_children = &old_children[start..old_end];
+        let new_children = &new_children[start..new_end];
+
+        // lengths[i][j] is the length of the longest common subsequence of old_children[i..] and
+        // new_children[j..].
+        let mut lengths = vec![vec![0usize; new_children.len() + 1]; old_children.len() + 1];
+        for i in (0..old_children.len()).rev() {
+            for j in (0..new_children.len()).rev() {
+                lengths[i][j] = if self.same(old_children[i], new_children[j]) {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        let (mut old_gap, mut new_gap) = (0, 0);
+        while i < old_children.len() && j < new_children.len() {
+            if self.same(old_children[i], new_children[j]) {
+                self.diff_gap(&old_children[old_gap..i], &new_children[new_gap..j]);
+                i += 1;
+                j += 1;
+                old_gap = i;
+                new_gap = j;
+            } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        self.diff_gap(&old_children[old_gap..], &new_children[new_gap..]);
+    }
+
+    /// Compares the children between two matching children of the old and new nodes.
+    fn diff_gap(&mut self, old: &[Node<'a>], new: &[Node<'a>]) {
+        for (old, new) in old.iter().zip(new) {
+            self.diff_nodes(*old, *new);
+        }
+        let paired = old.len().min(new.len());
+        for old in &old[paired..] {
+            self.changes.push(TreeChange::Removed(*old));
+        }
+        for new in &new[paired..] {
+            self.changes.push(TreeChange::Added(*new));
+        }
+    }
+}
+
+/// Adds `diff` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let diff = lua.create_function(|lua, (old, new): (Value, Value)| {
+        let (old, old_src) = pretty::node_and_source(lua, old)?;
+        let (new, new_src) = pretty::node_and_source(lua, new)?;
+        let (old_src, new_src) = match (old_src, new_src) {
+            (Some(old_src), Some(new_src)) => (old_src, new_src),
+            _ => {
+                return Err(mlua::Error::RuntimeError(
+                    "cannot diff a node without the source of its tree".to_string(),
+                ))
+            }
+        };
+        limits::check_depth(lua, old)?;
+        limits::check_depth(lua, new)?;
+        lua.create_sequence_from(diff_trees(old, old_src, new, new_src))
+    })?;
+    crate::companion_module(lua)?.set("diff", diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_diff_trees() {
+        let old_code = b"a = 1\nb = 2\nc = 3\n";
+        let new_code = b"a = 1\nb = 5\nc = 3\nd = 4\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let old = parser.parse(old_code, None).unwrap().with_source(old_code);
+        let new = parser.parse(new_code, None).unwrap().with_source(new_code);
+        let changes = old.diff(&new);
+        assert_eq!(2, changes.len());
+        assert!(matches!(
+            changes[0],
+            TreeChange::Changed(old, new)
+                if old.kind() == "integer" && old.start_byte() == 10 && new.start_byte() == 10
+        ));
+        assert!(matches!(
+            changes[1],
+            TreeChange::Added(new) if new.kind() == "expression_statement" && new.start_byte() == 18
+        ));
+        assert!(old.diff(&old).is_empty());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("old", old).unwrap();
+        l.globals().set("new", new).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local changes = ltreesitter_rs.diff(old, new)
+              assert(#changes == 2)
+              assert(changes[1].change == "changed" and changes[1].kind == "integer")
+              assert(changes[1].old_kind == "integer")
+              assert(changes[1].old.start_byte == 10 and changes[1].new.end_byte == 11)
+              assert(changes[2].change == "added" and changes[2].old == nil)
+              assert(changes[2].new.start_byte == 18)
+              local reversed = ltreesitter_rs.diff(new, old)
+              assert(#reversed == 2 and reversed[2].change == "removed")
+              assert(reversed[2].kind == "expression_statement" and reversed[2].new == nil)
+              assert(#ltreesitter_rs.diff(old, old) == 0)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! `Debug` and `Display` implementations for the wrapper types, which show each node's kind,
+//! range, and (truncated) text.
+
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::cursor::TSTreeCursor;
+use crate::stores;
+use crate::trees;
+use crate::LuaNode;
+use crate::TSNode;
+use crate::TreeWithSource;
+
+/// The maximum number of characters of source text to include when formatting a node.
+const MAX_EXCERPT_LENGTH: usize = 40;
+
+/// Returns a quoted, escaped, and possibly truncated copy of some source text.
+pub(crate) fn excerpt(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    let mut chars = text.chars();
+    let truncated = chars.by_ref().take(MAX_EXCERPT_LENGTH).collect::<String>();
+    if chars.next().is_some() {
+        format!("{:?}…", truncated)
+    } else {
+        format!("{:?}", truncated)
+    }
+}
+
+fn write_node(f: &mut Formatter, node: &tree_sitter::Node, src: Option<&[u8]>) -> std::fmt::Result {
+    let start = node.start_position();
+    let end = node.end_position();
+    write!(
+        f,
+        "{} [{}:{} - {}:{}]",
+        node.kind(),
+        start.row,
+        start.column,
+        end.row,
+        end.column
+    )?;
+    if let Some(text) = src.and_then(|src| src.get(node.byte_range())) {
+        write!(f, " {}", excerpt(text))?;
+    }
+    Ok(())
+}
+
+impl<'n> LuaNode<'n> {
+    /// Returns the source code of the tree that this node belongs to, if it's an ltreesitter tree
+    /// that this crate pushed into Lua.
+    fn tree_source(&self) -> Option<&'n [u8]> {
+        let (lua, value, _) = self.anchor().0.as_ref()?;
+        let tree = trees::owner(lua, value).ok()??;
+        stores::source(lua, &tree).ok()?
+    }
+}
+
+impl Display for TSNode<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_node(f, &self.0, None)
+    }
+}
+
+impl Debug for TSNode<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TSNode(")?;
+        write_node(f, &self.0, None)?;
+        write!(f, ")")
+    }
+}
+
+impl Display for LuaNode<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_node(f, self, self.tree_source())
+    }
+}
+
+impl Debug for LuaNode<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LuaNode(")?;
+        write_node(f, self, self.tree_source())?;
+        write!(f, ")")
+    }
+}
+
+impl Display for TreeWithSource<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_node(f, &self.tree.root_node(), Some(self.src))
+    }
+}
+
+impl Debug for TreeWithSource<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        struct Root<'a>(&'a TreeWithSource<'a>);
+        impl Debug for Root<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                write_node(f, &self.0.tree.root_node(), Some(self.0.src))
+            }
+        }
+        f.debug_struct("TreeWithSource")
+            .field("root", &Root(self))
+            .field("src_len", &self.src.len())
+            .field(
+                "secondary",
+                &self
+                    .secondary
+                    .iter()
+                    .map(|source| source.name.as_str())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Display for TSTreeCursor<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(field_name) = self.field_name() {
+            write!(f, "{}: ", field_name)?;
+        }
+        write_node(f, &self.node(), None)
+    }
+}
+
+impl Debug for TSTreeCursor<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TSTreeCursor(")?;
+        Display::fmt(self, f)?;
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::AnalysisContext;
+    use crate::Module;
+    use crate::SourceMap;
+    use crate::WithSource;
+    use mlua::Lua;
+
+    #[test]
+    fn can_format_wrappers() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let parsed = parsed.with_source(code);
+        assert_eq!(
+            r#"module [0:0 - 1:0] "def double(x): return x * 2\n""#,
+            parsed.to_string()
+        );
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        let name: LuaNode =
+            l.call(r#" return parsed:root():child(0):child_by_field_name("name") "#);
+        assert_eq!(r#"identifier [0:4 - 0:10] "double""#, name.to_string());
+        assert_eq!(
+            r#"LuaNode(identifier [0:4 - 0:10] "double")"#,
+            format!("{:?}", name)
+        );
+        let name = TSNode::from(name);
+        assert_eq!("TSNode(identifier [0:4 - 0:10])", format!("{:?}", name));
+
+        let mut map = SourceMap::new();
+        map.add(0..4, 0..8);
+        l.globals().set("map", map).unwrap();
+        l.globals()
+            .set("ctx", AnalysisContext::new().with_path("double.py"))
+            .unwrap();
+        l.check(
+            r#"
+              assert(tostring(map) == "SourceMap(0..4 => 0..8)")
+              assert(tostring(ctx) == "AnalysisContext(double.py, 0 config values)")
+            "#,
+        );
+
+        assert_eq!(
+            "\"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\"…",
+            excerpt(&[b'a'; 50])
+        );
+    }
+}
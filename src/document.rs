@@ -0,0 +1,210 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Documents whose trees are reparsed over time, and detection of nodes from outdated trees.
+//!
+//! Each time a [`Document`] is reparsed, it pushes a new tree into Lua and bumps its generation.
+//! Lua code might still hold onto nodes from an earlier tree; if it passes one of them back to
+//! Rust, the conversion fails with a [`StaleNode`] error instead of quietly handing you a node
+//! from an outdated tree.  (The same goes for trees and cursors from earlier generations.)  The
+//! error is an external [`mlua::Error`], so you can check for it via
+//! [`mlua::Error::downcast_ref`].
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Parser;
+use tree_sitter::Tree;
+
+use crate::edits;
+use crate::trees;
+use crate::TSInputEdit;
+use crate::TSRange;
+use crate::WithSource;
+
+static NEXT_DOCUMENT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The error that you get when converting a node, cursor, or tree from an outdated generation of
+/// a [`Document`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StaleNode {
+    /// The ID of the document.
+    pub document: u64,
+    /// The generation that the value belongs to.
+    pub generation: u64,
+    /// The document's current generation.
+    pub current_generation: u64,
+}
+
+impl std::fmt::Display for StaleNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stale node: document {} has been reparsed (node is from generation {}, document is at \
+             generation {})",
+            self.document, self.generation, self.current_generation
+        )
+    }
+}
+
+impl std::error::Error for StaleNode {}
+
+/// A source file that is parsed into a tree that is available to Lua, and that can be reparsed
+/// when the file changes.
+pub struct Document<'lua> {
+    lua: &'lua Lua,
+    id: u64,
+    generation: u64,
+    parser: Parser,
+    parsed: Option<Tree>,
+    changed: Vec<TSRange>,
+    tree: Value<'lua>,
+}
+
+impl<'lua> Document<'lua> {
+    /// Parses a document and pushes its tree into Lua.  The parser must already have its language
+    /// set.
+    pub fn new(lua: &'lua Lua, parser: Parser, src: &[u8]) -> Result<Document<'lua>, mlua::Error> {
+        let mut document = Document {
+            lua,
+            id: NEXT_DOCUMENT_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+            parser,
+            parsed: None,
+            changed: Vec::new(),
+            tree: Value::Nil,
+        };
+        let parsed = document.parse(src)?;
+        document.tree = document.push(parsed, src)?;
+        Ok(document)
+    }
+
+    /// Reparses the document from its new contents, pushing the new tree into Lua and starting a
+    /// new generation.  Nodes from earlier generations can no longer be converted into Rust.
+    pub fn reparse(&mut self, src: &[u8]) -> Result<(), mlua::Error> {
+        let parsed = self.parse(src)?;
+        self.changed = vec![TSRange(parsed.root_node().range())];
+        self.generation += 1;
+        self.tree = self.push(parsed, src)?;
+        Ok(())
+    }
+
+    /// Applies edits (typically ones that Lua code reported) to the document's tree, and reparses
+    /// its new contents incrementally, reusing the parts of the tree that the edits didn't touch.
+    /// Like [`reparse`][Self::reparse], this pushes the new tree into Lua and starts a new
+    /// generation.
+    pub fn edit(&mut self, edits: &[TSInputEdit], src: &[u8]) -> Result<(), mlua::Error> {
+        let (parsed, changed) = match self.parsed.as_mut() {
+            Some(old) => edits::reparse(&mut self.parser, old, edits, src)?,
+            None => {
+                let parsed = self.parse(src)?;
+                let range = TSRange(parsed.root_node().range());
+                (parsed, vec![range])
+            }
+        };
+        self.changed = changed;
+        self.generation += 1;
+        self.tree = self.push(parsed, src)?;
+        Ok(())
+    }
+
+    fn parse(&mut self, src: &[u8]) -> Result<Tree, mlua::Error> {
+        self.parser
+            .parse(src, None)
+            .ok_or_else(|| mlua::Error::RuntimeError("cannot parse document".to_string()))
+    }
+
+    #[track_caller]
+    fn push(&mut self, parsed: Tree, src: &[u8]) -> Result<Value<'lua>, mlua::Error> {
+        self.parsed = Some(crate::copies::copy_tree(&parsed));
+        let tree = parsed.with_source(src).into_lua(self.lua)?;
+        trees::set_generation(self.lua, &tree, self.id, self.generation)?;
+        Ok(tree)
+    }
+
+    /// Returns the ltreesitter tree of the document's current generation.
+    pub fn tree(&self) -> Value<'lua> {
+        self.tree.clone()
+    }
+
+    /// Returns the ranges of the document whose syntactic structure changed in its most recent
+    /// edit or reparse.  These can be pushed into Lua as a list of range tables.  A full reparse
+    /// reports the whole document as changed.
+    pub fn changed_ranges(&self) -> &[TSRange] {
+        &self.changed
+    }
+
+    /// Returns the document's unique ID.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the document's current generation, which starts at 0 and increases by one each
+    /// time the document is reparsed.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::TSNode;
+
+    #[test]
+    fn can_detect_stale_nodes() {
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let mut document = Document::new(&l, parser, b"def double(x): return x * 2\n").unwrap();
+        l.globals().set("document", document.tree()).unwrap();
+        l.load("old = document:root():child(0)").exec().unwrap();
+        let _: TSNode = l.globals().get("old").unwrap();
+
+        document.reparse(b"def triple(x): return x * 3\n").unwrap();
+        assert_eq!(1, document.generation());
+        assert_eq!(28, document.changed_ranges()[0].end_byte);
+        let err = l.globals().get::<_, TSNode>("old").unwrap_err();
+        let stale = err
+            .downcast_ref::<StaleNode>()
+            .expect("expected a StaleNode error");
+        assert_eq!((0, 1), (stale.generation, stale.current_generation));
+        assert_eq!(document.id(), stale.document);
+
+        l.globals().set("document", document.tree()).unwrap();
+        let current: TSNode = l.load("return document:root():child(0)").eval().unwrap();
+        assert_eq!("function_definition", current.kind());
+
+        let edit: TSInputEdit = l
+            .load(
+                r#"
+                  return {
+                    start_byte = 4, old_end_byte = 10, new_end_byte = 7,
+                    start_point = { row = 0, column = 4 },
+                    old_end_point = { row = 0, column = 10 },
+                    new_end_point = { row = 0, column = 7 },
+                  }
+                "#,
+            )
+            .eval()
+            .unwrap();
+        document
+            .edit(&[edit], b"def add(x): return x * 3\n")
+            .unwrap();
+        assert_eq!(2, document.generation());
+        l.globals().set("document", document.tree()).unwrap();
+        let name: String = l
+            .load(r#" return document:root():child(0):child_by_field_name("name"):source() "#)
+            .eval()
+            .unwrap();
+        assert_eq!("add", name);
+    }
+}
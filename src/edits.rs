@@ -0,0 +1,179 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Lets Lua code report edits, so that trees can be reparsed incrementally.
+//!
+//! An edit is a [`TSInputEdit`], which in Lua is a table with `start_byte`, `old_end_byte`, and
+//! `new_end_byte` fields, and `start_point`, `old_end_point`, and `new_end_point` fields that are
+//! [`TSPoint`] tables.  `require("ltreesitter_rs").reparse(tree, edits, new_source)` applies a
+//! list of edits to a copy of a tree, reparses the new source incrementally, and returns the new
+//! tree (so that Lua code can write `tree = ltreesitter_rs.reparse(tree, edits, source)`).  Hosts
+//! that own a [`Document`][crate::Document] can do the same with
+//! [`Document::edit`][crate::Document::edit].  The original tree isn't changed.
+//!
+//! An incremental reparse also tells you which parts of the tree changed, so that editor-style
+//! plugins can re-highlight only those.  `reparse` returns them as its second result, a list of
+//! [`TSRange`] tables, and [`Document::changed_ranges`][crate::Document::changed_ranges] returns
+//! the ranges that changed in the document's most recent edit.
+
+use std::ops::Deref;
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::InputEdit;
+use tree_sitter::Parser;
+use tree_sitter::Tree;
+
+use crate::offsets::Offset;
+use crate::TSPoint;
+use crate::TSRange;
+use crate::TreeWithSource;
+use crate::WithSource;
+
+/// A wrapper around a [`tree_sitter::InputEdit`].  This only exists to get around Rust's orphan
+/// rules, so that we can implement the [`mlua::IntoLua`] and [`mlua::FromLua`] traits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TSInputEdit(pub InputEdit);
+
+impl Deref for TSInputEdit {
+    type Target = InputEdit;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<InputEdit> for TSInputEdit {
+    fn from(edit: InputEdit) -> TSInputEdit {
+        TSInputEdit(edit)
+    }
+}
+
+impl<'lua> IntoLua<'lua> for TSInputEdit {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        table.set("start_byte", Offset(self.0.start_byte))?;
+        table.set("old_end_byte", Offset(self.0.old_end_byte))?;
+        table.set("new_end_byte", Offset(self.0.new_end_byte))?;
+        table.set("start_point", TSPoint(self.0.start_position))?;
+        table.set("old_end_point", TSPoint(self.0.old_end_position))?;
+        table.set("new_end_point", TSPoint(self.0.new_end_position))?;
+        Ok(Value::Table(table))
+    }
+}
+
+impl<'lua> FromLua<'lua> for TSInputEdit {
+    fn from_lua(value: Value<'lua>, _lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let table = match value {
+            Value::Table(table) => table,
+            value => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "TSInputEdit",
+                    message: Some("expected a table".to_string()),
+                })
+            }
+        };
+        let start_point: TSPoint = table.get("start_point")?;
+        let old_end_point: TSPoint = table.get("old_end_point")?;
+        let new_end_point: TSPoint = table.get("new_end_point")?;
+        Ok(TSInputEdit(InputEdit {
+            start_byte: table.get::<_, Offset>("start_byte")?.0,
+            old_end_byte: table.get::<_, Offset>("old_end_byte")?.0,
+            new_end_byte: table.get::<_, Offset>("new_end_byte")?.0,
+            start_position: start_point.0,
+            old_end_position: old_end_point.0,
+            new_end_position: new_end_point.0,
+        }))
+    }
+}
+
+/// Applies edits to a tree, and reparses the new source incrementally, reusing the unchanged parts
+/// of the tree.  Returns the new tree, and the ranges whose syntactic structure changed.
+pub(crate) fn reparse(
+    parser: &mut Parser,
+    tree: &mut Tree,
+    edits: &[TSInputEdit],
+    src: &[u8],
+) -> Result<(Tree, Vec<TSRange>), mlua::Error> {
+    for edit in edits {
+        tree.edit(edit);
+    }
+    let new = parser
+        .parse(src, Some(tree))
+        .ok_or_else(|| mlua::Error::RuntimeError("cannot reparse tree".to_string()))?;
+    let changed = tree.changed_ranges(&new).map(TSRange).collect();
+    Ok((new, changed))
+}
+
+/// Adds `reparse` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    crate::companion_module(lua)?.set(
+        "reparse",
+        lua.create_function(
+            |lua, (tree, edits, src): (TreeWithSource, Vec<TSInputEdit>, mlua::String)| {
+                let mut old = tree.tree;
+                let mut parser = Parser::new();
+                parser
+                    .set_language(old.language())
+                    .map_err(mlua::Error::external)?;
+                let (new, changed) = reparse(&mut parser, &mut old, &edits, src.as_bytes())?;
+                Ok((new.with_source(src.as_bytes()).into_lua(lua)?, changed))
+            },
+        )?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_reparse_edited_trees() {
+        let code = b"x = 1\n";
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let edit: TSInputEdit = l.call(
+            r#"
+              edit = {
+                start_byte = 4, old_end_byte = 5, new_end_byte = 5,
+                start_point = { row = 0, column = 4 },
+                old_end_point = { row = 0, column = 5 },
+                new_end_point = { row = 0, column = 5 },
+              }
+              return edit
+            "#,
+        );
+        assert_eq!(
+            (4, 5, 5),
+            (edit.start_byte, edit.old_end_byte, edit.new_end_byte)
+        );
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local edited, changed = ltreesitter_rs.reparse(parsed, { edit }, "x = y\n")
+              assert(#changed >= 1)
+              for _, range in ipairs(changed) do
+                assert(range.start_byte <= 5 and range.end_byte >= 4)
+                assert(range.start_point.row == 0)
+              end
+              local value = edited:root():child(0):child(0):child_by_field_name("right")
+              assert(value:type() == "identifier" and value:source() == "y")
+              assert(parsed:root():end_byte() == 6)
+              assert(not pcall(ltreesitter_rs.reparse, parsed, { { start_byte = 1 } }, "x"))
+            "#,
+        );
+        l.globals().set("pushed", edit).unwrap();
+        l.check(r#" assert(pushed.new_end_point.column == 5) "#);
+    }
+}
@@ -0,0 +1,132 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Lets Lua scripts stream typed results back to Rust.
+//!
+//! Scripts that produce many findings often want to report them as they go, rather than collecting
+//! them all into a single return value.  Once you've registered a channel for a tag, Lua code can
+//! call `require("ltreesitter_rs").emit(tag, payload)`; the payload is converted into a Rust value
+//! and sent to the channel's receiver.
+
+use std::sync::mpsc;
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::Lua;
+use mlua::MaybeSend;
+use mlua::Table;
+use mlua::Value;
+
+const EMITTERS_KEY: &str = "mlua_tree_sitter.emitters";
+
+/// An extension trait that lets you receive values that Lua code emits via
+/// `ltreesitter_rs.emit`.
+pub trait EmitChannels {
+    /// Registers a channel for the payloads that Lua code emits with the given tag.  Each payload
+    /// is converted into a `T` via its [`FromLua`] implementation.  Registering a new channel for
+    /// a tag replaces any existing channel for that tag.
+    fn emit_channel<T>(&self, tag: &str) -> Result<mpsc::Receiver<T>, mlua::Error>
+    where
+        T: for<'lua> FromLua<'lua> + MaybeSend + 'static,
+    {
+        self.emit_channel_with(tag, |value, lua| T::from_lua(value, lua))
+    }
+
+    /// Registers a channel for the payloads that Lua code emits with the given tag, using a custom
+    /// function to convert each payload into a Rust value.
+    fn emit_channel_with<T, F>(
+        &self,
+        tag: &str,
+        convert: F,
+    ) -> Result<mpsc::Receiver<T>, mlua::Error>
+    where
+        T: MaybeSend + 'static,
+        F: for<'lua> Fn(Value<'lua>, &'lua Lua) -> Result<T, mlua::Error> + MaybeSend + 'static;
+}
+
+impl EmitChannels for Lua {
+    fn emit_channel_with<T, F>(
+        &self,
+        tag: &str,
+        convert: F,
+    ) -> Result<mpsc::Receiver<T>, mlua::Error>
+    where
+        T: MaybeSend + 'static,
+        F: for<'lua> Fn(Value<'lua>, &'lua Lua) -> Result<T, mlua::Error> + MaybeSend + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let emitter = self.create_function(move |lua, payload: Value| {
+            let payload = convert(payload, lua)?;
+            // The host might have closed the channel because it isn't interested in any more
+            // results; let the script know that.
+            Ok(sender.send(payload).is_ok())
+        })?;
+        emitters(self)?.set(tag, emitter)?;
+        Ok(receiver)
+    }
+}
+
+fn emitters(lua: &Lua) -> Result<Table, mlua::Error> {
+    if let Some(emitters) = lua.named_registry_value::<Option<Table>>(EMITTERS_KEY)? {
+        return Ok(emitters);
+    }
+    let emitters = lua.create_table()?;
+    lua.set_named_registry_value(EMITTERS_KEY, emitters.clone())?;
+    let emit = lua.create_function(|lua, (tag, payload): (String, Value)| {
+        let emitter: Option<Function> = emitters(lua)?.get(tag.as_str())?;
+        let emitter = emitter.ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("no channel registered for tag {}", tag))
+        })?;
+        emitter.call::<_, bool>(payload)
+    })?;
+    crate::companion_module(lua)?.set("emit", emit)?;
+    Ok(emitters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::TSNode;
+    use crate::WithSource;
+
+    #[test]
+    fn can_stream_results_to_rust() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let messages = l.emit_channel::<String>("message").unwrap();
+        let kinds = l
+            .emit_channel_with("node", |value, lua| {
+                Ok(TSNode::from_lua(value, lua)?.kind().to_string())
+            })
+            .unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local root = parsed:root()
+              assert(ltreesitter_rs.emit("message", "first"))
+              assert(ltreesitter_rs.emit("node", root))
+              assert(ltreesitter_rs.emit("node", root:child(0)))
+              assert(ltreesitter_rs.emit("message", "second"))
+              assert(not pcall(ltreesitter_rs.emit, "unknown", 1))
+            "#,
+        );
+        assert_eq!(
+            vec!["first".to_string(), "second".to_string()],
+            messages.try_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["module".to_string(), "function_definition".to_string()],
+            kinds.try_iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Controls how the text of a node is decoded when it's returned to Rust.
+//!
+//! tree-sitter works on bytes, and is happy to parse sources that aren't valid UTF-8 (like files
+//! that contain binary string literals).  Each Lua environment has a [`TextEncoding`], which
+//! [`TreeWithSource::node_text`] and [`Captures::text`] use to decide what to do with such text:
+//! raise an error ([`Strict`][TextEncoding::Strict], the default), replace invalid sequences with
+//! U+FFFD ([`Lossy`][TextEncoding::Lossy]), or return the bytes as they are
+//! ([`Raw`][TextEncoding::Raw]).  Set it with [`TextEncodings::set_text_encoding`].
+//!
+//! To get at a node's text without a Lua environment, use [`TreeWithSource::node_str`], which
+//! always requires valid UTF-8, or [`TreeWithSource::node_bytes`].  In Lua,
+//! `require("ltreesitter_rs").node_text(node)` returns a node's text, decoded with the
+//! environment's encoding, so that Lua code doesn't have to slice the source by hand.
+
+use std::borrow::Cow;
+use std::str::Utf8Error;
+
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Node;
+
+use crate::pretty;
+use crate::Captures;
+use crate::TSNode;
+use crate::TreeWithSource;
+
+/// How node text is decoded.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TextEncoding {
+    /// Text must be valid UTF-8, and anything else is an error.
+    #[default]
+    Strict,
+    /// Invalid UTF-8 is replaced with U+FFFD.
+    Lossy,
+    /// Text is returned as raw bytes, without being decoded.
+    Raw,
+}
+
+impl TextEncoding {
+    /// Decodes some text.
+    pub fn decode(self, text: &[u8]) -> Result<NodeText<'_>, mlua::Error> {
+        match self {
+            TextEncoding::Strict => std::str::from_utf8(text)
+                .map(|text| NodeText::Utf8(Cow::Borrowed(text)))
+                .map_err(mlua::Error::external),
+            TextEncoding::Lossy => Ok(NodeText::Utf8(String::from_utf8_lossy(text))),
+            TextEncoding::Raw => Ok(NodeText::Raw(text)),
+        }
+    }
+}
+
+/// The text of a node, decoded according to a [`TextEncoding`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NodeText<'a> {
+    Utf8(Cow<'a, str>),
+    Raw(&'a [u8]),
+}
+
+impl<'a> NodeText<'a> {
+    /// Returns the text as a string, if it was decoded.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            NodeText::Utf8(text) => Some(text),
+            NodeText::Raw(_) => None,
+        }
+    }
+
+    /// Returns the text as bytes.  (For lossily decoded text, these are the bytes of the decoded
+    /// string, not of the original source.)
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            NodeText::Utf8(text) => text.as_bytes(),
+            NodeText::Raw(text) => text,
+        }
+    }
+
+    /// Returns the text as a string, replacing any invalid UTF-8.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        match self {
+            NodeText::Utf8(text) => Cow::Borrowed(text),
+            NodeText::Raw(text) => String::from_utf8_lossy(text),
+        }
+    }
+}
+
+impl<'lua> IntoLua<'lua> for NodeText<'_> {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        lua.create_string(self.as_bytes()).map(Value::String)
+    }
+}
+
+/// An extension trait that lets you choose how node text is decoded in a Lua environment.
+pub trait TextEncodings {
+    /// Sets how node text is decoded.
+    fn set_text_encoding(&self, encoding: TextEncoding);
+
+    /// Returns how node text is decoded.
+    fn text_encoding(&self) -> TextEncoding;
+}
+
+impl TextEncodings for Lua {
+    fn set_text_encoding(&self, encoding: TextEncoding) {
+        self.set_app_data(encoding);
+    }
+
+    fn text_encoding(&self) -> TextEncoding {
+        self.app_data_ref::<TextEncoding>()
+            .map(|encoding| *encoding)
+            .unwrap_or_default()
+    }
+}
+
+impl TSNode<'_> {
+    /// Returns the text of this node, decoded with the given encoding.
+    pub fn text<'a>(
+        &self,
+        src: &'a [u8],
+        encoding: TextEncoding,
+    ) -> Result<NodeText<'a>, mlua::Error> {
+        let text = src.get(self.byte_range()).ok_or_else(|| {
+            mlua::Error::RuntimeError("node is out of bounds of its source".to_string())
+        })?;
+        encoding.decode(text)
+    }
+}
+
+impl<'a> TreeWithSource<'a> {
+    /// Returns the text of a node of this tree, decoded with the Lua environment's encoding.
+    pub fn node_text(&self, lua: &Lua, node: Node) -> Result<NodeText<'a>, mlua::Error> {
+        TSNode::new(node).text(self.src, lua.text_encoding())
+    }
+
+    /// Returns the text of a node of this tree as a string, or an error if it isn't valid UTF-8.
+    ///
+    /// Panics if the node's range is out of bounds of the tree's source.
+    pub fn node_str(&self, node: Node) -> Result<&'a str, Utf8Error> {
+        std::str::from_utf8(self.node_bytes(node))
+    }
+
+    /// Returns the bytes of the text of a node of this tree.
+    ///
+    /// Panics if the node's range is out of bounds of the tree's source.
+    pub fn node_bytes(&self, node: Node) -> &'a [u8] {
+        &self.src[node.byte_range()]
+    }
+}
+
+/// Adds `node_text` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let node_text = lua.create_function(|lua, value: Value| {
+        let (node, src) = pretty::node_and_source(lua, value)?;
+        let src = src.ok_or_else(|| {
+            mlua::Error::RuntimeError("cannot find the source of the node's tree".to_string())
+        })?;
+        TSNode::new(node).text(src, lua.text_encoding())
+    })?;
+    crate::companion_module(lua)?.set("node_text", node_text)
+}
+
+impl Captures<'_, '_> {
+    /// Returns the text of the first node that was captured with the given name, decoded with the
+    /// Lua environment's encoding.
+    pub fn text<'s>(
+        &self,
+        lua: &Lua,
+        name: &str,
+        src: &'s [u8],
+    ) -> Result<Option<NodeText<'s>>, mlua::Error> {
+        self.get(name)
+            .map(|node| node.text(src, lua.text_encoding()))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_choose_text_encoding() {
+        let code = b"x = '\xff'\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(&code[..], None).unwrap().with_source(code);
+        let string = parsed
+            .tree
+            .root_node()
+            .descendant_for_byte_range(4, 7)
+            .unwrap();
+        assert_eq!("string", string.kind());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        assert_eq!(TextEncoding::Strict, l.text_encoding());
+        assert!(parsed.node_text(&l, string).is_err());
+        l.set_text_encoding(TextEncoding::Lossy);
+        let text = parsed.node_text(&l, string).unwrap();
+        assert_eq!(Some("'\u{fffd}'"), text.as_str());
+        l.set_text_encoding(TextEncoding::Raw);
+        let text = parsed.node_text(&l, string).unwrap();
+        assert_eq!(&b"'\xff'"[..], text.as_bytes());
+        assert_eq!(None, text.as_str());
+
+        let name = parsed
+            .tree
+            .root_node()
+            .descendant_for_byte_range(0, 1)
+            .unwrap();
+        let text = TSNode::new(name).text(code, TextEncoding::Strict).unwrap();
+        assert_eq!(Some("x"), text.as_str());
+    }
+
+    #[test]
+    fn can_get_node_text() {
+        let code = "x = 'h\u{e9}llo'\n".as_bytes();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap().with_source(code);
+        let assignment = parsed.tree.root_node().child(0).unwrap().child(0).unwrap();
+        let string = assignment.child_by_field_name("right").unwrap();
+        assert_eq!("string", string.kind());
+        assert_eq!(Ok("'h\u{e9}llo'"), parsed.node_str(string));
+        assert_eq!("'h\u{e9}llo'".as_bytes(), parsed.node_bytes(string));
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        let text: String = l
+            .load(
+                r#"
+                  local string = parsed:root():child(0):child(0):child(2)
+                  return require("ltreesitter_rs").node_text(string)
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!("'h\u{e9}llo'", text);
+    }
+}
@@ -0,0 +1,119 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Installs the `ltreesitter` module into isolated script environments.
+//!
+//! Hosts that run several kinds of scripts in one Lua state often give each of them its own
+//! environment table (via [`mlua::Chunk::set_environment`]).
+//! [`Module::open_ltreesitter_in`][crate::Module::open_ltreesitter_in] gives one of those
+//! environments its own copy of the `ltreesitter` module, with its own [`Options`], without
+//! touching the global `package` table.  That lets you, say, allow trusted plugins to load grammar
+//! libraries, while untrusted ones can only use the grammars that the host registered.
+//!
+//! Every copy shares the same parsers, trees, and nodes, so the policies only cover the module's
+//! own functions: `parsers` and `dynamic_loading` can be set per environment, but `queries` can't,
+//! since parser methods are shared by all environments.
+
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+
+use crate::ltreesitter;
+use crate::options;
+use crate::Module;
+use crate::Options;
+use crate::Placement;
+
+/// Installs a copy of the `ltreesitter` module into `env`, and returns it.  If the module hasn't
+/// been opened yet, it's opened without registering it in the global `package` table.
+pub(crate) fn open_in<'lua>(
+    lua: &'lua Lua,
+    env: &Table<'lua>,
+    options: Options,
+) -> Result<Table<'lua>, mlua::Error> {
+    if !options.queries {
+        return Err(mlua::Error::RuntimeError(
+            "queries can't be disabled in a single environment".to_string(),
+        ));
+    }
+    let shared = lua.named_registry_value::<Option<Table>>(ltreesitter::MODULE_KEY)?;
+    if shared.is_none() && !options::load_preloaded(lua)? {
+        lua.open_ltreesitter_with(Options::new().with_placement(Placement::Hidden))?;
+    }
+    let shared = ltreesitter::module(lua)?;
+
+    let module = lua.create_table()?;
+    for entry in shared.pairs::<Value, Value>() {
+        let (key, value) = entry?;
+        module.raw_set(key, value)?;
+    }
+    options::restrict(lua, &module, &options)?;
+    env.set(options.name.as_str(), module.clone())?;
+    if let Some(package) = env.get::<_, Option<Table>>("package")? {
+        options::place(lua, &package, &module, &options)?;
+    }
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_open_module_in_isolated_environments() {
+        let l = Lua::new();
+        let trusted = l.create_table().unwrap();
+        let untrusted = l.create_table().unwrap();
+        for env in [&trusted, &untrusted] {
+            env.set("assert", l.globals().get::<_, Value>("assert").unwrap())
+                .unwrap();
+            env.set("pcall", l.globals().get::<_, Value>("pcall").unwrap())
+                .unwrap();
+        }
+        l.open_ltreesitter_in(&trusted, Options::new()).unwrap();
+        l.open_ltreesitter_in(
+            &untrusted,
+            Options::new().with_name("ts").with_dynamic_loading(false),
+        )
+        .unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        assert!(l
+            .load(r#" return package.loaded.ltreesitter == nil and ltreesitter == nil "#)
+            .eval::<bool>()
+            .unwrap());
+
+        l.load(
+            r#"
+              assert(ltreesitter.require("python") ~= nil)
+              assert(ltreesitter.load ~= nil)
+            "#,
+        )
+        .set_environment(trusted)
+        .exec()
+        .unwrap();
+        l.load(
+            r#"
+              assert(ltreesitter == nil)
+              local parser = ts.require("python")
+              assert(parser:parse_string("x = 1"):root():type() == "module")
+              assert(not pcall(ts.load, "./libc.so", "c"))
+              assert(not pcall(ts.require, "c"))
+            "#,
+        )
+        .set_environment(untrusted)
+        .exec()
+        .unwrap();
+
+        let err = l
+            .open_ltreesitter_in(
+                &l.create_table().unwrap(),
+                Options::new().with_queries(false),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("queries"));
+    }
+}
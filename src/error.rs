@@ -0,0 +1,173 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! The ways that the bridge itself can fail.
+//!
+//! The bridge's functions return [`mlua::Error`]s, since that's what mlua's conversion traits and
+//! callbacks need.  When the failure comes from the bridge (rather than from Lua code, or from
+//! mlua), the `mlua::Error` wraps an [`Error`], which you can get back via [`Error::find`] and
+//! match on.  A few limits and checks have their own error types, which are wrapped in the same
+//! way: [`LimitExceeded`][crate::LimitExceeded], [`GrammarMismatch`][crate::GrammarMismatch], and
+//! [`StaleNode`][crate::StaleNode].
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+/// A failure in the bridge between tree-sitter and Lua.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A Lua value isn't the kind of ltreesitter object that we expected.  `expected` is the name
+    /// of ltreesitter's metatable for that kind of object (like `ltreesitter.Tree`), and `actual`
+    /// is the Lua type of the value.
+    WrongUserdataType {
+        expected: String,
+        actual: &'static str,
+    },
+    /// A tree's source code is shorter than the tree says that it is.
+    SourceLengthMismatch { tree_len: usize, source_len: usize },
+    /// A grammar was generated with a version of tree-sitter whose ABI we don't support.
+    LanguageVersionMismatch {
+        language: String,
+        version: usize,
+        min_version: usize,
+        max_version: usize,
+    },
+    /// A tree has already been closed.
+    ClosedTree,
+    /// A tree can't be closed, because Rust code is still using it.
+    PinnedTree,
+    /// A node can't be converted into an ltreesitter node, because it doesn't belong to a tree
+    /// that was passed to (or came from) Lua.
+    DetachedNode,
+    /// The `ltreesitter` module hasn't been loaded into the Lua environment.
+    ModuleNotLoaded,
+}
+
+impl Error {
+    /// Returns the bridge error that caused an `mlua::Error`, if any.  This looks through the
+    /// callback errors that mlua adds when an error passes through Lua code.
+    pub fn find(err: &mlua::Error) -> Option<&Error> {
+        match err {
+            mlua::Error::CallbackError { cause, .. } => Error::find(cause),
+            mlua::Error::WithContext { cause, .. } => Error::find(cause),
+            err => err.downcast_ref(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::WrongUserdataType { expected, actual } => {
+                write!(f, "expected {}, got {}", expected, actual)
+            }
+            Error::SourceLengthMismatch {
+                tree_len,
+                source_len,
+            } => write!(
+                f,
+                "tree covers {} bytes, but its source is only {} bytes long",
+                tree_len, source_len
+            ),
+            Error::LanguageVersionMismatch {
+                language,
+                version,
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "grammar {} has incompatible ABI version {} (expected {} through {})",
+                language, version, min_version, max_version
+            ),
+            Error::ClosedTree => write!(f, "tree has been closed"),
+            Error::PinnedTree => write!(f, "tree cannot be closed while Rust code is using it"),
+            Error::DetachedNode => write!(
+                f,
+                "node doesn't belong to a Lua tree; see LuaNode::in_lua_tree"
+            ),
+            Error::ModuleNotLoaded => write!(f, "the ltreesitter module hasn't been loaded"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for mlua::Error {
+    fn from(err: Error) -> mlua::Error {
+        mlua::Error::external(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use super::*;
+    use crate::HostFunctions;
+    use crate::Module;
+    use crate::TSNode;
+    use crate::TreeWithSource;
+    use crate::WithSource;
+
+    #[test]
+    fn can_match_on_bridge_errors() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let err = l
+            .load(r#" require("ltreesitter_rs").pretty_print(3) "#)
+            .exec()
+            .unwrap_err();
+        assert_eq!(
+            Some(&Error::WrongUserdataType {
+                expected: "ltreesitter.Node".to_string(),
+                actual: "integer",
+            }),
+            Error::find(&err)
+        );
+
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let err = l
+            .globals()
+            .set("parsed", parsed.with_source(&code[..10]))
+            .unwrap_err();
+        assert_eq!(
+            Some(&Error::SourceLengthMismatch {
+                tree_len: code.len(),
+                source_len: 10,
+            }),
+            Error::find(&err)
+        );
+    }
+
+    #[test]
+    fn can_reject_wrong_values_without_raising_lua_errors() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let err = l
+            .load(r#" return "x = 1" "#)
+            .eval::<TreeWithSource>()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            mlua::Error::FromLuaConversionError {
+                from: "string",
+                to: "TreeWithSource",
+                ..
+            }
+        ));
+        l.register_function("kind_of", |_, node: TSNode| Ok(node.kind().to_string()))
+            .unwrap();
+        let err = l
+            .load(r#" require("ltreesitter_rs").kind_of({}) "#)
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("expected an ltreesitter node"));
+    }
+}
@@ -0,0 +1,215 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Describes a node in terms of its grammar, for tools that teach users how their language is
+//! parsed.
+//!
+//! [`explain`] gathers everything that an "explain this node" panel needs in one pass: the node's
+//! kind and flags, which field of its parent it fills, its neighbors, and (if you provide the
+//! grammar's supertypes, as listed in its `node-types.json`) which supertypes it belongs to.
+//!
+//! In Lua, `require("ltreesitter_rs").explain(node [, supertypes])` returns a table with the same
+//! fields as [`NodeExplanation`], where `supertypes` maps each supertype to a list of its subtypes.
+//! If you don't pass `supertypes`, the grammar's registered [node types][crate::NodeTypes] are
+//! used, if there are any.  Child indexes are 0-based, like ltreesitter's.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use mlua::Lua;
+use tree_sitter::Node;
+
+use crate::offsets::Offset;
+use crate::NodeTypeMetadata;
+use crate::TSNode;
+
+/// Everything that [`explain`] knows about a node.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NodeExplanation {
+    pub kind: String,
+    pub kind_id: u16,
+    pub named: bool,
+    pub extra: bool,
+    pub missing: bool,
+    pub error: bool,
+    /// Whether the node or any of its descendants is an error or missing node.
+    pub has_error: bool,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub child_count: usize,
+    pub named_child_count: usize,
+    /// The kind of the node's parent.
+    pub parent: Option<String>,
+    /// The name of the field of the parent that the node fills.
+    pub field: Option<String>,
+    /// The (0-based) index of the node among all of its parent's children.
+    pub child_index: Option<usize>,
+    /// The kind of the closest named sibling before the node.
+    pub previous_sibling: Option<String>,
+    /// The kind of the closest named sibling after the node.
+    pub next_sibling: Option<String>,
+    /// The supertypes that the node's kind belongs to, directly or via other supertypes.
+    pub supertypes: Vec<String>,
+}
+
+/// Describes a node.  `supertypes` maps each of the grammar's supertypes to its subtypes, which
+/// can themselves be supertypes; it can be empty if you don't have that information.
+pub fn explain(node: Node, supertypes: &BTreeMap<String, Vec<String>>) -> NodeExplanation {
+    let mut explanation = NodeExplanation {
+        kind: node.kind().to_string(),
+        kind_id: node.kind_id(),
+        named: node.is_named(),
+        extra: node.is_extra(),
+        missing: node.is_missing(),
+        error: node.is_error(),
+        has_error: node.has_error(),
+        start_byte: node.start_byte(),
+        end_byte: node.end_byte(),
+        child_count: node.child_count(),
+        named_child_count: node.named_child_count(),
+        previous_sibling: node.prev_named_sibling().map(|n| n.kind().to_string()),
+        next_sibling: node.next_named_sibling().map(|n| n.kind().to_string()),
+        supertypes: supertypes_of(node.kind(), supertypes),
+        ..NodeExplanation::default()
+    };
+    if let Some(parent) = node.parent() {
+        explanation.parent = Some(parent.kind().to_string());
+        let mut cursor = parent.walk();
+        if cursor.goto_first_child() {
+            let mut index = 0;
+            loop {
+                if cursor.node() == node {
+                    explanation.child_index = Some(index);
+                    explanation.field = cursor.field_name().map(str::to_string);
+                    break;
+                }
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+                index += 1;
+            }
+        }
+    }
+    explanation
+}
+
+/// Returns every supertype that contains `kind`, in alphabetical order.
+fn supertypes_of(kind: &str, supertypes: &BTreeMap<String, Vec<String>>) -> Vec<String> {
+    fn contains(
+        supertype: &str,
+        kind: &str,
+        supertypes: &BTreeMap<String, Vec<String>>,
+        visited: &mut BTreeSet<String>,
+    ) -> bool {
+        if !visited.insert(supertype.to_string()) {
+            return false;
+        }
+        let subtypes = match supertypes.get(supertype) {
+            Some(subtypes) => subtypes,
+            None => return false,
+        };
+        subtypes
+            .iter()
+            .any(|subtype| subtype == kind || contains(subtype, kind, supertypes, visited))
+    }
+    supertypes
+        .keys()
+        .filter(|supertype| contains(supertype, kind, supertypes, &mut BTreeSet::new()))
+        .cloned()
+        .collect()
+}
+
+/// Adds `explain` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let explain = lua.create_function(
+        |lua, (node, supertypes): (TSNode, Option<BTreeMap<String, Vec<String>>>)| {
+            let supertypes = match supertypes {
+                Some(supertypes) => supertypes,
+                None => match lua.node_types(node.language()) {
+                    Some(node_types) => node_types.supertype_map(),
+                    None => BTreeMap::new(),
+                },
+            };
+            let explanation = explain(*node, &supertypes);
+            let result = lua.create_table()?;
+            result.set("kind", explanation.kind)?;
+            result.set("kind_id", explanation.kind_id)?;
+            result.set("named", explanation.named)?;
+            result.set("extra", explanation.extra)?;
+            result.set("missing", explanation.missing)?;
+            result.set("error", explanation.error)?;
+            result.set("has_error", explanation.has_error)?;
+            result.set("start_byte", Offset(explanation.start_byte))?;
+            result.set("end_byte", Offset(explanation.end_byte))?;
+            result.set("child_count", explanation.child_count)?;
+            result.set("named_child_count", explanation.named_child_count)?;
+            result.set("parent", explanation.parent)?;
+            result.set("field", explanation.field)?;
+            result.set("child_index", explanation.child_index)?;
+            result.set("previous_sibling", explanation.previous_sibling)?;
+            result.set("next_sibling", explanation.next_sibling)?;
+            result.set("supertypes", explanation.supertypes)?;
+            Ok(result)
+        },
+    )?;
+    crate::companion_module(lua)?.set("explain", explain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_explain_nodes() {
+        let code = b"x = 1\ndef f(a): pass\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+
+        let supertypes = BTreeMap::from([
+            (
+                "expression".to_string(),
+                vec!["primary_expression".to_string()],
+            ),
+            (
+                "primary_expression".to_string(),
+                vec!["identifier".to_string(), "integer".to_string()],
+            ),
+        ]);
+        let definition = parsed.root_node().child(1).unwrap();
+        let name = definition.child_by_field_name("name").unwrap();
+        let explanation = explain(name, &supertypes);
+        assert_eq!("identifier", explanation.kind);
+        assert_eq!(Some("function_definition".to_string()), explanation.parent);
+        assert_eq!(Some("name".to_string()), explanation.field);
+        assert_eq!(Some(1), explanation.child_index);
+        assert_eq!(Some("parameters".to_string()), explanation.next_sibling);
+        assert_eq!(
+            vec!["expression".to_string(), "primary_expression".to_string()],
+            explanation.supertypes
+        );
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local explain = require("ltreesitter_rs").explain
+              local root = parsed:root()
+              local info = explain(root:child(0))
+              assert(info.kind == "expression_statement" and info.parent == "module")
+              assert(info.child_index == 0 and info.field == nil)
+              assert(info.next_sibling == "function_definition" and #info.supertypes == 0)
+              local value = root:child(0):child(0):child_by_field_name("right")
+              info = explain(value, { expression = { "integer" } })
+              assert(info.field == "right" and info.supertypes[1] == "expression")
+            "#,
+        );
+    }
+}
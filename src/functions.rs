@@ -0,0 +1,95 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Lets you expose host functions to Lua code via the `ltreesitter_rs` module.
+
+use mlua::FromLuaMulti;
+use mlua::IntoLuaMulti;
+use mlua::Lua;
+use mlua::MaybeSend;
+use mlua::MultiValue;
+
+/// An extension trait that lets you add host functions to the `ltreesitter_rs` module.
+pub trait HostFunctions {
+    /// Adds a function to the `ltreesitter_rs` module.  The function's arguments are converted
+    /// from Lua via their [`FromLuaMulti`] implementations, so you can accept ltreesitter objects
+    /// directly using types like [`TSNode`][crate::TSNode].  If the arguments can't be converted,
+    /// the Lua caller gets an error that names the function.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), anyhow::Error> {
+    /// use mlua_tree_sitter::HostFunctions;
+    /// use mlua_tree_sitter::Module;
+    /// use mlua_tree_sitter::TSNode;
+    ///
+    /// let lua = mlua::Lua::new();
+    /// lua.open_ltreesitter()?;
+    /// lua.register_function("kind_of", |_, node: TSNode| Ok(node.kind().to_string()))?;
+    /// // Lua code can now call require("ltreesitter_rs").kind_of(node)
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn register_function<'lua, A, R, F>(&'lua self, name: &str, func: F) -> Result<(), mlua::Error>
+    where
+        A: FromLuaMulti<'lua>,
+        R: IntoLuaMulti<'lua>,
+        F: Fn(&'lua Lua, A) -> Result<R, mlua::Error> + MaybeSend + 'static;
+}
+
+impl HostFunctions for Lua {
+    fn register_function<'lua, A, R, F>(&'lua self, name: &str, func: F) -> Result<(), mlua::Error>
+    where
+        A: FromLuaMulti<'lua>,
+        R: IntoLuaMulti<'lua>,
+        F: Fn(&'lua Lua, A) -> Result<R, mlua::Error> + MaybeSend + 'static,
+    {
+        let qualified = format!("ltreesitter_rs.{}", name);
+        let function = self.create_function(move |lua, args: MultiValue<'lua>| {
+            let args = A::from_lua_multi(args, lua).map_err(|err| {
+                mlua::Error::RuntimeError(format!("bad arguments to {}: {}", qualified, err))
+            })?;
+            func(lua, args)
+        })?;
+        crate::companion_module(self)?.set(name, function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::TSNode;
+    use crate::WithSource;
+
+    #[test]
+    fn can_call_host_functions_with_nodes() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_function("kind_of", |_, node: TSNode| Ok(node.kind().to_string()))
+            .unwrap();
+        l.register_function("same_start", |_, (a, b): (TSNode, TSNode)| {
+            Ok(a.start_byte() == b.start_byte())
+        })
+        .unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local root = parsed:root()
+              assert(ltreesitter_rs.kind_of(root) == "module")
+              assert(ltreesitter_rs.same_start(root, root:child(0)))
+              local ok, err = pcall(ltreesitter_rs.kind_of, 42)
+              assert(not ok)
+              assert(tostring(err):find("ltreesitter_rs.kind_of", 1, true))
+            "#,
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Detects queries that are run over trees that were parsed with a different grammar.
+//!
+//! Running a query that was compiled for one grammar over a tree that was parsed with another
+//! doesn't fail; it just quietly produces no matches, or nonsensical ones.  To catch this, the
+//! bridge records the grammar of each query that `parser:query` compiles, and checks it against
+//! the grammar of the tree whenever the query's `match`, `capture`, or `exec` method is called.
+//! (Every tree already knows which grammar it was parsed with.)  By default a mismatch raises an
+//! external [`mlua::Error`] wrapping a [`GrammarMismatch`]; hosts can choose to only get a
+//! [warning][crate::ScriptWarning] instead via [`GrammarChecks::set_grammar_mismatch_policy`].
+
+use std::ffi::c_void;
+
+use mlua::LightUserData;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+use tree_sitter::Language;
+
+use crate::ltreesitter;
+use crate::warnings;
+use crate::warnings::WarningKind;
+
+const QUERY_LANGUAGES_KEY: &str = "mlua_tree_sitter.query_languages";
+const WARNED_KEY: &str = "mlua_tree_sitter.grammar_warnings";
+
+/// What to do when a query is run over a tree that was parsed with a different grammar.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MismatchPolicy {
+    /// Raise an error.
+    #[default]
+    Error,
+    /// Send a warning to the host's [warning channel][crate::ScriptWarnings::warning_channel],
+    /// once per query, and run the query anyway.
+    Warn,
+    /// Run the query anyway.
+    Ignore,
+}
+
+/// The error that you get when a query is run over a tree that was parsed with a different
+/// grammar.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GrammarMismatch {
+    /// The ABI version of the grammar that the query was compiled for.
+    pub query_version: usize,
+    /// The ABI version of the grammar that the tree was parsed with.
+    pub tree_version: usize,
+}
+
+impl std::fmt::Display for GrammarMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "query was compiled for a different grammar (ABI version {}) than the tree was \
+             parsed with (ABI version {})",
+            self.query_version, self.tree_version
+        )
+    }
+}
+
+impl std::error::Error for GrammarMismatch {}
+
+/// An extension trait that lets you control how grammar mismatches are handled.
+pub trait GrammarChecks {
+    /// Sets what happens when a query is run over a tree that was parsed with a different grammar.
+    fn set_grammar_mismatch_policy(&self, policy: MismatchPolicy);
+
+    /// Returns what happens when a query is run over a tree that was parsed with a different
+    /// grammar.
+    fn grammar_mismatch_policy(&self) -> MismatchPolicy;
+}
+
+impl GrammarChecks for Lua {
+    fn set_grammar_mismatch_policy(&self, policy: MismatchPolicy) {
+        self.set_app_data(policy);
+    }
+
+    fn grammar_mismatch_policy(&self) -> MismatchPolicy {
+        self.app_data_ref::<MismatchPolicy>()
+            .map(|policy| *policy)
+            .unwrap_or_default()
+    }
+}
+
+/// Records the grammar that a query was compiled for, and the source that it was compiled from.
+pub(crate) fn record_query_language<'lua>(
+    lua: &'lua Lua,
+    query: Value<'lua>,
+    language: *const c_void,
+    version: usize,
+    source: Option<mlua::String<'lua>>,
+) -> Result<(), mlua::Error> {
+    let languages: Table = lua.named_registry_value(QUERY_LANGUAGES_KEY)?;
+    let entry = lua.create_table()?;
+    entry.set("language", LightUserData(language as *mut c_void))?;
+    entry.set("version", version)?;
+    entry.set("source", source)?;
+    languages.raw_set(query, entry)
+}
+
+/// Records that a query that was derived from another one (via `query:with`) has the same grammar
+/// and source.
+pub(crate) fn share_query_entry<'lua>(
+    lua: &'lua Lua,
+    query: &Value<'lua>,
+    derived: Value<'lua>,
+) -> Result<(), mlua::Error> {
+    let languages: Table = lua.named_registry_value(QUERY_LANGUAGES_KEY)?;
+    let entry: Option<Table> = languages.raw_get(query.clone())?;
+    match entry {
+        Some(entry) => languages.raw_set(derived, entry),
+        None => Ok(()),
+    }
+}
+
+/// Returns the grammar that a query was compiled for and the source that it was compiled from, if
+/// we know them.  The returned table is where other modules can cache information about the query.
+pub(crate) fn query_entry<'lua>(
+    lua: &'lua Lua,
+    query: &Value<'lua>,
+) -> Result<Option<(Language, String, Table<'lua>)>, mlua::Error> {
+    let languages: Table = lua.named_registry_value(QUERY_LANGUAGES_KEY)?;
+    let entry: Option<Table> = languages.raw_get(query.clone())?;
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let source: Option<String> = entry.get("source")?;
+    let source = match source {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    let LightUserData(language) = entry.get("language")?;
+    // Language is a transparent wrapper around a TSLanguage pointer, since grammar crates return
+    // it directly from their C entry points.
+    let language = unsafe { std::mem::transmute::<*const c_void, Language>(language) };
+    Ok(Some((language, source, entry)))
+}
+
+/// Checks that a query was compiled for the grammar that a node's tree was parsed with, before the
+/// query's `method` is run over it.  Does nothing if we don't know which grammar the query was
+/// compiled for.
+fn check_query_language<'lua>(
+    lua: &'lua Lua,
+    method: &str,
+    query: &Value<'lua>,
+    node: Value<'lua>,
+) -> Result<(), mlua::Error> {
+    let languages: Table = lua.named_registry_value(QUERY_LANGUAGES_KEY)?;
+    let entry: Option<Table> = languages.raw_get(query.clone())?;
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    let LightUserData(query_language) = entry.get("language")?;
+    let ltreesitter_node = ltreesitter::node_ptr(lua, node)?;
+    let tree_language =
+        unsafe { tree_sitter::ffi::ts_tree_language((*ltreesitter_node).node.tree as *const _) };
+    if tree_language as *const c_void == query_language as *const c_void {
+        return Ok(());
+    }
+    let mismatch = GrammarMismatch {
+        query_version: entry.get("version")?,
+        tree_version: unsafe { tree_sitter::ffi::ts_language_version(tree_language) } as usize,
+    };
+    match lua.grammar_mismatch_policy() {
+        MismatchPolicy::Error => Err(mlua::Error::external(mismatch)),
+        MismatchPolicy::Warn => {
+            let warned: Table = lua.named_registry_value(WARNED_KEY)?;
+            if !warned.raw_get::<_, bool>(query.clone())? {
+                warned.raw_set(query.clone(), true)?;
+                let subject = format!("query:{}", method);
+                warnings::send(
+                    lua,
+                    WarningKind::GrammarMismatch,
+                    &subject,
+                    &mismatch.to_string(),
+                );
+            }
+            Ok(())
+        }
+        MismatchPolicy::Ignore => Ok(()),
+    }
+}
+
+/// Starts recording the grammar of each compiled query, and checking it whenever a query is run.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    lua.set_named_registry_value(QUERY_LANGUAGES_KEY, crate::weak_table(lua, "k")?)?;
+    lua.set_named_registry_value(WARNED_KEY, crate::weak_table(lua, "k")?)?;
+
+    if lua
+        .named_registry_value::<Option<Table>>(ltreesitter::PARSER_METATABLE)?
+        .is_some()
+    {
+        ltreesitter::wrap_method(
+            lua,
+            ltreesitter::PARSER_METATABLE,
+            "query",
+            |lua, original, args| {
+                let mut iter = args.iter();
+                let parser = iter.next().cloned().unwrap_or(Value::Nil);
+                let source = match iter.next() {
+                    Some(Value::String(source)) => Some(source.clone()),
+                    _ => None,
+                };
+                let result = original.call::<_, MultiValue>(args)?;
+                let query = match result.iter().next() {
+                    Some(query @ Value::UserData(_)) => query.clone(),
+                    _ => return Ok(result),
+                };
+                // ltreesitter doesn't expose a parser's grammar directly, but every tree knows
+                // which grammar it was parsed with.
+                let parse_string: mlua::Function =
+                    ltreesitter::methods(lua, ltreesitter::PARSER_METATABLE)?
+                        .get("parse_string")?;
+                if let Some(tree) = parse_string.call::<_, Option<Value>>((parser, ""))? {
+                    let tree = ltreesitter::tree_ptr(lua, tree)?;
+                    let language = unsafe { tree_sitter::ffi::ts_tree_language((*tree).tree) };
+                    let version = unsafe { tree_sitter::ffi::ts_language_version(language) };
+                    record_query_language(
+                        lua,
+                        query,
+                        language as *const c_void,
+                        version as usize,
+                        source,
+                    )?;
+                }
+                Ok(result)
+            },
+        )?;
+    }
+
+    for method in ["match", "capture", "exec"] {
+        ltreesitter::wrap_method(
+            lua,
+            ltreesitter::QUERY_METATABLE,
+            method,
+            move |lua, original, args| {
+                let mut iter = args.iter();
+                if let (Some(query), Some(node)) = (iter.next(), iter.next()) {
+                    check_query_language(lua, method, query, node.clone())?;
+                }
+                original.call::<_, MultiValue>(args)
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::ScriptWarnings;
+    use crate::WithSource;
+
+    #[test]
+    fn can_detect_grammar_mismatches() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let python_version = parsed.language().version();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let root: Value = l.call("return parsed:root()");
+
+        // Stand-ins for queries compiled for the same and for a different grammar.
+        let same: Value = l.call("return {}");
+        let tree = ltreesitter::tree_ptr(&l, l.globals().get("parsed").unwrap()).unwrap();
+        let python_ptr = unsafe { tree_sitter::ffi::ts_tree_language((*tree).tree) };
+        let python_ptr = python_ptr as *const c_void;
+        record_query_language(&l, same.clone(), python_ptr, python_version, None).unwrap();
+        check_query_language(&l, "match", &same, root.clone()).unwrap();
+
+        let other: Value = l.call("return {}");
+        static OTHER_GRAMMAR: u8 = 0;
+        let other_ptr = &OTHER_GRAMMAR as *const u8 as *const c_void;
+        record_query_language(&l, other.clone(), other_ptr, 13, None).unwrap();
+        let err = check_query_language(&l, "match", &other, root.clone()).unwrap_err();
+        let mismatch = err.downcast_ref::<GrammarMismatch>().unwrap();
+        assert_eq!(
+            (13, python_version),
+            (mismatch.query_version, mismatch.tree_version)
+        );
+
+        l.set_grammar_mismatch_policy(MismatchPolicy::Warn);
+        let warnings = l.warning_channel();
+        check_query_language(&l, "match", &other, root.clone()).unwrap();
+        check_query_language(&l, "capture", &other, root).unwrap();
+        let warnings = warnings.try_iter().collect::<Vec<_>>();
+        assert_eq!(1, warnings.len());
+        assert_eq!(WarningKind::GrammarMismatch, warnings[0].kind);
+        assert_eq!("query:match", warnings[0].subject);
+        assert_eq!(mismatch.to_string(), warnings[0].message);
+    }
+}
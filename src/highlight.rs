@@ -0,0 +1,237 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Highlights a document together with the languages that are injected into it.
+//!
+//! A [`Highlighter`] holds a [`HighlightConfig`] for each language that it knows about.  It runs
+//! the host language's highlights query, finds the host's [injections][crate::find_injections],
+//! and runs each guest language's highlights query over the injected text, recursively.  Guest
+//! trees are parsed with the injections' ranges as their included ranges, so that every guest
+//! span is already in the host document's coordinates.  All of the spans are then combined with
+//! [`merge_spans`]; a guest span beats a host span that covers the same text, so that (for
+//! instance) a code block is highlighted as code instead of as a string.
+//!
+//! In Lua, `require("ltreesitter_rs").highlight(tree, language)` does the same, using the
+//! `highlights` and `injections` queries from the [language registry][crate::LanguageRegistry]
+//! and the grammars that were registered via
+//! [`Module::register_language`][crate::Module::register_language].  It returns a list of spans
+//! like the ones that `merge_spans` returns.
+
+use std::collections::BTreeMap;
+
+use mlua::Lua;
+use tree_sitter::Language;
+use tree_sitter::Query;
+use tree_sitter::Tree;
+
+use crate::injections;
+use crate::languages;
+use crate::merge_spans;
+use crate::HighlightSpan;
+use crate::Languages;
+use crate::QuerySet;
+use crate::TreeWithSource;
+
+/// The queries that are needed to highlight one language.
+pub struct HighlightConfig {
+    language: Language,
+    highlights: QuerySet,
+    injections: Option<Query>,
+}
+
+impl HighlightConfig {
+    /// Creates a new configuration from a language's highlights query.
+    pub fn new(language: Language, highlights: Query) -> HighlightConfig {
+        let mut layers = QuerySet::new();
+        layers.add_layer("highlights", 0, highlights);
+        HighlightConfig {
+            language,
+            highlights: layers,
+            injections: None,
+        }
+    }
+
+    /// Sets the language's injections query.
+    pub fn with_injections(mut self, injections: Query) -> HighlightConfig {
+        self.injections = Some(injections);
+        self
+    }
+}
+
+/// Highlights documents whose languages can inject other languages.
+#[derive(Default)]
+pub struct Highlighter {
+    configs: BTreeMap<String, HighlightConfig>,
+}
+
+impl Highlighter {
+    /// Creates a new highlighter that doesn't know about any languages.
+    pub fn new() -> Highlighter {
+        Highlighter::default()
+    }
+
+    /// Adds a language, replacing any existing configuration with the same name.
+    pub fn add_language<S: Into<String>>(&mut self, name: S, config: HighlightConfig) -> &mut Self {
+        self.configs.insert(name.into(), config);
+        self
+    }
+
+    /// Highlights a tree of the named language, and all of the languages injected into it.  The
+    /// spans are sorted and don't overlap.
+    pub fn highlight(
+        &self,
+        name: &str,
+        tree: &Tree,
+        src: &[u8],
+    ) -> Result<Vec<HighlightSpan>, mlua::Error> {
+        let mut spans = Vec::new();
+        self.collect(name, tree, src, 0, &mut spans)?;
+        Ok(merge_spans(&spans))
+    }
+
+    fn collect(
+        &self,
+        name: &str,
+        tree: &Tree,
+        src: &[u8],
+        depth: usize,
+        spans: &mut Vec<HighlightSpan>,
+    ) -> Result<(), mlua::Error> {
+        let config = match self.configs.get(name) {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        spans.extend(
+            config
+                .highlights
+                .resolve_captures(tree.root_node(), src)
+                .iter()
+                .map(HighlightSpan::from),
+        );
+        let injections = match &config.injections {
+            Some(injections) if depth < injections::MAX_INJECTION_DEPTH => injections,
+            _ => return Ok(()),
+        };
+        for injection in injections::find_injections(injections, tree.root_node(), src) {
+            let guest = match self.configs.get(&injection.language) {
+                Some(guest) => guest,
+                None => continue,
+            };
+            // Guest spans come after the host's, so that they win ties in merge_spans.
+            if let Some(injected) = injections::parse_injection(guest.language, &injection, src)? {
+                self.collect(&injection.language, &injected, src, depth + 1, spans)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a highlighter for every linked grammar that has a highlights query in the registry.
+fn registry_highlighter(lua: &Lua) -> Result<Highlighter, mlua::Error> {
+    let mut highlighter = Highlighter::new();
+    for (name, language) in languages::linked_languages(lua) {
+        let (highlights, injections) = lua.with_language_registry(|registry| {
+            (
+                registry.query(&name, "highlights").map(str::to_string),
+                registry.query(&name, "injections").map(str::to_string),
+            )
+        })?;
+        let highlights = match highlights {
+            Some(highlights) => Query::new(language, &highlights).map_err(mlua::Error::external)?,
+            None => continue,
+        };
+        let mut config = HighlightConfig::new(language, highlights);
+        if let Some(injections) = injections {
+            config = config
+                .with_injections(Query::new(language, &injections).map_err(mlua::Error::external)?);
+        }
+        highlighter.add_language(name, config);
+    }
+    Ok(highlighter)
+}
+
+/// Adds `highlight` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let highlight = lua.create_function(|lua, (tree, name): (TreeWithSource, String)| {
+        registry_highlighter(lua)?.highlight(&name, &tree.tree, tree.src)
+    })?;
+    crate::companion_module(lua)?.set("highlight", highlight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::LanguagePack;
+    use crate::Module;
+    use crate::WithSource;
+
+    const HIGHLIGHTS: &str = r#"
+        (call function: (identifier) @function)
+        (identifier) @variable
+    "#;
+    const GUEST_HIGHLIGHTS: &str = "(identifier) @guest.variable";
+    const INJECTIONS: &str = r#"
+        ((call
+           function: (identifier) @_function
+           arguments: (argument_list) @injection.content)
+         (#eq? @_function "run")
+         (#set! injection.language "guest"))
+    "#;
+
+    #[test]
+    fn can_highlight_across_injections() {
+        let python = tree_sitter_python::language();
+        let code = b"run(y + z)\nw\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(python).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+
+        let query = |source| Query::new(python, source).unwrap();
+        let mut highlighter = Highlighter::new();
+        highlighter.add_language(
+            "python",
+            HighlightConfig::new(python, query(HIGHLIGHTS)).with_injections(query(INJECTIONS)),
+        );
+        highlighter.add_language(
+            "guest",
+            HighlightConfig::new(python, query(GUEST_HIGHLIGHTS)),
+        );
+        let expected = vec![
+            HighlightSpan::new(0, 3, "function"),
+            HighlightSpan::new(4, 5, "guest.variable"),
+            HighlightSpan::new(8, 9, "guest.variable"),
+            HighlightSpan::new(11, 12, "variable"),
+        ];
+        assert_eq!(
+            expected,
+            highlighter.highlight("python", &parsed, code).unwrap()
+        );
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", python).unwrap();
+        l.register_language("guest", python).unwrap();
+        l.with_language_registry(|registry| {
+            registry.add(
+                LanguagePack::new("python")
+                    .with_query("highlights", HIGHLIGHTS)
+                    .with_query("injections", INJECTIONS),
+            );
+            registry.add(LanguagePack::new("guest").with_query("highlights", GUEST_HIGHLIGHTS));
+        })
+        .unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local spans = require("ltreesitter_rs").highlight(parsed, "python")
+              assert(#spans == 4)
+              assert(spans[2].start_byte == 4 and spans[2].name == "guest.variable")
+              assert(spans[4].start_byte == 11 and spans[4].name == "variable")
+            "#,
+        );
+    }
+}
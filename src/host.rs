@@ -0,0 +1,223 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Reloads Lua analysis scripts when they change, without restarting the host application.
+//!
+//! A [`ScriptHost`] owns a Lua state and a list of script files.  Each call to
+//! [`ScriptHost::poll`] checks whether any of the files have changed, and if so, executes them
+//! again in the same Lua state.  Everything else in the state — registered languages, caches, and
+//! any globals that the scripts don't overwrite — is preserved.  Each script receives a boolean
+//! argument that is `true` when it's being reloaded, so it can keep its own state:
+//!
+//! ``` lua
+//! local reloading = ...
+//! if not reloading then findings = {} end
+//! ```
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use mlua::Lua;
+
+use crate::recording::StableHasher;
+use crate::Module;
+
+type ReloadCallback = Box<dyn Fn(&Lua) -> Result<(), mlua::Error>>;
+
+struct WatchedScript {
+    path: PathBuf,
+    /// A hash of the script's contents when it was last loaded.
+    fingerprint: u64,
+}
+
+/// What happened during a call to [`ScriptHost::poll`].
+#[derive(Debug, Default)]
+pub struct PollResult {
+    /// The scripts that were reloaded successfully.
+    pub reloaded: Vec<PathBuf>,
+    /// The errors from scripts that couldn't be read or reloaded, and from reload callbacks.
+    pub errors: Vec<mlua::Error>,
+}
+
+impl PollResult {
+    /// Returns whether the poll didn't run into any errors.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Owns a Lua state, and reloads the scripts in it whenever they change.
+pub struct ScriptHost {
+    lua: Lua,
+    scripts: Vec<WatchedScript>,
+    callbacks: Vec<ReloadCallback>,
+}
+
+impl ScriptHost {
+    /// Creates a new script host with a fresh Lua state that has the `ltreesitter` module loaded.
+    pub fn new() -> Result<ScriptHost, mlua::Error> {
+        let lua = Lua::new();
+        lua.open_ltreesitter()?;
+        Ok(ScriptHost::with_lua(lua))
+    }
+
+    /// Creates a new script host that manages an existing Lua state.
+    pub fn with_lua(lua: Lua) -> ScriptHost {
+        ScriptHost {
+            lua,
+            scripts: Vec::new(),
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Returns the Lua state that the scripts are loaded into.
+    pub fn lua(&self) -> &Lua {
+        &self.lua
+    }
+
+    /// Loads a script, and starts watching it for changes.
+    pub fn watch<P: Into<PathBuf>>(&mut self, path: P) -> Result<(), mlua::Error> {
+        let path = path.into();
+        let code = read(&path)?;
+        let fingerprint = fingerprint(&code);
+        run(&self.lua, &path, &code, false)?;
+        self.scripts.push(WatchedScript { path, fingerprint });
+        Ok(())
+    }
+
+    /// Registers a callback that is called after any script is reloaded.  Use this to re-fetch
+    /// any Lua functions that you hold onto in Rust, since the reloaded scripts will have replaced
+    /// them.
+    pub fn on_reload<F>(&mut self, callback: F)
+    where
+        F: Fn(&Lua) -> Result<(), mlua::Error> + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Reloads any scripts whose contents have changed since they were last loaded.  A script
+    /// that fails doesn't stop the others from being reloaded: its error is added to the result,
+    /// the definitions from its previous version remain in place, and it isn't loaded again until
+    /// it changes.  The reload callbacks are called if any script was reloaded successfully.
+    pub fn poll(&mut self) -> PollResult {
+        let mut result = PollResult::default();
+        for script in &mut self.scripts {
+            let code = match read(&script.path) {
+                Ok(code) => code,
+                Err(err) => {
+                    result.errors.push(err);
+                    continue;
+                }
+            };
+            let fingerprint = fingerprint(&code);
+            if fingerprint == script.fingerprint {
+                continue;
+            }
+            script.fingerprint = fingerprint;
+            match run(&self.lua, &script.path, &code, true) {
+                Ok(()) => result.reloaded.push(script.path.clone()),
+                Err(err) => result.errors.push(err),
+            }
+        }
+        if !result.reloaded.is_empty() {
+            for callback in &self.callbacks {
+                if let Err(err) = callback(&self.lua) {
+                    result.errors.push(err);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, mlua::Error> {
+    std::fs::read(path).map_err(|err| {
+        mlua::Error::RuntimeError(format!("cannot read {}: {}", path.display(), err))
+    })
+}
+
+fn fingerprint(code: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(code);
+    hasher.finish()
+}
+
+fn run(lua: &Lua, path: &Path, code: &[u8], reloading: bool) -> Result<(), mlua::Error> {
+    lua.load(code)
+        .set_name(path.to_string_lossy())
+        .call(reloading)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn can_reload_changed_scripts() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("mlua-tree-sitter-host-{}.lua", std::process::id()));
+        let other = dir.join(format!(
+            "mlua-tree-sitter-host-{}-b.lua",
+            std::process::id()
+        ));
+        let script = |version: i64| {
+            format!(
+                r#"
+                  local reloading = ...
+                  if not reloading then loads = 0 end
+                  loads = loads + 1
+                  function version() return {} end
+                "#,
+                version
+            )
+        };
+        std::fs::write(&path, script(1)).unwrap();
+        std::fs::write(&other, "function other() return 1 end").unwrap();
+
+        let mut host = ScriptHost::new().unwrap();
+        host.watch(&path).unwrap();
+        host.watch(&other).unwrap();
+        let reloads = Rc::new(Cell::new(0));
+        let callback_reloads = reloads.clone();
+        host.on_reload(move |_| {
+            callback_reloads.set(callback_reloads.get() + 1);
+            Ok(())
+        });
+        let result = host.poll();
+        assert!(result.is_ok() && result.reloaded.is_empty());
+
+        // The new version has the same length, and is probably written within the same mtime
+        // tick, so only its contents tell us that it changed.
+        std::fs::write(&path, script(2)).unwrap();
+        let result = host.poll();
+        assert!(result.is_ok());
+        assert_eq!(vec![path.clone()], result.reloaded);
+        assert_eq!(1, reloads.get());
+        let (version, loads): (i64, i64) =
+            host.lua().load("return version(), loads").eval().unwrap();
+        assert_eq!((2, 2), (version, loads));
+
+        // A broken script doesn't keep the others from reloading, or the callbacks from running.
+        std::fs::write(&path, "this is not lua").unwrap();
+        std::fs::write(&other, "function other() return 2 end").unwrap();
+        let result = host.poll();
+        assert_eq!(1, result.errors.len());
+        assert_eq!(vec![other.clone()], result.reloaded);
+        assert_eq!(2, reloads.get());
+        let (version, other_version): (i64, i64) =
+            host.lua().load("return version(), other()").eval().unwrap();
+        assert_eq!((2, 2), (version, other_version));
+        let result = host.poll();
+        assert!(result.is_ok() && result.reloaded.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&other).unwrap();
+        assert!(!host.poll().is_ok());
+    }
+}
@@ -0,0 +1,312 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Runs queries over a document and the languages that are injected into it.
+//!
+//! Injections are found with a language's `injections` query from the [language
+//! registry][crate::LanguageRegistry], using the usual captures: `@injection.content` marks the
+//! injected text, and the language comes from an `@injection.language` capture or a `#set!
+//! injection.language` property.  Patterns with an `injection.combined` property combine all of
+//! their matches into one injected document.  Injected text is parsed with the grammars that
+//! were registered via [`Module::register_language`][crate::Module::register_language]; an
+//! injection whose grammar isn't linked into the host is skipped, with a
+//! [warning][crate::ScriptWarnings].  Injected trees are parsed
+//! against the host's whole source, so their byte offsets and points are in host coordinates.
+//!
+//! In Lua, `require("ltreesitter_rs").run_query(tree, queries, options)` returns a list of match
+//! tables, like the ones that `query:match` produces.  `queries` is either the source of a query
+//! for the tree's language, or a table that maps language names to query sources.  `options` can
+//! contain:
+//!
+//! - `language`: the name of the tree's language, which is needed to look up its injections;
+//! - `injections`: whether to descend into injected languages (default `false`).
+//!
+//! Each match also has a `language` field, a `tree` field with the tree that the match is in, and
+//! an `injection_path` field with the list of languages that lead to it, starting with the host.
+
+use std::collections::BTreeMap;
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use tree_sitter::Language;
+use tree_sitter::Node;
+use tree_sitter::Parser;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+use tree_sitter::Tree;
+
+use crate::languages;
+use crate::ltreesitter;
+use crate::warnings;
+use crate::Languages;
+use crate::TSQuery;
+use crate::TreeWithSource;
+use crate::WarningKind;
+use crate::WithSource;
+
+/// How deeply injections can nest, so that a language that injects itself can't recurse forever.
+pub(crate) const MAX_INJECTION_DEPTH: usize = 8;
+
+/// A part of a document that is written in another language.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Injection {
+    /// The name of the injected language.
+    pub language: String,
+    /// The ranges of the document that contain the injected text, in order.
+    pub ranges: Vec<tree_sitter::Range>,
+}
+
+/// Finds the injections in a tree, using an `injections` query.
+pub fn find_injections(query: &Query, root: Node, src: &[u8]) -> Vec<Injection> {
+    let content_index = query.capture_index_for_name("injection.content");
+    let language_index = query.capture_index_for_name("injection.language");
+    let mut injections: Vec<Injection> = Vec::new();
+    let mut combined: BTreeMap<(usize, String), usize> = BTreeMap::new();
+    let mut cursor = QueryCursor::new();
+    for query_match in cursor.matches(query, root, src) {
+        let mut language = None;
+        let mut ranges = Vec::new();
+        for capture in query_match.captures {
+            if Some(capture.index) == language_index {
+                language = capture.node.utf8_text(src).ok().map(str::to_string);
+            } else if Some(capture.index) == content_index {
+                ranges.push(capture.node.range());
+            }
+        }
+        let mut is_combined = false;
+        for property in query.property_settings(query_match.pattern_index) {
+            match &*property.key {
+                "injection.language" if language.is_none() => {
+                    language = property.value.as_deref().map(str::to_string);
+                }
+                "injection.combined" => is_combined = true,
+                _ => {}
+            }
+        }
+        let language = match language {
+            Some(language) if !ranges.is_empty() => language,
+            _ => continue,
+        };
+        if is_combined {
+            let key = (query_match.pattern_index, language.clone());
+            if let Some(index) = combined.get(&key) {
+                injections[*index].ranges.extend(ranges);
+                continue;
+            }
+            combined.insert(key, injections.len());
+        }
+        injections.push(Injection { language, ranges });
+    }
+    for injection in &mut injections {
+        injection.ranges.sort_by_key(|range| range.start_byte);
+        injection.ranges.dedup();
+    }
+    injections
+}
+
+/// Parses the text of an injection.
+pub(crate) fn parse_injection(
+    language: Language,
+    injection: &Injection,
+    src: &[u8],
+) -> Result<Option<Tree>, mlua::Error> {
+    let mut parser = Parser::new();
+    parser
+        .set_language(language)
+        .map_err(mlua::Error::external)?;
+    parser
+        .set_included_ranges(&injection.ranges)
+        .map_err(|_| mlua::Error::RuntimeError("overlapping injection ranges".to_string()))?;
+    Ok(parser.parse(src, None))
+}
+
+struct QueryRun<'lua> {
+    queries: Value<'lua>,
+    injections: bool,
+    results: Table<'lua>,
+}
+
+impl<'lua> QueryRun<'lua> {
+    /// Returns the query source for a language, if the caller gave one.
+    fn source(&self, name: Option<&str>, depth: usize) -> Result<Option<String>, mlua::Error> {
+        match (&self.queries, name) {
+            (Value::String(source), _) if depth == 0 => Ok(Some(source.to_str()?.to_string())),
+            (Value::Table(queries), Some(name)) => queries.get(name),
+            _ => Ok(None),
+        }
+    }
+
+    fn run(
+        &self,
+        lua: &'lua Lua,
+        lua_tree: Value<'lua>,
+        tree: &Tree,
+        src: &[u8],
+        name: Option<&str>,
+        path: &mut Vec<String>,
+    ) -> Result<(), mlua::Error> {
+        let depth = path.len().saturating_sub(1);
+        if let Some(source) = self.source(name, depth)? {
+            let query = TSQuery::new(tree.language(), &source)
+                .map_err(mlua::Error::external)?
+                .into_lua(lua)?;
+            let root: Function =
+                ltreesitter::methods(lua, ltreesitter::TREE_METATABLE)?.get("root")?;
+            let root: Value = root.call(lua_tree.clone())?;
+            let query_match: Function =
+                ltreesitter::methods(lua, ltreesitter::QUERY_METATABLE)?.get("match")?;
+            let next_match: Function = query_match.call((query, root))?;
+            while let Some(found) = next_match.call::<_, Option<Table>>(())? {
+                found.set("language", name)?;
+                found.set("tree", lua_tree.clone())?;
+                found.set("injection_path", path.clone())?;
+                self.results.raw_push(found)?;
+            }
+        }
+
+        let name = match name {
+            Some(name) if self.injections && depth < MAX_INJECTION_DEPTH => name,
+            _ => return Ok(()),
+        };
+        let injections_query = lua.with_language_registry(|registry| {
+            registry.query(name, "injections").map(str::to_string)
+        })?;
+        let injections_query = match injections_query {
+            Some(source) => Query::new(tree.language(), &source).map_err(mlua::Error::external)?,
+            None => return Ok(()),
+        };
+        for injection in find_injections(&injections_query, tree.root_node(), src) {
+            let language = match languages::linked_language(lua, &injection.language) {
+                Some(language) => language,
+                None => {
+                    warnings::warn(
+                        lua,
+                        WarningKind::Unsupported,
+                        &injection.language,
+                        "its grammar isn't linked into the host, so it isn't queried",
+                    );
+                    continue;
+                }
+            };
+            let injected = match parse_injection(language, &injection, src)? {
+                Some(injected) => injected,
+                None => continue,
+            };
+            let lua_injected = injected.clone().with_source(src).into_lua(lua)?;
+            path.push(injection.language.clone());
+            self.run(
+                lua,
+                lua_injected,
+                &injected,
+                src,
+                Some(&injection.language),
+                path,
+            )?;
+            path.pop();
+        }
+        Ok(())
+    }
+}
+
+fn run_query<'lua>(
+    lua: &'lua Lua,
+    (lua_tree, queries, options): (Value<'lua>, Value<'lua>, Option<Table<'lua>>),
+) -> Result<Table<'lua>, mlua::Error> {
+    let tree = TreeWithSource::from_lua(lua_tree.clone(), lua)?;
+    let (name, injections) = match &options {
+        Some(options) => (
+            options.get::<_, Option<String>>("language")?,
+            options
+                .get::<_, Option<bool>>("injections")?
+                .unwrap_or(false),
+        ),
+        None => (None, false),
+    };
+    let run = QueryRun {
+        queries,
+        injections,
+        results: lua.create_table()?,
+    };
+    let mut path = name.iter().cloned().collect::<Vec<_>>();
+    run.run(
+        lua,
+        lua_tree,
+        &tree.tree,
+        tree.src,
+        name.as_deref(),
+        &mut path,
+    )?;
+    Ok(run.results)
+}
+
+/// Adds the `run_query` function to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    crate::companion_module(lua)?.set("run_query", lua.create_function(run_query)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::LanguagePack;
+    use crate::Module;
+
+    const INJECTIONS: &str = r#"
+        ((call
+           function: (identifier) @_function
+           arguments: (argument_list) @injection.content)
+         (#eq? @_function "run")
+         (#set! injection.language "python"))
+    "#;
+
+    #[test]
+    fn can_run_queries_across_injections() {
+        let code = b"run(y + z)\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let query = Query::new(tree_sitter_python::language(), INJECTIONS).unwrap();
+        let injections = find_injections(&query, parsed.root_node(), code);
+        assert_eq!(1, injections.len());
+        assert_eq!("python", injections[0].language);
+        assert_eq!(3, injections[0].ranges[0].start_byte);
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.with_language_registry(|registry| {
+            registry.add(LanguagePack::new("python").with_query("injections", INJECTIONS));
+        })
+        .unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local run_query = require("ltreesitter_rs").run_query
+              assert(#run_query(parsed, "(identifier) @id") == 3)
+              local matches = run_query(
+                parsed,
+                { python = "(identifier) @id" },
+                { language = "python", injections = true }
+              )
+              assert(#matches == 5)
+              local injected = {}
+              for _, match in ipairs(matches) do
+                assert(match.language == "python")
+                if #match.injection_path == 2 then
+                  assert(match.tree ~= parsed)
+                  injected[#injected + 1] = match.captures.id:source()
+                end
+              end
+              assert(#injected == 2 and injected[1] == "y" and injected[2] == "z")
+            "#,
+        );
+    }
+}
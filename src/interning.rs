@@ -0,0 +1,148 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Interns the node kind, field name, and capture name strings that cross the bridge.
+//!
+//! A grammar only has a few hundred distinct kind and field names, and a query only has a handful
+//! of capture names, but a capture-heavy workload can convert each of them millions of times.
+//! [`LuaInterning`] creates each distinct string in a Lua environment once, and keeps it in the
+//! Lua registry so that it can be reused; [`StringInterner`] does the same for strings that are
+//! kept on the Rust side.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use mlua::Lua;
+use mlua::RegistryKey;
+use tree_sitter::Node;
+
+#[derive(Default)]
+struct InternedStrings {
+    strings: HashMap<Box<str>, RegistryKey>,
+}
+
+/// An extension trait that lets you create interned strings in a Lua environment.
+pub trait LuaInterning {
+    /// Returns a Lua string with the given contents, creating it only the first time it's needed.
+    fn interned_string<'lua>(&'lua self, s: &str) -> Result<mlua::String<'lua>, mlua::Error>;
+
+    /// Returns the kind of a node as an interned Lua string.
+    fn node_kind<'lua>(&'lua self, node: Node) -> Result<mlua::String<'lua>, mlua::Error>;
+
+    /// Returns the number of strings that have been interned in this Lua environment.
+    fn interned_string_count(&self) -> usize;
+
+    /// Forgets all of the interned strings, so that Lua can garbage-collect them.
+    fn clear_interned_strings(&self);
+}
+
+impl LuaInterning for Lua {
+    fn interned_string<'lua>(&'lua self, s: &str) -> Result<mlua::String<'lua>, mlua::Error> {
+        if let Some(interned) = self.app_data_ref::<InternedStrings>() {
+            if let Some(key) = interned.strings.get(s) {
+                return self.registry_value(key);
+            }
+        }
+        let string = self.create_string(s)?;
+        let key = self.create_registry_value(string.clone())?;
+        match self.app_data_mut::<InternedStrings>() {
+            Some(mut interned) => {
+                interned.strings.insert(s.into(), key);
+            }
+            None => {
+                let mut interned = InternedStrings::default();
+                interned.strings.insert(s.into(), key);
+                self.set_app_data(interned);
+            }
+        }
+        Ok(string)
+    }
+
+    fn node_kind<'lua>(&'lua self, node: Node) -> Result<mlua::String<'lua>, mlua::Error> {
+        self.interned_string(node.kind())
+    }
+
+    fn interned_string_count(&self) -> usize {
+        self.app_data_ref::<InternedStrings>()
+            .map(|interned| interned.strings.len())
+            .unwrap_or(0)
+    }
+
+    fn clear_interned_strings(&self) {
+        if let Some(interned) = self.remove_app_data::<InternedStrings>() {
+            for (_, key) in interned.strings {
+                let _ = self.remove_registry_value(key);
+            }
+        }
+    }
+}
+
+/// A set of interned strings that are kept on the Rust side.  Each distinct string is allocated
+/// once, and every request for it returns a cheap clone of the same allocation.
+#[derive(Clone, Debug, Default)]
+pub struct StringInterner {
+    strings: HashSet<Arc<str>>,
+}
+
+impl StringInterner {
+    /// Creates a new, empty interner.
+    pub fn new() -> StringInterner {
+        StringInterner::default()
+    }
+
+    /// Returns the interned copy of a string, allocating it if this is the first request for it.
+    pub fn intern(&mut self, s: &str) -> Arc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = s.into();
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    /// Returns the number of distinct strings in the interner.
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Returns whether the interner is empty.
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::TSNode;
+    use crate::WithSource;
+
+    #[test]
+    fn can_intern_strings() {
+        let mut interner = StringInterner::new();
+        let a = interner.intern("identifier");
+        let b = interner.intern("identifier");
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(1, interner.len());
+
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let node: TSNode = l.load("return parsed:root():child(0)").eval().unwrap();
+        let kind = l.node_kind(*node).unwrap();
+        assert_eq!("function_definition", kind.to_str().unwrap());
+        l.interned_string("function_definition").unwrap();
+        assert_eq!(1, l.interned_string_count());
+        l.clear_interned_strings();
+        assert_eq!(0, l.interned_string_count());
+    }
+}
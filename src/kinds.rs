@@ -0,0 +1,94 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Predicates that check what kind of ltreesitter object a Lua value is.
+//!
+//! These check the value's metatable, so they never raise conversion errors or trip ltreesitter's
+//! argument assertions, and can be used to branch on arbitrary values.  Lua code can call the same
+//! predicates via `require("ltreesitter_rs").is_tree(value)` and friends.
+
+use mlua::Lua;
+use mlua::Value;
+
+use crate::ltreesitter;
+
+/// Returns whether a Lua value is an ltreesitter tree.  Closed trees are still trees.
+pub fn is_tree<'lua>(lua: &'lua Lua, value: &Value<'lua>) -> Result<bool, mlua::Error> {
+    ltreesitter::has_metatable(lua, value, ltreesitter::TREE_METATABLE)
+}
+
+/// Returns whether a Lua value is an ltreesitter node.
+pub fn is_node<'lua>(lua: &'lua Lua, value: &Value<'lua>) -> Result<bool, mlua::Error> {
+    ltreesitter::has_metatable(lua, value, ltreesitter::NODE_METATABLE)
+}
+
+/// Returns whether a Lua value is an ltreesitter tree cursor.
+pub fn is_tree_cursor<'lua>(lua: &'lua Lua, value: &Value<'lua>) -> Result<bool, mlua::Error> {
+    ltreesitter::has_metatable(lua, value, ltreesitter::TREE_CURSOR_METATABLE)
+}
+
+/// Returns whether a Lua value is an ltreesitter query.
+pub fn is_query<'lua>(lua: &'lua Lua, value: &Value<'lua>) -> Result<bool, mlua::Error> {
+    ltreesitter::has_metatable(lua, value, ltreesitter::QUERY_METATABLE)
+}
+
+/// Adds the predicates to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = crate::companion_module(lua)?;
+    module.set(
+        "is_tree",
+        lua.create_function(|lua, value: Value| is_tree(lua, &value))?,
+    )?;
+    module.set(
+        "is_node",
+        lua.create_function(|lua, value: Value| is_node(lua, &value))?,
+    )?;
+    module.set(
+        "is_tree_cursor",
+        lua.create_function(|lua, value: Value| is_tree_cursor(lua, &value))?,
+    )?;
+    module.set(
+        "is_query",
+        lua.create_function(|lua, value: Value| is_query(lua, &value))?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_check_value_kinds() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let tree: Value = l.globals().get("parsed").unwrap();
+        assert!(is_tree(&l, &tree).unwrap());
+        assert!(!is_node(&l, &tree).unwrap());
+        assert!(!is_tree(&l, &Value::Integer(1)).unwrap());
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local root = parsed:root()
+              assert(ltreesitter_rs.is_tree(parsed))
+              assert(ltreesitter_rs.is_node(root))
+              assert(ltreesitter_rs.is_tree_cursor(root:create_cursor()))
+              assert(not ltreesitter_rs.is_node(parsed))
+              assert(not ltreesitter_rs.is_query(root))
+              assert(not ltreesitter_rs.is_tree({}))
+              assert(not ltreesitter_rs.is_node(nil))
+              assert(not ltreesitter_rs.is_tree(require("ltreesitter_rs").languages))
+            "#,
+        );
+    }
+}
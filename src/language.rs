@@ -0,0 +1,124 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::ops::Deref;
+
+use mlua::Lua;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use mlua::Value;
+use tree_sitter::Language;
+
+use crate::abi;
+use crate::ltreesitter;
+use crate::node_types;
+
+/// A wrapper around a [`tree_sitter::Language`].  This only exists to get around Rust's orphan
+/// rules, so that we can implement the [`mlua::IntoLua`] and [`mlua::FromLua`] traits.
+///
+/// ltreesitter doesn't have a language object of its own, so a `TSLanguage` is pushed into Lua as
+/// a userdata with a `parser()` method, which creates a new ltreesitter parser for the language.
+/// It also has `version()`, `node_kind_count()`, and `field_count()` methods, and a
+/// `node_type_info(kind)` method that looks up the grammar's [node types][crate::NodeTypes].
+/// Both those userdata and ltreesitter parsers can be converted back into a `TSLanguage`.
+#[derive(Clone, Copy)]
+pub struct TSLanguage(pub Language);
+
+impl Deref for TSLanguage {
+    type Target = Language;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Language> for TSLanguage {
+    fn from(language: Language) -> TSLanguage {
+        TSLanguage(language)
+    }
+}
+
+/// The Lua representation of a [`TSLanguage`].
+struct LuaLanguage(Language);
+
+impl UserData for LuaLanguage {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("parser", |lua, language, ()| {
+            ltreesitter::new_parser(lua, language.0)
+        });
+        methods.add_method("version", |_, language, ()| Ok(language.0.version()));
+        methods.add_method("node_kind_count", |_, language, ()| {
+            Ok(language.0.node_kind_count())
+        });
+        methods.add_method("field_count", |_, language, ()| {
+            Ok(language.0.field_count())
+        });
+        methods.add_method("node_type_info", |lua, language, kind: String| {
+            node_types::lua_node_type_info(lua, language.0, &kind)
+        });
+    }
+}
+
+impl<'lua> mlua::IntoLua<'lua> for TSLanguage {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        lua.create_userdata(LuaLanguage(self.0))
+            .map(Value::UserData)
+    }
+}
+
+impl<'lua> mlua::FromLua<'lua> for TSLanguage {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        if let Value::UserData(udata) = &value {
+            if let Ok(language) = udata.borrow::<LuaLanguage>() {
+                return Ok(TSLanguage(language.0));
+            }
+        }
+        if let Some(parser) = ltreesitter::as_parser(lua, &value)? {
+            let language = unsafe { tree_sitter::ffi::ts_parser_language((*parser).parser) };
+            if !language.is_null() {
+                // Language is a transparent wrapper around a TSLanguage pointer, since grammar
+                // crates return it directly from their C entry points.
+                let language = unsafe {
+                    std::mem::transmute::<*const tree_sitter::ffi::TSLanguage, Language>(language)
+                };
+                abi::check_language(lua, language)?;
+                return Ok(TSLanguage(language));
+            }
+        }
+        Err(mlua::Error::FromLuaConversionError {
+            from: value.type_name(),
+            to: "TSLanguage",
+            message: Some("expected a language or an ltreesitter parser".to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_push_and_retrieve_languages() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let python = TSLanguage(tree_sitter_python::language());
+        l.globals().set("python", python).unwrap();
+        l.check(
+            r#"
+              local parser = python:parser()
+              assert(parser:parse_string("x = 1"):root():type() == "module")
+              assert(python:node_kind_count() > 0)
+            "#,
+        );
+        let kinds = python.node_kind_count();
+        let language: TSLanguage = l.call(r#" return python "#);
+        assert_eq!(kinds, language.node_kind_count());
+        let language: TSLanguage = l.call(r#" return python:parser() "#);
+        assert_eq!(kinds, language.node_kind_count());
+        assert!(l.load(r#" return 7 "#).eval::<TSLanguage>().is_err());
+    }
+}
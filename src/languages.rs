@@ -0,0 +1,493 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A registry of language packs, which bundle a grammar together with its queries and the file
+//! types that it applies to.
+//!
+//! With the `language-packs` feature, packs can be loaded from TOML manifests:
+//!
+//! ``` toml
+//! name = "python"
+//! library = "libtree-sitter-python.so"
+//! file-types = ["py", "pyi"]
+//!
+//! [queries]
+//! highlights = "queries/highlights.scm"
+//! locals = "queries/locals.scm"
+//! ```
+//!
+//! Paths are relative to the manifest, and query files can use `; inherits:` includes.  A `wasm`
+//! path can also be given, for hosts that load grammars that way; this crate only records it.
+//!
+//! The queries in a pack are that language's defaults.  Hosts and scripts can override any of
+//! them, and the subsystems that need a query (highlighting, injections, locals, textobjects) look
+//! it up with [`LanguageRegistry::query`], which prefers the override.
+//!
+//! Lua code can access the registry via `require("ltreesitter_rs").languages`, which has `get`,
+//! `names`, `for_path`, `query`, `default_query`, `set_query`, `reset_query`, and `parser` methods
+//! (and `load_pack`, with the feature enabled).  With the `grammar-compile` feature, it also has a
+//! `compile_grammar` method.
+//!
+//! Grammars that are linked into the host binary can be registered with
+//! [`Module::register_language`][crate::Module::register_language], which makes them available
+//! via `ltreesitter.require(name)` (and the registry's `parser` method), without needing a
+//! dynamic library on disk.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use mlua::AnyUserData;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use mlua::Value;
+use tree_sitter::Language;
+
+use crate::abi;
+use crate::ltreesitter;
+
+const LANGUAGES: &str = "languages";
+
+/// A grammar, together with its queries and the file types that it applies to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LanguagePack {
+    pub name: String,
+    pub library: Option<PathBuf>,
+    pub wasm: Option<PathBuf>,
+    pub file_types: Vec<String>,
+    pub queries: BTreeMap<String, String>,
+}
+
+impl LanguagePack {
+    /// Creates a new, empty language pack.
+    pub fn new<S: Into<String>>(name: S) -> LanguagePack {
+        LanguagePack {
+            name: name.into(),
+            ..LanguagePack::default()
+        }
+    }
+
+    /// Sets the path of the shared library that contains the grammar.
+    pub fn with_library<P: Into<PathBuf>>(mut self, library: P) -> LanguagePack {
+        self.library = Some(library.into());
+        self
+    }
+
+    /// Adds a file type, which is either a file extension or a full file name.
+    pub fn with_file_type<S: Into<String>>(mut self, file_type: S) -> LanguagePack {
+        self.file_types.push(file_type.into());
+        self
+    }
+
+    /// Adds a query, such as `highlights` or `injections`.
+    pub fn with_query<K: Into<String>, S: Into<String>>(
+        mut self,
+        kind: K,
+        source: S,
+    ) -> LanguagePack {
+        self.queries.insert(kind.into(), source.into());
+        self
+    }
+
+    /// Returns whether this pack applies to a file.
+    pub fn matches_path(&self, path: &Path) -> bool {
+        let file_name = path.file_name().and_then(|name| name.to_str());
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        self.file_types.iter().any(|file_type| {
+            Some(file_type.as_str()) == extension || Some(file_type.as_str()) == file_name
+        })
+    }
+
+    fn to_table<'lua>(&self, lua: &'lua Lua) -> Result<Table<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        table.set("name", self.name.as_str())?;
+        table.set(
+            "library",
+            self.library
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+        )?;
+        table.set(
+            "wasm",
+            self.wasm
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+        )?;
+        table.set("file_types", self.file_types.clone())?;
+        table.set("queries", self.queries.clone())?;
+        Ok(table)
+    }
+}
+
+/// A collection of language packs, indexed by name.
+#[derive(Clone, Debug, Default)]
+pub struct LanguageRegistry {
+    packs: BTreeMap<String, LanguagePack>,
+    overrides: BTreeMap<(String, String), String>,
+}
+
+impl LanguageRegistry {
+    /// Creates a new, empty registry.
+    pub fn new() -> LanguageRegistry {
+        LanguageRegistry::default()
+    }
+
+    /// Adds a language pack, replacing any existing pack with the same name.
+    pub fn add(&mut self, pack: LanguagePack) -> &mut LanguagePack {
+        let name = pack.name.clone();
+        self.packs.insert(name.clone(), pack);
+        self.packs.get_mut(&name).unwrap()
+    }
+
+    /// Returns the language pack with the given name.
+    pub fn get(&self, name: &str) -> Option<&LanguagePack> {
+        self.packs.get(name)
+    }
+
+    /// Returns the names of all of the registered languages.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.packs.keys().map(String::as_str)
+    }
+
+    /// Returns the first language pack (by name) that applies to a file.
+    pub fn for_path<P: AsRef<Path>>(&self, path: P) -> Option<&LanguagePack> {
+        self.packs
+            .values()
+            .find(|pack| pack.matches_path(path.as_ref()))
+    }
+
+    /// Returns the query of the given kind for a language, preferring an override over the
+    /// language pack's default.
+    pub fn query(&self, name: &str, kind: &str) -> Option<&str> {
+        self.overrides
+            .get(&(name.to_string(), kind.to_string()))
+            .map(String::as_str)
+            .or_else(|| self.default_query(name, kind))
+    }
+
+    /// Returns the default query of the given kind for a language, ignoring any override.
+    pub fn default_query(&self, name: &str, kind: &str) -> Option<&str> {
+        self.get(name)
+            .and_then(|pack| pack.queries.get(kind))
+            .map(String::as_str)
+    }
+
+    /// Overrides the query of the given kind for a language.  The override survives even if the
+    /// language pack is later replaced.
+    pub fn set_query<S: Into<String>>(&mut self, name: &str, kind: &str, source: S) {
+        self.overrides
+            .insert((name.to_string(), kind.to_string()), source.into());
+    }
+
+    /// Removes an override, so that the language pack's default query is used again.  Returns the
+    /// override, if there was one.
+    pub fn reset_query(&mut self, name: &str, kind: &str) -> Option<String> {
+        self.overrides.remove(&(name.to_string(), kind.to_string()))
+    }
+
+    /// Loads a language pack from a TOML manifest, and adds it to the registry.
+    #[cfg(feature = "language-packs")]
+    pub fn load_pack<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut LanguagePack, mlua::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Manifest {
+            name: String,
+            library: Option<PathBuf>,
+            wasm: Option<PathBuf>,
+            #[serde(default)]
+            file_types: Vec<String>,
+            #[serde(default)]
+            queries: BTreeMap<String, PathBuf>,
+        }
+
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            mlua::Error::RuntimeError(format!("cannot read {}: {}", path.display(), err))
+        })?;
+        let manifest: Manifest = toml::from_str(&text).map_err(|err| {
+            mlua::Error::RuntimeError(format!("invalid manifest {}: {}", path.display(), err))
+        })?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut pack = LanguagePack::new(manifest.name);
+        pack.library = manifest.library.map(|library| dir.join(library));
+        pack.wasm = manifest.wasm.map(|wasm| dir.join(wasm));
+        pack.file_types = manifest.file_types;
+        for (kind, query_path) in manifest.queries {
+            let source = crate::read_query_file(dir.join(query_path))?;
+            pack.queries.insert(kind, source);
+        }
+        Ok(self.add(pack))
+    }
+}
+
+impl UserData for LanguageRegistry {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |lua, registry, name: String| {
+            registry
+                .get(&name)
+                .map(|pack| pack.to_table(lua))
+                .transpose()
+        });
+        methods.add_method("names", |_, registry, ()| {
+            Ok(registry.names().map(str::to_string).collect::<Vec<_>>())
+        });
+        methods.add_method("for_path", |_, registry, path: String| {
+            Ok(registry.for_path(path).map(|pack| pack.name.clone()))
+        });
+        methods.add_method("query", |_, registry, (name, kind): (String, String)| {
+            Ok(registry.query(&name, &kind).map(str::to_string))
+        });
+        methods.add_method(
+            "default_query",
+            |_, registry, (name, kind): (String, String)| {
+                Ok(registry.default_query(&name, &kind).map(str::to_string))
+            },
+        );
+        methods.add_method_mut(
+            "set_query",
+            |_, registry, (name, kind, source): (String, String, String)| {
+                registry.set_query(&name, &kind, source);
+                Ok(())
+            },
+        );
+        methods.add_method_mut(
+            "reset_query",
+            |_, registry, (name, kind): (String, String)| Ok(registry.reset_query(&name, &kind)),
+        );
+        methods.add_method("parser", |lua, registry, name: String| {
+            crate::options::check_parsers_enabled(lua, "parser")?;
+            if let Some(language) = linked_language(lua, &name) {
+                return ltreesitter::new_parser(lua, language);
+            }
+            let library = registry
+                .get(&name)
+                .and_then(|pack| pack.library.as_ref())
+                .ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!("no grammar library for language {}", name))
+                })?;
+            let load: mlua::Function = ltreesitter::module(lua)?.get("load")?;
+            load.call::<_, Value>((library.to_string_lossy().into_owned(), name))
+        });
+        #[cfg(feature = "grammar-compile")]
+        methods.add_method_mut(
+            "compile_grammar",
+            |lua, registry, (repo, name): (String, Option<String>)| {
+                crate::sandbox::check_allowed(lua, "compile_grammar")?;
+                let pack = crate::GrammarCompiler::default().compile(repo, name.as_deref())?;
+                registry.add(pack).to_table(lua)
+            },
+        );
+        #[cfg(feature = "language-packs")]
+        methods.add_method_mut("load_pack", |lua, registry, path: String| {
+            crate::sandbox::check_allowed(lua, "load_pack")?;
+            registry.load_pack(path)?.to_table(lua)
+        });
+    }
+}
+
+/// An extension trait that gives you access to the language registry of a Lua environment.
+pub trait Languages {
+    /// Calls `f` with the Lua environment's language registry.  You must load the `ltreesitter`
+    /// module first.
+    fn with_language_registry<R, F>(&self, f: F) -> Result<R, mlua::Error>
+    where
+        F: FnOnce(&mut LanguageRegistry) -> R;
+}
+
+impl Languages for Lua {
+    fn with_language_registry<R, F>(&self, f: F) -> Result<R, mlua::Error>
+    where
+        F: FnOnce(&mut LanguageRegistry) -> R,
+    {
+        let registry: AnyUserData = crate::companion_module(self)?.get(LANGUAGES)?;
+        let mut registry = registry.borrow_mut::<LanguageRegistry>()?;
+        Ok(f(&mut registry))
+    }
+}
+
+/// The grammars that are linked into the host binary, indexed by name.
+#[derive(Default)]
+struct LinkedLanguages(BTreeMap<String, Language>);
+
+/// Makes a grammar that is linked into the host binary available to Lua code.
+pub(crate) fn register_language(
+    lua: &Lua,
+    name: &str,
+    language: Language,
+) -> Result<(), mlua::Error> {
+    abi::check_version(lua, name, language.version())?;
+    match lua.app_data_mut::<LinkedLanguages>() {
+        Some(mut linked) => {
+            linked.0.insert(name.to_string(), language);
+        }
+        None => {
+            let mut linked = LinkedLanguages::default();
+            linked.0.insert(name.to_string(), language);
+            lua.set_app_data(linked);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn linked_language(lua: &Lua, name: &str) -> Option<Language> {
+    lua.app_data_ref::<LinkedLanguages>()?.0.get(name).copied()
+}
+
+/// Returns all of the grammars that are linked into the host binary, ordered by name.
+pub(crate) fn linked_languages(lua: &Lua) -> Vec<(String, Language)> {
+    match lua.app_data_ref::<LinkedLanguages>() {
+        Some(linked) => linked
+            .0
+            .iter()
+            .map(|(name, language)| (name.clone(), *language))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Adds an empty language registry to the `ltreesitter_rs` module, and teaches
+/// `ltreesitter.require` about linked grammars.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    ltreesitter::wrap_module_function(lua, "require", |lua, original, args| {
+        let language = match args.iter().next() {
+            Some(Value::String(name)) => linked_language(lua, name.to_str()?),
+            _ => None,
+        };
+        match language {
+            Some(language) => Ok(MultiValue::from_vec(vec![ltreesitter::new_parser(
+                lua, language,
+            )?])),
+            None => original.call::<_, MultiValue>(args),
+        }
+    })?;
+    crate::companion_module(lua)?.set(LANGUAGES, LanguageRegistry::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_require_linked_languages() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.with_language_registry(|registry| {
+            registry.add(LanguagePack::new("python").with_file_type("py"));
+        })
+        .unwrap();
+        l.check(
+            r#"
+              local ltreesitter = require("ltreesitter")
+              local parser = ltreesitter.require("python")
+              local tree = parser:parse_string("def double(x): return x * 2\n")
+              assert(tree:root():type() == "module")
+              local query = parser:query("(identifier) @id")
+              local ids = 0
+              for _ in query:capture(tree:root()) do ids = ids + 1 end
+              assert(ids == 3)
+              local languages = require("ltreesitter_rs").languages
+              assert(languages:parser("python"):parse_string("x = 1"):root():type() == "module")
+            "#,
+        );
+    }
+
+    #[test]
+    fn can_look_up_language_packs() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.with_language_registry(|registry| {
+            registry.add(
+                LanguagePack::new("python")
+                    .with_file_type("py")
+                    .with_file_type("SConstruct")
+                    .with_query("highlights", "(identifier) @variable"),
+            );
+        })
+        .unwrap();
+        l.check(
+            r#"
+              local languages = require("ltreesitter_rs").languages
+              assert(languages:for_path("src/main.py") == "python")
+              assert(languages:for_path("SConstruct") == "python")
+              assert(languages:for_path("main.rs") == nil)
+              assert(languages:get("python").file_types[1] == "py")
+              assert(languages:query("python", "highlights") == "(identifier) @variable")
+              assert(not pcall(languages.parser, languages, "python"))
+            "#,
+        );
+    }
+
+    #[test]
+    fn can_override_default_queries() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.with_language_registry(|registry| {
+            registry.add(LanguagePack::new("python").with_query("highlights", "(identifier) @a"));
+        })
+        .unwrap();
+        l.check(
+            r#"
+              local languages = require("ltreesitter_rs").languages
+              languages:set_query("python", "highlights", "(identifier) @b")
+              languages:set_query("python", "locals", "(function_definition) @scope")
+              assert(languages:query("python", "highlights") == "(identifier) @b")
+              assert(languages:default_query("python", "highlights") == "(identifier) @a")
+              assert(languages:default_query("python", "locals") == nil)
+            "#,
+        );
+        let highlights = l
+            .with_language_registry(|registry| {
+                registry.add(LanguagePack::new("python"));
+                registry.query("python", "highlights").map(str::to_string)
+            })
+            .unwrap();
+        assert_eq!(Some("(identifier) @b"), highlights.as_deref());
+        l.check(
+            r#"
+              local languages = require("ltreesitter_rs").languages
+              assert(languages:reset_query("python", "highlights") == "(identifier) @b")
+              assert(languages:query("python", "highlights") == nil)
+              assert(languages:query("python", "locals") == "(function_definition) @scope")
+            "#,
+        );
+    }
+
+    #[cfg(feature = "language-packs")]
+    #[test]
+    fn can_load_language_pack_manifests() {
+        let dir =
+            std::env::temp_dir().join(format!("mlua-tree-sitter-pack-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("queries")).unwrap();
+        std::fs::write(
+            dir.join("queries/highlights.scm"),
+            "(identifier) @variable\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("pack.toml"),
+            r#"
+              name = "python"
+              library = "python.so"
+              file-types = ["py"]
+              [queries]
+              highlights = "queries/highlights.scm"
+            "#,
+        )
+        .unwrap();
+        let mut registry = LanguageRegistry::new();
+        let pack = registry.load_pack(dir.join("pack.toml")).unwrap();
+        assert_eq!(Some(dir.join("python.so")), pack.library);
+        assert_eq!("(identifier) @variable\n", pack.queries["highlights"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
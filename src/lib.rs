@@ -48,6 +48,23 @@
 //! # }
 //! ```
 //!
+//! ## Deterministic output
+//!
+//! Scripts are often checked with golden tests, so everything that this crate returns comes back
+//! in the same order from run to run:
+//!
+//! - Collections that the crate builds in Rust are either lists in document order (like the
+//!   matches from [`run_query`]) or sorted maps and sets (like [`SymbolIndex`] and
+//!   [`NodeTypes`]); none of them depend on a randomly seeded hash.
+//! - Values that are converted from Lua tables, whose iteration order Lua doesn't define, are
+//!   sorted: the captures of a [`TSQueryMatch`] by where their nodes start and then by name,
+//!   [`ConfigValue`] tables by key, and secondary sources by name.
+//! - The hashes in query caches and recordings use a fixed hash function, so they are the same
+//!   across runs and builds.
+//!
+//! The one exception is the `captures` table of an ltreesitter match, which is a plain Lua table
+//! keyed by capture name; scripts that iterate it with `pairs` should sort the names first.
+//!
 //! ## Building
 //!
 //! This crate depends on the [`mlua`][mlua] crate, which supports multiple Lua versions, and can
@@ -69,6 +86,24 @@
 //! mlua = { version="0.9", features=["lua54", "vendored"] }
 //! mlua-tree-sitter = { version="0.1" }
 //! ```
+//!
+//! To use the crate with [Luau](https://luau-lang.org/), turn on its `luau` feature, which also
+//! turns on mlua's, instead of picking a Lua version.  Luau doesn't run `__gc` metamethods, so the
+//! crate gives ltreesitter's objects destructors instead, using Luau's userdata tags from 120 up.
+//! If your own userdata uses those tags, set `CFLAGS=-DLTREESITTER_LUAU_FIRST_TAG=<tag>` when
+//! building.
+//!
+//! If you build mlua with its `send` feature, so that Lua states can move between threads, turn on
+//! this crate's `send` feature as well.  That requires the callbacks and [`SourceStore`]s that you
+//! hand to the crate to be `Send`, and makes [`SoftTreePool`], [`SoftTree`], and [`Budget`]
+//! `Send`.  Trees that borrow from Lua ([`TreeWithSource`], [`TSNode`], and [`LuaNode`]) are never
+//! `Send`; convert a tree into a [`TreeWithOwnedSource`] to move it to another thread.
+//!
+//! The `async` feature turns on mlua's async support, and adds [`parse_into_lua`], which parses
+//! on a background thread pool so that large parses don't block the thread that runs Lua.
+//!
+//! The `serde` feature implements `Serialize` for [`TreeWithSource`] and [`TSNode`], so that you
+//! can dump parse trees to JSON for debugging and golden tests.
 
 use std::ffi::c_char;
 use std::ffi::c_void;
@@ -78,44 +113,500 @@ use std::ops::DerefMut;
 use mlua::Lua;
 use tree_sitter::Tree;
 
+mod abi;
+mod affected;
+#[cfg(feature = "async")]
+mod async_parse;
+mod borrowed;
+mod budget;
+mod cancel;
+mod captures;
+#[cfg(feature = "grammar-compile")]
+mod compile;
+mod conformance;
+mod context;
+mod copies;
+mod cursor;
+mod delivery;
+mod diagrams;
+mod diff;
+mod display;
+mod document;
+mod edits;
+mod emit;
+mod encoding;
+mod environments;
+mod error;
+mod explain;
+mod functions;
+mod grammars;
+mod highlight;
+mod host;
+mod injections;
+mod interning;
+mod kinds;
+mod language;
+mod languages;
+mod limits;
+mod ltreesitter;
+#[cfg(feature = "luau")]
+mod luau;
+mod marks;
+mod match_buffer;
+mod match_classes;
+mod metrics;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod node_types;
+mod nvim;
+mod offsets;
+mod options;
+mod outcome;
+mod owned;
+mod parser;
+mod patterns;
+mod playground;
+mod point;
+mod positions;
+mod precedence;
+mod predicates;
+mod prelude;
+mod pretty;
+mod query;
+mod query_cache;
+mod query_files;
+mod query_match;
+mod ranges;
+mod reader;
+mod recording;
+#[cfg(feature = "repl")]
+mod repl;
+mod runner;
+mod sandbox;
+#[cfg(feature = "serde")]
+mod serialize;
+mod service;
+mod sexp;
+mod soft;
+mod sources;
+mod spans;
+mod stores;
+mod streaming;
+mod symbols;
+mod tables;
+mod text_predicates;
+mod textobjects;
+mod threads;
+mod tokens;
+mod trees;
+mod versions;
+mod warnings;
+
+pub use affected::affected_patterns;
+#[cfg(feature = "async")]
+pub use async_parse::parse_in_background;
+#[cfg(feature = "async")]
+pub use async_parse::parse_into_lua;
+pub use borrowed::TreeRef;
+pub use budget::Budget;
+pub use budget::Budgeted;
+pub use budget::Phase;
+pub use cancel::CancellationToken;
+pub use captures::typed_matches;
+pub use captures::CaptureField;
+pub use captures::Captures;
+pub use captures::FromCaptures;
+#[cfg(feature = "grammar-compile")]
+pub use compile::GrammarCompiler;
+pub use conformance::conformance;
+pub use conformance::ConformanceCase;
+pub use conformance::ConformanceFailure;
+pub use conformance::ConformanceHarness;
+pub use conformance::ConformanceReport;
+pub use context::AnalysisContext;
+pub use context::ConfigValue;
+pub use copies::disable_tree_copy_tracking;
+pub use copies::enable_tree_copy_tracking;
+pub use copies::tree_copy_report;
+pub use copies::TreeCopyReport;
+pub use copies::TreeCopySite;
+pub use cursor::TSTreeCursor;
+pub use delivery::ChunkedDelivery;
+pub use delivery::DeliveryStats;
+pub use diagrams::render_matches;
+pub use diagrams::DiagramFormat;
+pub use diff::diff_trees;
+pub use diff::TreeChange;
+pub use document::Document;
+pub use document::StaleNode;
+pub use edits::TSInputEdit;
+pub use emit::EmitChannels;
+pub use encoding::NodeText;
+pub use encoding::TextEncoding;
+pub use encoding::TextEncodings;
+pub use error::Error;
+pub use explain::explain;
+pub use explain::NodeExplanation;
+pub use functions::HostFunctions;
+pub use grammars::GrammarChecks;
+pub use grammars::GrammarMismatch;
+pub use grammars::MismatchPolicy;
+pub use highlight::HighlightConfig;
+pub use highlight::Highlighter;
+pub use host::PollResult;
+pub use host::ScriptHost;
+pub use injections::find_injections;
+pub use injections::Injection;
+pub use interning::LuaInterning;
+pub use interning::StringInterner;
+pub use kinds::is_node;
+pub use kinds::is_query;
+pub use kinds::is_tree;
+pub use kinds::is_tree_cursor;
+pub use language::TSLanguage;
+pub use languages::LanguagePack;
+pub use languages::LanguageRegistry;
+pub use languages::Languages;
+pub use limits::Limit;
+pub use limits::LimitExceeded;
+pub use limits::SizeGuards;
+pub use limits::SizeLimits;
+pub use ltreesitter::SourceText;
+pub use marks::MarkStats;
+pub use marks::PerformanceMarks;
+pub use marks::Profile;
+pub use match_buffer::MatchBuffer;
+pub use match_buffer::PackedCapture;
+pub use match_classes::MatchClasses;
+pub use metrics::ConversionInstrumentation;
+pub use metrics::ConversionMetrics;
+#[cfg(feature = "mmap")]
+pub use mmap::MmapSource;
+pub use node_types::ChildInfo;
+pub use node_types::NodeTypeInfo;
+pub use node_types::NodeTypeMetadata;
+pub use node_types::NodeTypeRef;
+pub use node_types::NodeTypes;
+pub use nvim::NvimCompat;
+pub use options::Options;
+pub use options::Placement;
+pub use outcome::ScriptError;
+pub use outcome::ScriptOutcome;
+pub use owned::TreeWithOwnedSource;
+pub use parser::TSParser;
+pub use patterns::pattern_metadata;
+pub use patterns::PatternMetadata;
+pub use playground::playground_json;
+pub use point::TSPoint;
+pub use point::TSRange;
+pub use positions::closest_ancestor_of_kind;
+pub use positions::next_node_of_kind_after;
+pub use positions::previous_node_of_kind_before;
+pub use precedence::QuerySet;
+pub use precedence::ResolvedCapture;
+pub use predicates::QueryPredicates;
+pub use prelude::PRELUDE_VERSION;
+pub use pretty::pretty_print;
+pub use query::run_query;
+pub use query::TSQuery;
+pub use query_cache::QueryCache;
+pub use query_files::load_query_file;
+pub use query_files::read_query_file;
+pub use query_match::TSQueryCapture;
+pub use query_match::TSQueryMatch;
+pub use ranges::edit_byte_range;
+pub use ranges::edit_range;
+pub use ranges::range_contains;
+pub use ranges::range_hull;
+pub use ranges::range_intersection;
+pub use ranges::range_union;
+pub use ranges::ranges_overlap;
+pub use ranges::split_range;
+pub use reader::parse_with_reader;
+pub use recording::BridgeEvent;
+pub use recording::BridgeRecorder;
+pub use recording::Divergence;
+pub use recording::Recording;
+pub use recording::ReplayStep;
+#[cfg(feature = "repl")]
+pub use repl::Repl;
+pub use runner::AnalysisJob;
+pub use runner::ScriptPool;
+pub use runner::ScriptRunner;
+pub use runner::SharedTree;
+pub use runner::TreeArena;
+pub use runner::TreeId;
+#[cfg(feature = "serde")]
+pub use serialize::SerializedNode;
+pub use service::AnalysisFuture;
+pub use service::LuaAnalysisService;
+pub use sexp::to_pretty_sexp;
+pub use sexp::to_sexp;
+pub use soft::SoftTree;
+pub use soft::SoftTreePool;
+pub use soft::SoftTreeStats;
+pub use sources::SecondarySource;
+pub use sources::SourceMap;
+pub use spans::merge_spans;
+pub use spans::HighlightSpan;
+pub use stores::FetchSource;
+pub use stores::SharedSource;
+pub use stores::SourceStore;
+pub use stores::TreeWithoutSource;
+pub use streaming::DirectoryStream;
+pub use streaming::StreamStats;
+pub use symbols::Symbol;
+pub use symbols::SymbolIndex;
+pub use tables::TableOptions;
+pub use text_predicates::TextPredicates;
+pub use textobjects::TextObjects;
+pub use tokens::token_class;
+pub use tokens::TokenClass;
+pub use versions::LUA_API_VERSION;
+pub use warnings::ScriptWarning;
+pub use warnings::ScriptWarnings;
+pub use warnings::WarningKind;
+
 /// An extension trait that lets you load the `ltreesitter` module into a Lua environment.
 pub trait Module {
     /// Loads the `ltreesitter` module into a Lua environment.
     fn open_ltreesitter(&self) -> Result<(), mlua::Error>;
+
+    /// Loads the `ltreesitter` module into a Lua environment, using [`Options`] to control the
+    /// name that it's registered under, where it's registered, and which of its features Lua code
+    /// can use.
+    fn open_ltreesitter_with(&self, options: Options) -> Result<(), mlua::Error>;
+
+    /// Installs a copy of the `ltreesitter` module into an environment table, rather than into the
+    /// global `package` table, and returns it.  The copy is stored in `env` under the options'
+    /// name, and if `env` has its own `package` table, it's registered there according to the
+    /// options' placement.  Each environment can have its own `parsers` and `dynamic_loading`
+    /// options, so that isolated scripts in one Lua state can have independent access policies.
+    fn open_ltreesitter_in<'lua>(
+        &'lua self,
+        env: &mlua::Table<'lua>,
+        options: Options,
+    ) -> Result<mlua::Table<'lua>, mlua::Error>;
+
+    /// Registers the `ltreesitter` module in `package.preload`, so that it's only loaded the first
+    /// time that Lua code requires it (or that you pass a tree to Lua).  Scripts that never use
+    /// the module don't pay for loading it.  Sandboxed environments can remove the preloader (or
+    /// `require` itself) to make the module unreachable.
+    fn preload_ltreesitter(&self) -> Result<(), mlua::Error>;
+
+    /// Loads the `ltreesitter` module into a Lua environment, along with a prelude of pure-Lua
+    /// helpers (iterators over children and descendants, node printers, and range helpers), which
+    /// Lua code can load via `require("ltreesitter_rs.prelude")`.  The prelude's `version` field
+    /// holds [`PRELUDE_VERSION`].
+    fn open_ltreesitter_with_prelude(&self) -> Result<(), mlua::Error>;
+
+    /// Loads the `ltreesitter` module into a Lua environment that will run untrusted code.  Lua
+    /// code can't load grammars from shared libraries; `ltreesitter.require` only returns parsers
+    /// for the grammars that you register via [`register_language`][Module::register_language].
+    fn open_ltreesitter_sandboxed(&self) -> Result<(), mlua::Error>;
+
+    /// Makes a grammar that is linked into the current binary available to Lua code, so that
+    /// `ltreesitter.require(name)` returns a parser for it without loading a dynamic library.
+    fn register_language(
+        &self,
+        name: &str,
+        language: tree_sitter::Language,
+    ) -> Result<(), mlua::Error>;
 }
 
 impl Module for Lua {
     fn open_ltreesitter(&self) -> Result<(), mlua::Error> {
-        unsafe extern "C-unwind" fn load_ltreesitter(l: *mut mlua::lua_State) -> i32 {
-            extern "C-unwind" {
-                fn luaopen_ltreesitter(l: *mut mlua::lua_State) -> i32;
-            }
-            mlua::ffi::luaL_requiref(
-                l,
-                "ltreesitter".as_ptr() as *const _,
-                luaopen_ltreesitter,
-                false as i32,
-            );
-            1
+        self.open_ltreesitter_with(Options::default())
+    }
+
+    fn open_ltreesitter_with(&self, options: Options) -> Result<(), mlua::Error> {
+        extern "C-unwind" {
+            fn luaopen_ltreesitter(l: *mut mlua::lua_State) -> i32;
+        }
+        #[cfg(feature = "luau")]
+        luau::install(self)?;
+        let load = unsafe { self.create_c_function(luaopen_ltreesitter) }?;
+        metrics::record_c_function(self);
+        let module: mlua::Table = load.call(options.name.as_str())?;
+        self.set_named_registry_value(ltreesitter::MODULE_KEY, module)?;
+        abi::install(self)?;
+        affected::install(self)?;
+        #[cfg(feature = "async")]
+        async_parse::install(self)?;
+        conformance::install(self)?;
+        cursor::install_methods(self)?;
+        diagrams::install(self)?;
+        diff::install(self)?;
+        edits::install(self)?;
+        encoding::install(self)?;
+        explain::install(self)?;
+        grammars::install(self)?;
+        highlight::install(self)?;
+        injections::install(self)?;
+        kinds::install(self)?;
+        languages::install(self)?;
+        marks::install(self)?;
+        match_buffer::install(self)?;
+        match_classes::install(self)?;
+        node_types::install_methods(self)?;
+        offsets::install(self)?;
+        outcome::install(self)?;
+        patterns::install_methods(self)?;
+        playground::install(self)?;
+        positions::install(self)?;
+        predicates::install(self)?;
+        pretty::install(self)?;
+        query_files::install(self)?;
+        ranges::install(self)?;
+        reader::install(self)?;
+        #[cfg(feature = "serde")]
+        serialize::install(self)?;
+        sexp::install(self)?;
+        sources::install_methods(self)?;
+        spans::install(self)?;
+        stores::install_methods(self)?;
+        tables::install(self)?;
+        textobjects::install(self)?;
+        tokens::install_methods(self)?;
+        versions::install(self)?;
+        trees::install_close(self)?;
+        options::apply(self, &options)
+    }
+
+    fn open_ltreesitter_in<'lua>(
+        &'lua self,
+        env: &mlua::Table<'lua>,
+        options: Options,
+    ) -> Result<mlua::Table<'lua>, mlua::Error> {
+        environments::open_in(self, env, options)
+    }
+
+    fn preload_ltreesitter(&self) -> Result<(), mlua::Error> {
+        options::preload(self, Options::default())
+    }
+
+    fn open_ltreesitter_with_prelude(&self) -> Result<(), mlua::Error> {
+        self.open_ltreesitter()?;
+        prelude::install(self)
+    }
+
+    fn open_ltreesitter_sandboxed(&self) -> Result<(), mlua::Error> {
+        self.open_ltreesitter_with(Options::default().with_dynamic_loading(false))
+    }
+
+    fn register_language(
+        &self,
+        name: &str,
+        language: tree_sitter::Language,
+    ) -> Result<(), mlua::Error> {
+        languages::register_language(self, name, language)
+    }
+}
+
+/// Returns the `ltreesitter_rs` module, creating it if necessary.  This module holds the Lua-facing
+/// helpers that this crate provides on top of ltreesitter.  Lua code can load it via `require
+/// "ltreesitter_rs"`.
+pub(crate) fn companion_module(lua: &Lua) -> Result<mlua::Table, mlua::Error> {
+    const MODULE_KEY: &str = "mlua_tree_sitter.ltreesitter_rs";
+    if let Some(module) = lua.named_registry_value::<Option<mlua::Table>>(MODULE_KEY)? {
+        return Ok(module);
+    }
+    let module = lua.create_table()?;
+    lua.set_named_registry_value(MODULE_KEY, module.clone())?;
+    let loaded: mlua::Table = lua
+        .globals()
+        .get::<_, mlua::Table>("package")?
+        .get("loaded")?;
+    loaded.set("ltreesitter_rs", module.clone())?;
+    Ok(module)
+}
+
+/// Keeps a Lua value alive for as long as a Rust value that borrows from it.  mlua holds a strong
+/// reference to every Lua value that Rust code has a handle on, so the anchored value won't be
+/// garbage-collected even if Lua code drops all of its own references to it.  The anchor also pins
+/// the tree-sitter tree that the value belongs to, so that Lua code can't close it.
+#[derive(Default)]
+pub(crate) struct Anchor<'lua>(
+    Option<(
+        &'lua Lua,
+        mlua::Value<'lua>,
+        *const tree_sitter::ffi::TSTree,
+    )>,
+);
+
+impl<'lua> Anchor<'lua> {
+    pub(crate) fn new(
+        lua: &'lua Lua,
+        value: mlua::Value<'lua>,
+        ts_tree: *const tree_sitter::ffi::TSTree,
+    ) -> Anchor<'lua> {
+        trees::pin(lua, ts_tree);
+        Anchor(Some((lua, value, ts_tree)))
+    }
+}
+
+impl Clone for Anchor<'_> {
+    fn clone(&self) -> Self {
+        match &self.0 {
+            Some((lua, value, ts_tree)) => Anchor::new(lua, value.clone(), *ts_tree),
+            None => Anchor(None),
+        }
+    }
+}
+
+impl Drop for Anchor<'_> {
+    fn drop(&mut self) {
+        if let Some((lua, _, ts_tree)) = &self.0 {
+            trees::unpin(lua, *ts_tree);
         }
-        let load = unsafe { self.create_c_function(load_ltreesitter) }?;
-        load.call(())?;
-        Ok(())
     }
 }
 
+/// Creates a new table whose keys or values (depending on `mode`) are weak references.
+pub(crate) fn weak_table<'lua>(
+    lua: &'lua Lua,
+    mode: &str,
+) -> Result<mlua::Table<'lua>, mlua::Error> {
+    let table = lua.create_table()?;
+    let metatable = lua.create_table()?;
+    metatable.set("__mode", mode)?;
+    table.set_metatable(Some(metatable));
+    Ok(table)
+}
+
 /// An extension trait that lets you combine a [`tree_sitter::Tree`] with the source code that it
 /// was parsed from.
 pub trait WithSource {
     /// Combines a [`tree_sitter::Tree`] with the source code that it was parsed from.
     fn with_source<'a>(self, src: &'a [u8]) -> TreeWithSource<'a>;
+
+    /// Combines a [`tree_sitter::Tree`] with an owned copy of the source code that it was parsed
+    /// from.
+    fn with_owned_source<S: AsRef<[u8]>>(self, src: S) -> TreeWithOwnedSource<S>;
+
+    /// Combines a [`tree_sitter::Tree`] with a [`SourceStore`] that holds the source code that it
+    /// was parsed from.  When the tree is pushed into Lua, its source is read from the store,
+    /// instead of being copied into the Lua state.
+    fn with_source_store<S: SourceStore>(self, store: S) -> TreeWithSource<'static>;
+
+    /// Wraps a [`tree_sitter::Tree`] so that it can be pushed into Lua without any source code.
+    fn without_source(self) -> TreeWithoutSource;
 }
 
 /// The combination of a [`tree_sitter::Tree`] with the source code that it was parsed from.  This
 /// type implements the [`mlua::IntoLua`] trait, so you can push it onto a Lua stack.
+///
+/// When you convert an ltreesitter tree into a `TreeWithSource`, `src` borrows the copy of the
+/// source code that ltreesitter owns.  The `TreeWithSource` keeps the Lua tree alive, so the
+/// source remains valid even if Lua code drops its last reference to the tree.  (If the tree was
+/// pushed with a [`SourceStore`], `src` borrows the store's contents instead.  The conversion fails
+/// if the store doesn't hold them in memory.)
 pub struct TreeWithSource<'a> {
     pub tree: Tree,
     pub src: &'a [u8],
+    secondary: Vec<SecondarySource<'a>>,
+    store: Option<Box<dyn SourceStore>>,
+    anchor: Anchor<'a>,
 }
 
 impl WithSource for Tree {
@@ -123,12 +614,56 @@ impl WithSource for Tree {
         TreeWithSource {
             tree: self,
             src: src.as_ref(),
+            secondary: Vec::new(),
+            store: None,
+            anchor: Anchor::default(),
         }
     }
+
+    fn with_owned_source<S: AsRef<[u8]>>(self, src: S) -> TreeWithOwnedSource<S> {
+        TreeWithOwnedSource::new(self, src)
+    }
+
+    fn with_source_store<S: SourceStore>(self, store: S) -> TreeWithSource<'static> {
+        TreeWithSource {
+            tree: self,
+            src: &[],
+            secondary: Vec::new(),
+            store: Some(Box::new(store)),
+            anchor: Anchor::default(),
+        }
+    }
+
+    fn without_source(self) -> TreeWithoutSource {
+        TreeWithoutSource::new(self)
+    }
 }
 
+// A borrowed tree is copied (which is cheap, since tree-sitter trees are reference-counted), so
+// that the caller can keep using their own tree after showing it to Lua.
+impl WithSource for &Tree {
+    fn with_source<'a>(self, src: &'a [u8]) -> TreeWithSource<'a> {
+        copies::copy_tree(self).with_source(src)
+    }
+
+    fn with_owned_source<S: AsRef<[u8]>>(self, src: S) -> TreeWithOwnedSource<S> {
+        copies::copy_tree(self).with_owned_source(src)
+    }
+
+    fn with_source_store<S: SourceStore>(self, store: S) -> TreeWithSource<'static> {
+        copies::copy_tree(self).with_source_store(store)
+    }
+
+    fn without_source(self) -> TreeWithoutSource {
+        copies::copy_tree(self).without_source()
+    }
+}
+
+const LOAD_TREE_KEY: &str = "mlua_tree_sitter.load_tree";
+
 // We can implement this for any lifetime because Lua takes ownership of the tree, and will free it
-// when the Lua wrapper is garbage-collected; and ltreesitter makes a copy of the source code.
+// when the Lua wrapper is garbage-collected (or closed); and the Lua tree gets its own copy of the
+// source code, or takes ownership of its source store.
 impl mlua::IntoLua<'_> for TreeWithSource<'_> {
     fn into_lua(self, l: &Lua) -> Result<mlua::Value, mlua::Error> {
         unsafe extern "C-unwind" fn load_tree(l: *mut mlua::lua_State) -> i32 {
@@ -147,12 +682,68 @@ impl mlua::IntoLua<'_> for TreeWithSource<'_> {
             1
         }
 
+        options::load_preloaded(l)?;
+        let stored_len = self.store.as_ref().map(|store| store.len());
+        let src_len = stored_len.unwrap_or(self.src.len());
+        abi::check_language(l, self.tree.language())?;
+        let tree_len = self.tree.root_node().end_byte();
+        if tree_len > src_len {
+            return Err(Error::SourceLengthMismatch {
+                tree_len,
+                source_len: src_len,
+            }
+            .into());
+        }
+        limits::check_source(l, src_len)?;
+        // A store-backed tree has no `src`, so we hash the store's bytes if they're in memory.
+        let input = recording::is_recording(l).then(|| {
+            let src = match &self.store {
+                Some(store) => store.as_bytes().unwrap_or_default(),
+                None => self.src,
+            };
+            recording::hash_tree(self.tree.root_node(), src)
+        });
         let tree =
             mlua::Value::LightUserData(mlua::LightUserData(self.tree.into_raw() as *mut c_void));
-        let src_len = self.src.len();
-        let src = mlua::Value::LightUserData(mlua::LightUserData(self.src.as_ptr() as *mut _));
-        let load = unsafe { l.create_c_function(load_tree) }?;
-        load.call((tree, src_len, src))
+        // ltreesitter would copy the source into a buffer that only the garbage collector can
+        // free, so we give it an empty source, and then swap in a copy that we own, or the
+        // contents of the source store.
+        let src = mlua::Value::LightUserData(mlua::LightUserData(b"".as_ptr() as *mut _));
+        let load = ltreesitter::cached_c_function(l, LOAD_TREE_KEY, load_tree)?;
+        let in_memory = match &self.store {
+            Some(store) => store.as_bytes().is_some(),
+            None => true,
+        };
+        let tree = load.call((tree, 0, src))?;
+        match self.store {
+            Some(store) => stores::attach(l, &tree, store)?,
+            None => {
+                metrics::record_source_copy(l, self.src.len());
+                trees::set_source(l, &tree, ltreesitter::SourceBuffer::new(self.src))?;
+            }
+        }
+        trees::register_tree(l, &tree)?;
+        sources::attach(l, &tree, &self.secondary)?;
+        if let Some(input) = input {
+            let ltreesitter_tree = ltreesitter::tree_ptr(l, tree.clone())?;
+            let (root, src) = unsafe {
+                (
+                    ltreesitter::root_node(ltreesitter_tree),
+                    ltreesitter::source(ltreesitter_tree),
+                )
+            };
+            let output = recording::hash_tree(root, src);
+            // We can only replay the push if we have the tree's source and know its grammar.
+            let replay = match recording::language_name(l, root.language()) {
+                Some(language) if in_memory => Some(recording::ReplayStep::PushTree {
+                    language,
+                    source: src.to_vec(),
+                }),
+                _ => None,
+            };
+            recording::record_push(l, &tree, input, output, replay)?;
+        }
+        Ok(tree)
     }
 }
 
@@ -160,52 +751,90 @@ impl mlua::IntoLua<'_> for TreeWithSource<'_> {
 // only valid while the Lua interpreter is live.
 impl<'lua> mlua::FromLua<'lua> for TreeWithSource<'lua> {
     fn from_lua(value: mlua::Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
-        // Use some trickery to use ltreesitter's C accessor to get at the tree-sitter
-        // Tree.  Return it back up to the "safe" mlua code as a light userdata.
-        unsafe extern "C-unwind" fn get_tree(l: *mut mlua::lua_State) -> i32 {
-            extern "C-unwind" {
-                fn ltreesitter_check_tree_arg(l: *mut mlua::lua_State, index: u32) -> *mut c_void;
+        let ltreesitter_tree = ltreesitter::as_tree(lua, &value)?.ok_or_else(|| {
+            mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "TreeWithSource",
+                message: Some("expected an ltreesitter tree".to_string()),
             }
-            let ltreesitter_tree = ltreesitter_check_tree_arg(l, 1);
-            mlua::ffi::lua_pushlightuserdata(l, ltreesitter_tree);
-            1
-        }
-
-        #[repr(C)]
-        struct LTreeSitterSourceText {
-            length: usize,
-            text: u8, // this is a VLA down in C
-        }
-
-        #[repr(C)]
-        struct LTreeSitterTree {
-            tree: *mut tree_sitter::ffi::TSTree,
-            source: *const LTreeSitterSourceText,
+        })?;
+        if unsafe { (*ltreesitter_tree).tree.is_null() } {
+            return Err(trees::closed_error());
         }
-
-        let get_tree = unsafe { lua.create_c_function(get_tree) }?;
-        let mlua::LightUserData(ltreesitter_tree) = get_tree.call(value)?;
-        let ltreesitter_tree = ltreesitter_tree as *mut LTreeSitterTree;
-        unsafe {
-            let ltreesitter_source = (*ltreesitter_tree).source;
-            let src = std::slice::from_raw_parts(
-                &(*ltreesitter_source).text,
-                (*ltreesitter_source).length,
-            );
+        abi::check_language(
+            lua,
+            unsafe { ltreesitter::root_node(ltreesitter_tree) }.language(),
+        )?;
+        trees::check_generation(lua, &value)?;
+        limits::check_depth(lua, unsafe { ltreesitter::root_node(ltreesitter_tree) })?;
+        let secondary = sources::load(lua, &value)?;
+        let src = stores::source(lua, &value)?
+            .ok_or_else(|| stores::not_in_memory(value.type_name(), "TreeWithSource"))?;
+        let result = unsafe {
             let tree = (*ltreesitter_tree).tree;
             // The Rust tree-sitter bindings want to take ownership of the tree, so we need to make
             // a copy first.
-            let tree = tree_sitter::ffi::ts_tree_copy(tree);
+            let tree = copies::copy_raw_tree(lua, tree);
             let tree = tree_sitter::Tree::from_raw(tree);
-            Ok(TreeWithSource { tree, src })
+            TreeWithSource {
+                tree,
+                src,
+                secondary,
+                store: None,
+                anchor: Anchor::new(lua, value.clone(), (*ltreesitter_tree).tree),
+            }
+        };
+        if recording::is_recording(lua) {
+            let input = unsafe {
+                recording::hash_tree(
+                    ltreesitter::root_node(ltreesitter_tree),
+                    ltreesitter::source(ltreesitter_tree),
+                )
+            };
+            let output = recording::hash_tree(result.tree.root_node(), result.src);
+            let replay = recording::tree_index(lua, &value)
+                .map(|tree| recording::ReplayStep::TreeFromLua { tree });
+            recording::record(lua, "tree_from_lua", input, output, replay);
         }
+        Ok(result)
+    }
+}
+
+impl<'a> TreeWithSource<'a> {
+    /// Wraps a node of this tree so that it can be converted into an ltreesitter node.  This only
+    /// works if the `TreeWithSource` was converted from an ltreesitter tree.
+    pub fn lua_node<'t>(&'t self, node: tree_sitter::Node<'t>) -> LuaNode<'t> {
+        LuaNode::anchored(node, self.anchor.clone())
+    }
+
+    /// Closes an ltreesitter tree, freeing its underlying tree-sitter tree right away instead of
+    /// waiting for the garbage collector.  Any further use of the tree, or of its nodes and
+    /// cursors, raises a Lua error.  (Lua code can do the same via `tree:close()`.)  Returns an
+    /// error if any Rust values converted from the tree are still live.
+    pub fn close_in_lua<'lua>(lua: &'lua Lua, tree: mlua::Value<'lua>) -> Result<(), mlua::Error> {
+        trees::close_tree(lua, &tree)
     }
 }
 
 // A wrapper around a [`tree_sitter::Node`].  This only exists to get around Rust's orphan rules,
 // so that we can implement the [`mlua::FromLua`] trait.
+//
+// A `TSNode` doesn't keep its Lua tree alive, and can't be pushed back into Lua; use a
+// [`LuaNode`] for that.
 pub struct TSNode<'n>(pub tree_sitter::Node<'n>);
 
+impl<'n> TSNode<'n> {
+    /// Wraps a node.
+    pub fn new(node: tree_sitter::Node<'n>) -> TSNode<'n> {
+        TSNode(node)
+    }
+
+    /// Returns whether this node's kind is `supertype`, or one of its subtypes.
+    pub fn is_subtype_of(&self, node_types: &NodeTypes, supertype: &str) -> bool {
+        node_types.is_subtype_of(self.kind(), supertype)
+    }
+}
+
 impl<'n> Deref for TSNode<'n> {
     type Target = tree_sitter::Node<'n>;
     fn deref(&self) -> &Self::Target {
@@ -219,40 +848,204 @@ impl<'n> DerefMut for TSNode<'n> {
     }
 }
 
+/// Returns the Rust node that an ltreesitter node wraps, along with its tree-sitter tree, after
+/// checking that it's safe to use.
+fn node_from_lua<'lua>(
+    value: &mlua::Value<'lua>,
+    lua: &'lua Lua,
+    to: &'static str,
+) -> Result<(tree_sitter::Node<'lua>, *const tree_sitter::ffi::TSTree), mlua::Error> {
+    let ltreesitter_node = match ltreesitter::as_node(lua, value)? {
+        Some(ltreesitter_node) => ltreesitter_node,
+        None if trees::is_closed_object(lua, value)? => return Err(trees::closed_error()),
+        None => {
+            return Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to,
+                message: Some("expected an ltreesitter node".to_string()),
+            })
+        }
+    };
+    let tree = trees::check_open(lua, value)?;
+    trees::check_generation(lua, value)?;
+    let node = unsafe { tree_sitter::Node::from_raw((*ltreesitter_node).node) };
+    let ts_tree = unsafe { (*ltreesitter_node).node.tree };
+    if recording::is_recording(lua) {
+        let input =
+            recording::hash_node(&unsafe { tree_sitter::Node::from_raw((*ltreesitter_node).node) });
+        let src = match &tree {
+            Some(tree) => stores::source(lua, tree)?,
+            None => None,
+        };
+        let output = recording::hash_node_contents(&node, src);
+        let replay = tree
+            .as_ref()
+            .and_then(|tree| recording::tree_index(lua, tree))
+            .map(|tree| recording::ReplayStep::NodeFromLua {
+                tree,
+                path: recording::node_path(node),
+            });
+        recording::record(lua, "node_from_lua", input, output, replay);
+    }
+    Ok((node, ts_tree))
+}
+
 // We can only implement this for the 'lua lifetime, to express that the returned Rust value is
 // only valid while the Lua interpreter is live.
 impl<'lua> mlua::FromLua<'lua> for TSNode<'lua> {
     fn from_lua(value: mlua::Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
-        // Use some trickery to use ltreesitter's C accessor to get at the tree-sitter
-        // Node.  Return it back up to the "safe" mlua code as a light userdata.
-        unsafe extern "C-unwind" fn get_node(l: *mut mlua::lua_State) -> i32 {
-            extern "C-unwind" {
-                fn ltreesitter_check_node(l: *mut mlua::lua_State, index: u32) -> *mut c_void;
-            }
-            let ltreesitter_node = ltreesitter_check_node(l, 1);
-            mlua::ffi::lua_pushlightuserdata(l, ltreesitter_node);
-            1
+        let (node, _) = node_from_lua(&value, lua, "TSNode")?;
+        Ok(TSNode(node))
+    }
+}
+
+/// A [`tree_sitter::Node`] that belongs to an ltreesitter tree.
+///
+/// A `LuaNode` that was converted from an ltreesitter node keeps that Lua node (and therefore its
+/// tree) alive, so that the node remains valid even if Lua code drops its last reference to the
+/// tree.  A `LuaNode` can also be pushed into Lua, creating an ltreesitter node.  You can create
+/// one for a node that you found in Rust with [`LuaNode::in_lua_tree`] or
+/// [`TreeWithSource::lua_node`].
+pub struct LuaNode<'n> {
+    node: tree_sitter::Node<'n>,
+    anchor: Anchor<'n>,
+}
+
+impl<'n> LuaNode<'n> {
+    /// Wraps a node that belongs to a tree that has already been pushed into Lua, so that it can
+    /// be converted into an ltreesitter node of that tree.  `node` can come from any copy of the
+    /// Rust tree that was pushed.
+    pub fn in_lua_tree(
+        lua: &'n Lua,
+        tree: mlua::Value<'n>,
+        node: tree_sitter::Node<'n>,
+    ) -> Result<LuaNode<'n>, mlua::Error> {
+        let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+        let ts_tree = unsafe { (*ltreesitter_tree).tree };
+        if ts_tree.is_null() {
+            return Err(trees::closed_error());
         }
+        Ok(LuaNode {
+            node,
+            anchor: Anchor::new(lua, tree, ts_tree),
+        })
+    }
+
+    pub(crate) fn anchored(node: tree_sitter::Node<'n>, anchor: Anchor<'n>) -> LuaNode<'n> {
+        LuaNode { node, anchor }
+    }
 
-        #[repr(C)]
-        struct LTreeSitterNode {
-            node: tree_sitter::ffi::TSNode,
+    /// Returns another node of the same Lua tree.
+    pub fn with_node(&self, node: tree_sitter::Node<'n>) -> LuaNode<'n> {
+        LuaNode {
+            node,
+            anchor: self.anchor.clone(),
         }
+    }
+
+    /// Returns the wrapped node.
+    pub fn node(&self) -> tree_sitter::Node<'n> {
+        self.node
+    }
+
+    pub(crate) fn anchor(&self) -> &Anchor<'n> {
+        &self.anchor
+    }
+}
+
+impl<'n> Deref for LuaNode<'n> {
+    type Target = tree_sitter::Node<'n>;
+    fn deref(&self) -> &Self::Target {
+        &self.node
+    }
+}
+
+impl<'n> From<LuaNode<'n>> for TSNode<'n> {
+    fn from(node: LuaNode<'n>) -> TSNode<'n> {
+        TSNode(node.node)
+    }
+}
+
+// We can only implement this for the 'lua lifetime, to express that the returned Rust value is
+// only valid while the Lua interpreter is live.
+impl<'lua> mlua::FromLua<'lua> for LuaNode<'lua> {
+    fn from_lua(value: mlua::Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let (node, ts_tree) = node_from_lua(&value, lua, "LuaNode")?;
+        Ok(LuaNode {
+            node,
+            anchor: Anchor::new(lua, value, ts_tree),
+        })
+    }
+}
 
-        let get_node = unsafe { lua.create_c_function(get_node) }?;
-        let mlua::LightUserData(ltreesitter_node) = get_node.call(value)?;
-        let ltreesitter_node = ltreesitter_node as *mut LTreeSitterNode;
-        Ok(TSNode(unsafe {
-            tree_sitter::Node::from_raw((*ltreesitter_node).node)
-        }))
+impl<'lua> mlua::IntoLua<'lua> for LuaNode<'lua> {
+    fn into_lua(self, lua: &'lua Lua) -> Result<mlua::Value<'lua>, mlua::Error> {
+        let Anchor(anchor) = &self.anchor;
+        let anchor = match anchor {
+            Some((_, value, _)) => value.clone(),
+            None => return Err(Error::DetachedNode.into()),
+        };
+        let lua_root = if ltreesitter::as_tree(lua, &anchor)?.is_some() {
+            let root: mlua::Function =
+                ltreesitter::methods(lua, ltreesitter::TREE_METATABLE)?.get("root")?;
+            root.call(anchor)?
+        } else {
+            let parent: mlua::Function =
+                ltreesitter::methods(lua, ltreesitter::NODE_METATABLE)?.get("parent")?;
+            let mut node = anchor;
+            while let Some(parent) = parent.call::<_, Option<mlua::Value>>(node.clone())? {
+                node = parent;
+            }
+            node
+        };
+        let mut root = self.node;
+        while let Some(parent) = root.parent() {
+            root = parent;
+        }
+        let lua_root_node = ltreesitter::node_ptr(lua, lua_root.clone())?;
+        if unsafe { tree_sitter::Node::from_raw((*lua_root_node).node) }.id() != root.id() {
+            return Err(mlua::Error::RuntimeError(
+                "node doesn't belong to the Lua tree that it was anchored to".to_string(),
+            ));
+        }
+        positions::descendant_in_lua(lua, lua_root, root, self.node)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::Context;
+    use std::task::Poll;
+    use std::task::Wake;
+    use std::task::Waker;
+    use std::thread::Thread;
+
     use super::*;
 
-    trait CheckLua {
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Runs a future to completion on the current thread.
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    pub(crate) trait CheckLua {
         fn call<'lua, R: mlua::FromLuaMulti<'lua>>(&'lua self, chunk: &str) -> R;
         fn check(&self, chunk: &str);
     }
@@ -287,6 +1080,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn can_push_borrowed_trees() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("first", (&parsed).with_source(code))
+            .unwrap();
+        l.globals()
+            .set("second", (&parsed).with_source(code))
+            .unwrap();
+        l.check(
+            r#"
+              first:close()
+              assert(second:root():child(0):type() == "function_definition")
+            "#,
+        );
+        assert_eq!("module", parsed.root_node().kind());
+    }
+
     #[test]
     fn can_return_trees_back_to_rust() {
         let code = br#"
@@ -319,4 +1135,117 @@ mod tests {
         let root: TSNode = l.call(r#" return parsed:root() "#);
         assert_eq!("module", root.kind());
     }
+
+    #[test]
+    fn can_push_nodes_found_in_rust() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("parsed", parsed.clone().with_source(code))
+            .unwrap();
+        let tree: mlua::Value = l.globals().get("parsed").unwrap();
+        let function = parsed.root_node().child(0).unwrap();
+        let name = function.child_by_field_name("name").unwrap();
+        let node = LuaNode::in_lua_tree(&l, tree, name).unwrap();
+        l.globals().set("name", node).unwrap();
+        l.check(r#" assert(name:source() == "double") "#);
+
+        let tws: TreeWithSource = l.call(r#" return parsed "#);
+        let body = tws.tree.root_node().child(0).unwrap();
+        let body = body.child_by_field_name("body").unwrap();
+        l.globals().set("body", tws.lua_node(body)).unwrap();
+        l.check(r#" assert(body:type() == "block") "#);
+
+        let anchored: LuaNode = l.call(r#" return name "#);
+        let parameters = anchored.parent().unwrap().child_by_field_name("parameters");
+        let parameters = anchored.with_node(parameters.unwrap());
+        l.globals().set("parameters", parameters).unwrap();
+        l.check(r#" assert(parameters:source() == "(x)") "#);
+        let detached = parsed.with_source(code);
+        let orphan = detached.lua_node(detached.tree.root_node());
+        assert!(l.globals().set("orphan", orphan).is_err());
+    }
+
+    #[test]
+    fn keeps_trees_alive_during_garbage_collection() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let tws: TreeWithSource = l.call(r#" return parsed "#);
+        let root: LuaNode = l.call(r#" return parsed:root() "#);
+        let mut cursor: TSTreeCursor = l.call(r#" return parsed:root():create_cursor() "#);
+        l.check(
+            r#"
+              parsed = nil
+              collectgarbage("collect")
+              collectgarbage("collect")
+            "#,
+        );
+        assert_eq!(code, tws.src);
+        assert_eq!("function_definition", root.child(0).unwrap().kind());
+        let mut kinds = Vec::new();
+        while cursor.goto_first_child() {
+            kinds.push(cursor.node().kind());
+            l.check(r#" collectgarbage("collect") "#);
+        }
+        assert_eq!(vec!["function_definition", "def"], kinds);
+    }
+
+    #[test]
+    fn can_close_trees() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              root = parsed:root()
+              local cursor = root:create_cursor()
+              local children = root:children()
+              local query = require("ltreesitter").require("python"):query("(identifier) @id")
+              local matches = query:match(root)
+              parsed:close()
+              parsed:close()
+              assert(not pcall(parsed.root, parsed), "expected closed tree")
+              assert(not pcall(root.type, root), "expected closed node")
+              assert(not pcall(cursor.current_node, cursor), "expected closed cursor")
+              assert(not pcall(children), "expected closed iterator")
+              assert(not pcall(matches), "expected closed iterator")
+            "#,
+        );
+        let err = l.globals().get::<_, TSNode>("root").unwrap_err();
+        assert!(matches!(Error::find(&err), Some(Error::ClosedTree)));
+        let tree: mlua::Value = l.globals().get("parsed").unwrap();
+        let ltreesitter_tree = ltreesitter::tree_ptr(&l, tree).unwrap();
+        assert!(unsafe { ltreesitter::source(ltreesitter_tree) }.is_empty());
+
+        // Nodes of a closed tree stay closed, even if a new tree reuses the old tree's memory.
+        for _ in 0..10 {
+            let parsed = parser.parse(code, None).unwrap();
+            l.globals().set("reused", parsed.with_source(code)).unwrap();
+            l.check(r#" assert(not pcall(root.type, root), "expected closed node") "#);
+        }
+
+        let parsed = parser.parse(code, None).unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let root: LuaNode = l.call(r#" return parsed:root() "#);
+        let tree: mlua::Value = l.globals().get("parsed").unwrap();
+        assert!(TreeWithSource::close_in_lua(&l, tree.clone()).is_err());
+        drop(root);
+        TreeWithSource::close_in_lua(&l, tree).unwrap();
+        l.check(r#" assert(not pcall(parsed.root, parsed), "expected closed tree") "#);
+    }
 }
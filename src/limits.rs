@@ -0,0 +1,240 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Host-configurable limits on the size of the trees that cross the bridge.
+//!
+//! Long-running hosts that accept input from untrusted sources can set [`SizeLimits`] to protect
+//! themselves from pathological inputs.  With a source limit, pushing a tree whose source is too
+//! large into Lua fails; with a depth limit, converting a tree that is nested too deeply back into
+//! Rust (or printing it from Lua) fails; with a table depth limit, converting configuration values
+//! that are nested too deeply fails.  Either way, the error is an external [`mlua::Error`] wrapping
+//! a [`LimitExceeded`], which you can check for via [`mlua::Error::downcast_ref`].
+//!
+//! The bridge never walks trees or tables recursively, so even without any limits, deeply nested
+//! inputs (like machine-generated code) can't overflow the stack.  The limits let hosts bound the
+//! time and memory that those inputs use.
+
+use mlua::Lua;
+use tree_sitter::Node;
+
+/// Limits on the size of the trees that cross the bridge.  `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SizeLimits {
+    pub max_source_bytes: Option<usize>,
+    pub max_tree_depth: Option<usize>,
+    pub max_table_depth: Option<usize>,
+}
+
+impl SizeLimits {
+    /// Creates a new set of limits, which doesn't limit anything.
+    pub fn new() -> SizeLimits {
+        SizeLimits::default()
+    }
+
+    /// Limits the number of bytes of source code of each tree pushed into Lua.
+    pub fn with_max_source_bytes(mut self, max_source_bytes: usize) -> SizeLimits {
+        self.max_source_bytes = Some(max_source_bytes);
+        self
+    }
+
+    /// Limits the depth of each tree converted from Lua into Rust.  The root node has depth 1.
+    pub fn with_max_tree_depth(mut self, max_tree_depth: usize) -> SizeLimits {
+        self.max_tree_depth = Some(max_tree_depth);
+        self
+    }
+
+    /// Limits how deeply the lists and tables of a [`ConfigValue`][crate::ConfigValue] can be
+    /// nested when it's converted to or from Lua.  A table that contains no other tables has
+    /// depth 1.
+    pub fn with_max_table_depth(mut self, max_table_depth: usize) -> SizeLimits {
+        self.max_table_depth = Some(max_table_depth);
+        self
+    }
+}
+
+/// Which of the [`SizeLimits`] was exceeded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    SourceBytes,
+    TreeDepth,
+    TableDepth,
+}
+
+/// The error that you get when a tree exceeds one of the [`SizeLimits`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    pub max: usize,
+    /// The actual size.  For tree and table depths, this is the depth at which the check stopped,
+    /// which is one more than the limit.
+    pub actual: usize,
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.limit {
+            Limit::SourceBytes => write!(
+                f,
+                "source is {} bytes, which exceeds the limit of {} bytes",
+                self.actual, self.max
+            ),
+            Limit::TreeDepth => write!(
+                f,
+                "tree is more than {} levels deep, which exceeds the depth limit",
+                self.max
+            ),
+            Limit::TableDepth => write!(
+                f,
+                "table is more than {} levels deep, which exceeds the table depth limit",
+                self.max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// An extension trait that lets you limit the size of the trees that cross the bridge.
+pub trait SizeGuards {
+    /// Sets the limits that apply to this Lua environment.
+    fn set_size_limits(&self, limits: SizeLimits);
+
+    /// Returns the limits that apply to this Lua environment.
+    fn size_limits(&self) -> SizeLimits;
+}
+
+impl SizeGuards for Lua {
+    fn set_size_limits(&self, limits: SizeLimits) {
+        self.set_app_data(limits);
+    }
+
+    fn size_limits(&self) -> SizeLimits {
+        self.app_data_ref::<SizeLimits>()
+            .map(|limits| *limits)
+            .unwrap_or_default()
+    }
+}
+
+/// Returns an error if a tree's source is too large to push into Lua.
+pub(crate) fn check_source(lua: &Lua, len: usize) -> Result<(), mlua::Error> {
+    match lua.size_limits().max_source_bytes {
+        Some(max) if len > max => Err(mlua::Error::external(LimitExceeded {
+            limit: Limit::SourceBytes,
+            max,
+            actual: len,
+        })),
+        _ => Ok(()),
+    }
+}
+
+/// Returns an error if a tree is too deep to convert into Rust.
+pub(crate) fn check_depth(lua: &Lua, root: Node) -> Result<(), mlua::Error> {
+    let max = match lua.size_limits().max_tree_depth {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    let mut cursor = root.walk();
+    let mut depth = 1;
+    loop {
+        if depth > max {
+            return Err(mlua::Error::external(LimitExceeded {
+                limit: Limit::TreeDepth,
+                max,
+                actual: depth,
+            }));
+        }
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return Ok(());
+            }
+            depth -= 1;
+        }
+    }
+}
+
+/// Returns an error if a table that is `depth` levels deep is nested too deeply to convert.
+pub(crate) fn check_table_depth(lua: &Lua, depth: usize) -> Result<(), mlua::Error> {
+    match lua.size_limits().max_table_depth {
+        Some(max) if depth > max => Err(mlua::Error::external(LimitExceeded {
+            limit: Limit::TableDepth,
+            max,
+            actual: depth,
+        })),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::ConfigValue;
+    use crate::Module;
+    use crate::TreeWithSource;
+    use crate::WithSource;
+
+    #[test]
+    fn can_enforce_size_limits() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+
+        l.set_size_limits(SizeLimits::new().with_max_source_bytes(10));
+        let parsed = parser.parse(code, None).unwrap();
+        let err = l
+            .globals()
+            .set("parsed", parsed.with_source(code))
+            .unwrap_err();
+        let exceeded = err.downcast_ref::<LimitExceeded>().unwrap();
+        assert_eq!(Limit::SourceBytes, exceeded.limit);
+        assert_eq!(code.len(), exceeded.actual);
+
+        l.set_size_limits(SizeLimits::new().with_max_tree_depth(3));
+        let parsed = parser.parse(code, None).unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let err = l.globals().get::<_, TreeWithSource>("parsed").unwrap_err();
+        let exceeded = err.downcast_ref::<LimitExceeded>().unwrap();
+        assert_eq!((Limit::TreeDepth, 4), (exceeded.limit, exceeded.actual));
+
+        l.set_size_limits(SizeLimits::new());
+        let _: TreeWithSource = l.globals().get("parsed").unwrap();
+    }
+
+    #[test]
+    fn can_convert_deeply_nested_tables() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let nested: ConfigValue = l.call(
+            r#"
+              local root = {}
+              local current = root
+              for i = 1, 10000 do
+                current.child = {}
+                current = current.child
+              end
+              return root
+            "#,
+        );
+        l.globals().set("nested", nested).unwrap();
+        l.check(r#" assert(nested.child.child.child ~= nil) "#);
+
+        let cyclic = l
+            .load(r#" local t = {}; t.self = t; return t "#)
+            .eval::<ConfigValue>();
+        assert!(cyclic.unwrap_err().to_string().contains("cycle"));
+
+        l.set_size_limits(SizeLimits::new().with_max_table_depth(100));
+        let err = l.globals().get::<_, ConfigValue>("nested").unwrap_err();
+        let exceeded = err.downcast_ref::<LimitExceeded>().unwrap();
+        assert_eq!((Limit::TableDepth, 101), (exceeded.limit, exceeded.actual));
+    }
+}
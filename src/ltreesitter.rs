@@ -0,0 +1,520 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Low-level helpers for poking at the objects that ltreesitter creates.
+//!
+//! ltreesitter doesn't export much of a C API, so this module contains mirrors of the C structs
+//! that it wraps in its userdata, along with some trickery for calling into the few accessor
+//! functions that it does export.
+
+use std::alloc::Layout;
+use std::ffi::c_void;
+
+use mlua::Function;
+use mlua::Lua;
+use mlua::MaybeSend;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+
+use crate::abi;
+use crate::metrics;
+use crate::options;
+use crate::Error;
+
+/// The names of the metatables that ltreesitter registers for each of its object types.
+pub(crate) const NODE_METATABLE: &str = "ltreesitter.Node";
+pub(crate) const TREE_METATABLE: &str = "ltreesitter.Tree";
+pub(crate) const TREE_CURSOR_METATABLE: &str = "ltreesitter.TreeCursor";
+pub(crate) const QUERY_METATABLE: &str = "ltreesitter.Query";
+pub(crate) const PARSER_METATABLE: &str = "ltreesitter.Parser";
+
+// csrc/layout.c checks that these mirrors match the structs in ltreesitter's headers.
+
+/// The source code of a tree, laid out the way that ltreesitter's C code reads it: its length,
+/// followed by that many bytes and a NUL terminator.  A [`SourceStore`][crate::SourceStore] that
+/// holds its contents this way can be read by ltreesitter in place.  You can't create one yourself,
+/// but you can borrow one from a store that has one.
+#[repr(C)]
+pub struct SourceText {
+    pub(crate) length: usize,
+    pub(crate) text: u8, // this is a VLA down in C
+}
+
+impl SourceText {
+    /// Returns the source code.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(&self.text, self.length) }
+    }
+}
+
+/// An empty source, for trees whose source has been freed.
+pub(crate) static EMPTY_SOURCE: SourceText = SourceText { length: 0, text: 0 };
+
+/// A copy of a tree's source code, laid out like ltreesitter's `SourceText`.  We own these rather
+/// than ltreesitter, so that we can free them as soon as their tree is closed, instead of waiting
+/// for the garbage collector.
+pub(crate) struct SourceBuffer {
+    text: *mut SourceText,
+    layout: Layout,
+}
+
+// The buffer is only ever read once it has been filled in.
+unsafe impl Send for SourceBuffer {}
+unsafe impl Sync for SourceBuffer {}
+
+impl SourceBuffer {
+    /// Creates a buffer that holds a copy of `src`.
+    pub(crate) fn new(src: &[u8]) -> SourceBuffer {
+        let buffer = SourceBuffer::zeroed(src.len());
+        unsafe {
+            let text = std::ptr::addr_of_mut!((*buffer.text).text);
+            std::ptr::copy_nonoverlapping(src.as_ptr(), text, src.len());
+        }
+        buffer
+    }
+
+    /// Creates a buffer that holds `len` zero bytes.
+    fn zeroed(len: usize) -> SourceBuffer {
+        // Leave room for a NUL terminator, like ltreesitter does.
+        let (layout, _) = Layout::new::<usize>()
+            .extend(Layout::array::<u8>(len + 1).expect("source is too large"))
+            .expect("source is too large");
+        let layout = layout.pad_to_align();
+        let text = unsafe { std::alloc::alloc_zeroed(layout) } as *mut SourceText;
+        if text.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        unsafe { (*text).length = len };
+        SourceBuffer { text, layout }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const SourceText {
+        self.text
+    }
+
+    pub(crate) fn text(&self) -> &SourceText {
+        unsafe { &*self.text }
+    }
+}
+
+impl Drop for SourceBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.text as *mut u8, self.layout) };
+    }
+}
+
+impl mlua::UserData for SourceBuffer {}
+
+#[repr(C)]
+pub(crate) struct Tree {
+    pub tree: *mut tree_sitter::ffi::TSTree,
+    pub source: *const SourceText,
+}
+
+#[repr(C)]
+pub(crate) struct Node {
+    pub node: tree_sitter::ffi::TSNode,
+}
+
+#[repr(C)]
+pub(crate) struct TreeCursor {
+    pub cursor: tree_sitter::ffi::TSTreeCursor,
+}
+
+#[repr(C)]
+pub(crate) struct Parser {
+    pub parser: *mut tree_sitter::ffi::TSParser,
+}
+
+/// Returns the source code of an ltreesitter tree.  The result is only valid for as long as the
+/// tree is.
+pub(crate) unsafe fn source<'a>(tree: *const Tree) -> &'a [u8] {
+    (*(*tree).source).bytes()
+}
+
+/// Returns the root node of an ltreesitter tree.  The result is only valid for as long as the tree
+/// is.
+pub(crate) unsafe fn root_node<'a>(tree: *const Tree) -> tree_sitter::Node<'a> {
+    tree_sitter::Node::from_raw(tree_sitter::ffi::ts_tree_root_node((*tree).tree))
+}
+
+/// Returns a pointer to the ltreesitter tree wrapped by a Lua value.  Returns an
+/// [`Error::WrongUserdataType`] if the value is not an ltreesitter tree.
+pub(crate) fn tree_ptr<'lua>(lua: &'lua Lua, value: Value<'lua>) -> Result<*mut Tree, mlua::Error> {
+    Ok(check_udata(lua, value, TREE_METATABLE)? as *mut Tree)
+}
+
+/// Returns a pointer to the ltreesitter node wrapped by a Lua value.  Returns an
+/// [`Error::WrongUserdataType`] if the value is not an ltreesitter node.
+pub(crate) fn node_ptr<'lua>(lua: &'lua Lua, value: Value<'lua>) -> Result<*mut Node, mlua::Error> {
+    Ok(check_udata(lua, value, NODE_METATABLE)? as *mut Node)
+}
+
+/// Returns a pointer to the ltreesitter tree cursor wrapped by a Lua value.  Returns an
+/// [`Error::WrongUserdataType`] if the value is not an ltreesitter tree cursor.
+pub(crate) fn tree_cursor_ptr<'lua>(
+    lua: &'lua Lua,
+    value: Value<'lua>,
+) -> Result<*mut TreeCursor, mlua::Error> {
+    Ok(check_udata(lua, value, TREE_CURSOR_METATABLE)? as *mut TreeCursor)
+}
+
+/// Returns a pointer to the ltreesitter tree wrapped by a Lua value, or `None` if the value is not
+/// an ltreesitter tree.
+pub(crate) fn as_tree<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<*mut Tree>, mlua::Error> {
+    Ok(test_udata(lua, value, TREE_METATABLE)?.map(|udata| udata as *mut Tree))
+}
+
+/// Returns a pointer to the ltreesitter node wrapped by a Lua value, or `None` if the value is not
+/// an ltreesitter node.
+pub(crate) fn as_node<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<*mut Node>, mlua::Error> {
+    Ok(test_udata(lua, value, NODE_METATABLE)?.map(|udata| udata as *mut Node))
+}
+
+/// Returns a pointer to the ltreesitter tree cursor wrapped by a Lua value, or `None` if the value
+/// is not an ltreesitter tree cursor.
+pub(crate) fn as_tree_cursor<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<*mut TreeCursor>, mlua::Error> {
+    Ok(test_udata(lua, value, TREE_CURSOR_METATABLE)?.map(|udata| udata as *mut TreeCursor))
+}
+
+/// Returns a pointer to the ltreesitter parser wrapped by a Lua value, or `None` if the value is
+/// not an ltreesitter parser.
+pub(crate) fn as_parser<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<*mut Parser>, mlua::Error> {
+    Ok(test_udata(lua, value, PARSER_METATABLE)?.map(|udata| udata as *mut Parser))
+}
+
+/// Returns whether a Lua value is a userdata with the metatable that ltreesitter registered under
+/// the given name.
+pub(crate) fn has_metatable<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+    metatable: &str,
+) -> Result<bool, mlua::Error> {
+    Ok(test_udata(lua, value, metatable)?.is_some())
+}
+
+/// The kinds of ltreesitter object that belong to a tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Object {
+    Tree,
+    Node,
+    TreeCursor,
+}
+
+/// Returns which kind of ltreesitter object a Lua value is, or `None` if it isn't a tree, node, or
+/// tree cursor.  This only makes one call into Lua, instead of one for each kind of object.
+pub(crate) fn object_kind<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<Object>, mlua::Error> {
+    unsafe extern "C-unwind" fn object_kind(l: *mut mlua::lua_State) -> i32 {
+        if mlua::ffi::lua_touserdata(l, 1).is_null() || mlua::ffi::lua_getmetatable(l, 1) == 0 {
+            mlua::ffi::lua_pushnil(l);
+            return 1;
+        }
+        // The names of the metatables to compare against are arguments 2 through 4, so the
+        // userdata's metatable is at index 5.
+        for index in 2..=4 {
+            mlua::ffi::luaL_getmetatable(l, mlua::ffi::lua_tostring(l, index));
+            let matches = mlua::ffi::lua_rawequal(l, -1, 5) != 0;
+            mlua::ffi::lua_pop(l, 1);
+            if matches {
+                mlua::ffi::lua_pushinteger(l, index as mlua::ffi::lua_Integer);
+                return 1;
+            }
+        }
+        mlua::ffi::lua_pushnil(l);
+        1
+    }
+
+    if !matches!(value, Value::UserData(_)) {
+        return Ok(None);
+    }
+    let object_kind = cached_c_function(lua, OBJECT_KIND_KEY, object_kind)?;
+    let index: Option<i64> = object_kind.call((
+        value.clone(),
+        TREE_METATABLE,
+        NODE_METATABLE,
+        TREE_CURSOR_METATABLE,
+    ))?;
+    Ok(match index {
+        Some(2) => Some(Object::Tree),
+        Some(3) => Some(Object::Node),
+        Some(4) => Some(Object::TreeCursor),
+        _ => None,
+    })
+}
+
+/// Replaces the metatable of a userdata.
+pub(crate) fn set_metatable<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+    metatable: Table<'lua>,
+) -> Result<(), mlua::Error> {
+    unsafe extern "C-unwind" fn set_metatable(l: *mut mlua::lua_State) -> i32 {
+        mlua::ffi::lua_settop(l, 2);
+        lua_setmetatable(l, 1);
+        0
+    }
+
+    let set_metatable = cached_c_function(lua, SET_METATABLE_KEY, set_metatable)?;
+    set_metatable.call((value.clone(), metatable))
+}
+
+/// Sets the metatable of the value at `index` to the table on top of the stack.  On Luau, this goes
+/// through our shim in `csrc/luau`, which also gives ltreesitter's objects a destructor, since Luau
+/// doesn't run `__gc` metamethods.
+#[cfg(feature = "luau")]
+unsafe fn lua_setmetatable(l: *mut mlua::lua_State, index: i32) -> i32 {
+    extern "C" {
+        fn ltreesitter_luau_setmetatable(l: *mut mlua::lua_State, index: i32) -> i32;
+    }
+    ltreesitter_luau_setmetatable(l, index)
+}
+
+#[cfg(not(feature = "luau"))]
+unsafe fn lua_setmetatable(l: *mut mlua::lua_State, index: i32) -> i32 {
+    mlua::ffi::lua_setmetatable(l, index)
+}
+
+const TEST_UDATA_KEY: &str = "mlua_tree_sitter.test_udata";
+const OBJECT_KIND_KEY: &str = "mlua_tree_sitter.object_kind";
+const SET_METATABLE_KEY: &str = "mlua_tree_sitter.set_metatable";
+const NEW_PARSER_KEY: &str = "mlua_tree_sitter.new_parser";
+
+/// Returns a Lua function that calls a C function.  The Lua function is created the first time
+/// that it's needed, and is then cached in the registry under `key`.
+pub(crate) fn cached_c_function<'lua>(
+    lua: &'lua Lua,
+    key: &str,
+    function: mlua::ffi::lua_CFunction,
+) -> Result<Function<'lua>, mlua::Error> {
+    if let Some(function) = lua.named_registry_value::<Option<Function>>(key)? {
+        return Ok(function);
+    }
+    let function = unsafe { lua.create_c_function(function) }?;
+    metrics::record_c_function(lua);
+    lua.set_named_registry_value(key, function.clone())?;
+    Ok(function)
+}
+
+/// Returns a pointer to the contents of a userdata if it has the metatable that ltreesitter
+/// registered under the given name, or `None` if it doesn't.
+fn test_udata<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+    metatable: &str,
+) -> Result<Option<*mut c_void>, mlua::Error> {
+    // This is luaL_testudata, which Luau doesn't provide.
+    unsafe extern "C-unwind" fn test_udata(l: *mut mlua::lua_State) -> i32 {
+        let metatable = mlua::ffi::lua_tostring(l, 2);
+        let udata = mlua::ffi::lua_touserdata(l, 1);
+        if udata.is_null() || mlua::ffi::lua_getmetatable(l, 1) == 0 {
+            mlua::ffi::lua_pushnil(l);
+            return 1;
+        }
+        mlua::ffi::luaL_getmetatable(l, metatable);
+        let matches = mlua::ffi::lua_rawequal(l, -1, -2) != 0;
+        mlua::ffi::lua_pop(l, 2);
+        if matches {
+            mlua::ffi::lua_pushlightuserdata(l, udata);
+        } else {
+            mlua::ffi::lua_pushnil(l);
+        }
+        1
+    }
+
+    if !matches!(value, Value::UserData(_)) {
+        return Ok(None);
+    }
+    // This runs on every guarded method call, so we only create the C function once.
+    let test_udata = cached_c_function(lua, TEST_UDATA_KEY, test_udata)?;
+    let udata: Option<mlua::LightUserData> = test_udata.call((value.clone(), metatable))?;
+    Ok(udata.map(|mlua::LightUserData(udata)| udata))
+}
+
+/// Returns a pointer to the contents of a userdata, verifying that it has the metatable that
+/// ltreesitter registered under the given name.  Returns an [`Error::WrongUserdataType`] if it
+/// doesn't.
+fn check_udata<'lua>(
+    lua: &'lua Lua,
+    value: Value<'lua>,
+    metatable: &str,
+) -> Result<*mut c_void, mlua::Error> {
+    test_udata(lua, &value, metatable)?.ok_or_else(|| {
+        Error::WrongUserdataType {
+            expected: metatable.to_string(),
+            actual: value.type_name(),
+        }
+        .into()
+    })
+}
+
+/// Creates a new ltreesitter parser for a language that is linked into the current binary, rather
+/// than loaded from a dynamic library.
+pub(crate) fn new_parser<'lua>(
+    lua: &'lua Lua,
+    language: tree_sitter::Language,
+) -> Result<Value<'lua>, mlua::Error> {
+    extern "C" {
+        // Defined in csrc/layout.c, which also checks that our Parser matches ltreesitter's.
+        fn mlua_tree_sitter_parser_size() -> usize;
+    }
+
+    unsafe extern "C-unwind" fn new_parser(l: *mut mlua::lua_State) -> i32 {
+        let language = mlua::ffi::lua_touserdata(l, 1) as *const tree_sitter::ffi::TSLanguage;
+        let metatable = mlua::ffi::lua_tostring(l, 2);
+        let size = mlua_tree_sitter_parser_size();
+        let parser = mlua::ffi::lua_newuserdata(l, size) as *mut Parser;
+        std::ptr::write_bytes(parser as *mut u8, 0, size);
+        (*parser).parser = tree_sitter::ffi::ts_parser_new();
+        tree_sitter::ffi::ts_parser_set_language((*parser).parser, language);
+        mlua::ffi::luaL_getmetatable(l, metatable);
+        lua_setmetatable(l, -2);
+        1
+    }
+
+    abi::check_language(lua, language)?;
+    // Make sure that the metatable exists before we create any parsers that use it.
+    metatable(lua, PARSER_METATABLE)?;
+    // Language is a transparent wrapper around a TSLanguage pointer, since grammar crates return
+    // it directly from their C entry points.
+    let language = unsafe { std::mem::transmute::<tree_sitter::Language, *mut c_void>(language) };
+    let new_parser = cached_c_function(lua, NEW_PARSER_KEY, new_parser)?;
+    new_parser.call((mlua::LightUserData(language), PARSER_METATABLE))
+}
+
+/// The registry key of the `ltreesitter` module table.  The module can be opened under any name
+/// (or none at all), so we can't look it up in `package.loaded`.
+pub(crate) const MODULE_KEY: &str = "mlua_tree_sitter.ltreesitter";
+
+/// Returns the `ltreesitter` module table.
+pub(crate) fn module(lua: &Lua) -> Result<Table, mlua::Error> {
+    let mut module: Option<Table> = lua.named_registry_value(MODULE_KEY)?;
+    if module.is_none() && options::load_preloaded(lua)? {
+        module = lua.named_registry_value(MODULE_KEY)?;
+    }
+    module.ok_or_else(|| Error::ModuleNotLoaded.into())
+}
+
+/// Replaces one of the functions in the `ltreesitter` module.  The wrapper receives the original
+/// function along with the arguments that it was called with.  Does nothing if there is no
+/// function with the given name.
+pub(crate) fn wrap_module_function<F>(lua: &Lua, name: &str, wrapper: F) -> Result<(), mlua::Error>
+where
+    F: for<'lua> Fn(
+            &'lua Lua,
+            Function<'lua>,
+            MultiValue<'lua>,
+        ) -> Result<MultiValue<'lua>, mlua::Error>
+        + MaybeSend
+        + 'static,
+{
+    wrap_function(lua, &module(lua)?, name, wrapper)
+}
+
+/// Returns the method table of one of ltreesitter's object types.  You can add new entries to this
+/// table to make new methods available to all objects of that type.
+pub(crate) fn methods<'lua>(lua: &'lua Lua, name: &str) -> Result<Table<'lua>, mlua::Error> {
+    metatable(lua, name)?.raw_get("__index")
+}
+
+/// Returns the metatable of one of ltreesitter's object types.
+fn metatable<'lua>(lua: &'lua Lua, name: &str) -> Result<Table<'lua>, mlua::Error> {
+    let mut metatable: Option<Table> = lua.named_registry_value(name)?;
+    if metatable.is_none() && options::load_preloaded(lua)? {
+        metatable = lua.named_registry_value(name)?;
+    }
+    metatable.ok_or_else(|| Error::ModuleNotLoaded.into())
+}
+
+/// Replaces one of the methods of an ltreesitter object type.  The wrapper receives the original
+/// method along with the arguments that it was called with.  Does nothing if there is no method
+/// with the given name.
+pub(crate) fn wrap_method<F>(
+    lua: &Lua,
+    metatable: &str,
+    name: &str,
+    wrapper: F,
+) -> Result<(), mlua::Error>
+where
+    F: for<'lua> Fn(
+            &'lua Lua,
+            Function<'lua>,
+            MultiValue<'lua>,
+        ) -> Result<MultiValue<'lua>, mlua::Error>
+        + MaybeSend
+        + 'static,
+{
+    wrap_function(lua, &methods(lua, metatable)?, name, wrapper)
+}
+
+/// Wraps every method and metamethod of an ltreesitter object type with the same wrapper, which
+/// receives the original method along with the arguments that it was called with.
+pub(crate) fn guard_methods(
+    lua: &Lua,
+    metatable_name: &str,
+    wrapper: for<'lua> fn(
+        &'lua Lua,
+        Function<'lua>,
+        MultiValue<'lua>,
+    ) -> Result<MultiValue<'lua>, mlua::Error>,
+) -> Result<(), mlua::Error> {
+    let metatable = metatable(lua, metatable_name)?;
+    let methods: Table = metatable.raw_get("__index")?;
+    for table in [methods, metatable] {
+        let mut names = Vec::new();
+        for pair in table.clone().pairs::<Value, Value>() {
+            if let (Value::String(name), Value::Function(_)) = pair? {
+                names.push(name.to_str()?.to_string());
+            }
+        }
+        for name in names {
+            // The garbage collector must always be able to free an object, and __index is the
+            // methods table itself.
+            if name == "__gc" || name == "__index" {
+                continue;
+            }
+            wrap_function(lua, &table, &name, wrapper)?;
+        }
+    }
+    Ok(())
+}
+
+fn wrap_function<F>(lua: &Lua, table: &Table, name: &str, wrapper: F) -> Result<(), mlua::Error>
+where
+    F: for<'lua> Fn(
+            &'lua Lua,
+            Function<'lua>,
+            MultiValue<'lua>,
+        ) -> Result<MultiValue<'lua>, mlua::Error>
+        + MaybeSend
+        + 'static,
+{
+    let original: Option<Function> = table.raw_get(name)?;
+    let original = match original {
+        Some(original) => lua.create_registry_value(original)?,
+        None => return Ok(()),
+    };
+    let wrapped = lua.create_function(move |lua, args: MultiValue| {
+        let original: Function = lua.registry_value(&original)?;
+        wrapper(lua, original, args)
+    })?;
+    table.raw_set(name, wrapped)
+}
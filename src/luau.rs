@@ -0,0 +1,101 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Support for running the `ltreesitter` module on [Luau][luau].
+//!
+//! [luau]: https://luau-lang.org/
+//!
+//! With the `luau` feature, ltreesitter's C sources are compiled against a set of shims (in
+//! `csrc/luau`) that fill in the parts of the Lua 5.1 C API that Luau renamed or dropped.  Luau
+//! also doesn't have Lua's `package` library, which is how this crate makes the module available
+//! to Lua code.  If the environment doesn't have one (and a `require` function that uses it), we
+//! install a minimal version that only knows about `package.loaded` and `package.preload`, so that
+//! every [`Placement`][crate::Placement] works the same way that it does on other Lua versions.
+//!
+//! Luau doesn't call `__gc` metamethods on userdata, so the shims also give each ltreesitter
+//! parser, tree, cursor, and query a destructor when it gets its metatable, which frees its
+//! tree-sitter data when it's garbage-collected.  The destructors use Luau's userdata tags, from
+//! 120 up; define `LTREESITTER_LUAU_FIRST_TAG` when compiling the shims (for instance, via
+//! `CFLAGS`) if your own userdata already uses those tags.
+
+use mlua::Lua;
+
+const PACKAGE: &str = r#"
+    if package == nil then
+      package = {}
+    end
+    local package = package
+    if package.loaded == nil then
+      package.loaded = {}
+    end
+    if package.preload ~= nil then
+      return
+    end
+    local preload = {}
+    package.preload = preload
+    local loaders = package.loaders or package.searchers
+    if loaders ~= nil then
+      table.insert(loaders, 1, function(name)
+        local loader = preload[name]
+        if loader == nil then
+          return "\n\tno field package.preload['" .. name .. "']"
+        end
+        return loader
+      end)
+    end
+    if require == nil then
+      function require(name)
+        local module = package.loaded[name]
+        if module ~= nil then
+          return module
+        end
+        local loader = preload[name]
+        if loader == nil then
+          error("module '" .. name .. "' not found", 2)
+        end
+        module = loader(name)
+        if module == nil then
+          module = true
+        end
+        package.loaded[name] = module
+        return module
+      end
+    end
+"#;
+
+/// Makes sure that the Lua environment has the parts of the `package` library that we use to
+/// register the `ltreesitter` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    lua.load(PACKAGE).set_name("luau package").exec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::Options;
+    use crate::Placement;
+
+    #[test]
+    fn can_use_module_from_luau() {
+        let l = Lua::new();
+        l.open_ltreesitter_with(Options::new().with_placement(Placement::Preload))
+            .unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.check(
+            r#"
+              assert(package.loaded.ltreesitter == nil)
+              local ltreesitter = require("ltreesitter")
+              assert(package.loaded.ltreesitter == ltreesitter)
+              local tree = ltreesitter.require("python"):parse_string("x = 1")
+              assert(tree:root():type() == "module")
+              tree:close()
+            "#,
+        );
+    }
+}
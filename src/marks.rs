@@ -0,0 +1,171 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Lets scripts time their own phases, so that hosts can tell where analysis time goes.
+//!
+//! In Lua, `require("ltreesitter_rs").mark(name)` starts timing a phase, and `measure(name)` stops
+//! timing the most recent unmeasured mark with that name, returning the elapsed time in seconds.
+//! Marks with the same name can nest.  The timings are aggregated in Rust, and the host can
+//! retrieve them as a [`Profile`] via [`PerformanceMarks::performance_profile`].
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use mlua::Lua;
+
+/// The aggregated timings of one mark name.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MarkStats {
+    /// The number of times the mark was measured.
+    pub count: u64,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl MarkStats {
+    /// Returns the mean time per measurement.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        self.total / self.count as u32
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        if self.count == 0 || elapsed < self.min {
+            self.min = elapsed;
+        }
+        self.max = self.max.max(elapsed);
+        self.total += elapsed;
+        self.count += 1;
+    }
+}
+
+/// The timings that scripts have measured, keyed by mark name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Profile {
+    pub marks: BTreeMap<String, MarkStats>,
+}
+
+impl Profile {
+    /// Returns the timings of one mark name.
+    pub fn get(&self, name: &str) -> Option<&MarkStats> {
+        self.marks.get(name)
+    }
+}
+
+#[derive(Default)]
+struct MarkState {
+    open: BTreeMap<String, Vec<Instant>>,
+    profile: Profile,
+}
+
+/// An extension trait that gives you access to the timings that scripts have measured.
+pub trait PerformanceMarks {
+    /// Returns the timings that have been measured so far.
+    fn performance_profile(&self) -> Profile;
+
+    /// Discards all timings, including any marks that haven't been measured yet, and returns the
+    /// timings that had been measured.
+    fn reset_performance_profile(&self) -> Profile;
+}
+
+impl PerformanceMarks for Lua {
+    fn performance_profile(&self) -> Profile {
+        self.app_data_ref::<MarkState>()
+            .map(|state| state.profile.clone())
+            .unwrap_or_default()
+    }
+
+    fn reset_performance_profile(&self) -> Profile {
+        self.remove_app_data::<MarkState>()
+            .map(|state| state.profile)
+            .unwrap_or_default()
+    }
+}
+
+fn mark(lua: &Lua, name: String) {
+    let now = Instant::now();
+    match lua.app_data_mut::<MarkState>() {
+        Some(mut state) => state.open.entry(name).or_default().push(now),
+        None => {
+            let mut state = MarkState::default();
+            state.open.insert(name, vec![now]);
+            lua.set_app_data(state);
+        }
+    }
+}
+
+fn measure(lua: &Lua, name: String) -> Result<Duration, mlua::Error> {
+    let now = Instant::now();
+    let no_mark = || mlua::Error::RuntimeError(format!("no mark named {} to measure", name));
+    let mut state = lua.app_data_mut::<MarkState>().ok_or_else(no_mark)?;
+    let start = state
+        .open
+        .get_mut(&name)
+        .and_then(Vec::pop)
+        .ok_or_else(no_mark)?;
+    let elapsed = now - start;
+    state.profile.marks.entry(name).or_default().record(elapsed);
+    Ok(elapsed)
+}
+
+/// Adds `mark` and `measure` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = crate::companion_module(lua)?;
+    module.set(
+        "mark",
+        lua.create_function(|lua, name: String| {
+            mark(lua, name);
+            Ok(())
+        })?,
+    )?;
+    module.set(
+        "measure",
+        lua.create_function(|lua, name: String| Ok(measure(lua, name)?.as_secs_f64()))?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_aggregate_performance_marks() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              for i = 1, 3 do
+                ltreesitter_rs.mark("outer")
+                ltreesitter_rs.mark("inner")
+                assert(ltreesitter_rs.measure("inner") >= 0)
+                ltreesitter_rs.mark("inner")
+                ltreesitter_rs.measure("inner")
+                ltreesitter_rs.measure("outer")
+              end
+              assert(not pcall(ltreesitter_rs.measure, "outer"))
+              assert(not pcall(ltreesitter_rs.measure, "never"))
+            "#,
+        );
+        let profile = l.performance_profile();
+        assert_eq!(
+            vec!["inner", "outer"],
+            profile.marks.keys().collect::<Vec<_>>()
+        );
+        let outer = profile.get("outer").unwrap();
+        assert_eq!(3, outer.count);
+        assert!(outer.min <= outer.mean() && outer.mean() <= outer.max);
+        assert_eq!(6, profile.get("inner").unwrap().count);
+        assert_eq!(profile, l.reset_performance_profile());
+        assert!(l.performance_profile().marks.is_empty());
+    }
+}
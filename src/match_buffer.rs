@@ -0,0 +1,310 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Packs the results of a query into a single buffer, instead of creating a Lua table for every
+//! match and capture.
+//!
+//! A whole-repo scan can produce millions of matches.  A [`MatchBuffer`] stores all of them in two
+//! flat vectors, and is pushed into Lua as a single userdata.  Lua code only pays for the matches
+//! that it actually looks at: `#buffer` is the number of matches, and `buffer[i]` materializes
+//! the `i`th match as a table with `pattern` and `captures` fields, where `captures` maps each
+//! capture name to a table with `kind`, `start_byte`, `end_byte`, `start_point`, and `end_point`
+//! fields.  For the tightest loops, `buffer:pattern(i)`, `buffer:capture_count(i)`, and
+//! `buffer:capture(i, j)` read the buffer without creating any tables at all.  To visit every
+//! match while staying within a memory limit, `buffer:deliver(consumer)` passes the materialized
+//! matches to `consumer` in chunks, and returns the number of matches that were delivered.
+//!
+//! Lua code can create a buffer via `require("ltreesitter_rs").packed_matches(tree, query, ctx)`,
+//! where `query` is the source of a tree-sitter query.  If `ctx` (an analysis context or a
+//! cancellation token) is given, the matches stop once it's cancelled, and a second result says
+//! whether they ran to completion.  Indexes are 1-based in Lua and 0-based in Rust.
+
+use std::ops::Range;
+
+use mlua::AnyUserData;
+use mlua::Function;
+use mlua::Lua;
+use mlua::MetaMethod;
+use mlua::Table;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use mlua::Value;
+use tree_sitter::Node;
+use tree_sitter::Point;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+
+use crate::cancel;
+use crate::offsets::Offset;
+use crate::CancellationToken;
+use crate::ChunkedDelivery;
+use crate::LuaInterning;
+use crate::TreeWithSource;
+
+/// One capture of a packed match.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PackedCapture {
+    /// The index of the capture's name in the query.
+    pub index: u32,
+    /// The kind of the captured node.
+    pub kind: &'static str,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub start_point: Point,
+    pub end_point: Point,
+}
+
+impl PackedCapture {
+    /// Returns the byte range of the captured node.
+    pub fn byte_range(&self) -> Range<usize> {
+        self.start_byte..self.end_byte
+    }
+}
+
+/// The matches of a query, packed into a compact buffer.
+#[derive(Clone, Debug, Default)]
+pub struct MatchBuffer {
+    capture_names: Vec<String>,
+    /// The pattern index of each match, and the offset of its first capture in `captures`.
+    matches: Vec<(u32, u32)>,
+    captures: Vec<PackedCapture>,
+}
+
+impl MatchBuffer {
+    /// Runs a query over a node, and packs all of its matches into a new buffer.
+    pub fn new(query: &Query, node: Node, src: &[u8]) -> MatchBuffer {
+        MatchBuffer::new_while(query, node, src, || true).0
+    }
+
+    /// Runs a query over a node, packing its matches into a new buffer for as long as
+    /// `keep_going` returns true.  (It's checked before the first match, and then periodically.)
+    /// Also returns whether every match was packed.
+    /// Collects the matches of `query` over `node`, stopping early if `cancellation` is
+    /// cancelled.  Returns the buffer and whether it holds all of the matches.
+    pub fn new_cancellable(
+        query: &Query,
+        node: Node,
+        src: &[u8],
+        cancellation: &CancellationToken,
+    ) -> (MatchBuffer, bool) {
+        MatchBuffer::new_while(query, node, src, || !cancellation.is_cancelled())
+    }
+
+    pub(crate) fn new_while<F>(
+        query: &Query,
+        node: Node,
+        src: &[u8],
+        mut keep_going: F,
+    ) -> (MatchBuffer, bool)
+    where
+        F: FnMut() -> bool,
+    {
+        let mut buffer = MatchBuffer {
+            capture_names: query.capture_names().to_vec(),
+            ..MatchBuffer::default()
+        };
+        let mut cursor = QueryCursor::new();
+        for (count, query_match) in cursor.matches(query, node, src).enumerate() {
+            if count % 64 == 0 && !keep_going() {
+                return (buffer, false);
+            }
+            buffer.matches.push((
+                query_match.pattern_index as u32,
+                buffer.captures.len() as u32,
+            ));
+            buffer
+                .captures
+                .extend(query_match.captures.iter().map(|capture| PackedCapture {
+                    index: capture.index,
+                    kind: capture.node.kind(),
+                    start_byte: capture.node.start_byte(),
+                    end_byte: capture.node.end_byte(),
+                    start_point: capture.node.start_position(),
+                    end_point: capture.node.end_position(),
+                }));
+        }
+        (buffer, true)
+    }
+
+    /// Returns the number of matches in the buffer.
+    pub fn len(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// Returns whether the buffer has no matches.
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    /// Returns the index of the pattern that produced a match.
+    pub fn pattern_index(&self, match_index: usize) -> usize {
+        self.matches[match_index].0 as usize
+    }
+
+    /// Returns the captures of a match.
+    pub fn captures(&self, match_index: usize) -> &[PackedCapture] {
+        let start = self.matches[match_index].1 as usize;
+        let end = self
+            .matches
+            .get(match_index + 1)
+            .map(|(_, offset)| *offset as usize)
+            .unwrap_or(self.captures.len());
+        &self.captures[start..end]
+    }
+
+    /// Returns the name of a capture.
+    pub fn capture_name(&self, capture: &PackedCapture) -> &str {
+        &self.capture_names[capture.index as usize]
+    }
+
+    fn lua_index(&self, index: usize) -> Result<usize, mlua::Error> {
+        if index == 0 || index > self.len() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "match index {} is out of bounds of {} matches",
+                index,
+                self.len()
+            )));
+        }
+        Ok(index - 1)
+    }
+
+    fn capture_table<'lua>(
+        &self,
+        lua: &'lua Lua,
+        capture: &PackedCapture,
+    ) -> Result<Table<'lua>, mlua::Error> {
+        let point = |point: Point| -> Result<Table<'lua>, mlua::Error> {
+            let table = lua.create_table()?;
+            table.set("row", Offset(point.row))?;
+            table.set("column", Offset(point.column))?;
+            Ok(table)
+        };
+        let table = lua.create_table()?;
+        table.set("kind", lua.interned_string(capture.kind)?)?;
+        table.set("start_byte", Offset(capture.start_byte))?;
+        table.set("end_byte", Offset(capture.end_byte))?;
+        table.set("start_point", point(capture.start_point)?)?;
+        table.set("end_point", point(capture.end_point)?)?;
+        Ok(table)
+    }
+
+    fn match_table<'lua>(&self, lua: &'lua Lua, index: usize) -> Result<Table<'lua>, mlua::Error> {
+        let captures = lua.create_table()?;
+        for capture in self.captures(index) {
+            captures.set(
+                lua.interned_string(self.capture_name(capture))?,
+                self.capture_table(lua, capture)?,
+            )?;
+        }
+        let table = lua.create_table()?;
+        table.set("pattern", self.pattern_index(index) + 1)?;
+        table.set("captures", captures)?;
+        Ok(table)
+    }
+}
+
+impl UserData for MatchBuffer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Len, |_, buffer, ()| Ok(buffer.len()));
+        methods.add_meta_method(MetaMethod::Index, |lua, buffer, index: Value| match index {
+            Value::Integer(index) if index >= 1 && index as usize <= buffer.len() => {
+                Ok(Value::Table(buffer.match_table(lua, index as usize - 1)?))
+            }
+            _ => Ok(Value::Nil),
+        });
+        methods.add_method("deliver", |lua, buffer, consumer: Function| {
+            let matches = (0..buffer.len()).map(|index| buffer.match_table(lua, index));
+            Ok(lua.deliver_chunked(matches, &consumer)?.delivered)
+        });
+        methods.add_method("pattern", |_, buffer, index: usize| {
+            Ok(buffer.pattern_index(buffer.lua_index(index)?) + 1)
+        });
+        methods.add_method("capture_count", |_, buffer, index: usize| {
+            Ok(buffer.captures(buffer.lua_index(index)?).len())
+        });
+        methods.add_method(
+            "capture",
+            |lua, buffer, (index, capture_index): (usize, usize)| {
+                let captures = buffer.captures(buffer.lua_index(index)?);
+                let capture = match capture_index.checked_sub(1).and_then(|i| captures.get(i)) {
+                    Some(capture) => capture,
+                    None => return Ok((Value::Nil, Value::Nil, Value::Nil)),
+                };
+                Ok((
+                    Value::String(lua.interned_string(buffer.capture_name(capture))?),
+                    Value::Integer(capture.start_byte as i64),
+                    Value::Integer(capture.end_byte as i64),
+                ))
+            },
+        );
+    }
+}
+
+/// Adds `packed_matches` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let packed_matches = lua.create_function(
+        |_, (tree, query, cancellation): (TreeWithSource, String, Option<AnyUserData>)| {
+            let query = Query::new(tree.tree.language(), &query).map_err(mlua::Error::external)?;
+            let cancellation = match cancellation {
+                Some(udata) => cancel::token_from(&udata)?,
+                None => CancellationToken::new(),
+            };
+            Ok(MatchBuffer::new_cancellable(
+                &query,
+                tree.tree.root_node(),
+                tree.src,
+                &cancellation,
+            ))
+        },
+    )?;
+    crate::companion_module(lua)?.set("packed_matches", packed_matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_pack_matches() {
+        let code = b"def double(x): return x * 2\ndef triple(y): return y * 3\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let query = Query::new(
+            tree_sitter_python::language(),
+            "(function_definition name: (identifier) @name parameters: (parameters) @params)",
+        )
+        .unwrap();
+        let buffer = MatchBuffer::new(&query, parsed.root_node(), code);
+        assert_eq!(2, buffer.len());
+        let captures = buffer.captures(1);
+        assert_eq!("name", buffer.capture_name(&captures[0]));
+        assert_eq!(b"triple", &code[captures[0].byte_range()]);
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local packed_matches = require("ltreesitter_rs").packed_matches
+              local buffer = packed_matches(parsed, "(integer) @number")
+              assert(#buffer == 2)
+              assert(buffer[3] == nil)
+              assert(buffer[2].pattern == 1)
+              assert(buffer[2].captures.number.kind == "integer")
+              local name, start_byte, end_byte = buffer:capture(1, 1)
+              assert(name == "number" and end_byte - start_byte == 1)
+              assert(buffer:capture_count(2) == 1)
+              local seen = 0
+              assert(buffer:deliver(function(chunk) seen = seen + #chunk end) == 2)
+              assert(seen == 2)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,195 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Turns query matches into instances of Lua classes.
+//!
+//! Lua code can call `require("ltreesitter_rs").set_match_class(query, pattern, class)` to
+//! register a class for one of a query's patterns, and then `match_objects(query, node)` to get a
+//! list of the query's matches over a node (or a whole tree), with each match of that pattern
+//! converted into an instance of the class.  The query is run natively with
+//! [`run_query`][crate::run_query], so it must have been compiled via `parser:query`, and patterns
+//! are numbered the same way as the `pattern` field of its match tables.  If the class is a table,
+//! it becomes the metatable of the match's captures table, so it should usually set `__index` to
+//! itself.  If the class is a function, it's called with the captures table and the pattern index,
+//! and its result is used instead.  Matches of patterns without a class are returned as plain
+//! match tables.
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+
+use crate::ltreesitter;
+use crate::trees;
+use crate::TSNode;
+use crate::TreeWithSource;
+
+const CLASSES_KEY: &str = "mlua_tree_sitter.match_classes";
+
+/// An extension trait that lets you convert query matches into instances of Lua classes.
+pub trait MatchClasses {
+    /// Registers the class that matches of one of a query's patterns should be converted into.
+    fn set_match_class<'lua>(
+        &'lua self,
+        query: Value<'lua>,
+        pattern: i64,
+        class: Value<'lua>,
+    ) -> Result<(), mlua::Error>;
+
+    /// Returns the list of matches of `query` over `node`, converting each one into an instance of
+    /// the class registered for its pattern.
+    fn match_objects<'lua>(
+        &'lua self,
+        query: Value<'lua>,
+        node: Value<'lua>,
+    ) -> Result<Table<'lua>, mlua::Error>;
+}
+
+impl MatchClasses for Lua {
+    fn set_match_class<'lua>(
+        &'lua self,
+        query: Value<'lua>,
+        pattern: i64,
+        class: Value<'lua>,
+    ) -> Result<(), mlua::Error> {
+        match class {
+            Value::Table(_) | Value::Function(_) | Value::Nil => {}
+            _ => {
+                return Err(mlua::Error::RuntimeError(
+                    "match class must be a table or a function".to_string(),
+                ))
+            }
+        }
+        let classes = classes(self)?;
+        let per_query = match classes.raw_get::<_, Option<Table>>(query.clone())? {
+            Some(per_query) => per_query,
+            None => {
+                let per_query = self.create_table()?;
+                classes.raw_set(query, per_query.clone())?;
+                per_query
+            }
+        };
+        per_query.raw_set(pattern, class)
+    }
+
+    fn match_objects<'lua>(
+        &'lua self,
+        query: Value<'lua>,
+        node: Value<'lua>,
+    ) -> Result<Table<'lua>, mlua::Error> {
+        let per_query = classes(self)?.raw_get::<_, Option<Table>>(query.clone())?;
+        let tree = trees::check_open(self, &node)?.ok_or_else(|| {
+            mlua::Error::RuntimeError(
+                "match_objects expects an ltreesitter node or tree".to_string(),
+            )
+        })?;
+        let is_tree = ltreesitter::as_tree(self, &node)?.is_some();
+        let tree = TreeWithSource::from_lua(tree, self)?;
+        let node = if is_tree {
+            tree.tree.root_node()
+        } else {
+            TSNode::from_lua(node, self)?.0
+        };
+        let result = self.create_table()?;
+        for m in crate::query::run_query_at(self, query, &tree, node)? {
+            let pattern = m.pattern_index as i64;
+            let m = Table::from_lua(m.into_lua(self)?, self)?;
+            let class = match &per_query {
+                Some(per_query) => per_query.raw_get::<_, Value>(pattern)?,
+                None => Value::Nil,
+            };
+            let object = match class {
+                Value::Table(class) => {
+                    let captures: Table = m.get("captures")?;
+                    captures.set_metatable(Some(class));
+                    Value::Table(captures)
+                }
+                Value::Function(constructor) => {
+                    let captures: Table = m.get("captures")?;
+                    constructor.call((captures, pattern))?
+                }
+                _ => Value::Table(m),
+            };
+            result.raw_push(object)?;
+        }
+        Ok(result)
+    }
+}
+
+fn classes(lua: &Lua) -> Result<Table, mlua::Error> {
+    lua.named_registry_value(CLASSES_KEY)
+}
+
+/// Adds `set_match_class` and `match_objects` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    lua.set_named_registry_value(CLASSES_KEY, crate::weak_table(lua, "k")?)?;
+
+    let module = crate::companion_module(lua)?;
+    module.set(
+        "set_match_class",
+        lua.create_function(|lua, (query, pattern, class): (Value, i64, Value)| {
+            lua.set_match_class(query, pattern, class)
+        })?,
+    )?;
+    module.set(
+        "match_objects",
+        lua.create_function(|lua, (query, node): (Value, Value)| lua.match_objects(query, node))?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_convert_matches_into_objects() {
+        let code = b"def double(x): return x * 2\nclass A: pass\nprint(double(1))\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local query = require("ltreesitter").require("python"):query([[
+                (function_definition name: (identifier) @name) @def
+                (class_definition name: (identifier) @name) @def
+                (call function: (identifier) @callee)
+              ]])
+
+              local FnDef = {}
+              FnDef.__index = FnDef
+              function FnDef:kind() return self.def:type() end
+              ltreesitter_rs.set_match_class(query, 0, FnDef)
+              ltreesitter_rs.set_match_class(query, 1, function(captures, pattern)
+                return { pattern = pattern, name = captures.name }
+              end)
+
+              local objects = ltreesitter_rs.match_objects(query, parsed)
+              assert(#objects == 4, "expected four matches")
+              assert(objects[1]:kind() == "function_definition", "expected FnDef instance")
+              assert(objects[1].name:source() == "double", "expected FnDef instance")
+              assert(objects[2].pattern == 1, "expected constructed object")
+              assert(objects[2].name:source() == "A", "expected constructed object")
+              assert(objects[3].pattern == 2, "expected plain match table")
+              assert(objects[3].captures.callee:source() == "print", "expected plain match table")
+              assert(objects[4].captures.callee:source() == "double", "expected plain match table")
+
+              local within = ltreesitter_rs.match_objects(query, parsed:root():child(2))
+              assert(#within == 2 and within[1].captures.callee:source() == "print")
+            "#,
+        );
+    }
+}
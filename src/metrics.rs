@@ -0,0 +1,166 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Counts how often conversions between Rust and Lua take their expensive paths.
+//!
+//! While metrics are enabled, this crate counts every copy of a tree-sitter tree (when converting
+//! an ltreesitter tree into a [`TreeWithSource`][crate::TreeWithSource]), every copy of source
+//! code (when pushing a tree into Lua), and every C function that it creates to call into
+//! ltreesitter (which only happens once per Lua state for each kind of call).  Once a copy count
+//! passes a threshold, a warning is recorded (and optionally printed to stderr), once per kind of
+//! slow path, with a suggestion for a cheaper way to do the same thing.
+
+use mlua::Lua;
+
+const TREE_COPY_THRESHOLD: u64 = 100;
+const SOURCE_COPY_THRESHOLD: u64 = 100;
+
+const TREE_COPY_ADVICE: &str = "converting ltreesitter trees into TreeWithSource copies the \
+    tree each time; convert the nodes or cursors that you need into TSNode or TSTreeCursor \
+    instead, which don't copy anything";
+const SOURCE_COPY_ADVICE: &str = "pushing a tree into Lua copies its source code each time; \
+    push each tree once and keep the Lua value around, or store trees that many jobs analyze in \
+    a ScriptPool's TreeArena";
+
+/// Counts of the expensive conversion paths that have run since metrics were enabled.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConversionMetrics {
+    /// The number of tree-sitter trees that were copied.
+    pub tree_copies: u64,
+    /// The number of times that source code was copied.
+    pub source_copies: u64,
+    /// The total number of bytes of source code that were copied.
+    pub source_bytes_copied: u64,
+    /// The number of C functions that were created.
+    pub c_functions_created: u64,
+    /// The slow-path warnings that have been issued, at most one per kind of slow path.
+    pub warnings: Vec<String>,
+}
+
+struct MetricsState {
+    metrics: ConversionMetrics,
+    print_warnings: bool,
+}
+
+/// An extension trait that lets you count the expensive conversions in a Lua environment.
+pub trait ConversionInstrumentation {
+    /// Starts counting expensive conversions, discarding any previous counts.  Slow-path warnings
+    /// are only collected in [`ConversionMetrics::warnings`] unless you ask for them to be printed
+    /// via [`print_slow_path_warnings`][Self::print_slow_path_warnings].
+    fn enable_metrics(&self);
+
+    /// Sets whether slow-path warnings are also printed to stderr, until metrics are next enabled.
+    /// Does nothing if metrics aren't enabled.
+    fn print_slow_path_warnings(&self, print: bool);
+
+    /// Returns the current counts, or `None` if metrics aren't enabled.
+    fn metrics(&self) -> Option<ConversionMetrics>;
+
+    /// Stops counting expensive conversions, and returns the final counts.
+    fn disable_metrics(&self) -> Option<ConversionMetrics>;
+}
+
+impl ConversionInstrumentation for Lua {
+    fn enable_metrics(&self) {
+        self.set_app_data(MetricsState {
+            metrics: ConversionMetrics::default(),
+            print_warnings: false,
+        });
+    }
+
+    fn print_slow_path_warnings(&self, print: bool) {
+        if let Some(mut state) = self.app_data_mut::<MetricsState>() {
+            state.print_warnings = print;
+        }
+    }
+
+    fn metrics(&self) -> Option<ConversionMetrics> {
+        self.app_data_ref::<MetricsState>()
+            .map(|state| state.metrics.clone())
+    }
+
+    fn disable_metrics(&self) -> Option<ConversionMetrics> {
+        self.remove_app_data::<MetricsState>()
+            .map(|state| state.metrics)
+    }
+}
+
+/// Records a copy of a tree-sitter tree.
+pub(crate) fn record_tree_copy(lua: &Lua) {
+    update(lua, |metrics| {
+        metrics.tree_copies += 1;
+        (metrics.tree_copies == TREE_COPY_THRESHOLD).then_some(TREE_COPY_ADVICE)
+    });
+}
+
+/// Records a copy of some source code.
+pub(crate) fn record_source_copy(lua: &Lua, bytes: usize) {
+    update(lua, |metrics| {
+        metrics.source_copies += 1;
+        metrics.source_bytes_copied += bytes as u64;
+        (metrics.source_copies == SOURCE_COPY_THRESHOLD).then_some(SOURCE_COPY_ADVICE)
+    });
+}
+
+/// Records the creation of a C function.
+pub(crate) fn record_c_function(lua: &Lua) {
+    update(lua, |metrics| {
+        metrics.c_functions_created += 1;
+        None
+    });
+}
+
+fn update<F>(lua: &Lua, f: F)
+where
+    F: FnOnce(&mut ConversionMetrics) -> Option<&'static str>,
+{
+    let mut state = match lua.app_data_mut::<MetricsState>() {
+        Some(state) => state,
+        None => return,
+    };
+    if let Some(advice) = f(&mut state.metrics) {
+        let warning = format!("mlua-tree-sitter: slow path: {}", advice);
+        if state.print_warnings {
+            eprintln!("{}", warning);
+        }
+        state.metrics.warnings.push(warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::TreeWithSource;
+    use crate::WithSource;
+
+    #[test]
+    fn can_count_slow_paths() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        assert_eq!(None, l.metrics());
+
+        l.enable_metrics();
+        assert!(!l.app_data_ref::<MetricsState>().unwrap().print_warnings);
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        for _ in 0..TREE_COPY_THRESHOLD + 1 {
+            let _: TreeWithSource = l.globals().get("parsed").unwrap();
+        }
+        let metrics = l.disable_metrics().unwrap();
+        assert_eq!(1, metrics.source_copies);
+        assert_eq!(code.len() as u64, metrics.source_bytes_copied);
+        assert_eq!(TREE_COPY_THRESHOLD + 1, metrics.tree_copies);
+        // The C functions that push trees and check userdata types are created once per state.
+        assert!(metrics.c_functions_created <= 2);
+        assert_eq!(1, metrics.warnings.len());
+        assert!(metrics.warnings[0].contains("TSNode"));
+        assert_eq!(None, l.metrics());
+    }
+}
@@ -0,0 +1,319 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Memory-mapped source files, for analyses that don't want to read every file into memory.
+//!
+//! An [`MmapSource`] maps a file into memory, so that its contents are paged in on demand.  It is
+//! a [`SourceStore`], so you can parse its contents and then push the tree into Lua with
+//! [`WithSource::with_source_store`][crate::WithSource::with_source_store]:
+//!
+//! ``` no_run
+//! # fn main() -> Result<(), anyhow::Error> {
+//! use mlua_tree_sitter::MmapSource;
+//! use mlua_tree_sitter::WithSource;
+//!
+//! // Safety: nothing else modifies or truncates src/main.py while we use it.
+//! let source = unsafe { MmapSource::open("src/main.py")? };
+//! let mut parser = tree_sitter::Parser::new();
+//! parser.set_language(tree_sitter_python::language())?;
+//! let parsed = parser.parse(source.bytes(), None).expect("Could not parse Python code");
+//! let lua = mlua::Lua::new();
+//! lua.globals().set("parsed", parsed.with_source_store(source))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! On Unix, the file is mapped right after a page that holds its length, which is how ltreesitter's
+//! C code expects a tree's source to be laid out, so a pushed tree reads its source straight from
+//! the mapping, even when running queries.  On other platforms, the file's contents are copied into
+//! the Lua state when the tree is pushed.
+//!
+//! The mapping reads the file's pages straight from disk, so a file that changes while it is
+//! mapped changes the source out from under the tree, and one that's truncated makes reading the
+//! missing pages crash the process with `SIGBUS`.  No check that we could make before each read
+//! can rule that out, since the file can change right after the check, so [`MmapSource::open`] is
+//! `unsafe`, and it's up to the caller to make sure that nothing modifies the file while it's
+//! mapped.  To catch changes that happen anyway, the file's length and modification time are
+//! checked when the tree is pushed into Lua, and whenever Lua code calls `node:source()`, and
+//! those raise an error if the file has changed.
+//!
+//! This module is only available with the `mmap` feature.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::SourceStore;
+use crate::SourceText;
+
+/// A source file that is mapped into memory.
+pub struct MmapSource {
+    path: PathBuf,
+    mapping: Mapping,
+    len: u64,
+    modified: SystemTime,
+}
+
+impl MmapSource {
+    /// Maps a file into memory.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or any other, until the
+    /// `MmapSource` and every tree that uses it have been dropped.  Reading a mapped page that a
+    /// truncation removed raises `SIGBUS`, and a modification changes the bytes behind any `&[u8]`
+    /// that [`bytes`][Self::bytes] has returned.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<MmapSource, mlua::Error> {
+        let path = path.as_ref();
+        let io_error = |err: std::io::Error| {
+            mlua::Error::RuntimeError(format!("cannot map {}: {}", path.display(), err))
+        };
+        let file = File::open(path).map_err(io_error)?;
+        let metadata = file.metadata().map_err(io_error)?;
+        // Safety: our caller promises that the file won't change while it's mapped.
+        let mapping = unsafe { Mapping::new(&file, metadata.len() as usize) }.map_err(io_error)?;
+        Ok(MmapSource {
+            path: path.to_path_buf(),
+            mapping,
+            len: metadata.len(),
+            modified: metadata.modified().map_err(io_error)?,
+        })
+    }
+
+    /// Returns the path of the mapped file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the file still has the same length and modification time that it had when
+    /// it was mapped.  This reads the file's metadata.
+    pub fn is_valid(&self) -> bool {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => {
+                metadata.len() == self.len && metadata.modified().ok() == Some(self.modified)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the contents of the file.
+    pub fn bytes(&self) -> &[u8] {
+        self.mapping.bytes()
+    }
+}
+
+impl SourceStore for MmapSource {
+    fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error> {
+        self.bytes()
+            .get(range.clone())
+            .map(Cow::Borrowed)
+            .ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "range {}..{} is out of bounds of {}",
+                    range.start,
+                    range.end,
+                    self.path.display()
+                ))
+            })
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        Some(self.bytes())
+    }
+
+    fn source_text(&self) -> Option<&SourceText> {
+        self.mapping.source_text()
+    }
+
+    fn validate(&self) -> Result<(), mlua::Error> {
+        if self.is_valid() {
+            return Ok(());
+        }
+        Err(mlua::Error::RuntimeError(format!(
+            "{} has changed since it was mapped",
+            self.path.display()
+        )))
+    }
+}
+
+/// A read-only mapping of a file, laid out like ltreesitter's `SourceText`: a page that ends with
+/// the file's length, then the file's pages, then zeros up to the end of the page after the
+/// file's last byte.
+#[cfg(unix)]
+struct Mapping {
+    base: *mut libc::c_void,
+    size: usize,
+    page: usize,
+    len: usize,
+}
+
+// The mapping is never written to once it's set up.
+#[cfg(unix)]
+unsafe impl Send for Mapping {}
+#[cfg(unix)]
+unsafe impl Sync for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    unsafe fn new(file: &File, len: usize) -> Result<Mapping, std::io::Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        // Leave room for a NUL terminator, like ltreesitter does.  Past the end of the file, the
+        // mapping reads as zeros.
+        let size = page + (len + page) / page * page;
+        let base = libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if base == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mapping = Mapping {
+            base,
+            size,
+            page,
+            len,
+        };
+        (*mapping.header()).length = len;
+        if libc::mprotect(base, page, libc::PROT_READ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // mmap can't map an empty file, but an empty source doesn't need anything from it.
+        if len > 0 {
+            let mapped = libc::mmap(
+                mapping.base.add(page),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                0,
+            );
+            if mapped == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(mapping)
+    }
+
+    fn header(&self) -> *mut SourceText {
+        unsafe { self.base.add(self.page - std::mem::size_of::<usize>()) as *mut SourceText }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.base.add(self.page) as *const u8, self.len) }
+    }
+
+    fn source_text(&self) -> Option<&SourceText> {
+        Some(unsafe { &*self.header() })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base, self.size) };
+    }
+}
+
+/// A read-only mapping of a file.
+#[cfg(not(unix))]
+struct Mapping {
+    // memmap2 can't map empty files, so we don't try.
+    mmap: Option<memmap2::Mmap>,
+}
+
+#[cfg(not(unix))]
+impl Mapping {
+    unsafe fn new(file: &File, len: usize) -> Result<Mapping, std::io::Error> {
+        let mmap = match len {
+            0 => None,
+            _ => Some(memmap2::Mmap::map(file)?),
+        };
+        Ok(Mapping { mmap })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.mmap.as_deref().unwrap_or_default()
+    }
+
+    fn source_text(&self) -> Option<&SourceText> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+    use mlua::Lua;
+
+    #[test]
+    fn can_serve_sources_from_mapped_files() {
+        let path =
+            std::env::temp_dir().join(format!("mlua-tree-sitter-mmap-{}.py", std::process::id()));
+        std::fs::write(&path, b"def double(x): return x * 2\n").unwrap();
+        let source = unsafe { MmapSource::open(&path) }.unwrap();
+        assert!(source.is_valid());
+        #[cfg(unix)]
+        assert_eq!(
+            Some(source.bytes().as_ptr()),
+            source.source_text().map(|text| text.bytes().as_ptr())
+        );
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(source.bytes(), None).unwrap();
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.globals()
+            .set("parsed", parsed.with_source_store(source))
+            .unwrap();
+        l.check(
+            r#"
+              local name = parsed:root():child(0):child_by_field_name("name")
+              assert(name:source() == "double")
+              local query = require("ltreesitter")
+                .require("python")
+                :query([[((identifier) @id (#eq? @id "double"))]])
+              local count = 0
+              for _ in query:capture(parsed:root()) do
+                count = count + 1
+              end
+              assert(count == 1)
+            "#,
+        );
+
+        // Touching the file only changes its metadata, so it's a change that the contract allows,
+        // and that reading the source can still see.
+        let touched = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86400);
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(touched).unwrap();
+        l.check(
+            r#"
+              local name = parsed:root():child(0):child_by_field_name("name")
+              local ok, err = pcall(name.source, name)
+              assert(not ok and tostring(err):find("has changed since it was mapped"))
+            "#,
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
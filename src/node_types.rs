@@ -0,0 +1,503 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Metadata about the kinds of nodes that a grammar can produce, as described by the grammar's
+//! `node-types.json` file.
+//!
+//! This is what you need to answer questions like "what fields can a `function_definition` have"
+//! or "is an `identifier` an `expression`", which the parser itself can't answer.  A host
+//! registers a grammar's [`NodeTypes`] with [`NodeTypeMetadata::register_node_types`].  With the
+//! `node-types` feature, [`NodeTypes::from_json`] reads them straight from `node-types.json`.
+//!
+//! Lua code can then call `language:node_type_info(kind)` on a [language][crate::TSLanguage],
+//! which returns a table with `kind`, `named`, `fields`, `children`, `subtypes`, and `supertypes`
+//! fields (or `nil` if there's no metadata for that kind).  Each field, and `children`, is a table
+//! with `multiple`, `required`, and `types` fields, where `types` lists the kinds of node that can
+//! appear there.
+//!
+//! With node types registered, scripts can also check a node against a supertype with
+//! `node:is_a("expression")`, and anything that takes a list of node kinds (like the
+//! [positional queries][crate::closest_ancestor_of_kind]) accepts supertypes too, expanding them
+//! into the kinds that they stand for.  `require("ltreesitter_rs").expand_kinds(language, kinds)`
+//! does that expansion directly.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::c_void;
+use std::sync::Arc;
+
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use tree_sitter::Language;
+use tree_sitter::Node;
+
+use crate::ltreesitter;
+use crate::TSLanguage;
+use crate::TSNode;
+
+/// A kind of node that can appear in a field, or as a subtype of a supertype.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct NodeTypeRef {
+    pub kind: String,
+    pub named: bool,
+}
+
+/// The nodes that can appear in one of a node's fields, or among its unnamed children.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChildInfo {
+    /// Whether more than one node can appear.
+    pub multiple: bool,
+    /// Whether at least one node must appear.
+    pub required: bool,
+    pub types: Vec<NodeTypeRef>,
+}
+
+/// Everything that `node-types.json` says about one kind of node.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NodeTypeInfo {
+    pub kind: String,
+    pub named: bool,
+    pub fields: BTreeMap<String, ChildInfo>,
+    /// The children that aren't in any field.
+    pub children: Option<ChildInfo>,
+    /// The kinds that this kind stands for, if it's a supertype.
+    pub subtypes: Vec<NodeTypeRef>,
+}
+
+impl NodeTypeInfo {
+    /// Returns whether this kind is a supertype.
+    pub fn is_supertype(&self) -> bool {
+        !self.subtypes.is_empty()
+    }
+}
+
+/// The metadata about every kind of node that a grammar can produce.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NodeTypes {
+    // Named and anonymous nodes can share a kind (like the `string` rule and the `"string"`
+    // keyword), so we index them separately.
+    kinds: BTreeMap<(String, bool), NodeTypeInfo>,
+}
+
+impl NodeTypes {
+    /// Creates an empty set of metadata.
+    pub fn new() -> NodeTypes {
+        NodeTypes::default()
+    }
+
+    /// Adds the metadata for one kind of node, replacing any existing metadata for that kind.
+    pub fn add(&mut self, info: NodeTypeInfo) -> &mut Self {
+        self.kinds.insert((info.kind.clone(), info.named), info);
+        self
+    }
+
+    /// Returns the metadata for a kind of node, preferring named nodes over anonymous ones.
+    pub fn get(&self, kind: &str) -> Option<&NodeTypeInfo> {
+        self.kinds
+            .get(&(kind.to_string(), true))
+            .or_else(|| self.kinds.get(&(kind.to_string(), false)))
+    }
+
+    /// Returns the metadata for every kind of node, ordered by kind.
+    pub fn iter(&self) -> impl Iterator<Item = &NodeTypeInfo> {
+        self.kinds.values()
+    }
+
+    /// Returns whether `kind` is `supertype`, or one of its subtypes (directly or via other
+    /// supertypes).
+    pub fn is_subtype_of(&self, kind: &str, supertype: &str) -> bool {
+        self.subtypes_of(supertype).contains(kind)
+    }
+
+    /// Returns `supertype` and every kind that it stands for, directly or via other supertypes.
+    /// A kind that isn't a supertype only stands for itself.
+    pub fn subtypes_of(&self, supertype: &str) -> BTreeSet<String> {
+        let mut result = BTreeSet::new();
+        let mut stack = vec![supertype.to_string()];
+        while let Some(kind) = stack.pop() {
+            if !result.insert(kind.clone()) {
+                continue;
+            }
+            if let Some(info) = self.kinds.get(&(kind, true)) {
+                stack.extend(info.subtypes.iter().map(|subtype| subtype.kind.clone()));
+            }
+        }
+        result
+    }
+
+    /// Returns every supertype that `kind` belongs to, directly or via other supertypes, in
+    /// alphabetical order.
+    pub fn supertypes_of(&self, kind: &str) -> Vec<String> {
+        self.supertypes()
+            .filter(|supertype| supertype.kind != kind && self.is_subtype_of(kind, &supertype.kind))
+            .map(|supertype| supertype.kind.clone())
+            .collect()
+    }
+
+    /// Replaces each supertype in a list of kinds with the kinds of the concrete nodes that it
+    /// stands for, so that the list can be compared against
+    /// [`Node::kind`][tree_sitter::Node::kind].  Other kinds are kept as they are.
+    pub fn expand_kinds<S: AsRef<str>>(&self, kinds: &[S]) -> Vec<String> {
+        let mut seen = BTreeSet::new();
+        let mut result = Vec::new();
+        for kind in kinds {
+            let kind = kind.as_ref();
+            let expanded = match self.kinds.get(&(kind.to_string(), true)) {
+                Some(info) if info.is_supertype() => self
+                    .subtypes_of(kind)
+                    .into_iter()
+                    .filter(|subtype| !self.get(subtype).map_or(false, NodeTypeInfo::is_supertype))
+                    .collect(),
+                _ => vec![kind.to_string()],
+            };
+            for kind in expanded {
+                if seen.insert(kind.clone()) {
+                    result.push(kind);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the grammar's supertypes, mapped to the kinds of their direct subtypes, in the
+    /// form that [`explain`][crate::explain] expects.
+    pub fn supertype_map(&self) -> BTreeMap<String, Vec<String>> {
+        self.supertypes()
+            .map(|supertype| {
+                let subtypes = supertype.subtypes.iter().map(|s| s.kind.clone()).collect();
+                (supertype.kind.clone(), subtypes)
+            })
+            .collect()
+    }
+
+    fn supertypes(&self) -> impl Iterator<Item = &NodeTypeInfo> {
+        self.kinds.values().filter(|info| info.is_supertype())
+    }
+}
+
+#[cfg(feature = "node-types")]
+mod json {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub(super) struct NodeType {
+        #[serde(rename = "type")]
+        pub(super) kind: String,
+        pub(super) named: bool,
+        #[serde(default)]
+        pub(super) fields: BTreeMap<String, Children>,
+        pub(super) children: Option<Children>,
+        #[serde(default)]
+        pub(super) subtypes: Vec<TypeRef>,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct Children {
+        pub(super) multiple: bool,
+        pub(super) required: bool,
+        pub(super) types: Vec<TypeRef>,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct TypeRef {
+        #[serde(rename = "type")]
+        pub(super) kind: String,
+        pub(super) named: bool,
+    }
+}
+
+#[cfg(feature = "node-types")]
+impl NodeTypes {
+    /// Parses the contents of a grammar's `node-types.json` file.
+    pub fn from_json(json: &str) -> Result<NodeTypes, mlua::Error> {
+        fn type_refs(types: Vec<json::TypeRef>) -> Vec<NodeTypeRef> {
+            types
+                .into_iter()
+                .map(|t| NodeTypeRef {
+                    kind: t.kind,
+                    named: t.named,
+                })
+                .collect()
+        }
+        fn child_info(children: json::Children) -> ChildInfo {
+            ChildInfo {
+                multiple: children.multiple,
+                required: children.required,
+                types: type_refs(children.types),
+            }
+        }
+        let parsed: Vec<json::NodeType> =
+            serde_json::from_str(json).map_err(mlua::Error::external)?;
+        let mut node_types = NodeTypes::new();
+        for node_type in parsed {
+            node_types.add(NodeTypeInfo {
+                kind: node_type.kind,
+                named: node_type.named,
+                fields: node_type
+                    .fields
+                    .into_iter()
+                    .map(|(name, children)| (name, child_info(children)))
+                    .collect(),
+                children: node_type.children.map(child_info),
+                subtypes: type_refs(node_type.subtypes),
+            });
+        }
+        Ok(node_types)
+    }
+}
+
+/// The node types that have been registered for each language, indexed by the address of the
+/// language.
+#[derive(Default)]
+struct RegisteredNodeTypes(BTreeMap<usize, Arc<NodeTypes>>);
+
+fn language_key(language: Language) -> usize {
+    // Language is a transparent wrapper around a TSLanguage pointer.
+    unsafe { std::mem::transmute::<Language, *const c_void>(language) as usize }
+}
+
+/// An extension trait that lets you register the node types of the grammars that Lua code uses.
+pub trait NodeTypeMetadata {
+    /// Registers the node types of a grammar, replacing any that were registered before.
+    fn register_node_types(&self, language: Language, node_types: NodeTypes);
+
+    /// Returns the node types that were registered for a grammar.
+    fn node_types(&self, language: Language) -> Option<Arc<NodeTypes>>;
+}
+
+impl NodeTypeMetadata for Lua {
+    fn register_node_types(&self, language: Language, node_types: NodeTypes) {
+        let node_types = Arc::new(node_types);
+        match self.app_data_mut::<RegisteredNodeTypes>() {
+            Some(mut registered) => {
+                registered.0.insert(language_key(language), node_types);
+            }
+            None => {
+                let mut registered = RegisteredNodeTypes::default();
+                registered.0.insert(language_key(language), node_types);
+                self.set_app_data(registered);
+            }
+        }
+    }
+
+    fn node_types(&self, language: Language) -> Option<Arc<NodeTypes>> {
+        self.app_data_ref::<RegisteredNodeTypes>()?
+            .0
+            .get(&language_key(language))
+            .cloned()
+    }
+}
+
+/// Expands the supertypes in a list of kinds, using the node types that were registered for
+/// `language`.  Without any, the list is returned unchanged.
+pub(crate) fn expand_kinds(lua: &Lua, language: Language, kinds: Vec<String>) -> Vec<String> {
+    match lua.node_types(language) {
+        Some(node_types) => node_types.expand_kinds(&kinds),
+        None => kinds,
+    }
+}
+
+/// Returns whether a node's kind is `kind`, or one of its subtypes, using the node types that were
+/// registered for the node's language.
+pub(crate) fn node_is_a(lua: &Lua, node: Node, kind: &str) -> bool {
+    match lua.node_types(node.language()) {
+        Some(node_types) => node_types.is_subtype_of(node.kind(), kind),
+        None => node.kind() == kind,
+    }
+}
+
+fn child_info_table<'lua>(lua: &'lua Lua, info: &ChildInfo) -> Result<Table<'lua>, mlua::Error> {
+    let table = lua.create_table()?;
+    table.set("multiple", info.multiple)?;
+    table.set("required", info.required)?;
+    table.set("types", type_refs_table(lua, &info.types)?)?;
+    Ok(table)
+}
+
+fn type_refs_table<'lua>(
+    lua: &'lua Lua,
+    types: &[NodeTypeRef],
+) -> Result<Table<'lua>, mlua::Error> {
+    let table = lua.create_table()?;
+    for node_type in types {
+        let entry = lua.create_table()?;
+        entry.set("kind", node_type.kind.as_str())?;
+        entry.set("named", node_type.named)?;
+        table.raw_push(entry)?;
+    }
+    Ok(table)
+}
+
+/// Implements `language:node_type_info(kind)`.
+pub(crate) fn lua_node_type_info<'lua>(
+    lua: &'lua Lua,
+    language: Language,
+    kind: &str,
+) -> Result<Value<'lua>, mlua::Error> {
+    let node_types = match lua.node_types(language) {
+        Some(node_types) => node_types,
+        None => return Ok(Value::Nil),
+    };
+    let info = match node_types.get(kind) {
+        Some(info) => info,
+        None => return Ok(Value::Nil),
+    };
+    let table = lua.create_table()?;
+    table.set("kind", info.kind.as_str())?;
+    table.set("named", info.named)?;
+    let fields = lua.create_table()?;
+    for (name, field) in &info.fields {
+        fields.set(name.as_str(), child_info_table(lua, field)?)?;
+    }
+    table.set("fields", fields)?;
+    if let Some(children) = &info.children {
+        table.set("children", child_info_table(lua, children)?)?;
+    }
+    table.set("subtypes", type_refs_table(lua, &info.subtypes)?)?;
+    table.set("supertypes", node_types.supertypes_of(kind))?;
+    Ok(Value::Table(table))
+}
+
+/// Adds `is_a` to ltreesitter nodes and `expand_kinds` to the `ltreesitter_rs` module.
+pub(crate) fn install_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    let methods = ltreesitter::methods(lua, ltreesitter::NODE_METATABLE)?;
+    methods.set(
+        "is_a",
+        lua.create_function(|lua, (node, kind): (TSNode, String)| {
+            Ok(node_is_a(lua, *node, &kind))
+        })?,
+    )?;
+    crate::companion_module(lua)?.set(
+        "expand_kinds",
+        lua.create_function(|lua, (language, kinds): (TSLanguage, Vec<String>)| {
+            Ok(expand_kinds(lua, *language, kinds))
+        })?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    fn expression_types() -> NodeTypes {
+        let named = |kind: &str| NodeTypeRef {
+            kind: kind.to_string(),
+            named: true,
+        };
+        let mut node_types = NodeTypes::new();
+        node_types.add(NodeTypeInfo {
+            kind: "expression".to_string(),
+            named: true,
+            subtypes: vec![named("primary_expression"), named("not_operator")],
+            ..NodeTypeInfo::default()
+        });
+        node_types.add(NodeTypeInfo {
+            kind: "primary_expression".to_string(),
+            named: true,
+            subtypes: vec![named("identifier"), named("integer")],
+            ..NodeTypeInfo::default()
+        });
+        node_types.add(NodeTypeInfo {
+            kind: "assignment".to_string(),
+            named: true,
+            fields: BTreeMap::from([(
+                "right".to_string(),
+                ChildInfo {
+                    multiple: false,
+                    required: false,
+                    types: vec![named("expression")],
+                },
+            )]),
+            ..NodeTypeInfo::default()
+        });
+        node_types
+    }
+
+    #[test]
+    fn can_look_up_node_types() {
+        let node_types = expression_types();
+        assert!(node_types.is_subtype_of("integer", "expression"));
+        assert!(node_types.is_subtype_of("expression", "expression"));
+        assert!(!node_types.is_subtype_of("assignment", "expression"));
+        assert_eq!(
+            vec!["expression".to_string(), "primary_expression".to_string()],
+            node_types.supertypes_of("identifier")
+        );
+        assert!(node_types
+            .get("assignment")
+            .unwrap()
+            .fields
+            .contains_key("right"));
+
+        let python = tree_sitter_python::language();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_node_types(python, node_types);
+        l.globals().set("python", TSLanguage(python)).unwrap();
+        l.check(
+            r#"
+              local info = python:node_type_info("assignment")
+              assert(info.named and info.fields.right.types[1].kind == "expression")
+              assert(info.children == nil and #info.subtypes == 0)
+              info = python:node_type_info("integer")
+              assert(info == nil)
+              info = python:node_type_info("primary_expression")
+              assert(#info.subtypes == 2 and info.supertypes[1] == "expression")
+            "#,
+        );
+    }
+
+    #[test]
+    fn can_match_supertypes() {
+        let code = b"x = y\n";
+        let python = tree_sitter_python::language();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(python).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let node_types = expression_types();
+        assert_eq!(
+            vec!["identifier", "integer", "not_operator", "call"],
+            node_types.expand_kinds(&["expression", "call"])
+        );
+        let value = parsed.root_node().descendant_for_byte_range(4, 5).unwrap();
+        assert!(TSNode::new(value).is_subtype_of(&node_types, "expression"));
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_node_types(python, node_types);
+        l.globals().set("python", TSLanguage(python)).unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local assignment = parsed:root():child(0):child(0)
+              assert(assignment:is_a("assignment") and not assignment:is_a("expression"))
+              assert(assignment:child_by_field_name("right"):is_a("primary_expression"))
+              assert(#ltreesitter_rs.expand_kinds(python, { "expression" }) == 3)
+              local found = ltreesitter_rs.next_node_of_kind_after(parsed, 1, "expression")
+              assert(found:source() == "y")
+              found = ltreesitter_rs.closest_ancestor_of_kind(found, { "expression_statement" })
+              assert(found:type() == "expression_statement")
+            "#,
+        );
+    }
+
+    #[cfg(feature = "node-types")]
+    #[test]
+    fn can_parse_node_types_json() {
+        let node_types = NodeTypes::from_json(tree_sitter_python::NODE_TYPES).unwrap();
+        let definition = node_types.get("function_definition").unwrap();
+        assert!(definition.fields["name"].required);
+        assert!(node_types.is_subtype_of("identifier", "expression"));
+        assert!(node_types.get("def").is_some());
+    }
+}
@@ -0,0 +1,258 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! An adapter that exposes the most commonly used parts of Neovim's `vim.treesitter` API on top of
+//! ltreesitter, so that scripts written for Neovim can run outside of it.
+//!
+//! The adapter provides:
+//!
+//! - `get_node_text(node, source)`, where `source` is either the file's contents as a string, or
+//!   anything else (such as a buffer number) to use the node's own tree's source
+//! - `query.parse(lang, source)`, which returns a query object with a `captures` list and
+//!   `iter_captures(node, source, start, stop)` and `iter_matches(node, source, start, stop)`
+//!   methods
+//! - `query.get(lang, name)`, which loads `queries/<lang>/<name>.scm` from the directories in
+//!   `query_paths`, following `; inherits:` includes (this is disabled in
+//!   [sandboxed][crate::Module::open_ltreesitter_sandboxed] environments)
+//! - `node:range()`, `node:start()`, `node:end_()`, and `node:iter_children()` methods on
+//!   ltreesitter nodes
+//!
+//! Languages are looked up in the adapter's `register_language(lang, parser)` table first, and
+//! are otherwise loaded via `ltreesitter.require(lang)`.  Capture IDs are assigned in the order
+//! that captures are first seen, rather than in the order that they appear in the query; the
+//! captures of each match are visited in name order, so the IDs are the same from run to run.
+
+use mlua::Function;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+
+use crate::ltreesitter;
+use crate::sandbox;
+
+const ADAPTER: &str = r#"
+    local read_query_file, ltreesitter = ...
+    local M = { query = {}, languages = {}, query_paths = {} }
+
+    function M.register_language(lang, parser)
+      M.languages[lang] = parser
+    end
+
+    function M.language_parser(lang)
+      local parser = M.languages[lang]
+      if parser == nil then
+        parser = ltreesitter.require(lang)
+        M.languages[lang] = parser
+      end
+      return parser
+    end
+
+    function M.get_node_text(node, source)
+      if type(source) == "string" then
+        return source:sub(node:start_byte() + 1, node:end_byte())
+      end
+      return node:source()
+    end
+
+    local Query = {}
+    Query.__index = Query
+
+    function M.query.parse(lang, source)
+      local inner = M.language_parser(lang):query(source)
+      return setmetatable({ inner = inner, captures = {}, ids = {} }, Query)
+    end
+
+    function M.query.get(lang, name)
+      for _, dir in ipairs(M.query_paths) do
+        local path = dir .. "/queries/" .. lang .. "/" .. name .. ".scm"
+        local file = io.open(path, "r")
+        if file ~= nil then
+          file:close()
+          return M.query.parse(lang, read_query_file(path))
+        end
+      end
+      return nil
+    end
+
+    function Query:capture_id(name)
+      local id = self.ids[name]
+      if id == nil then
+        id = #self.captures + 1
+        self.captures[id] = name
+        self.ids[name] = id
+      end
+      return id
+    end
+
+    local function in_rows(node, start, stop)
+      local row = node:start_point().row
+      return (start == nil or row >= start) and (stop == nil or row < stop)
+    end
+
+    function Query:iter_captures(node, source, start, stop)
+      local next_capture = self.inner:capture(node)
+      return function()
+        while true do
+          local captured, name = next_capture()
+          if captured == nil then return nil end
+          if in_rows(captured, start, stop) then
+            return self:capture_id(name), captured, {}
+          end
+        end
+      end
+    end
+
+    function Query:iter_matches(node, source, start, stop)
+      local next_match = self.inner:match(node)
+      return function()
+        while true do
+          local match = next_match()
+          if match == nil then return nil end
+          local by_id = {}
+          local any = false
+          -- Visit the captures in name order, so that capture IDs don't depend on the order that
+          -- `pairs` happens to return them in.
+          local names = {}
+          for name in pairs(match.captures) do names[#names + 1] = name end
+          table.sort(names)
+          for _, name in ipairs(names) do
+            local captured = match.captures[name]
+            local first = captured
+            if type(captured) == "table" then first = captured[1] end
+            if first ~= nil and in_rows(first, start, stop) then any = true end
+            by_id[self:capture_id(name)] = captured
+          end
+          if any then return match.pattern, by_id, {} end
+        end
+      end
+    end
+
+    return M
+"#;
+
+const NODE_METHODS: &str = r#"
+    local methods = ...
+    function methods:range()
+      local start, stop = self:start_point(), self:end_point()
+      return start.row, start.column, stop.row, stop.column
+    end
+    function methods:start()
+      local start = self:start_point()
+      return start.row, start.column, self:start_byte()
+    end
+    function methods:end_()
+      local stop = self:end_point()
+      return stop.row, stop.column, self:end_byte()
+    end
+    function methods:iter_children()
+      local i = -1
+      return function()
+        i = i + 1
+        if i < self:child_count() then return self:child(i), nil end
+      end
+    end
+"#;
+
+/// An extension trait that lets you run scripts written against Neovim's `vim.treesitter` API.
+///
+/// Once enabled, Lua code can get the adapter via `require("ltreesitter_rs.nvim")`.  If there's no
+/// `vim` global already, one is created with the adapter as `vim.treesitter`.
+pub trait NvimCompat {
+    /// Enables the `vim.treesitter` adapter.  You must load the `ltreesitter` module first.
+    fn open_nvim_compat<'lua>(&'lua self) -> Result<Table<'lua>, mlua::Error>;
+}
+
+impl NvimCompat for Lua {
+    fn open_nvim_compat<'lua>(&'lua self) -> Result<Table<'lua>, mlua::Error> {
+        let node_methods = ltreesitter::methods(self, ltreesitter::NODE_METATABLE)?;
+        self.load(NODE_METHODS)
+            .set_name("nvim_node_methods")
+            .call::<_, ()>(node_methods)?;
+
+        let read_query_file: Function = crate::companion_module(self)?.get("read_query_file")?;
+        let adapter: Table = self
+            .load(ADAPTER)
+            .set_name("nvim_adapter")
+            .call((read_query_file, ltreesitter::module(self)?))?;
+        let loaded: Table = self.globals().get::<_, Table>("package")?.get("loaded")?;
+        if sandbox::is_sandboxed(self) {
+            let query: Table = adapter.get("query")?;
+            query.set(
+                "get",
+                self.create_function(|_, _: MultiValue| -> Result<(), mlua::Error> {
+                    Err(sandbox::disabled("vim.treesitter.query.get"))
+                })?,
+            )?;
+        }
+        loaded.set("ltreesitter_rs.nvim", adapter.clone())?;
+        if self.globals().get::<_, Option<Table>>("vim")?.is_none() {
+            let vim = self.create_table()?;
+            vim.set("treesitter", adapter.clone())?;
+            self.globals().set("vim", vim)?;
+        }
+        Ok(adapter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_run_nvim_style_scripts() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.open_nvim_compat().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ts = vim.treesitter
+              assert(ts == require("ltreesitter_rs.nvim"))
+              local func = parsed:root():child(0)
+              local name = func:child_by_field_name("name")
+              assert(ts.get_node_text(name, 0) == "double")
+              assert(ts.get_node_text(name, "def double") == "double")
+              local sr, sc, er, ec = name:range()
+              assert(sr == 0 and sc == 4 and er == 0 and ec == 10)
+              local children = 0
+              for child in func:iter_children() do children = children + 1 end
+              assert(children == func:child_count())
+
+              local parser = require("ltreesitter").require("python")
+              ts.register_language("python", parser)
+              local query = ts.query.parse("python", "(function_definition name: (_) @name)")
+              local seen = {}
+              for id, node in query:iter_captures(parsed:root(), 0) do
+                seen[#seen + 1] = query.captures[id] .. "=" .. ts.get_node_text(node, 0)
+              end
+              assert(#seen == 1 and seen[1] == "name=double")
+              for id in query:iter_captures(parsed:root(), 0, 1, 2) do
+                error("capture outside of the requested rows")
+              end
+              local matches = ts.query.parse("python", [[
+                (function_definition name: (_) @zeta parameters: (_) @mid) @alpha
+              ]])
+              local count = 0
+              for pattern, captured in matches:iter_matches(parsed:root(), 0) do
+                count = count + 1
+                assert(ts.get_node_text(captured[matches.ids["zeta"]], 0) == "double")
+                assert(ts.get_node_text(captured[matches.ids["mid"]], 0) == "(x)")
+              end
+              assert(count == 1)
+              assert(table.concat(matches.captures, ",") == "alpha,mid,zeta")
+            "#,
+        );
+    }
+}
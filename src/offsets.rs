@@ -0,0 +1,172 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Passes byte offsets, rows, and columns to and from Lua without losing precision.
+//!
+//! Tree-sitter measures offsets with `usize`, but some Lua configurations can't represent all of
+//! those values: Lua 5.3 and 5.4 can be built with 32-bit integers (or 32-bit floats), and Lua 5.1
+//! and LuaJIT only have floats.  When the `ltreesitter` module is opened, we detect the largest
+//! integer and the largest exactly representable float of the Lua environment.  Offsets up to the
+//! largest integer are passed as Lua integers.  Larger offsets are passed as floats, which are
+//! exact up to 2^53 with double-precision floats (or 2^24 with single-precision floats).  Offsets
+//! that can't be represented exactly either way raise an error, instead of silently wrapping
+//! around or being rounded.  Offsets coming from Lua must be non-negative whole numbers in the same
+//! range.
+//!
+//! This covers the conversions that this crate performs.  The node methods that ltreesitter
+//! itself provides (like `node:start_byte()`) push Lua integers directly.
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+
+const DETECT: &str = r#"
+    local limit = 1.0
+    while limit < 2^64 and (limit * 2 + 1) - limit * 2 == 1 do
+      limit = limit * 2
+    end
+    return math.maxinteger, limit * 2
+"#;
+
+/// The largest offsets that a Lua environment can represent exactly.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct OffsetLimits {
+    /// The largest Lua integer, or `None` if the environment doesn't have an integer subtype.
+    max_integer: Option<u64>,
+    /// All whole numbers up to this value are exactly representable as Lua floats.
+    max_float: u64,
+}
+
+impl OffsetLimits {
+    /// The limits of a standard Lua 5.3 or 5.4 build, which we assume if the ltreesitter module
+    /// hasn't been opened yet.
+    const STANDARD: OffsetLimits = OffsetLimits {
+        max_integer: Some(i64::MAX as u64),
+        max_float: 1 << 53,
+    };
+
+    fn get(lua: &Lua) -> OffsetLimits {
+        lua.app_data_ref::<OffsetLimits>()
+            .map(|limits| *limits)
+            .unwrap_or(OffsetLimits::STANDARD)
+    }
+
+    fn max_offset(&self) -> u64 {
+        self.max_integer.unwrap_or(0).max(self.max_float)
+    }
+}
+
+/// Detects which offsets the Lua environment can represent exactly.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let (max_integer, max_float): (Option<mlua::Integer>, mlua::Number) =
+        lua.load(DETECT).set_name("offset limits").call(())?;
+    lua.set_app_data(OffsetLimits {
+        max_integer: max_integer.and_then(|max| u64::try_from(max).ok()),
+        max_float: max_float.min(u64::MAX as mlua::Number) as u64,
+    });
+    Ok(())
+}
+
+/// A byte offset, row, or column, which is converted to and from Lua without losing precision.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Offset(pub usize);
+
+impl<'lua> IntoLua<'lua> for Offset {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let limits = OffsetLimits::get(lua);
+        let offset = self.0 as u64;
+        if limits.max_integer.map_or(false, |max| offset <= max) {
+            if let Ok(offset) = mlua::Integer::try_from(self.0) {
+                return Ok(Value::Integer(offset));
+            }
+        }
+        if offset <= limits.max_float {
+            return Ok(Value::Number(offset as mlua::Number));
+        }
+        Err(mlua::Error::ToLuaConversionError {
+            from: "usize",
+            to: "number",
+            message: Some(format!(
+                "offset {} is larger than {}, the largest offset that this Lua environment can \
+                 represent exactly",
+                offset,
+                limits.max_offset()
+            )),
+        })
+    }
+}
+
+impl<'lua> FromLua<'lua> for Offset {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let from = value.type_name();
+        let error = |message: String| mlua::Error::FromLuaConversionError {
+            from,
+            to: "offset",
+            message: Some(message),
+        };
+        let offset = match value {
+            Value::Integer(offset) => u64::try_from(offset)
+                .map_err(|_| error(format!("offset {} is negative", offset)))?,
+            Value::Number(offset) => {
+                let limits = OffsetLimits::get(lua);
+                if offset < 0.0 || offset.fract() != 0.0 {
+                    return Err(error(format!("offset {} isn't a whole number", offset)));
+                }
+                if offset > limits.max_float as mlua::Number {
+                    return Err(error(format!(
+                        "offset {} is larger than {}, the largest offset that this Lua \
+                         environment can represent exactly",
+                        offset, limits.max_float
+                    )));
+                }
+                offset as u64
+            }
+            _ => return Err(error("expected a number".to_string())),
+        };
+        usize::try_from(offset)
+            .map(Offset)
+            .map_err(|_| error(format!("offset {} doesn't fit in a usize", offset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn can_convert_offsets_safely() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        assert_eq!(
+            Some(OffsetLimits::STANDARD),
+            l.app_data_ref::<OffsetLimits>().map(|limits| *limits)
+        );
+        assert!(matches!(Offset(7).into_lua(&l).unwrap(), Value::Integer(7)));
+        let offset: Offset = l.load("return 2^40").eval().unwrap();
+        assert_eq!(Offset(1 << 40), offset);
+        assert!(l.load("return -1").eval::<Offset>().is_err());
+        assert!(l.load("return 1.5").eval::<Offset>().is_err());
+        assert!(l.load("return 2^60").eval::<Offset>().is_err());
+
+        // Simulate a Lua build with 32-bit integers and double-precision floats.
+        l.set_app_data(OffsetLimits {
+            max_integer: Some(i32::MAX as u64),
+            max_float: 1 << 53,
+        });
+        let large = (1usize << 31) + 5;
+        match Offset(large).into_lua(&l).unwrap() {
+            Value::Number(offset) => assert_eq!(large as f64, offset),
+            value => panic!("expected a float, got {:?}", value),
+        }
+        assert_eq!(
+            Offset(large),
+            Offset::from_lua(Offset(large).into_lua(&l).unwrap(), &l).unwrap()
+        );
+        assert!(Offset(((1u64 << 53) + 1) as usize).into_lua(&l).is_err());
+    }
+}
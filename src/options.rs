@@ -0,0 +1,312 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Controls how [`Module::open_ltreesitter_with`][crate::Module::open_ltreesitter_with] makes the
+//! `ltreesitter` module available to Lua code, and which parts of it are enabled.
+
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+
+use crate::ltreesitter;
+use crate::sandbox;
+use crate::Module;
+
+/// Where the `ltreesitter` module is registered with Lua's package system.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Placement {
+    /// The module is added to `package.loaded`, so `require` returns it right away.
+    #[default]
+    Loaded,
+    /// The module is added to `package.preload`, so it only shows up in `package.loaded` once Lua
+    /// code requires it.  (The module is still set up right away, so that Rust code can pass trees
+    /// and nodes to Lua before then.  To put off setting it up, use
+    /// [`Module::preload_ltreesitter`][crate::Module::preload_ltreesitter].)
+    Preload,
+    /// The module isn't registered at all.  Lua code can only get to it via a global (see
+    /// [`Options::with_global`]).
+    Hidden,
+}
+
+/// Options for opening the `ltreesitter` module.  The defaults match
+/// [`Module::open_ltreesitter`][crate::Module::open_ltreesitter].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Options {
+    /// The name that Lua code uses to require the module.  Defaults to `ltreesitter`.
+    pub name: String,
+    /// Whether the module is also stored in a global variable with the same name.  Defaults to
+    /// `false`.
+    pub global: bool,
+    pub placement: Placement,
+    /// Whether Lua code can create parsers, via `ltreesitter.require`, `ltreesitter.load`, and the
+    /// language registry's `parser` method.  Defaults to `true`.
+    pub parsers: bool,
+    /// Whether Lua code can load grammars from shared libraries.  Turning this off is the same as
+    /// [`Module::open_ltreesitter_sandboxed`][crate::Module::open_ltreesitter_sandboxed].
+    /// Defaults to `true`.
+    pub dynamic_loading: bool,
+    /// Whether Lua code can compile queries, via `parser:query`.  Defaults to `true`.
+    pub queries: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            name: "ltreesitter".to_string(),
+            global: false,
+            placement: Placement::default(),
+            parsers: true,
+            dynamic_loading: true,
+            queries: true,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Options {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_global(mut self, global: bool) -> Options {
+        self.global = global;
+        self
+    }
+
+    pub fn with_placement(mut self, placement: Placement) -> Options {
+        self.placement = placement;
+        self
+    }
+
+    pub fn with_parsers(mut self, parsers: bool) -> Options {
+        self.parsers = parsers;
+        self
+    }
+
+    pub fn with_dynamic_loading(mut self, dynamic_loading: bool) -> Options {
+        self.dynamic_loading = dynamic_loading;
+        self
+    }
+
+    pub fn with_queries(mut self, queries: bool) -> Options {
+        self.queries = queries;
+        self
+    }
+}
+
+/// The options to use when a preloaded `ltreesitter` module is actually loaded.
+struct Preloaded(Options);
+
+/// Registers loaders in `package.preload` that open the `ltreesitter` module the first time that
+/// Lua code requires it (or `ltreesitter_rs`).
+pub(crate) fn preload(lua: &Lua, options: Options) -> Result<(), mlua::Error> {
+    #[cfg(feature = "luau")]
+    crate::luau::install(lua)?;
+    let name = options.name.clone();
+    lua.set_app_data(Preloaded(options.with_placement(Placement::Hidden)));
+    let preload: Table = lua.globals().get::<_, Table>("package")?.get("preload")?;
+    preload.set(
+        name,
+        lua.create_function(|lua, _: MultiValue| {
+            load_preloaded(lua)?;
+            ltreesitter::module(lua)
+        })?,
+    )?;
+    preload.set(
+        "ltreesitter_rs",
+        lua.create_function(|lua, _: MultiValue| {
+            load_preloaded(lua)?;
+            crate::companion_module(lua)
+        })?,
+    )
+}
+
+/// Opens the `ltreesitter` module if it was preloaded but hasn't been loaded yet.  Returns whether
+/// it was loaded.
+pub(crate) fn load_preloaded(lua: &Lua) -> Result<bool, mlua::Error> {
+    match lua.remove_app_data::<Preloaded>() {
+        Some(Preloaded(options)) => {
+            lua.open_ltreesitter_with(options)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Marks a Lua environment in which Lua code can't create parsers.
+struct ParsersDisabled;
+
+/// Returns an error if Lua code isn't allowed to create parsers.
+pub(crate) fn check_parsers_enabled(lua: &Lua, what: &str) -> Result<(), mlua::Error> {
+    if lua.app_data_ref::<ParsersDisabled>().is_some() {
+        return Err(disabled(what));
+    }
+    Ok(())
+}
+
+fn disabled(what: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("{} is disabled", what))
+}
+
+fn disable<'lua>(
+    lua: &'lua Lua,
+    table: &Table<'lua>,
+    name: &str,
+    what: &'static str,
+) -> Result<(), mlua::Error> {
+    table.set(
+        name,
+        lua.create_function(move |_, _: MultiValue| -> Result<(), mlua::Error> {
+            Err(disabled(what))
+        })?,
+    )
+}
+
+/// Turns off the features that the options disable, and makes the module available to Lua code.
+/// The module must already be loaded and set up.
+pub(crate) fn apply(lua: &Lua, options: &Options) -> Result<(), mlua::Error> {
+    if !options.dynamic_loading {
+        sandbox::mark(lua);
+    }
+    if !options.parsers {
+        lua.set_app_data(ParsersDisabled);
+    }
+    let module = ltreesitter::module(lua)?;
+    restrict(lua, &module, options)?;
+    if !options.queries {
+        let methods = ltreesitter::methods(lua, ltreesitter::PARSER_METATABLE)?;
+        disable(lua, &methods, "query", "parser:query")?;
+    }
+    place(lua, &lua.globals().get("package")?, &module, options)?;
+    if options.global {
+        lua.globals().set(options.name.as_str(), module)?;
+    }
+    Ok(())
+}
+
+/// Turns off the features that the options disable in one copy of the `ltreesitter` module.  This
+/// only covers the module's own functions.
+pub(crate) fn restrict(lua: &Lua, module: &Table, options: &Options) -> Result<(), mlua::Error> {
+    if !options.dynamic_loading {
+        sandbox::restrict(lua, module)?;
+    }
+    if !options.parsers {
+        disable(lua, module, "require", "ltreesitter.require")?;
+        disable(lua, module, "load", "ltreesitter.load")?;
+    }
+    Ok(())
+}
+
+/// Registers a module in a `package` table, according to the options' placement.
+pub(crate) fn place<'lua>(
+    lua: &'lua Lua,
+    package: &Table<'lua>,
+    module: &Table<'lua>,
+    options: &Options,
+) -> Result<(), mlua::Error> {
+    match options.placement {
+        Placement::Loaded => package
+            .get::<_, Table>("loaded")?
+            .set(options.name.as_str(), module.clone()),
+        Placement::Preload => {
+            let loader = lua.create_registry_value(module.clone())?;
+            package.get::<_, Table>("preload")?.set(
+                options.name.as_str(),
+                lua.create_function(move |lua, _: MultiValue| {
+                    lua.registry_value::<Table>(&loader)
+                })?,
+            )
+        }
+        Placement::Hidden => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::WithSource;
+
+    #[test]
+    fn can_open_module_with_options() {
+        let l = Lua::new();
+        l.open_ltreesitter_with(
+            Options::new()
+                .with_name("ts")
+                .with_global(true)
+                .with_placement(Placement::Preload)
+                .with_queries(false),
+        )
+        .unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.check(
+            r#"
+              assert(package.loaded.ts == nil and package.loaded.ltreesitter == nil)
+              assert(require("ts") == ts)
+              assert(not pcall(require, "ltreesitter"))
+              local parser = ts.require("python")
+              assert(parser:parse_string("x = 1"):root():type() == "module")
+              local ok, err = pcall(parser.query, parser, "(identifier) @id")
+              assert(not ok and tostring(err):find("parser:query is disabled", 1, true))
+            "#,
+        );
+
+        let l = Lua::new();
+        l.open_ltreesitter_with(Options::new().with_parsers(false))
+            .unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.check(
+            r#"
+              local ltreesitter = require("ltreesitter")
+              assert(ltreesitter == package.loaded.ltreesitter and _G.ltreesitter == nil)
+              local ok, err = pcall(ltreesitter.require, "python")
+              assert(not ok and tostring(err):find("ltreesitter.require is disabled", 1, true))
+              local languages = require("ltreesitter_rs").languages
+              assert(not pcall(languages.parser, languages, "python"))
+            "#,
+        );
+    }
+
+    #[test]
+    fn can_preload_module() {
+        let l = Lua::new();
+        l.preload_ltreesitter().unwrap();
+        assert!(l
+            .named_registry_value::<Option<Table>>(ltreesitter::MODULE_KEY)
+            .unwrap()
+            .is_none());
+        l.check(
+            r#"
+              assert(package.loaded.ltreesitter == nil)
+              assert(package.loaded.ltreesitter_rs == nil)
+              local ltreesitter = require("ltreesitter")
+              assert(package.loaded.ltreesitter == ltreesitter)
+              assert(require("ltreesitter_rs").languages ~= nil)
+            "#,
+        );
+
+        // Pushing a tree into Lua loads the module right away.
+        let code = b"x = 1\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.preload_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              assert(parsed:root():type() == "module")
+              assert(require("ltreesitter").require ~= nil)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A convention for returning results from Lua functions.
+//!
+//! A Lua function that can fail returns either `{ok = value}` or `{err = error}`, where `error` is
+//! either a message string or a table with a `message` field, an optional `range` field, and an
+//! optional `traceback` field.  The range can be a node, or a table with `start_byte`, `end_byte`,
+//! `start_point`, and `end_point` fields.  The `ltreesitter_rs.ok(value)` and
+//! `ltreesitter_rs.err(message, [range])` helpers build these tables; `err` also captures a
+//! traceback.  In Rust, convert the returned value into a [`ScriptOutcome`].
+
+use std::fmt::Display;
+
+use mlua::FromLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Range;
+
+use crate::TSRange;
+
+/// An error that a Lua script reported via an `{err = ...}` result.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    pub message: String,
+    pub range: Option<Range>,
+    pub traceback: Option<String>,
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(range) = &self.range {
+            write!(
+                f,
+                " at {}:{}",
+                range.start_point.row + 1,
+                range.start_point.column + 1
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl<'lua> FromLua<'lua> for ScriptError {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let error = match value {
+            Value::String(message) => {
+                return Ok(ScriptError {
+                    message: message.to_str()?.to_string(),
+                    range: None,
+                    traceback: None,
+                })
+            }
+            Value::Table(error) => error,
+            value => return Err(conversion_error(value.type_name(), "ScriptError")),
+        };
+        let range = match error.get::<_, Value>("range")? {
+            Value::Nil => None,
+            range => Some(TSRange::from_lua(range, lua)?.0),
+        };
+        Ok(ScriptError {
+            message: error.get("message")?,
+            range,
+            traceback: error.get("traceback")?,
+        })
+    }
+}
+
+/// The result of a Lua function that follows the `{ok = ...}` / `{err = ...}` convention.  (This
+/// is a newtype so that we can implement [`FromLua`] for it.)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptOutcome<T>(pub Result<T, ScriptError>);
+
+impl<T> ScriptOutcome<T> {
+    pub fn into_result(self) -> Result<T, ScriptError> {
+        self.0
+    }
+}
+
+impl<T> From<ScriptOutcome<T>> for Result<T, ScriptError> {
+    fn from(outcome: ScriptOutcome<T>) -> Self {
+        outcome.0
+    }
+}
+
+impl<'lua, T: FromLua<'lua>> FromLua<'lua> for ScriptOutcome<T> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let outcome = match value {
+            Value::Table(outcome) => outcome,
+            value => return Err(conversion_error(value.type_name(), "ScriptOutcome")),
+        };
+        match outcome.get::<_, Value>("err")? {
+            Value::Nil => Ok(ScriptOutcome(Ok(T::from_lua(outcome.get("ok")?, lua)?))),
+            error => Ok(ScriptOutcome(Err(ScriptError::from_lua(error, lua)?))),
+        }
+    }
+}
+
+fn conversion_error(from: &'static str, to: &'static str) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from,
+        to,
+        message: Some("expected {ok = ...} or {err = ...}".to_string()),
+    }
+}
+
+const HELPERS: &str = r#"
+    local module = ...
+    function module.ok(value)
+      return { ok = value }
+    end
+    function module.err(message, range)
+      return { err = { message = message, range = range, traceback = debug.traceback(nil, 2) } }
+    end
+"#;
+
+/// Adds the `ok` and `err` helpers to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    lua.load(HELPERS)
+        .set_name("ltreesitter_rs outcomes")
+        .call(crate::companion_module(lua)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_convert_result_tables() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+
+        let ok: ScriptOutcome<i64> = l.call(r#" return { ok = 42 } "#);
+        assert_eq!(Ok(42), ok.into_result());
+
+        let err: ScriptOutcome<i64> = l.call(r#" return { err = "oops" } "#);
+        assert_eq!("oops", err.into_result().unwrap_err().message);
+
+        let err: ScriptOutcome<i64> = l.call(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local name = parsed:root():child(0):child_by_field_name("name")
+              return ltreesitter_rs.err("bad name", name)
+            "#,
+        );
+        let err = err.into_result().unwrap_err();
+        assert_eq!("bad name", err.message);
+        assert_eq!(Some(4..10), err.range.map(|r| r.start_byte..r.end_byte));
+        assert!(err.traceback.unwrap().contains("traceback"));
+        assert_eq!("bad name at 1:5", err.to_string());
+
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              assert(ltreesitter_rs.ok(1).ok == 1)
+            "#,
+        );
+    }
+}
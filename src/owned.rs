@@ -0,0 +1,112 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Tree;
+
+use crate::SharedSource;
+use crate::SourceStore;
+use crate::TreeWithSource;
+use crate::WithSource;
+
+/// The combination of a [`tree_sitter::Tree`] with the source code that it was parsed from, where
+/// the source is owned rather than borrowed.  This makes it easy to store in long-lived structs,
+/// or to send to another thread before pushing it into Lua.
+///
+/// `S` can be any owned buffer, such as a `Vec<u8>`, an `Arc<[u8]>`, or (the default) a
+/// [`SharedSource`].  Pushing a `TreeWithOwnedSource` into Lua hands the buffer to the Lua tree as
+/// its [source store][SourceStore].  A `SharedSource` isn't copied at all, so the host and the Lua
+/// state share a single copy of a large document, and the Lua tree keeps it alive for as long as
+/// the tree is live.  Other buffers are copied into the Lua state once.  Converting an ltreesitter
+/// tree into a `TreeWithOwnedSource` copies the tree's source out of the Lua state, so the result
+/// doesn't borrow anything from Lua.
+pub struct TreeWithOwnedSource<S = SharedSource> {
+    pub tree: Tree,
+    pub src: S,
+}
+
+impl<S> TreeWithOwnedSource<S>
+where
+    S: AsRef<[u8]>,
+{
+    /// Combines a tree with the source code that it was parsed from.
+    pub fn new(tree: Tree, src: S) -> TreeWithOwnedSource<S> {
+        TreeWithOwnedSource { tree, src }
+    }
+
+    /// Borrows the source, returning a [`TreeWithSource`] that holds a copy of the tree.
+    pub fn borrowed(&self) -> TreeWithSource<'_> {
+        crate::copies::copy_tree(&self.tree).with_source(self.src.as_ref())
+    }
+}
+
+impl<'lua, S> IntoLua<'lua> for TreeWithOwnedSource<S>
+where
+    S: AsRef<[u8]> + SourceStore,
+{
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        self.tree.with_source_store(self.src).into_lua(lua)
+    }
+}
+
+impl<'lua, S> FromLua<'lua> for TreeWithOwnedSource<S>
+where
+    S: AsRef<[u8]> + From<Vec<u8>>,
+{
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let borrowed = TreeWithSource::from_lua(value, lua)?;
+        let src = S::from(borrowed.src.to_vec());
+        Ok(TreeWithOwnedSource {
+            tree: borrowed.tree,
+            src,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_push_and_retrieve_owned_sources() {
+        let code = SharedSource::from("def double(x): return x * 2\n");
+        let tree = std::thread::spawn({
+            let code = code.clone();
+            move || {
+                let mut parser = tree_sitter::Parser::new();
+                parser.set_language(tree_sitter_python::language()).unwrap();
+                let parsed = parser.parse(&code, None).unwrap();
+                parsed.with_owned_source(code)
+            }
+        })
+        .join()
+        .unwrap();
+        assert_eq!("module", tree.borrowed().tree.root_node().kind());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", tree).unwrap();
+        l.check(
+            r#"
+              local name = parsed:root():child(0):child_by_field_name("name")
+              assert(name:source() == "double")
+            "#,
+        );
+        // The Lua tree reads from the shared buffer, instead of from a copy.
+        let shared: TreeWithSource = l.call(r#" return parsed "#);
+        assert_eq!(code.as_ptr(), shared.src.as_ptr());
+        drop(shared);
+        let owned: TreeWithOwnedSource<Vec<u8>> = l.call(r#" return parsed "#);
+        l.check(r#" parsed = nil; collectgarbage("collect") "#);
+        assert_eq!(&code[..], &owned.src[..]);
+        assert_eq!("module", owned.tree.root_node().kind());
+    }
+}
@@ -0,0 +1,217 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::ops::Deref;
+use std::ops::DerefMut;
+
+use mlua::FromLua;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::ffi;
+use tree_sitter::Language;
+use tree_sitter::Parser;
+use tree_sitter::Point;
+use tree_sitter::Range;
+
+use crate::ltreesitter;
+use crate::TSLanguage;
+
+/// A [`tree_sitter::Parser`], together with its configuration, which can be handed back and forth
+/// between Rust and Lua.
+///
+/// When a `TSParser` is pushed into Lua, it becomes an ltreesitter parser with the same language,
+/// timeout, and included ranges.  An ltreesitter parser can be converted back into a `TSParser`
+/// with the same configuration, so that Rust code can parse with whatever parser the Lua code set
+/// up.  (ltreesitter and the Rust bindings each need to own their parser, so the configuration is
+/// copied across, rather than the parser itself.)
+pub struct TSParser {
+    parser: Parser,
+    included_ranges: Vec<Range>,
+}
+
+impl TSParser {
+    /// Creates a new parser for a language.
+    pub fn new(language: Language) -> Result<TSParser, mlua::Error> {
+        let mut parser = Parser::new();
+        parser
+            .set_language(language)
+            .map_err(mlua::Error::external)?;
+        Ok(TSParser {
+            parser,
+            included_ranges: Vec::new(),
+        })
+    }
+
+    /// Sets the maximum duration, in microseconds, that parsing is allowed to take.
+    pub fn with_timeout_micros(mut self, timeout_micros: u64) -> TSParser {
+        self.parser.set_timeout_micros(timeout_micros);
+        self
+    }
+
+    /// Sets the ranges of the document that the parser should include.
+    pub fn with_included_ranges(mut self, ranges: &[Range]) -> Result<TSParser, mlua::Error> {
+        self.set_included_ranges(ranges)?;
+        Ok(self)
+    }
+
+    /// Sets the ranges of the document that the parser should include.  An empty list includes
+    /// the whole document.
+    pub fn set_included_ranges(&mut self, ranges: &[Range]) -> Result<(), mlua::Error> {
+        self.parser
+            .set_included_ranges(ranges)
+            .map_err(|_| mlua::Error::RuntimeError("invalid included ranges".to_string()))?;
+        self.included_ranges = ranges.to_vec();
+        Ok(())
+    }
+
+    /// Returns the ranges of the document that the parser includes.  An empty list means the
+    /// whole document.
+    pub fn included_ranges(&self) -> &[Range] {
+        &self.included_ranges
+    }
+}
+
+impl Deref for TSParser {
+    type Target = Parser;
+    fn deref(&self) -> &Self::Target {
+        &self.parser
+    }
+}
+
+impl DerefMut for TSParser {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.parser
+    }
+}
+
+fn to_ffi_point(point: Point) -> ffi::TSPoint {
+    ffi::TSPoint {
+        row: point.row as u32,
+        column: point.column as u32,
+    }
+}
+
+fn from_ffi_point(point: ffi::TSPoint) -> Point {
+    Point::new(point.row as usize, point.column as usize)
+}
+
+impl<'lua> mlua::IntoLua<'lua> for TSParser {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let language = self
+            .parser
+            .language()
+            .ok_or_else(|| mlua::Error::RuntimeError("parser has no language".to_string()))?;
+        let parser = ltreesitter::new_parser(lua, language)?;
+        let ptr = ltreesitter::as_parser(lua, &parser)?
+            .ok_or_else(|| mlua::Error::RuntimeError("cannot create parser".to_string()))?;
+        let ranges = self
+            .included_ranges
+            .iter()
+            .map(|range| ffi::TSRange {
+                start_point: to_ffi_point(range.start_point),
+                end_point: to_ffi_point(range.end_point),
+                start_byte: range.start_byte as u32,
+                end_byte: range.end_byte as u32,
+            })
+            .collect::<Vec<_>>();
+        unsafe {
+            ffi::ts_parser_set_timeout_micros((*ptr).parser, self.parser.timeout_micros());
+            if !ranges.is_empty() {
+                ffi::ts_parser_set_included_ranges(
+                    (*ptr).parser,
+                    ranges.as_ptr(),
+                    ranges.len() as u32,
+                );
+            }
+        }
+        Ok(parser)
+    }
+}
+
+impl<'lua> mlua::FromLua<'lua> for TSParser {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let ptr = ltreesitter::as_parser(lua, &value)?.ok_or_else(|| {
+            mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "TSParser",
+                message: Some("expected an ltreesitter parser".to_string()),
+            }
+        })?;
+        let language = TSLanguage::from_lua(value, lua)?;
+        let (timeout_micros, ranges) = unsafe {
+            let mut count = 0u32;
+            let ranges = ffi::ts_parser_included_ranges((*ptr).parser, &mut count);
+            let ranges: &[ffi::TSRange] = if ranges.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(ranges, count as usize)
+            };
+            let ranges = ranges
+                .iter()
+                // A parser that includes the whole document reports a single range that covers
+                // everything.
+                .filter(|range| !(range.start_byte == 0 && range.end_byte == u32::MAX))
+                .map(|range| Range {
+                    start_byte: range.start_byte as usize,
+                    end_byte: range.end_byte as usize,
+                    start_point: from_ffi_point(range.start_point),
+                    end_point: from_ffi_point(range.end_point),
+                })
+                .collect::<Vec<_>>();
+            (ffi::ts_parser_timeout_micros((*ptr).parser), ranges)
+        };
+        TSParser::new(*language)?
+            .with_timeout_micros(timeout_micros)
+            .with_included_ranges(&ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_share_parser_configuration() {
+        let code = "x = 1\ny = 2\n";
+        let second_line = Range {
+            start_byte: 6,
+            end_byte: 12,
+            start_point: Point::new(1, 0),
+            end_point: Point::new(2, 0),
+        };
+        let parser = TSParser::new(tree_sitter_python::language())
+            .unwrap()
+            .with_timeout_micros(5_000_000)
+            .with_included_ranges(&[second_line])
+            .unwrap();
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.globals().set("code", code).unwrap();
+        l.globals().set("parser", parser).unwrap();
+        l.check(
+            r#"
+              local root = parser:parse_string(code):root()
+              assert(root:child_count() == 1)
+              assert(root:child(0):source() == "y = 2")
+            "#,
+        );
+
+        let mut parser: TSParser = l.call(r#" return parser "#);
+        assert_eq!(5_000_000, parser.timeout_micros());
+        assert_eq!(&[second_line], parser.included_ranges());
+        let parsed = parser.parse(code, None).unwrap();
+        assert_eq!(1, parsed.root_node().child_count());
+
+        let parser: TSParser = l.call(r#" return require("ltreesitter").require("python") "#);
+        assert!(parser.included_ranges().is_empty());
+        assert!(l.load(r#" return 7 "#).eval::<TSParser>().is_err());
+    }
+}
@@ -0,0 +1,173 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Exposes tree-sitter's metadata about the patterns in a query.
+//!
+//! Schedulers such as incremental highlighters can use this to decide which patterns need to be
+//! rerun.  A pattern is _rooted_ if it has a single root node, so that all of its matches are
+//! contained within one node; a _non-local_ pattern can match sibling nodes that are spread out
+//! across a larger range, so its matches can be affected by edits farther away.
+//!
+//! In Lua, ltreesitter queries gain a `patterns()` method, which returns a list of tables with
+//! `index`, `start_byte`, `rooted`, and `non_local` fields (indexes are 1-based), and an
+//! `is_pattern_guaranteed_at_step(byte_offset)` method.  These only work for queries that were
+//! compiled via `parser:query` after the bridge was loaded.
+
+use mlua::AnyUserData;
+use mlua::Lua;
+use mlua::Table;
+use mlua::UserData;
+use mlua::Value;
+use tree_sitter::Query;
+
+use crate::grammars;
+use crate::ltreesitter;
+use crate::offsets::Offset;
+
+/// Information about one of the patterns in a query.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PatternMetadata {
+    /// The index of the pattern within its query.
+    pub index: usize,
+    /// The byte offset of the start of the pattern within the query's source.
+    pub start_byte: usize,
+    /// Whether the pattern has a single root node.
+    pub rooted: bool,
+    /// Whether the pattern can match nodes that are spread out across several siblings.
+    pub non_local: bool,
+}
+
+/// Returns the metadata of every pattern in a query.
+pub fn pattern_metadata(query: &Query) -> Vec<PatternMetadata> {
+    (0..query.pattern_count())
+        .map(|index| PatternMetadata {
+            index,
+            start_byte: query.start_byte_for_pattern(index),
+            rooted: query.is_pattern_rooted(index),
+            non_local: query.is_pattern_non_local(index),
+        })
+        .collect()
+}
+
+/// The Rust equivalent of an ltreesitter query, which is compiled the first time that we need it
+/// and then kept in the query's entry.
+struct CompiledQuery(Query);
+
+impl UserData for CompiledQuery {}
+
+/// Calls `f` with the Rust equivalent of an ltreesitter query, and the query's entry.
+pub(crate) fn with_compiled<'lua, R, F>(
+    lua: &'lua Lua,
+    query: &Value<'lua>,
+    f: F,
+) -> Result<R, mlua::Error>
+where
+    F: FnOnce(&Query, &Table<'lua>) -> Result<R, mlua::Error>,
+{
+    let (language, source, entry) = grammars::query_entry(lua, query)?.ok_or_else(|| {
+        mlua::Error::RuntimeError("query was not compiled via parser:query".to_string())
+    })?;
+    let compiled = match entry.get::<_, Option<AnyUserData>>("compiled")? {
+        Some(compiled) => compiled,
+        None => {
+            let query = Query::new(language, &source).map_err(mlua::Error::external)?;
+            let compiled = lua.create_userdata(CompiledQuery(query))?;
+            entry.set("compiled", compiled.clone())?;
+            compiled
+        }
+    };
+    let compiled = compiled.borrow::<CompiledQuery>()?;
+    f(&compiled.0, &entry)
+}
+
+fn lua_patterns<'lua>(lua: &'lua Lua, query: Value<'lua>) -> Result<Table<'lua>, mlua::Error> {
+    with_compiled(lua, &query, |rust_query, entry| {
+        if let Some(patterns) = entry.get::<_, Option<Table>>("patterns")? {
+            return Ok(patterns);
+        }
+        let patterns = lua.create_table()?;
+        for metadata in pattern_metadata(rust_query) {
+            let pattern = lua.create_table()?;
+            pattern.set("index", metadata.index + 1)?;
+            pattern.set("start_byte", Offset(metadata.start_byte))?;
+            pattern.set("rooted", metadata.rooted)?;
+            pattern.set("non_local", metadata.non_local)?;
+            patterns.raw_push(pattern)?;
+        }
+        entry.set("patterns", patterns.clone())?;
+        Ok(patterns)
+    })
+}
+
+/// Adds the pattern metadata methods to ltreesitter's query objects.
+pub(crate) fn install_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    let methods = ltreesitter::methods(lua, ltreesitter::QUERY_METATABLE)?;
+    methods.set("patterns", lua.create_function(lua_patterns)?)?;
+    methods.set(
+        "is_pattern_guaranteed_at_step",
+        lua.create_function(|lua, (query, byte_offset): (Value, usize)| {
+            with_compiled(lua, &query, |query, _| {
+                Ok(query.is_pattern_guaranteed_at_step(byte_offset))
+            })
+        })?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    // tree-sitter doesn't mark every pattern with several top-level nodes as non-local, only the
+    // ones whose first node can be a child of a repetition in the grammar.  Those repetitions are
+    // parsed into nested hidden nodes, so a sequence of matching siblings can be split across them.
+    // Python's statements are repeated that way inside of modules and blocks, so a sequence of
+    // statements is non-local; a sequence of nodes that don't repeat directly, like
+    // `(identifier) . (identifier)`, isn't.
+    const QUERY: &str = r#"
+        (function_definition name: (identifier) @name)
+        ((expression_statement) @a . (expression_statement) @b)
+    "#;
+
+    #[test]
+    fn can_describe_patterns() {
+        let query = Query::new(tree_sitter_python::language(), QUERY).unwrap();
+        let metadata = pattern_metadata(&query);
+        assert_eq!(2, metadata.len());
+        assert!(metadata[0].rooted && !metadata[0].non_local);
+        assert!(!metadata[1].rooted && metadata[1].non_local);
+        assert!(metadata[0].start_byte < metadata[1].start_byte);
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.globals().set("source", QUERY).unwrap();
+        l.check(
+            r#"
+              query = require("ltreesitter").require("python"):query(source)
+              local patterns = query:patterns()
+              assert(#patterns == 2)
+              assert(patterns[1].index == 1 and patterns[1].rooted)
+              assert(patterns[2].non_local)
+              assert(rawequal(patterns, query:patterns()))
+              assert(type(query:is_pattern_guaranteed_at_step(0)) == "boolean")
+            "#,
+        );
+
+        // The query is compiled once, and then reused.
+        let query: Value = l.globals().get("query").unwrap();
+        let compiled = || {
+            let (_, _, entry) = grammars::query_entry(&l, &query).unwrap().unwrap();
+            entry.get::<_, Value>("compiled").unwrap().to_pointer()
+        };
+        let first = compiled();
+        l.check(r#" query:is_pattern_guaranteed_at_step(1) "#);
+        assert_eq!(first, compiled());
+    }
+}
@@ -0,0 +1,180 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Serializes syntax trees as JSON in the shape that the tree-sitter web playground uses.
+//!
+//! Each node is an object with the same property names that web-tree-sitter's `SyntaxNode` uses:
+//!
+//! ``` json
+//! {
+//!   "type": "identifier", "fieldName": "name", "isNamed": true, "isMissing": false,
+//!   "startIndex": 4, "endIndex": 10,
+//!   "startPosition": { "row": 0, "column": 4 }, "endPosition": { "row": 0, "column": 10 },
+//!   "text": "double", "children": []
+//! }
+//! ```
+//!
+//! `fieldName` is `null` for nodes that aren't in a field, and `text` is only included for leaf
+//! nodes, and only if the source is available.  Like the playground, anonymous nodes are left out
+//! unless you ask for them.
+//!
+//! In Lua, `require("ltreesitter_rs").playground_json(tree_or_node, anonymous)` returns the same
+//! JSON.
+
+use std::fmt::Write;
+
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Node;
+use tree_sitter::Point;
+
+use crate::limits;
+use crate::pretty;
+
+/// Returns a JSON description of the syntax tree starting at `node`, in the shape that the
+/// tree-sitter web playground uses.  Anonymous nodes are only included if `anonymous` is true.
+pub fn playground_json(node: Node, src: Option<&[u8]>, anonymous: bool) -> String {
+    let mut json = String::new();
+    let mut cursor = node.walk();
+    // Whether we've written any children yet at each level that's currently open.
+    let mut open: Vec<bool> = Vec::new();
+    'nodes: loop {
+        let node = cursor.node();
+        let visible = anonymous || node.is_named() || open.is_empty();
+        if visible {
+            if let Some(wrote_child) = open.last_mut() {
+                if *wrote_child {
+                    json.push(',');
+                }
+                *wrote_child = true;
+            }
+            write_node(&mut json, node, cursor.field_name(), src);
+            if cursor.goto_first_child() {
+                json.push_str(",\"children\":[");
+                open.push(false);
+                continue;
+            }
+            json.push_str(",\"children\":[]}");
+        }
+        loop {
+            if open.is_empty() {
+                break 'nodes;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            cursor.goto_parent();
+            open.pop();
+            json.push_str("]}");
+        }
+    }
+    json
+}
+
+fn write_node(json: &mut String, node: Node, field_name: Option<&str>, src: Option<&[u8]>) {
+    json.push_str("{\"type\":");
+    write_string(json, node.kind());
+    json.push_str(",\"fieldName\":");
+    match field_name {
+        Some(field_name) => write_string(json, field_name),
+        None => json.push_str("null"),
+    }
+    let _ = write!(
+        json,
+        ",\"isNamed\":{},\"isMissing\":{},\"startIndex\":{},\"endIndex\":{}",
+        node.is_named(),
+        node.is_missing(),
+        node.start_byte(),
+        node.end_byte()
+    );
+    json.push_str(",\"startPosition\":");
+    write_point(json, node.start_position());
+    json.push_str(",\"endPosition\":");
+    write_point(json, node.end_position());
+    if node.child_count() == 0 {
+        if let Some(text) = src.and_then(|src| src.get(node.byte_range())) {
+            json.push_str(",\"text\":");
+            write_string(json, &String::from_utf8_lossy(text));
+        }
+    }
+}
+
+fn write_point(json: &mut String, point: Point) {
+    let _ = write!(
+        json,
+        "{{\"row\":{},\"column\":{}}}",
+        point.row, point.column
+    );
+}
+
+fn write_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Adds `playground_json` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let to_json = lua.create_function(|lua, (value, anonymous): (Value, Option<bool>)| {
+        let (node, src) = pretty::node_and_source(lua, value)?;
+        limits::check_depth(lua, node)?;
+        Ok(playground_json(node, src, anonymous.unwrap_or(false)))
+    })?;
+    crate::companion_module(lua)?.set("playground_json", to_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_export_playground_json() {
+        let code = b"x = \"a\\n\"\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let json = playground_json(parsed.root_node(), Some(code), false);
+        assert!(json.starts_with(r#"{"type":"module","fieldName":null,"isNamed":true,"#));
+        assert!(json.contains(r#""type":"identifier","fieldName":"left""#));
+        assert!(json.contains(r#""text":"x","children":[]}"#));
+        assert!(!json.contains(r#""type":"=""#));
+        assert!(json.contains(r#""type":"escape_sequence""#));
+        assert!(json.contains(r#""text":"\\n""#));
+        assert!(json.ends_with("]}]}]}"));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+
+        let with_anonymous = playground_json(parsed.root_node(), None, true);
+        assert!(with_anonymous.contains(r#""type":"=""#));
+        assert!(!with_anonymous.contains(r#""text""#));
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("expected", json).unwrap();
+        l.check(
+            r#"
+              local playground_json = require("ltreesitter_rs").playground_json
+              assert(playground_json(parsed) == expected)
+              assert(playground_json(parsed:root():child(0), true):find('"type":"="'))
+            "#,
+        );
+    }
+}
@@ -0,0 +1,154 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::ops::Deref;
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use tree_sitter::Point;
+use tree_sitter::Range;
+
+use crate::offsets::Offset;
+use crate::TSNode;
+
+/// A wrapper around a [`tree_sitter::Point`].  This only exists to get around Rust's orphan rules,
+/// so that we can implement the [`mlua::IntoLua`] and [`mlua::FromLua`] traits.
+///
+/// In Lua, a point is a table with (0-based) `row` and `column` fields, which is the shape that
+/// ltreesitter uses.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TSPoint(pub Point);
+
+impl Deref for TSPoint {
+    type Target = Point;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Point> for TSPoint {
+    fn from(point: Point) -> TSPoint {
+        TSPoint(point)
+    }
+}
+
+impl<'lua> IntoLua<'lua> for TSPoint {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        table.set("row", Offset(self.0.row))?;
+        table.set("column", Offset(self.0.column))?;
+        Ok(Value::Table(table))
+    }
+}
+
+impl<'lua> FromLua<'lua> for TSPoint {
+    fn from_lua(value: Value<'lua>, _lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let table = match value {
+            Value::Table(table) => table,
+            value => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "TSPoint",
+                    message: Some("expected a table with row and column fields".to_string()),
+                })
+            }
+        };
+        Ok(TSPoint(Point::new(
+            table.get::<_, Offset>("row")?.0,
+            table.get::<_, Offset>("column")?.0,
+        )))
+    }
+}
+
+/// A wrapper around a [`tree_sitter::Range`].  This only exists to get around Rust's orphan rules,
+/// so that we can implement the [`mlua::IntoLua`] and [`mlua::FromLua`] traits.
+///
+/// In Lua, a range is a table with `start_byte`, `end_byte`, `start_point`, and `end_point` fields,
+/// where the points are [`TSPoint`] tables.  A range can also be converted from an ltreesitter
+/// node, giving the range that the node spans.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TSRange(pub Range);
+
+impl Deref for TSRange {
+    type Target = Range;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Range> for TSRange {
+    fn from(range: Range) -> TSRange {
+        TSRange(range)
+    }
+}
+
+impl<'lua> IntoLua<'lua> for TSRange {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        table.set("start_byte", Offset(self.0.start_byte))?;
+        table.set("end_byte", Offset(self.0.end_byte))?;
+        table.set("start_point", TSPoint(self.0.start_point))?;
+        table.set("end_point", TSPoint(self.0.end_point))?;
+        Ok(Value::Table(table))
+    }
+}
+
+impl<'lua> FromLua<'lua> for TSRange {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let table: Table = match value {
+            Value::Table(table) => table,
+            node => return Ok(TSRange(TSNode::from_lua(node, lua)?.range())),
+        };
+        let start_point: TSPoint = table.get("start_point")?;
+        let end_point: TSPoint = table.get("end_point")?;
+        Ok(TSRange(Range {
+            start_byte: table.get::<_, Offset>("start_byte")?.0,
+            end_byte: table.get::<_, Offset>("end_byte")?.0,
+            start_point: start_point.0,
+            end_point: end_point.0,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_push_and_retrieve_points_and_ranges() {
+        let code = b"x = 1\ny = 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let second = parsed.root_node().child(1).unwrap().range();
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("point", TSPoint(Point::new(1, 4))).unwrap();
+        l.globals().set("range", TSRange(second)).unwrap();
+        l.check(
+            r#"
+              assert(point.row == 1 and point.column == 4)
+              assert(range.start_byte == 6 and range.end_byte == 11)
+              assert(range.start_point.row == 1 and range.end_point.column == 5)
+            "#,
+        );
+        let point: TSPoint = l.call(r#" return { row = 2, column = 3 } "#);
+        assert_eq!(Point::new(2, 3), *point);
+        let range: TSRange = l.call(r#" return range "#);
+        assert_eq!(second, *range);
+        let range: TSRange = l.call(r#" return parsed:root():child(1) "#);
+        assert_eq!(second, *range);
+        assert!(l.load(r#" return "1:4" "#).eval::<TSPoint>().is_err());
+    }
+}
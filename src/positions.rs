@@ -0,0 +1,295 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Fast positional queries, like "the closest enclosing function" or "the next class after the
+//! cursor", which editor motions and textobjects need constantly.
+//!
+//! Each function takes a list of node kinds, and finds the nearest node of any of those kinds.
+//! The searches skip over subtrees that can't contain a result, so they don't have to visit the
+//! whole tree.
+//!
+//! The same functions are available in Lua via `require("ltreesitter_rs")`, where `kinds` can be a
+//! single kind or a list of them (including supertypes, if the grammar's
+//! [node types][crate::NodeTypes] are registered), and the results are ltreesitter nodes (or
+//! `nil`):
+//!
+//! - `closest_ancestor_of_kind(node, kinds)`
+//! - `next_node_of_kind_after(tree_or_node, byte, kinds)`
+//! - `previous_node_of_kind_before(tree_or_node, byte, kinds)`
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Language;
+use tree_sitter::Node;
+
+use crate::ltreesitter;
+use crate::node_types;
+use crate::TSNode;
+
+/// Returns the closest proper ancestor of `node` whose kind is one of `kinds`.
+pub fn closest_ancestor_of_kind<'tree>(node: Node<'tree>, kinds: &[&str]) -> Option<Node<'tree>> {
+    let mut current = node.parent();
+    while let Some(node) = current {
+        if kinds.contains(&node.kind()) {
+            return Some(node);
+        }
+        current = node.parent();
+    }
+    None
+}
+
+/// Returns the first node within `root`, in document order, that starts at or after `byte` and
+/// whose kind is one of `kinds`.
+pub fn next_node_of_kind_after<'tree>(
+    root: Node<'tree>,
+    byte: usize,
+    kinds: &[&str],
+) -> Option<Node<'tree>> {
+    let mut cursor = root.walk();
+    let mut depth = 0;
+    loop {
+        let node = cursor.node();
+        if node.start_byte() >= byte && kinds.contains(&node.kind()) {
+            return Some(node);
+        }
+        // A subtree that ends before `byte` can't contain anything that starts after it.
+        if node.end_byte() >= byte && cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        loop {
+            if depth == 0 {
+                return None;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            cursor.goto_parent();
+            depth -= 1;
+        }
+    }
+}
+
+/// Returns the last node within `root`, in document order, that starts before `byte` and whose
+/// kind is one of `kinds`.
+pub fn previous_node_of_kind_before<'tree>(
+    root: Node<'tree>,
+    byte: usize,
+    kinds: &[&str],
+) -> Option<Node<'tree>> {
+    let mut found = None;
+    let mut cursor = root.walk();
+    let mut depth = 0;
+    loop {
+        let node = cursor.node();
+        // Every node in a subtree starts at or after the subtree's root, and every later sibling
+        // starts at or after this one ends.
+        if node.start_byte() >= byte {
+            return found;
+        }
+        if kinds.contains(&node.kind()) {
+            found = Some(node);
+        }
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        loop {
+            if depth == 0 {
+                return found;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            cursor.goto_parent();
+            depth -= 1;
+        }
+    }
+}
+
+/// A list of node kinds, which Lua code can give as a single string or a list of strings.
+struct Kinds(Vec<String>);
+
+impl<'lua> FromLua<'lua> for Kinds {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        match value {
+            Value::String(kind) => Ok(Kinds(vec![kind.to_str()?.to_string()])),
+            value => Ok(Kinds(Vec::from_lua(value, lua)?)),
+        }
+    }
+}
+
+impl Kinds {
+    /// Expands any supertypes, using the node types that were registered for `language`.
+    fn expand(self, lua: &Lua, language: Language) -> Kinds {
+        Kinds(node_types::expand_kinds(lua, language, self.0))
+    }
+
+    fn as_strs(&self) -> Vec<&str> {
+        self.0.iter().map(String::as_str).collect()
+    }
+}
+
+/// Resolves a Lua tree or node into a Lua node and the Rust node that it wraps.
+fn lua_root<'lua>(
+    lua: &'lua Lua,
+    value: Value<'lua>,
+) -> Result<(Value<'lua>, TSNode<'lua>), mlua::Error> {
+    let node = if ltreesitter::as_tree(lua, &value)?.is_some() {
+        let root: Function = ltreesitter::methods(lua, ltreesitter::TREE_METATABLE)?.get("root")?;
+        root.call(value)?
+    } else {
+        value
+    };
+    Ok((node.clone(), FromLua::from_lua(node, lua)?))
+}
+
+/// Returns the Lua node for `target`, which must be within the subtree of the Lua node `root`,
+/// whose Rust node is `root_node`.
+pub(crate) fn descendant_in_lua<'lua>(
+    lua: &'lua Lua,
+    root: Value<'lua>,
+    root_node: Node,
+    target: Node,
+) -> Result<Value<'lua>, mlua::Error> {
+    let mut path = Vec::new();
+    let mut current = target;
+    while current.id() != root_node.id() {
+        let parent = match current.parent() {
+            Some(parent) => parent,
+            None => return Ok(Value::Nil),
+        };
+        let mut cursor = parent.walk();
+        let index = parent
+            .children(&mut cursor)
+            .position(|child| child.id() == current.id())
+            .unwrap_or(0);
+        path.push(index);
+        current = parent;
+    }
+    let child: Function = ltreesitter::methods(lua, ltreesitter::NODE_METATABLE)?.get("child")?;
+    let mut node = root;
+    for index in path.into_iter().rev() {
+        node = child.call((node, index))?;
+    }
+    Ok(node)
+}
+
+/// Adds the positional queries to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = crate::companion_module(lua)?;
+    module.set(
+        "closest_ancestor_of_kind",
+        lua.create_function(|lua, (value, kinds): (Value, Kinds)| {
+            let node: TSNode = FromLua::from_lua(value.clone(), lua)?;
+            let kinds = kinds.expand(lua, node.language());
+            let found = match closest_ancestor_of_kind(*node, &kinds.as_strs()) {
+                Some(found) => found,
+                None => return Ok(Value::Nil),
+            };
+            let parent: Function =
+                ltreesitter::methods(lua, ltreesitter::NODE_METATABLE)?.get("parent")?;
+            let mut result = value;
+            let mut current = *node;
+            while current.id() != found.id() {
+                result = parent.call(result)?;
+                current = match current.parent() {
+                    Some(parent) => parent,
+                    None => break,
+                };
+            }
+            Ok(result)
+        })?,
+    )?;
+    module.set(
+        "next_node_of_kind_after",
+        lua.create_function(|lua, (value, byte, kinds): (Value, usize, Kinds)| {
+            let (root, root_node) = lua_root(lua, value)?;
+            let kinds = kinds.expand(lua, root_node.language());
+            match next_node_of_kind_after(*root_node, byte, &kinds.as_strs()) {
+                Some(found) => descendant_in_lua(lua, root, *root_node, found),
+                None => Ok(Value::Nil),
+            }
+        })?,
+    )?;
+    module.set(
+        "previous_node_of_kind_before",
+        lua.create_function(|lua, (value, byte, kinds): (Value, usize, Kinds)| {
+            let (root, root_node) = lua_root(lua, value)?;
+            let kinds = kinds.expand(lua, root_node.language());
+            match previous_node_of_kind_before(*root_node, byte, &kinds.as_strs()) {
+                Some(found) => descendant_in_lua(lua, root, *root_node, found),
+                None => Ok(Value::Nil),
+            }
+        })?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_find_nodes_by_position() {
+        let code = b"class A:\n    def f(self):\n        return 1\n\ndef g():\n    return 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let root = parsed.root_node();
+        let text =
+            |node: Option<Node>| node.map(|node| &code[node.start_byte()..node.start_byte() + 5]);
+
+        let one = root.descendant_for_byte_range(41, 42).unwrap();
+        assert_eq!("integer", one.kind());
+        let function = closest_ancestor_of_kind(one, &["function_definition"]);
+        assert_eq!(Some(&b"def f"[..]), text(function));
+        let class = closest_ancestor_of_kind(one, &["class_definition", "module"]);
+        assert_eq!(Some(&b"class"[..]), text(class));
+        assert!(closest_ancestor_of_kind(root, &["module"]).is_none());
+
+        let functions = ["function_definition"];
+        assert_eq!(
+            Some(&b"def f"[..]),
+            text(next_node_of_kind_after(root, 0, &functions))
+        );
+        assert_eq!(
+            Some(&b"def g"[..]),
+            text(next_node_of_kind_after(root, 14, &functions))
+        );
+        assert!(next_node_of_kind_after(root, 44, &["class_definition"]).is_none());
+        assert_eq!(
+            Some(&b"def g"[..]),
+            text(previous_node_of_kind_before(root, 60, &functions))
+        );
+        assert_eq!(
+            Some(&b"def f"[..]),
+            text(previous_node_of_kind_before(root, 44, &functions))
+        );
+        assert!(previous_node_of_kind_before(root, 0, &functions).is_none());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local g = ltreesitter_rs.next_node_of_kind_after(parsed, 14, "function_definition")
+              assert(g:source():sub(1, 5) == "def g")
+              local two = ltreesitter_rs.next_node_of_kind_after(g, 0, { "integer", "float" })
+              assert(two:source() == "2")
+              local enclosing = ltreesitter_rs.closest_ancestor_of_kind(two, "function_definition")
+              assert(enclosing:source() == g:source())
+              assert(ltreesitter_rs.previous_node_of_kind_before(parsed, 0, "class_definition") == nil)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Resolves conflicting captures from several layered queries, the way that editors resolve
+//! highlight queries that combine a language's defaults with user overrides.
+//!
+//! Each capture has a priority.  By default this is the priority of the layer that its query was
+//! added with, but a pattern can override it with a `(#set! priority N)` property.  When several
+//! captures cover exactly the same range of the source, only one of them is kept:
+//!
+//! 1. the capture with the highest priority wins;
+//! 2. among captures with the same priority, the one from the most recently added layer wins;
+//! 3. within a single layer, the capture from the earliest pattern wins, as in Helix.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use tree_sitter::Node;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+
+const PRIORITY: &str = "priority";
+
+struct Layer {
+    name: String,
+    priority: i64,
+    query: Query,
+}
+
+/// A set of layered queries whose captures are resolved by precedence.
+#[derive(Default)]
+pub struct QuerySet {
+    layers: Vec<Layer>,
+}
+
+/// A capture that won its precedence contest.
+#[derive(Clone, Debug)]
+pub struct ResolvedCapture<'tree> {
+    /// The captured node.
+    pub node: Node<'tree>,
+    /// The name of the capture, without the leading `@`.
+    pub name: String,
+    /// The name of the layer whose query produced the capture.
+    pub layer: String,
+    /// The index of the pattern within that layer's query.
+    pub pattern_index: usize,
+    /// The capture's effective priority.
+    pub priority: i64,
+}
+
+impl QuerySet {
+    /// Creates a new, empty query set.
+    pub fn new() -> QuerySet {
+        QuerySet::default()
+    }
+
+    /// Adds a query as a new layer, which takes precedence over all of the existing layers with
+    /// the same priority.
+    pub fn add_layer<N: Into<String>>(
+        &mut self,
+        name: N,
+        priority: i64,
+        query: Query,
+    ) -> &mut Self {
+        self.layers.push(Layer {
+            name: name.into(),
+            priority,
+            query,
+        });
+        self
+    }
+
+    /// Returns the number of layers in the set.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns whether the set has no layers.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Runs every layer's query over `node`, and returns the winning capture for each captured
+    /// range, ordered by start byte and then from the outermost range to the innermost.
+    pub fn resolve_captures<'tree>(
+        &self,
+        node: Node<'tree>,
+        src: &[u8],
+    ) -> Vec<ResolvedCapture<'tree>> {
+        // The key orders winners last: higher priority, later layer, earlier pattern.
+        type Key = (i64, usize, std::cmp::Reverse<usize>);
+        let mut winners: BTreeMap<(usize, std::cmp::Reverse<usize>), (Key, ResolvedCapture)> =
+            BTreeMap::new();
+        let mut cursor = QueryCursor::new();
+        for (layer_index, layer) in self.layers.iter().enumerate() {
+            let names = layer.query.capture_names();
+            for query_match in cursor.matches(&layer.query, node, src) {
+                let pattern_index = query_match.pattern_index;
+                let priority =
+                    pattern_priority(&layer.query, pattern_index).unwrap_or(layer.priority);
+                let key = (priority, layer_index, std::cmp::Reverse(pattern_index));
+                for capture in query_match.captures {
+                    let name = &names[capture.index as usize];
+                    // Captures whose names start with an underscore are only used by predicates.
+                    if name.starts_with('_') {
+                        continue;
+                    }
+                    let Range { start, end } = capture.node.byte_range();
+                    let range = (start, std::cmp::Reverse(end));
+                    if let Some((existing, _)) = winners.get(&range) {
+                        if *existing >= key {
+                            continue;
+                        }
+                    }
+                    let resolved = ResolvedCapture {
+                        node: capture.node,
+                        name: name.to_string(),
+                        layer: layer.name.clone(),
+                        pattern_index,
+                        priority,
+                    };
+                    winners.insert(range, (key, resolved));
+                }
+            }
+        }
+        winners.into_values().map(|(_, capture)| capture).collect()
+    }
+}
+
+/// Returns the priority that a pattern sets for itself via `(#set! priority N)`, if any.
+fn pattern_priority(query: &Query, pattern_index: usize) -> Option<i64> {
+    query
+        .property_settings(pattern_index)
+        .iter()
+        .filter(|property| &*property.key == PRIORITY)
+        .find_map(|property| property.value.as_deref()?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_resolve_captures_by_precedence() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let query = |source| Query::new(tree_sitter_python::language(), source).unwrap();
+
+        let mut set = QuerySet::new();
+        set.add_layer(
+            "defaults",
+            100,
+            query(
+                r#"
+                  (function_definition name: (identifier) @function)
+                  (identifier) @variable
+                  ((integer) @number (#set! priority 200))
+                "#,
+            ),
+        );
+        set.add_layer(
+            "user",
+            100,
+            query(
+                r#"
+                  (parameters (identifier) @parameter)
+                  (integer) @constant
+                "#,
+            ),
+        );
+        let resolved = set
+            .resolve_captures(parsed.root_node(), code)
+            .into_iter()
+            .map(|capture| {
+                let text = capture.node.utf8_text(code).unwrap();
+                format!("{}={}@{}", text, capture.name, capture.layer)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "double=function@defaults",
+                "x=parameter@user",
+                "x=variable@defaults",
+                "2=number@defaults",
+            ],
+            resolved
+        );
+    }
+}
@@ -0,0 +1,180 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Lets Rust closures implement the custom query predicates that Lua queries use.
+//!
+//! ltreesitter evaluates a query's custom predicates (like `#my-check?`) by calling functions from
+//! a table that Lua code attaches to the query via `query:with(predicates)`.
+//! [`QueryPredicates::register_predicate`] adds a Rust closure to every such table.  The bridge
+//! attaches the registered predicates to each query that `parser:query` compiles, and merges them
+//! into the table that Lua code passes to `query:with`, where predicates written in Lua take
+//! precedence.  Predicates are looked up when the query runs, so it doesn't matter whether a
+//! predicate is registered before or after the queries that use it are compiled.
+
+use mlua::FromLuaMulti;
+use mlua::Lua;
+use mlua::MaybeSend;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+
+use crate::grammars;
+use crate::ltreesitter;
+
+const PREDICATES_KEY: &str = "mlua_tree_sitter.predicates";
+
+/// An extension trait that lets you implement query predicates in Rust.
+pub trait QueryPredicates {
+    /// Registers a predicate that Lua queries can use.  `name` is the predicate's name without its
+    /// leading `#`, like `"my-check?"`.  The predicate's arguments (nodes for captures, strings for
+    /// literals) are converted via their [`FromLuaMulti`] implementations, so you can accept
+    /// ltreesitter nodes directly using [`TSNode`][crate::TSNode].  A match is kept if the
+    /// predicate returns `true`.
+    fn register_predicate<'lua, A, F>(&'lua self, name: &str, func: F) -> Result<(), mlua::Error>
+    where
+        A: FromLuaMulti<'lua>,
+        F: Fn(&'lua Lua, A) -> Result<bool, mlua::Error> + MaybeSend + 'static;
+}
+
+impl QueryPredicates for Lua {
+    fn register_predicate<'lua, A, F>(&'lua self, name: &str, func: F) -> Result<(), mlua::Error>
+    where
+        A: FromLuaMulti<'lua>,
+        F: Fn(&'lua Lua, A) -> Result<bool, mlua::Error> + MaybeSend + 'static,
+    {
+        let qualified = format!("#{}", name);
+        let function = self.create_function(move |lua, args: MultiValue<'lua>| {
+            let args = A::from_lua_multi(args, lua).map_err(|err| {
+                mlua::Error::RuntimeError(format!("bad arguments to {}: {}", qualified, err))
+            })?;
+            func(lua, args)
+        })?;
+        predicates(self)?.set(name, function)
+    }
+}
+
+fn predicates(lua: &Lua) -> Result<Table, mlua::Error> {
+    if let Some(predicates) = lua.named_registry_value::<Option<Table>>(PREDICATES_KEY)? {
+        return Ok(predicates);
+    }
+    let predicates = lua.create_table()?;
+    lua.set_named_registry_value(PREDICATES_KEY, predicates.clone())?;
+    Ok(predicates)
+}
+
+/// Returns a table of predicates that looks up the registered Rust predicates for any name that
+/// `overrides` doesn't define.
+fn merged_predicates<'lua>(
+    lua: &'lua Lua,
+    overrides: Option<Table<'lua>>,
+) -> Result<Table<'lua>, mlua::Error> {
+    let merged = lua.create_table()?;
+    if let Some(overrides) = overrides {
+        for pair in overrides.pairs::<Value, Value>() {
+            let (name, predicate) = pair?;
+            merged.raw_set(name, predicate)?;
+        }
+    }
+    let metatable = lua.create_table()?;
+    metatable.set("__index", predicates(lua)?)?;
+    merged.set_metatable(Some(metatable));
+    Ok(merged)
+}
+
+/// Attaches the registered predicates to compiled queries.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    predicates(lua)?;
+    ltreesitter::wrap_method(
+        lua,
+        ltreesitter::QUERY_METATABLE,
+        "with",
+        |lua, original, args| {
+            let mut args = args.into_iter();
+            let query = args.next().unwrap_or(Value::Nil);
+            let overrides = match args.next() {
+                Some(Value::Table(overrides)) => Some(overrides),
+                _ => None,
+            };
+            let result = original
+                .call::<_, MultiValue>((query.clone(), merged_predicates(lua, overrides)?))?;
+            if let Some(derived @ Value::UserData(_)) = result.iter().next() {
+                grammars::share_query_entry(lua, &query, derived.clone())?;
+            }
+            Ok(result)
+        },
+    )?;
+    ltreesitter::wrap_method(
+        lua,
+        ltreesitter::PARSER_METATABLE,
+        "query",
+        |lua, original, args| {
+            let result = original.call::<_, MultiValue>(args)?;
+            let query = match result.iter().next() {
+                Some(query @ Value::UserData(_)) => query.clone(),
+                _ => return Ok(result),
+            };
+            let with: Option<mlua::Function> =
+                ltreesitter::methods(lua, ltreesitter::QUERY_METATABLE)?.get("with")?;
+            match with {
+                Some(with) => with.call::<_, MultiValue>(query),
+                None => Ok(result),
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::TSNode;
+
+    #[test]
+    fn can_implement_predicates_in_rust() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.register_predicate("has-parent?", |_, (node, kind): (TSNode, String)| {
+            Ok(node.parent().map_or(false, |parent| parent.kind() == kind))
+        })
+        .unwrap();
+        l.check(
+            r#"
+              local parser = require("ltreesitter").require("python")
+              local tree = parser:parse_string("def double(x): return x * 2\n")
+              local function count(query)
+                local found = 0
+                for _ in query:match(tree:root()) do found = found + 1 end
+                return found
+              end
+              local query =
+                parser:query('((identifier) @id (#has-parent? @id "function_definition"))')
+              assert(count(query) == 1)
+              assert(count(parser:query("(identifier) @id")) == 3)
+              local overridden = query:with({ ["has-parent?"] = function() return true end })
+              assert(count(overridden) == 3)
+              assert(require("ltreesitter_rs").is_query(overridden))
+            "#,
+        );
+        l.register_predicate("never?", |_, _: TSNode| Ok(false))
+            .unwrap();
+        l.check(
+            r##"
+              local parser = require("ltreesitter").require("python")
+              local tree = parser:parse_string("x = 1\n")
+              local query = parser:query("((identifier) @id (#never? @id))")
+              for _ in query:match(tree:root()) do error("unexpected match") end
+              local _, err = pcall(function()
+                local bad = parser:query('((identifier) @id (#never? "x"))')
+                for _ in bad:match(tree:root()) do end
+              end)
+              assert(tostring(err):find("#never?", 1, true))
+            "##,
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! An optional library of pure-Lua helpers that most plugins end up writing for themselves.
+//!
+//! [`Module::open_ltreesitter_with_prelude`][crate::Module::open_ltreesitter_with_prelude] loads
+//! the prelude, which Lua code can then get via `require("ltreesitter_rs.prelude")` (or
+//! `require("ltreesitter_rs").prelude`).  It provides:
+//!
+//! - iterators: `children(node)`, `named_children(node)`, `descendants(node)` (in document
+//!   order, starting with `node` itself), and `ancestors(node)`
+//! - functional helpers over iterators: `filter(iter, pred)`, `map(iter, f)`, and `collect(iter)`
+//! - printers: `describe(node)`, which returns something like `identifier [0:4-0:10]`, and
+//!   `dump(node)`, which returns an indented outline of a node's named descendants
+//! - range helpers: `range(node)`, which returns a range table, `contains(node, byte)`, and
+//!   `text(node)`
+//!
+//! The prelude's `version` field holds [`PRELUDE_VERSION`], so that scripts can check for the
+//! helpers that they need.
+
+use mlua::Lua;
+use mlua::Table;
+
+/// The version of the Lua prelude.  This changes whenever helpers are added (a minor version) or
+/// changed incompatibly (a major version).
+pub const PRELUDE_VERSION: &str = "1.0";
+
+const PRELUDE: &str = r#"
+    local version = ...
+    local M = { version = version }
+
+    local function indexed(node, count, get)
+      local i = 0
+      local n = count(node)
+      return function()
+        if i >= n then return nil end
+        i = i + 1
+        return get(node, i - 1)
+      end
+    end
+
+    function M.children(node)
+      return indexed(node, node.child_count, node.child)
+    end
+
+    function M.named_children(node)
+      return indexed(node, node.named_child_count, node.named_child)
+    end
+
+    function M.descendants(node)
+      local stack = { node }
+      return function()
+        local next = table.remove(stack)
+        if next == nil then return nil end
+        for i = next:child_count() - 1, 0, -1 do
+          stack[#stack + 1] = next:child(i)
+        end
+        return next
+      end
+    end
+
+    function M.ancestors(node)
+      local current = node
+      return function()
+        current = current:parent()
+        return current
+      end
+    end
+
+    function M.filter(iter, pred)
+      return function()
+        while true do
+          local value = iter()
+          if value == nil or pred(value) then return value end
+        end
+      end
+    end
+
+    function M.map(iter, f)
+      return function()
+        local value = iter()
+        if value == nil then return nil end
+        return f(value)
+      end
+    end
+
+    function M.collect(iter)
+      local result = {}
+      for value in iter do result[#result + 1] = value end
+      return result
+    end
+
+    function M.describe(node)
+      local start, stop = node:start_point(), node:end_point()
+      return string.format(
+        "%s [%d:%d-%d:%d]", node:type(), start.row, start.column, stop.row, stop.column
+      )
+    end
+
+    function M.dump(node)
+      local lines = {}
+      local function visit(node, depth)
+        lines[#lines + 1] = string.rep("  ", depth) .. M.describe(node)
+        for child in M.named_children(node) do visit(child, depth + 1) end
+      end
+      visit(node, 0)
+      return table.concat(lines, "\n")
+    end
+
+    function M.range(node)
+      return {
+        start_byte = node:start_byte(),
+        end_byte = node:end_byte(),
+        start_point = node:start_point(),
+        end_point = node:end_point(),
+      }
+    end
+
+    function M.contains(node, byte)
+      return node:start_byte() <= byte and byte < node:end_byte()
+    end
+
+    function M.text(node)
+      return node:source()
+    end
+
+    return M
+"#;
+
+/// Loads the prelude into the `ltreesitter_rs.prelude` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let prelude: Table = lua
+        .load(PRELUDE)
+        .set_name("ltreesitter_rs prelude")
+        .call(PRELUDE_VERSION)?;
+    let loaded: Table = lua.globals().get::<_, Table>("package")?.get("loaded")?;
+    loaded.set("ltreesitter_rs.prelude", prelude.clone())?;
+    crate::companion_module(lua)?.set("prelude", prelude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_use_prelude_helpers() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter_with_prelude().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("version", PRELUDE_VERSION).unwrap();
+        l.check(
+            r#"
+              local prelude = require("ltreesitter_rs.prelude")
+              assert(prelude == require("ltreesitter_rs").prelude)
+              assert(prelude.version == version)
+              local root = parsed:root()
+              local func = root:child(0)
+              assert(#prelude.collect(prelude.children(func)) == func:child_count())
+              local named = prelude.collect(prelude.named_children(func))
+              assert(#named == func:named_child_count())
+              local ids = prelude.collect(prelude.map(
+                prelude.filter(prelude.descendants(root), function(node)
+                  return node:type() == "identifier"
+                end),
+                prelude.text
+              ))
+              assert(table.concat(ids, ",") == "double,x,x")
+              local name = func:child_by_field_name("name")
+              assert(prelude.describe(name) == "identifier [0:4-0:10]")
+              assert(#prelude.collect(prelude.ancestors(name)) == 2)
+              assert(prelude.dump(root):find("\n  function_definition", 1, true))
+              assert(prelude.range(name).end_byte == 10)
+              assert(prelude.contains(name, 4) and not prelude.contains(name, 10))
+            "#,
+        );
+
+        let plain = Lua::new();
+        plain.open_ltreesitter().unwrap();
+        assert!(plain
+            .load(r#" require("ltreesitter_rs.prelude") "#)
+            .exec()
+            .is_err());
+    }
+}
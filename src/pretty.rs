@@ -0,0 +1,162 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Prints syntax trees as indented outlines, with aligned byte ranges and source excerpts.
+//!
+//! The output is similar to what `tree-sitter parse` prints, but with the text of each node:
+//!
+//! ``` text
+//! module                        0..28  "def double(x): return x * 2\n"
+//!   function_definition         0..27  "def double(x): return x * 2"
+//!     name: identifier          4..10  "double"
+//! ```
+//!
+//! In Lua, `require("ltreesitter_rs").pretty_print(tree_or_node)` returns the same outline.
+
+use std::fmt::Write;
+
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Node;
+
+use crate::display::excerpt;
+use crate::limits;
+use crate::ltreesitter;
+use crate::stores;
+use crate::trees;
+use crate::TreeWithSource;
+
+/// Returns an indented outline of the named nodes in a syntax tree, starting at `node`.  If `src`
+/// is given, each line includes an excerpt of the node's text.
+pub fn pretty_print(node: Node, src: Option<&[u8]>) -> String {
+    let mut lines = Vec::new();
+    let mut cursor = node.walk();
+    let mut depth = 0;
+    'nodes: loop {
+        let node = cursor.node();
+        if node.is_named() {
+            let mut label = "  ".repeat(depth);
+            if let Some(field_name) = cursor.field_name() {
+                label.push_str(field_name);
+                label.push_str(": ");
+            }
+            label.push_str(node.kind());
+            let range = format!("{}..{}", node.start_byte(), node.end_byte());
+            let text = src.and_then(|src| src.get(node.byte_range())).map(excerpt);
+            lines.push((label, range, text));
+        }
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        loop {
+            if depth == 0 {
+                break 'nodes;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            cursor.goto_parent();
+            depth -= 1;
+        }
+    }
+
+    let label_width = lines.iter().map(|line| line.0.chars().count()).max();
+    let range_width = lines.iter().map(|line| line.1.len()).max();
+    let mut result = String::new();
+    for (label, range, text) in &lines {
+        let label_width = label_width.unwrap_or(0);
+        let range_width = range_width.unwrap_or(0);
+        let _ = match text {
+            Some(text) => writeln!(
+                result,
+                "{:label_width$}  {:>range_width$}  {}",
+                label, range, text
+            ),
+            None => writeln!(result, "{:label_width$}  {:>range_width$}", label, range),
+        };
+    }
+    result
+}
+
+impl TreeWithSource<'_> {
+    /// Returns an indented outline of the named nodes in this tree.
+    pub fn pretty_print(&self) -> String {
+        pretty_print(self.tree.root_node(), Some(self.src))
+    }
+}
+
+/// Resolves a Lua tree or node into the node to start from, along with its tree's source code if
+/// we can find it.
+pub(crate) fn node_and_source<'lua>(
+    lua: &'lua Lua,
+    value: Value<'lua>,
+) -> Result<(Node<'lua>, Option<&'lua [u8]>), mlua::Error> {
+    if let Some(ltreesitter_tree) = ltreesitter::as_tree(lua, &value)? {
+        if unsafe { (*ltreesitter_tree).tree.is_null() } {
+            return Err(trees::closed_error());
+        }
+        let src = stores::source(lua, &value)?;
+        return Ok((unsafe { ltreesitter::root_node(ltreesitter_tree) }, src));
+    }
+    let ltreesitter_node = ltreesitter::node_ptr(lua, value.clone())?;
+    let node = unsafe { (*ltreesitter_node).node };
+    let src = match trees::check_open(lua, &value)? {
+        Some(tree) => stores::source(lua, &tree)?,
+        None => None,
+    };
+    Ok((unsafe { Node::from_raw(node) }, src))
+}
+
+/// Adds `pretty_print` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let print = lua.create_function(|lua, value: Value| {
+        let (node, src) = node_and_source(lua, value)?;
+        limits::check_depth(lua, node)?;
+        Ok(pretty_print(node, src))
+    })?;
+    crate::companion_module(lua)?.set("pretty_print", print)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_pretty_print_trees() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap().with_source(code);
+        let printed = parsed.pretty_print();
+        let lines = printed.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("module "));
+        assert!(lines[0].ends_with(r#"0..28  "def double(x): return x * 2\n""#));
+        assert!(lines[2].starts_with("    name: identifier "));
+        assert!(lines[2].ends_with(r#"4..10  "double""#));
+        let text_columns = lines
+            .iter()
+            .map(|line| line.find("  \""))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(1, text_columns.len(), "expected aligned columns");
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        l.globals().set("expected", printed).unwrap();
+        let from_lua: String = l.call(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              assert(ltreesitter_rs.pretty_print(parsed) == expected)
+              return ltreesitter_rs.pretty_print(parsed:root():child(0))
+            "#,
+        );
+        assert!(from_lua.starts_with("function_definition"));
+    }
+}
@@ -0,0 +1,283 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::ops::Deref;
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Language;
+use tree_sitter::Node;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+use tree_sitter::QueryError;
+
+use crate::grammars;
+use crate::ltreesitter;
+use crate::recording;
+use crate::TSQueryCapture;
+use crate::TSQueryMatch;
+use crate::TextPredicates;
+use crate::TreeWithSource;
+
+/// A tree-sitter query that was compiled in Rust, which can be pushed into Lua as an ltreesitter
+/// query.
+///
+/// This lets you compile queries with [`tree_sitter::Query::new`], so that you get its detailed
+/// error reporting, and only hand them to Lua once you know that they're valid.  The Rust query
+/// API doesn't give up ownership of its underlying `TSQuery`, so the ltreesitter query is compiled
+/// again from the same source when it's pushed into Lua; that can't fail, since the source has
+/// already been checked.
+///
+/// Going the other way, an ltreesitter query can be converted back into a `TSQuery`, so that you
+/// can run it with a [`tree_sitter::QueryCursor`] over trees that are held in Rust.  That only
+/// works for queries that were compiled via `parser:query` after the bridge was loaded, since
+/// those are the only ones whose grammar and source we know.
+pub struct TSQuery {
+    query: Query,
+    language: Language,
+    source: String,
+}
+
+impl TSQuery {
+    /// Compiles a query for a language.
+    pub fn new(language: Language, source: &str) -> Result<TSQuery, QueryError> {
+        Ok(TSQuery {
+            query: Query::new(language, source)?,
+            language,
+            source: source.to_string(),
+        })
+    }
+
+    /// Returns the language that the query was compiled for.
+    pub fn language(&self) -> Language {
+        self.language
+    }
+
+    /// Returns the source of the query.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the compiled query.
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+}
+
+impl Deref for TSQuery {
+    type Target = Query;
+    fn deref(&self) -> &Self::Target {
+        &self.query
+    }
+}
+
+impl<'lua> mlua::IntoLua<'lua> for TSQuery {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let parser = ltreesitter::new_parser(lua, self.language)?;
+        let query: Function =
+            ltreesitter::methods(lua, ltreesitter::PARSER_METATABLE)?.get("query")?;
+        query.call((parser, self.source))
+    }
+}
+
+impl<'lua> FromLua<'lua> for TSQuery {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let conversion_error = |message: String| mlua::Error::FromLuaConversionError {
+            from: value.type_name(),
+            to: "TSQuery",
+            message: Some(message),
+        };
+        if !ltreesitter::has_metatable(lua, &value, ltreesitter::QUERY_METATABLE)? {
+            return Err(conversion_error(
+                "expected an ltreesitter query".to_string(),
+            ));
+        }
+        let (language, source, _) = grammars::query_entry(lua, &value)?.ok_or_else(|| {
+            conversion_error("query was not compiled via parser:query".to_string())
+        })?;
+        TSQuery::new(language, &source).map_err(|err| conversion_error(err.to_string()))
+    }
+}
+
+/// Runs an ltreesitter query over a tree natively, with a [`tree_sitter::QueryCursor`], instead
+/// of iterating `query:match` in Lua.
+///
+/// The query must have been compiled via `parser:query` (see [`TSQuery`]).  Its `#eq?`,
+/// `#match?`, and `#any-of?` predicates are evaluated with [`TextPredicates`].  The matches are
+/// collected in Rust; each one is only converted into a Lua match table if you push it into Lua.
+/// That only works if `tree` was converted from an ltreesitter tree, so that its nodes can be
+/// converted into ltreesitter nodes.
+pub fn run_query<'t, 'lua>(
+    lua: &'lua Lua,
+    query: Value<'lua>,
+    tree: &'t TreeWithSource<'_>,
+) -> Result<impl Iterator<Item = TSQueryMatch<'t>>, mlua::Error> {
+    run_query_at(lua, query, tree, tree.tree.root_node())
+}
+
+/// Runs an ltreesitter query natively over the subtree of `tree` rooted at `node`.
+pub(crate) fn run_query_at<'t, 'lua>(
+    lua: &'lua Lua,
+    query: Value<'lua>,
+    tree: &'t TreeWithSource<'_>,
+    node: Node<'t>,
+) -> Result<impl Iterator<Item = TSQueryMatch<'t>>, mlua::Error> {
+    let query = TSQuery::from_lua(query, lua)?;
+    let predicates = TextPredicates::new(&query)?;
+    let names = query.capture_names();
+    let mut cursor = QueryCursor::new();
+    let matches = cursor
+        .matches(&query, node, tree.src)
+        .filter(|m| predicates.satisfied(m, tree.src))
+        .map(|m| TSQueryMatch {
+            pattern_index: m.pattern_index,
+            captures: m
+                .captures
+                .iter()
+                .map(|capture| TSQueryCapture {
+                    name: names[capture.index as usize].to_string(),
+                    node: tree.lua_node(capture.node),
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    if recording::is_recording(lua) {
+        record_matches(lua, &query, tree, node, &matches);
+    }
+    Ok(matches.into_iter())
+}
+
+fn record_matches(
+    lua: &Lua,
+    query: &TSQuery,
+    tree: &TreeWithSource,
+    node: Node,
+    matches: &[TSQueryMatch],
+) {
+    let mut hasher = recording::StableHasher::new();
+    hasher.write(query.source().as_bytes());
+    hasher.write_u64(recording::hash_node_contents(&node, Some(tree.src)));
+    let input = hasher.finish();
+    let mut hasher = recording::StableHasher::new();
+    for m in matches {
+        let captures = m
+            .captures
+            .iter()
+            .map(|capture| {
+                let name = capture.name.as_bytes().to_vec();
+                (name, recording::hash_node(&capture.node))
+            })
+            .collect();
+        recording::write_match(&mut hasher, m.pattern_index as i64, captures);
+    }
+    let output = hasher.finish();
+    let replay = tree
+        .anchor
+        .0
+        .as_ref()
+        .and_then(|(lua, value, _)| recording::tree_index(lua, value))
+        .map(|index| recording::ReplayStep::RunQuery {
+            tree: index,
+            path: recording::node_path(node),
+            query: query.source().to_string(),
+        });
+    recording::record(lua, "run_query", input, output, replay);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_push_queries_compiled_in_rust() {
+        let python = tree_sitter_python::language();
+        assert!(TSQuery::new(python, "(not_a_node) @x").is_err());
+        let query = TSQuery::new(python, "(identifier) @id").unwrap();
+        assert_eq!(1, query.pattern_count());
+
+        let code = b"x = y\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(python).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("query", query).unwrap();
+        l.check(
+            r#"
+              local ids = {}
+              for match in query:match(parsed:root()) do
+                ids[#ids + 1] = match.captures.id:source()
+              end
+              assert(#ids == 2 and ids[1] == "x" and ids[2] == "y")
+            "#,
+        );
+    }
+
+    #[test]
+    fn can_retrieve_queries_compiled_in_lua() {
+        let python = tree_sitter_python::language();
+        let code = b"def f(x): return x\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(python).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("python", crate::TSLanguage(python))
+            .unwrap();
+        let query: TSQuery = l.call(
+            r#"
+              return python:parser():query("(function_definition name: (identifier) @name)")
+            "#,
+        );
+        let mut cursor = QueryCursor::new();
+        let names = cursor
+            .matches(&query, parsed.root_node(), &code[..])
+            .flat_map(|m| m.captures.iter().map(|c| c.node.utf8_text(code).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["f"], names);
+        assert!(l.load(r#" return 7 "#).eval::<TSQuery>().is_err());
+    }
+
+    #[test]
+    fn can_run_lua_queries_natively() {
+        let python = tree_sitter_python::language();
+        let code = b"def f(x): return x\ndef g(): pass\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(python).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals()
+            .set("python", crate::TSLanguage(python))
+            .unwrap();
+        let query: Value = l.call(
+            r#" return python:parser():query("(function_definition name: (identifier) @name)") "#,
+        );
+        let tree: TreeWithSource = l.call(r#" return parsed "#);
+        let matches = run_query(&l, query, &tree).unwrap().collect::<Vec<_>>();
+        let names = matches
+            .iter()
+            .map(|m| m.get("name").unwrap().utf8_text(code).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["f", "g"], names);
+
+        let first = matches.into_iter().next().unwrap();
+        l.globals().set("first", first).unwrap();
+        l.check(
+            r#"
+              assert(first.pattern == 0 and first.captures.name:source() == "f")
+            "#,
+        );
+    }
+}
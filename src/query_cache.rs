@@ -0,0 +1,228 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Memoizes the results of running ltreesitter queries over trees.
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+
+use crate::grammars;
+use crate::ltreesitter;
+use crate::recording;
+use crate::TSNode;
+
+const CACHE_KEY: &str = "mlua_tree_sitter.query_cache";
+const COLLECT_MATCHES_KEY: &str = "mlua_tree_sitter.collect_matches";
+
+const COLLECT_MATCHES: &str = r#"
+    local query, tree = ...
+    local matches = {}
+    for match in query:match(tree:root()) do
+      matches[#matches + 1] = match
+    end
+    return matches
+"#;
+
+/// An extension trait that lets you memoize the results of running ltreesitter queries.
+///
+/// Once the cache is enabled, Lua code can call `require("ltreesitter_rs").cached_matches(query,
+/// tree)` to get a list of the match tables that `query:match(tree:root())` produces.  The list is
+/// only computed once for each (tree, query) pair.  Cached results are invalidated whenever the
+/// tree is edited via `tree:edit`; reparsing produces a new tree, which starts off with an empty
+/// cache.  The cache holds its trees and queries weakly, so it won't keep them from being
+/// garbage-collected.
+pub trait QueryCache {
+    /// Enables the query cache.  You must load the `ltreesitter` module first.
+    fn enable_query_cache(&self) -> Result<(), mlua::Error>;
+
+    /// Returns the (possibly cached) list of matches of `query` over `tree`.
+    fn cached_matches<'lua>(
+        &'lua self,
+        query: Value<'lua>,
+        tree: Value<'lua>,
+    ) -> Result<Table<'lua>, mlua::Error>;
+
+    /// Discards all cached query results.
+    fn clear_query_cache(&self) -> Result<(), mlua::Error>;
+}
+
+impl QueryCache for Lua {
+    fn enable_query_cache(&self) -> Result<(), mlua::Error> {
+        self.set_named_registry_value(CACHE_KEY, crate::weak_table(self, "k")?)?;
+        let collect_matches = self
+            .load(COLLECT_MATCHES)
+            .set_name("collect_matches")
+            .into_function()?;
+        self.set_named_registry_value(COLLECT_MATCHES_KEY, collect_matches)?;
+
+        for method in ["edit", "edit_s"] {
+            ltreesitter::wrap_method(
+                self,
+                ltreesitter::TREE_METATABLE,
+                method,
+                |lua, original, args| {
+                    if let Some(tree) = args.iter().next() {
+                        let cache: Table = lua.named_registry_value(CACHE_KEY)?;
+                        cache.raw_set(tree.clone(), Value::Nil)?;
+                    }
+                    original.call::<_, MultiValue>(args)
+                },
+            )?;
+        }
+
+        let cached_matches = self.create_function(|lua, (query, tree): (Value, Value)| {
+            lua.cached_matches(query, tree)
+        })?;
+        crate::companion_module(self)?.set("cached_matches", cached_matches)?;
+        Ok(())
+    }
+
+    fn cached_matches<'lua>(
+        &'lua self,
+        query: Value<'lua>,
+        tree: Value<'lua>,
+    ) -> Result<Table<'lua>, mlua::Error> {
+        // Make sure that we were actually given a tree.
+        let ltreesitter_tree = ltreesitter::tree_ptr(self, tree.clone())?;
+        let matches = lookup_matches(self, query.clone(), tree.clone())?;
+        if recording::is_recording(self) {
+            let input = unsafe {
+                recording::hash_tree(
+                    ltreesitter::root_node(ltreesitter_tree),
+                    ltreesitter::source(ltreesitter_tree),
+                )
+            };
+            // Hashing the matches converts their nodes, which isn't a call to record.
+            let output = recording::without_recording(self, || hash_matches(self, &matches))?;
+            let tree = recording::tree_index(self, &tree);
+            let replay = match (tree, grammars::query_entry(self, &query)?) {
+                (Some(tree), Some((_, query, _))) => {
+                    Some(recording::ReplayStep::CachedMatches { tree, query })
+                }
+                _ => None,
+            };
+            recording::record(self, "cached_matches", input, output, replay);
+        }
+        Ok(matches)
+    }
+
+    fn clear_query_cache(&self) -> Result<(), mlua::Error> {
+        self.set_named_registry_value(CACHE_KEY, crate::weak_table(self, "k")?)
+    }
+}
+
+/// Discards the cached query results for a tree.
+pub(crate) fn forget_tree<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Result<(), mlua::Error> {
+    match lua.named_registry_value::<Option<Table>>(CACHE_KEY)? {
+        Some(cache) => cache.raw_set(tree.clone(), Value::Nil),
+        None => Ok(()),
+    }
+}
+
+/// Returns the cached list of matches of `query` over `tree`, computing it if necessary.
+fn lookup_matches<'lua>(
+    lua: &'lua Lua,
+    query: Value<'lua>,
+    tree: Value<'lua>,
+) -> Result<Table<'lua>, mlua::Error> {
+    let cache: Option<Table> = lua.named_registry_value(CACHE_KEY)?;
+    let cache = cache.ok_or_else(|| {
+        mlua::Error::RuntimeError("the query cache has not been enabled".to_string())
+    })?;
+    let per_tree = match cache.raw_get::<_, Option<Table>>(tree.clone())? {
+        Some(per_tree) => per_tree,
+        None => {
+            let per_tree = crate::weak_table(lua, "k")?;
+            cache.raw_set(tree.clone(), per_tree.clone())?;
+            per_tree
+        }
+    };
+    if let Some(matches) = per_tree.raw_get::<_, Option<Table>>(query.clone())? {
+        return Ok(matches);
+    }
+
+    let collect_matches: Function = lua.named_registry_value(COLLECT_MATCHES_KEY)?;
+    let matches: Table = collect_matches.call((query.clone(), tree))?;
+    per_tree.raw_set(query, matches.clone())?;
+    Ok(matches)
+}
+
+/// Hashes the pattern indices and captured nodes of a list of match tables.
+fn hash_matches<'lua>(lua: &'lua Lua, matches: &Table<'lua>) -> Result<u64, mlua::Error> {
+    let mut hasher = recording::StableHasher::new();
+    for m in matches.clone().sequence_values::<Table>() {
+        let m = m?;
+        let pattern = m.get::<_, Option<i64>>("pattern")?.unwrap_or(-1);
+        let mut captures = Vec::new();
+        if let Some(captured) = m.get::<_, Option<Table>>("captures")? {
+            for pair in captured.pairs::<Value, Value>() {
+                let (name, node) = pair?;
+                if let (Value::String(name), Ok(node)) = (name, TSNode::from_lua(node, lua)) {
+                    captures.push((name.as_bytes().to_vec(), recording::hash_node(&node)));
+                }
+            }
+        }
+        recording::write_match(&mut hasher, pattern, captures);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn caches_matches_until_tree_is_edited() {
+        let code = br#"
+          def double(x):
+              return x * 2
+        "#;
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.enable_query_cache().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local query = require("ltreesitter").require("python"):query([[
+                (function_definition name: (identifier) @name)
+                (identifier) @id
+              ]])
+
+              local first = ltreesitter_rs.cached_matches(query, parsed)
+              local second = ltreesitter_rs.cached_matches(query, parsed)
+              assert(first == second, "expected cached result")
+              assert(#first == 4, "expected four matches")
+              local names = {}
+              for _, match in ipairs(first) do
+                if match.captures.name then names[#names + 1] = match.captures.name:source() end
+              end
+              assert(#names == 1 and names[1] == "double", "expected one function name")
+
+              local other = require("ltreesitter").require("python"):query("(identifier) @id")
+              assert(#ltreesitter_rs.cached_matches(other, parsed) == 3)
+              assert(ltreesitter_rs.cached_matches(query, parsed) == first)
+
+              parsed:edit(0, 0, 1, 0, 0, 0, 0, 0, 1)
+              local third = ltreesitter_rs.cached_matches(query, parsed)
+              assert(first ~= third, "expected edit to invalidate cache")
+              assert(#third == 4, "expected the same matches after edit")
+            "#,
+        );
+    }
+}
@@ -0,0 +1,216 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Loads queries from `.scm` files, following the `; inherits:` comments that nvim-treesitter and
+//! Helix use to share patterns between languages.
+//!
+//! Query files are expected to live in a `queries/<language>/<name>.scm` layout.  A modeline like
+//!
+//! ``` scheme
+//! ; inherits: ecma,jsx
+//! ```
+//!
+//! at the top of `queries/javascript/highlights.scm` includes the contents of
+//! `queries/ecma/highlights.scm` and `queries/jsx/highlights.scm` before the file's own patterns.
+//! Entries in parentheses, like `(jsx)`, are optional, and are skipped if that file doesn't exist.
+//! Entries that end in `.scm` are paths relative to the including file.  Each file is included at
+//! most once, even if several files inherit from it.
+//!
+//! In Lua, `require("ltreesitter_rs").load_query_file(language, path)` compiles a query file for a
+//! [language][crate::TSLanguage] or an ltreesitter parser, and `read_query_file(path)` returns the
+//! resolved query source.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use mlua::Lua;
+use tree_sitter::Language;
+use tree_sitter::Query;
+
+use crate::abi;
+use crate::sandbox;
+use crate::TSLanguage;
+use crate::TSQuery;
+
+const INHERITS: &str = "inherits:";
+
+/// Reads a query file, returning its contents with all of the files it inherits from prepended.
+pub fn read_query_file<P: AsRef<Path>>(path: P) -> Result<String, mlua::Error> {
+    let mut visited = HashSet::new();
+    let mut result = String::new();
+    include(path.as_ref(), &mut visited, &mut result)?;
+    Ok(result)
+}
+
+/// Reads a query file, following its includes, and compiles it for a language.
+pub fn load_query_file<P: AsRef<Path>>(language: Language, path: P) -> Result<Query, mlua::Error> {
+    let source = read_query_file(path.as_ref())?;
+    Query::new(language, &source)
+        .map_err(|err| mlua::Error::RuntimeError(format!("{}: {}", path.as_ref().display(), err)))
+}
+
+fn include(
+    path: &Path,
+    visited: &mut HashSet<PathBuf>,
+    result: &mut String,
+) -> Result<(), mlua::Error> {
+    let canonical = path.canonicalize().map_err(|err| {
+        mlua::Error::RuntimeError(format!("cannot read {}: {}", path.display(), err))
+    })?;
+    if !visited.insert(canonical) {
+        return Ok(());
+    }
+    let source = std::fs::read_to_string(path).map_err(|err| {
+        mlua::Error::RuntimeError(format!("cannot read {}: {}", path.display(), err))
+    })?;
+    for line in source.lines() {
+        let line = line.trim();
+        if !line.starts_with(';') {
+            if line.is_empty() {
+                continue;
+            }
+            break;
+        }
+        let Some(inherits) = line.trim_start_matches(';').trim().strip_prefix(INHERITS) else {
+            continue;
+        };
+        for entry in inherits.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (entry, optional) = match entry.strip_prefix('(').and_then(|e| e.strip_suffix(')'))
+            {
+                Some(entry) => (entry, true),
+                None => (entry, false),
+            };
+            let inherited = inherited_path(path, entry);
+            if optional && !inherited.exists() {
+                continue;
+            }
+            include(&inherited, visited, result)?;
+        }
+    }
+    result.push_str(&source);
+    if !source.ends_with('\n') {
+        result.push('\n');
+    }
+    Ok(())
+}
+
+/// Returns the path of a file that `path` inherits from.  `entry` is either a path relative to
+/// `path`, or the name of a sibling language directory.
+fn inherited_path(path: &Path, entry: &str) -> PathBuf {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if entry.ends_with(".scm") {
+        return dir.join(entry);
+    }
+    let file_name = path.file_name().unwrap_or_default();
+    dir.parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(entry)
+        .join(file_name)
+}
+
+/// Adds `read_query_file` and `load_query_file` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = crate::companion_module(lua)?;
+    module.set(
+        "read_query_file",
+        lua.create_function(|lua, path: String| {
+            sandbox::check_allowed(lua, "read_query_file")?;
+            read_query_file(path)
+        })?,
+    )?;
+    module.set(
+        "load_query_file",
+        lua.create_function(|lua, (language, path): (TSLanguage, String)| {
+            sandbox::check_allowed(lua, "load_query_file")?;
+            abi::check_language(lua, *language)?;
+            let source = read_query_file(&path)?;
+            TSQuery::new(*language, &source)
+                .map_err(|err| mlua::Error::RuntimeError(format!("{}: {}", path, err)))
+        })?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_load_query_files_with_includes() {
+        let root =
+            std::env::temp_dir().join(format!("mlua-tree-sitter-queries-{}", std::process::id()));
+        let queries = root.join("queries");
+        std::fs::create_dir_all(queries.join("base")).unwrap();
+        std::fs::create_dir_all(queries.join("python")).unwrap();
+        std::fs::write(
+            queries.join("base/tags.scm"),
+            "(function_definition name: (identifier) @name) @function\n",
+        )
+        .unwrap();
+        std::fs::write(
+            queries.join("python/shared.scm"),
+            "; inherits: ../base/tags.scm\n(identifier) @identifier\n",
+        )
+        .unwrap();
+        std::fs::write(
+            queries.join("python/tags.scm"),
+            "; inherits: base,(missing)\n; inherits: shared.scm\n(class_definition) @class",
+        )
+        .unwrap();
+
+        let path = queries.join("python/tags.scm");
+        let source = read_query_file(&path).unwrap();
+        assert_eq!(1, source.matches("@function").count());
+        assert!(source.find("@function") < source.find("@identifier"));
+        assert!(source.find("@identifier") < source.find("@class"));
+        let query = load_query_file(tree_sitter_python::language(), &path).unwrap();
+        assert_eq!(3, query.pattern_count());
+
+        let code = b"class A:\n  def f(self): pass\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.globals()
+            .set("python", TSLanguage(tree_sitter_python::language()))
+            .unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals()
+            .set("path", path.to_string_lossy().to_string())
+            .unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local function count(query)
+                local captures = {}
+                for _, name in query:capture(parsed:root()) do
+                  captures[name] = (captures[name] or 0) + 1
+                end
+                return captures
+              end
+              local query = ltreesitter_rs.load_query_file(python, path)
+              local captures = count(query)
+              assert(captures["class"] == 1 and captures["function"] == 1)
+              assert(captures["name"] == 1 and captures["identifier"] == 3)
+
+              local parser = require("ltreesitter").require("python")
+              local from_parser = ltreesitter_rs.load_query_file(parser, path)
+              assert(count(from_parser)["identifier"] == 3)
+
+              assert(not pcall(ltreesitter_rs.load_query_file, "python", path))
+              assert(not pcall(ltreesitter_rs.read_query_file, path .. ".missing"))
+            "#,
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
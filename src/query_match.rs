@@ -0,0 +1,176 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+use std::collections::BTreeMap;
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+
+use crate::ltreesitter;
+use crate::LuaNode;
+
+/// A single capture of a query match that Lua code returned to Rust.
+///
+/// This can be converted from a Lua table with `name` and `node` fields.
+pub struct TSQueryCapture<'lua> {
+    pub name: String,
+    pub node: LuaNode<'lua>,
+}
+
+impl<'lua> FromLua<'lua> for TSQueryCapture<'lua> {
+    fn from_lua(value: Value<'lua>, _lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let table = match value {
+            Value::Table(table) => table,
+            value => return Err(conversion_error(value, "TSQueryCapture")),
+        };
+        Ok(TSQueryCapture {
+            name: table.get("name")?,
+            node: table.get("node")?,
+        })
+    }
+}
+
+/// A query match that Lua code returned to Rust, like the ones that ltreesitter's `query:match`
+/// produces.
+///
+/// This can be converted from a Lua table with `pattern` and `captures` fields, where `captures`
+/// maps each capture name to a node, or to a list of nodes for quantified captures.  The captures
+/// are sorted by where their nodes start, and then by name, since Lua tables don't have an order.
+/// Pushing a `TSQueryMatch` into Lua creates the same kind of table, which only works if its nodes
+/// know which Lua tree they belong to (like the matches that [`run_query`][crate::run_query]
+/// returns).
+pub struct TSQueryMatch<'lua> {
+    /// The index of the pattern that produced the match.
+    pub pattern_index: usize,
+    pub captures: Vec<TSQueryCapture<'lua>>,
+}
+
+impl<'lua> TSQueryMatch<'lua> {
+    /// Returns the first node that was captured with the given name, if there is one.
+    pub fn get(&self, name: &str) -> Option<&LuaNode<'lua>> {
+        self.captures
+            .iter()
+            .find(|capture| capture.name == name)
+            .map(|capture| &capture.node)
+    }
+
+    /// Returns all of the nodes that were captured with the given name.
+    pub fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a LuaNode<'lua>> + 'a {
+        self.captures
+            .iter()
+            .filter(move |capture| capture.name == name)
+            .map(|capture| &capture.node)
+    }
+}
+
+impl<'lua> FromLua<'lua> for TSQueryMatch<'lua> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let table = match value {
+            Value::Table(table) => table,
+            value => return Err(conversion_error(value, "TSQueryMatch")),
+        };
+        let mut captures = Vec::new();
+        let lua_captures: Table = table.get("captures")?;
+        for pair in lua_captures.pairs::<String, Value>() {
+            let (name, value) = pair?;
+            if ltreesitter::as_node(lua, &value)?.is_some() {
+                let node = LuaNode::from_lua(value, lua)?;
+                captures.push(TSQueryCapture { name, node });
+                continue;
+            }
+            let nodes: Vec<LuaNode> = FromLua::from_lua(value, lua)?;
+            for node in nodes {
+                captures.push(TSQueryCapture {
+                    name: name.clone(),
+                    node,
+                });
+            }
+        }
+        captures
+            .sort_by(|a, b| (a.node.start_byte(), &a.name).cmp(&(b.node.start_byte(), &b.name)));
+        Ok(TSQueryMatch {
+            pattern_index: table.get("pattern")?,
+            captures,
+        })
+    }
+}
+
+impl<'lua> IntoLua<'lua> for TSQueryMatch<'lua> {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let mut grouped: BTreeMap<String, Vec<LuaNode<'lua>>> = BTreeMap::new();
+        for capture in self.captures {
+            grouped.entry(capture.name).or_default().push(capture.node);
+        }
+        let captures = lua.create_table()?;
+        for (name, mut nodes) in grouped {
+            if nodes.len() == 1 {
+                captures.set(name, nodes.pop())?;
+            } else {
+                captures.set(name, nodes)?;
+            }
+        }
+        let table = lua.create_table()?;
+        table.set("pattern", self.pattern_index)?;
+        table.set("captures", captures)?;
+        Ok(Value::Table(table))
+    }
+}
+
+fn conversion_error(value: Value, to: &'static str) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from: value.type_name(),
+        to,
+        message: Some("expected a table".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_return_matches_to_rust() {
+        let code = b"def f(a, b): pass\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let m: TSQueryMatch = l.call(
+            r#"
+              local definition = parsed:root():child(0)
+              local parameters = definition:child_by_field_name("parameters")
+              return {
+                pattern = 1,
+                captures = {
+                  name = definition:child_by_field_name("name"),
+                  param = { parameters:named_child(1), parameters:named_child(0) },
+                },
+              }
+            "#,
+        );
+        assert_eq!(1, m.pattern_index);
+        assert_eq!("identifier", m.get("name").unwrap().kind());
+        let params = m
+            .all("param")
+            .map(|node| node.start_byte())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![6, 9], params);
+        assert!(m.get("missing").is_none());
+
+        let capture: TSQueryCapture =
+            l.call(r#" return { name = "definition", node = parsed:root():child(0) } "#);
+        assert_eq!("function_definition", capture.node.kind());
+        assert!(l.load(r#" return 7 "#).eval::<TSQueryMatch>().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Arithmetic on byte ranges, using the same half-open conventions as tree-sitter.
+//!
+//! A range `start..end` contains the bytes `start` up to but not including `end`.  Two ranges
+//! overlap if they share at least one byte, so ranges that merely touch do not overlap, but they
+//! can still be joined into a single range.  Mapping a range through an edit follows the same
+//! rules that tree-sitter uses when you edit a tree: positions before the edit are unchanged,
+//! positions after it are shifted, and positions inside it are moved to the start of the edit.
+//!
+//! Lua code can use the same functions via `require("ltreesitter_rs").ranges`.  There, a range is
+//! a table with `start_byte` and `end_byte` fields, or a node, and an edit is a table with
+//! `start_byte`, `old_end_byte`, and `new_end_byte` fields.
+
+use std::ops::Range;
+
+use mlua::FromLua;
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use tree_sitter::InputEdit;
+use tree_sitter::Point;
+
+use crate::offsets::Offset;
+use crate::TSNode;
+
+/// Returns whether two ranges share at least one byte.
+pub fn ranges_overlap(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Returns whether `outer` contains every byte of `inner`.  Every range contains the empty
+/// ranges at its boundaries.
+pub fn range_contains(outer: &Range<usize>, inner: &Range<usize>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
+}
+
+/// Returns the bytes that are in both ranges, or `None` if they don't overlap.
+pub fn range_intersection(a: &Range<usize>, b: &Range<usize>) -> Option<Range<usize>> {
+    ranges_overlap(a, b).then(|| a.start.max(b.start)..a.end.min(b.end))
+}
+
+/// Returns the bytes that are in either range, or `None` if that isn't a single range because
+/// the ranges neither overlap nor touch.
+pub fn range_union(a: &Range<usize>, b: &Range<usize>) -> Option<Range<usize>> {
+    (a.start <= b.end && b.start <= a.end).then(|| range_hull(a, b))
+}
+
+/// Returns the smallest range that contains both ranges.
+pub fn range_hull(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
+    a.start.min(b.start)..a.end.max(b.end)
+}
+
+/// Splits a range at each of the boundaries that fall strictly inside it.  The pieces are in order
+/// and cover the original range exactly.
+pub fn split_range(range: &Range<usize>, boundaries: &[usize]) -> Vec<Range<usize>> {
+    let mut boundaries = boundaries
+        .iter()
+        .copied()
+        .filter(|boundary| range.start < *boundary && *boundary < range.end)
+        .collect::<Vec<_>>();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    let mut pieces = Vec::with_capacity(boundaries.len() + 1);
+    let mut start = range.start;
+    for boundary in boundaries {
+        pieces.push(start..boundary);
+        start = boundary;
+    }
+    pieces.push(start..range.end);
+    pieces
+}
+
+fn edit_byte(byte: usize, edit: &InputEdit) -> usize {
+    if byte >= edit.old_end_byte {
+        edit.new_end_byte + (byte - edit.old_end_byte)
+    } else if byte > edit.start_byte {
+        edit.start_byte
+    } else {
+        byte
+    }
+}
+
+fn edit_point(point: Point, byte: usize, edit: &InputEdit) -> Point {
+    if byte >= edit.old_end_byte {
+        if point.row == edit.old_end_position.row {
+            Point::new(
+                edit.new_end_position.row,
+                edit.new_end_position.column + (point.column - edit.old_end_position.column),
+            )
+        } else {
+            Point::new(
+                edit.new_end_position.row + (point.row - edit.old_end_position.row),
+                point.column,
+            )
+        }
+    } else if byte > edit.start_byte {
+        edit.start_position
+    } else {
+        point
+    }
+}
+
+/// Maps a byte range through an edit.
+pub fn edit_byte_range(range: &Range<usize>, edit: &InputEdit) -> Range<usize> {
+    edit_byte(range.start, edit)..edit_byte(range.end, edit)
+}
+
+/// Maps a tree-sitter range, including its points, through an edit.
+pub fn edit_range(range: &tree_sitter::Range, edit: &InputEdit) -> tree_sitter::Range {
+    tree_sitter::Range {
+        start_byte: edit_byte(range.start_byte, edit),
+        end_byte: edit_byte(range.end_byte, edit),
+        start_point: edit_point(range.start_point, range.start_byte, edit),
+        end_point: edit_point(range.end_point, range.end_byte, edit),
+    }
+}
+
+/// A byte range that can be read from a Lua range table or node.
+struct LuaRange(Range<usize>);
+
+impl<'lua> FromLua<'lua> for LuaRange {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        match value {
+            Value::Table(table) => Ok(LuaRange(
+                table.get::<_, Offset>("start_byte")?.0..table.get::<_, Offset>("end_byte")?.0,
+            )),
+            value => {
+                let node = TSNode::from_lua(value, lua)?;
+                Ok(LuaRange(node.byte_range()))
+            }
+        }
+    }
+}
+
+pub(crate) fn range_table<'lua>(
+    lua: &'lua Lua,
+    range: Range<usize>,
+) -> Result<Table<'lua>, mlua::Error> {
+    let table = lua.create_table()?;
+    table.set("start_byte", Offset(range.start))?;
+    table.set("end_byte", Offset(range.end))?;
+    Ok(table)
+}
+
+/// Adds the `ranges` helpers to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let ranges = lua.create_table()?;
+    ranges.set(
+        "overlap",
+        lua.create_function(|_, (a, b): (LuaRange, LuaRange)| Ok(ranges_overlap(&a.0, &b.0)))?,
+    )?;
+    ranges.set(
+        "contains",
+        lua.create_function(|_, (a, b): (LuaRange, LuaRange)| Ok(range_contains(&a.0, &b.0)))?,
+    )?;
+    ranges.set(
+        "intersection",
+        lua.create_function(|lua, (a, b): (LuaRange, LuaRange)| {
+            range_intersection(&a.0, &b.0)
+                .map(|range| range_table(lua, range))
+                .transpose()
+        })?,
+    )?;
+    ranges.set(
+        "union",
+        lua.create_function(|lua, (a, b): (LuaRange, LuaRange)| {
+            range_union(&a.0, &b.0)
+                .map(|range| range_table(lua, range))
+                .transpose()
+        })?,
+    )?;
+    ranges.set(
+        "hull",
+        lua.create_function(|lua, (a, b): (LuaRange, LuaRange)| {
+            range_table(lua, range_hull(&a.0, &b.0))
+        })?,
+    )?;
+    ranges.set(
+        "split",
+        lua.create_function(|lua, (range, boundaries): (LuaRange, Vec<usize>)| {
+            split_range(&range.0, &boundaries)
+                .into_iter()
+                .map(|piece| range_table(lua, piece))
+                .collect::<Result<Vec<_>, _>>()
+        })?,
+    )?;
+    ranges.set(
+        "edit",
+        lua.create_function(|lua, (range, edit): (LuaRange, Table)| {
+            let edit = InputEdit {
+                start_byte: edit.get::<_, Offset>("start_byte")?.0,
+                old_end_byte: edit.get::<_, Offset>("old_end_byte")?.0,
+                new_end_byte: edit.get::<_, Offset>("new_end_byte")?.0,
+                start_position: Point::default(),
+                old_end_position: Point::default(),
+                new_end_position: Point::default(),
+            };
+            range_table(lua, edit_byte_range(&range.0, &edit))
+        })?,
+    )?;
+    crate::companion_module(lua)?.set("ranges", ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_do_range_arithmetic() {
+        assert!(ranges_overlap(&(0..5), &(4..8)));
+        assert!(!ranges_overlap(&(0..4), &(4..8)));
+        assert!(range_contains(&(0..8), &(8..8)));
+        assert_eq!(Some(4..5), range_intersection(&(0..5), &(4..8)));
+        assert_eq!(None, range_intersection(&(0..4), &(4..8)));
+        assert_eq!(Some(0..8), range_union(&(0..4), &(4..8)));
+        assert_eq!(None, range_union(&(0..3), &(4..8)));
+        assert_eq!(
+            vec![0..2, 2..5, 5..8],
+            split_range(&(0..8), &[5, 2, 0, 8, 12, 2])
+        );
+
+        // Replace bytes 4..6 of "  x = 12\n  y\n" with "345".
+        let edit = InputEdit {
+            start_byte: 4,
+            old_end_byte: 6,
+            new_end_byte: 7,
+            start_position: Point::new(0, 4),
+            old_end_position: Point::new(0, 6),
+            new_end_position: Point::new(0, 7),
+        };
+        assert_eq!(0..4, edit_byte_range(&(0..4), &edit));
+        assert_eq!(4..4, edit_byte_range(&(5..5), &edit));
+        assert_eq!(7..10, edit_byte_range(&(6..9), &edit));
+        let range = tree_sitter::Range {
+            start_byte: 6,
+            end_byte: 12,
+            start_point: Point::new(0, 6),
+            end_point: Point::new(1, 3),
+        };
+        let edited = edit_range(&range, &edit);
+        assert_eq!(7..13, edited.start_byte..edited.end_byte);
+        assert_eq!(Point::new(0, 7), edited.start_point);
+        assert_eq!(Point::new(1, 3), edited.end_point);
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.check(
+            r#"
+              local ranges = require("ltreesitter_rs").ranges
+              local a = { start_byte = 0, end_byte = 5 }
+              local b = { start_byte = 4, end_byte = 8 }
+              assert(ranges.overlap(a, b))
+              assert(ranges.intersection(a, b).start_byte == 4)
+              assert(ranges.union(a, b).end_byte == 8)
+              assert(#ranges.split(a, { 1, 3 }) == 3)
+              local edit = { start_byte = 0, old_end_byte = 1, new_end_byte = 3 }
+              assert(ranges.edit(b, edit).start_byte == 6)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,190 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Parses source code that a Lua function provides a chunk at a time.
+//!
+//! [`parse_with_reader`] is like [`Parser::parse_with`], but the text comes from a Lua function,
+//! so that a huge or rope-backed document that Lua owns doesn't have to be concatenated into a
+//! single Lua string before it can be parsed.  The function is called with the byte offset and
+//! [point][crate::TSPoint] of the text that it should return next, and returns a string of any
+//! length, or `nil` or an empty string at the end of the document.  It's always asked for the text
+//! that comes right after what it has already returned.
+//!
+//! tree-sitter can ask for the same text more than once, and the tree's nodes need their text
+//! after parsing, so the chunks are collected into a buffer on the Rust side, which the result
+//! owns.
+//!
+//! In Lua, `require("ltreesitter_rs").parse_with(parser, reader [, old_tree])` does the same, where
+//! `parser` is an ltreesitter parser or a language.
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Parser;
+use tree_sitter::Point;
+use tree_sitter::Tree;
+
+use crate::abi;
+use crate::ltreesitter;
+use crate::offsets::Offset;
+use crate::TSLanguage;
+use crate::TSParser;
+use crate::TSPoint;
+use crate::TreeWithOwnedSource;
+use crate::TreeWithSource;
+
+/// The most text that we hand to tree-sitter at once.
+const CHUNK_SIZE: usize = 4096;
+
+struct Chunks<'lua> {
+    reader: Function<'lua>,
+    buffer: Vec<u8>,
+    end: Point,
+    done: bool,
+    error: Option<mlua::Error>,
+}
+
+impl Chunks<'_> {
+    fn read(&mut self, offset: usize) -> &[u8] {
+        while !self.done && self.buffer.len() <= offset {
+            if let Err(err) = self.read_chunk() {
+                // Returning an empty chunk ends the parse, and the error is reported afterwards.
+                self.error = Some(err);
+                self.done = true;
+            }
+        }
+        let start = offset.min(self.buffer.len());
+        let end = (start + CHUNK_SIZE).min(self.buffer.len());
+        &self.buffer[start..end]
+    }
+
+    fn read_chunk(&mut self) -> Result<(), mlua::Error> {
+        let chunk: Option<mlua::String> = self
+            .reader
+            .call((Offset(self.buffer.len()), TSPoint(self.end)))?;
+        let chunk = match chunk {
+            Some(chunk) if !chunk.as_bytes().is_empty() => chunk,
+            _ => {
+                self.done = true;
+                return Ok(());
+            }
+        };
+        for byte in chunk.as_bytes() {
+            if *byte == b'\n' {
+                self.end.row += 1;
+                self.end.column = 0;
+            } else {
+                self.end.column += 1;
+            }
+        }
+        self.buffer.extend_from_slice(chunk.as_bytes());
+        Ok(())
+    }
+}
+
+/// Parses a document whose text is returned, a chunk at a time, by the Lua function `reader`.
+/// The result owns a copy of the text that `reader` returned.
+pub fn parse_with_reader(
+    parser: &mut Parser,
+    reader: Function,
+    old_tree: Option<&Tree>,
+) -> Result<TreeWithOwnedSource<Vec<u8>>, mlua::Error> {
+    let mut chunks = Chunks {
+        reader,
+        buffer: Vec::new(),
+        end: Point::new(0, 0),
+        done: false,
+        error: None,
+    };
+    let tree = parser.parse_with(&mut |offset, _| chunks.read(offset).to_vec(), old_tree);
+    if let Some(err) = chunks.error {
+        return Err(err);
+    }
+    let tree = tree.ok_or_else(|| mlua::Error::RuntimeError("parse did not finish".to_string()))?;
+    Ok(TreeWithOwnedSource::new(tree, chunks.buffer))
+}
+
+/// Adds `parse_with` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let parse_with = lua.create_function(
+        |lua, (parser, reader, old_tree): (Value, Function, Option<TreeWithSource>)| {
+            let mut parser = match ltreesitter::as_parser(lua, &parser)? {
+                Some(_) => TSParser::from_lua(parser, lua)?,
+                None => {
+                    let language = TSLanguage::from_lua(parser, lua)?;
+                    abi::check_language(lua, *language)?;
+                    TSParser::new(*language)?
+                }
+            };
+            let old_tree = old_tree.as_ref().map(|old_tree| &old_tree.tree);
+            parse_with_reader(&mut parser, reader, old_tree)
+        },
+    )?;
+    crate::companion_module(lua)?.set("parse_with", parse_with)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_parse_with_a_reader() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        let reader: Function = l
+            .load(
+                r#"
+                  local lines = { "def double(x):\n", "  return x * 2\n", "x = 1\n" }
+                  local index = 0
+                  return function(offset, point)
+                    index = index + 1
+                    assert(point.row == index - 1 and point.column == 0)
+                    return lines[index]
+                  end
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parse_with_reader(&mut parser, reader, None).unwrap();
+        assert_eq!(
+            &b"def double(x):\n  return x * 2\nx = 1\n"[..],
+            &parsed.src[..]
+        );
+        assert_eq!(2, parsed.tree.root_node().named_child_count());
+
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local rope = { "class A:", " pass\n", "y = [", "1, 2]\n" }
+              local function reader(offset)
+                local start = 0
+                for _, piece in ipairs(rope) do
+                  if offset < start + #piece then
+                    return piece:sub(offset - start + 1)
+                  end
+                  start = start + #piece
+                end
+              end
+              local parser = require("ltreesitter").require("python")
+              local parsed = ltreesitter_rs.parse_with(parser, reader)
+              assert(parsed:root():child(0):type() == "class_definition")
+              assert(parsed:root():child(1):source() == "y = [1, 2]")
+
+              local ok, err = pcall(ltreesitter_rs.parse_with, parser, function()
+                error("cannot read")
+              end)
+              assert(not ok and tostring(err):find("cannot read"))
+            "#,
+        );
+    }
+}
@@ -0,0 +1,757 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Records the calls that cross the bridge between Rust and Lua, so that you can detect behavioral
+//! changes when upgrading this crate.
+//!
+//! While recording is enabled, every push of a tree into Lua, every conversion of an ltreesitter
+//! object back into Rust, and every query that this crate runs appends a [`BridgeEvent`] to the
+//! recording.  Events compare the data that crossed the bridge by stable hashes.  Where it can,
+//! each event also keeps a [`ReplayStep`] with what it takes to make the same call again: the
+//! grammar and source of each pushed tree, the position of each node within its tree, and the
+//! source of each query.  (A tree's grammar is only known if it was registered via
+//! [`Module::register_language`].)
+//!
+//! To check for regressions, run a workload against one version of this crate, saving its
+//! recording, and then load the recording with a newer version and [replay][Recording::replay]
+//! it.  [`Recording::first_divergence`] finds the first call whose result changed.  You can also
+//! record the same workload against both versions and compare the recordings directly.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::BufRead;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use mlua::FromLua;
+use mlua::Function;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::RegistryKey;
+use mlua::Value;
+use tree_sitter::Language;
+
+use crate::languages;
+use crate::ltreesitter;
+use crate::trees;
+use crate::Module;
+use crate::QueryCache;
+use crate::TSNode;
+use crate::TSQuery;
+use crate::TSTreeCursor;
+use crate::TreeWithSource;
+use crate::WithSource;
+
+/// A single call across the bridge.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BridgeEvent {
+    /// The kind of call, such as `push_tree` or `node_from_lua`.
+    pub operation: String,
+    /// A hash of the inputs to the call.
+    pub input: u64,
+    /// A hash of the result of the call.
+    pub output: u64,
+    /// How to make the call again, if we know.
+    pub replay: Option<ReplayStep>,
+}
+
+impl BridgeEvent {
+    /// Returns whether two events are the same call with the same result.  This doesn't compare
+    /// their replay steps.
+    pub fn same_call(&self, other: &BridgeEvent) -> bool {
+        (&self.operation, self.input, self.output) == (&other.operation, other.input, other.output)
+    }
+}
+
+impl Display for BridgeEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:016x} {:016x}",
+            self.operation, self.input, self.output
+        )?;
+        if let Some(replay) = &self.replay {
+            write!(f, " {}", replay)?;
+        }
+        Ok(())
+    }
+}
+
+/// What it takes to make a recorded call again.  Trees are identified by the index of the
+/// `push_tree` event that created them, and nodes by the child indices that lead to them from
+/// their tree's root.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReplayStep {
+    /// Parse `source` with the grammar registered as `language`, and push the tree into Lua.
+    PushTree { language: String, source: Vec<u8> },
+    /// Convert a tree back into Rust.
+    TreeFromLua { tree: usize },
+    /// Convert a node of a tree back into Rust.
+    NodeFromLua { tree: usize, path: Vec<usize> },
+    /// Convert a cursor that starts at a node of a tree back into Rust.
+    CursorFromLua { tree: usize, path: Vec<usize> },
+    /// Get the cached matches of a query over a tree.
+    CachedMatches { tree: usize, query: String },
+    /// Run a query natively over the subtree rooted at a node of a tree.
+    RunQuery {
+        tree: usize,
+        path: Vec<usize>,
+        query: String,
+    },
+}
+
+impl Display for ReplayStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayStep::PushTree { language, source } => {
+                write!(f, "{} {}", language, to_hex(source))
+            }
+            ReplayStep::TreeFromLua { tree } => write!(f, "{}", tree),
+            ReplayStep::NodeFromLua { tree, path } | ReplayStep::CursorFromLua { tree, path } => {
+                write!(f, "{} {}", tree, path_to_string(path))
+            }
+            ReplayStep::CachedMatches { tree, query } => {
+                write!(f, "{} {}", tree, to_hex(query.as_bytes()))
+            }
+            ReplayStep::RunQuery { tree, path, query } => write!(
+                f,
+                "{} {} {}",
+                tree,
+                path_to_string(path),
+                to_hex(query.as_bytes())
+            ),
+        }
+    }
+}
+
+impl ReplayStep {
+    /// Parses the replay fields of a saved event, returning `None` if they're malformed.
+    fn parse(operation: &str, fields: &[&str]) -> Option<ReplayStep> {
+        let tree = |field: &str| field.parse::<usize>().ok();
+        let query = |field: &str| String::from_utf8(from_hex(field)?).ok();
+        Some(match (operation, fields) {
+            ("push_tree", [language, source]) => ReplayStep::PushTree {
+                language: language.to_string(),
+                source: from_hex(source)?,
+            },
+            ("tree_from_lua", [t]) => ReplayStep::TreeFromLua { tree: tree(t)? },
+            ("node_from_lua", [t, path]) => ReplayStep::NodeFromLua {
+                tree: tree(t)?,
+                path: path_from_string(path)?,
+            },
+            ("cursor_from_lua", [t, path]) => ReplayStep::CursorFromLua {
+                tree: tree(t)?,
+                path: path_from_string(path)?,
+            },
+            ("cached_matches", [t, q]) => ReplayStep::CachedMatches {
+                tree: tree(t)?,
+                query: query(q)?,
+            },
+            ("run_query", [t, path, q]) => ReplayStep::RunQuery {
+                tree: tree(t)?,
+                path: path_from_string(path)?,
+                query: query(q)?,
+            },
+            _ => return None,
+        })
+    }
+}
+
+// Fields are separated by whitespace, so empty values are written as `-`.
+
+fn to_hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(field: &str) -> Option<Vec<u8>> {
+    if field == "-" {
+        return Some(Vec::new());
+    }
+    if field.len() % 2 != 0 {
+        return None;
+    }
+    (0..field.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(field.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn path_to_string(path: &[usize]) -> String {
+    if path.is_empty() {
+        return "-".to_string();
+    }
+    path.iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn path_from_string(field: &str) -> Option<Vec<usize>> {
+    if field == "-" {
+        return Some(Vec::new());
+    }
+    field.split('.').map(|index| index.parse().ok()).collect()
+}
+
+/// The first point at which two recordings differ.  `expected` and `actual` are `None` if the
+/// corresponding recording ended before the divergence.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    pub index: usize,
+    pub expected: Option<BridgeEvent>,
+    pub actual: Option<BridgeEvent>,
+}
+
+/// A sequence of recorded bridge calls.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Recording {
+    pub events: Vec<BridgeEvent>,
+}
+
+impl Recording {
+    /// Writes a recording in a line-oriented text format that [`Recording::read`] can load.
+    pub fn write<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        for event in &self.events {
+            writeln!(writer, "{}", event)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a recording that was saved via [`Recording::write`].
+    pub fn read<R: BufRead>(reader: R) -> std::io::Result<Recording> {
+        let invalid = |line: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid bridge event: {}", line),
+            )
+        };
+        let mut events = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (operation, input, output, rest) = match &fields[..] {
+                [operation, input, output, rest @ ..] => (operation, input, output, rest),
+                _ => return Err(invalid(&line)),
+            };
+            let replay = match rest {
+                [] => None,
+                rest => Some(ReplayStep::parse(operation, rest).ok_or_else(|| invalid(&line))?),
+            };
+            events.push(BridgeEvent {
+                operation: operation.to_string(),
+                input: u64::from_str_radix(input, 16).map_err(|_| invalid(&line))?,
+                output: u64::from_str_radix(output, 16).map_err(|_| invalid(&line))?,
+                replay,
+            });
+        }
+        Ok(Recording { events })
+    }
+
+    /// Compares this (expected) recording with another (actual) one, returning the first event
+    /// where they differ, or `None` if they are identical.  Only the calls and their hashes are
+    /// compared, not their replay steps.
+    pub fn first_divergence(&self, actual: &Recording) -> Option<Divergence> {
+        let length = self.events.len().max(actual.events.len());
+        (0..length).find_map(|index| {
+            let expected = self.events.get(index);
+            let actual = actual.events.get(index);
+            let same = match (expected, actual) {
+                (Some(expected), Some(actual)) => expected.same_call(actual),
+                _ => false,
+            };
+            (!same).then(|| Divergence {
+                index,
+                expected: expected.cloned(),
+                actual: actual.cloned(),
+            })
+        })
+    }
+
+    /// Makes each of the recorded calls again, in a fresh Lua environment where the grammars in
+    /// `languages` are registered, and returns the recording of the replay.  Compare it with this
+    /// recording via [`first_divergence`][Self::first_divergence] to see whether any results have
+    /// changed.  Returns an error if an event can't be replayed, or if making its call fails.
+    pub fn replay(&self, languages: &[(&str, Language)]) -> Result<Recording, mlua::Error> {
+        let lua = Lua::new();
+        lua.open_ltreesitter()?;
+        for (name, language) in languages {
+            lua.register_language(name, *language)?;
+        }
+        lua.enable_query_cache()?;
+        let mut replayer = Replayer {
+            lua: &lua,
+            trees: HashMap::new(),
+            queries: HashMap::new(),
+        };
+        lua.start_recording();
+        let result = self
+            .events
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, event)| {
+                let step = event.replay.as_ref().ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "event {} ({}) was recorded without a replay step",
+                        index, event.operation
+                    ))
+                })?;
+                replayer.step(index, step)
+            });
+        let replayed = lua.finish_recording();
+        result.map(|()| replayed)
+    }
+}
+
+/// Makes recorded calls again.
+struct Replayer<'lua> {
+    lua: &'lua Lua,
+    /// The tree that each replayed `push_tree` event created.
+    trees: HashMap<usize, RegistryKey>,
+    /// The queries that we've compiled, by tree and source.
+    queries: HashMap<(usize, String), RegistryKey>,
+}
+
+impl<'lua> Replayer<'lua> {
+    fn step(&mut self, index: usize, step: &ReplayStep) -> Result<(), mlua::Error> {
+        let lua = self.lua;
+        match step {
+            ReplayStep::PushTree { language, source } => {
+                let language = languages::linked_language(lua, language).ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "no grammar named {} is registered",
+                        language
+                    ))
+                })?;
+                let mut parser = tree_sitter::Parser::new();
+                parser
+                    .set_language(language)
+                    .map_err(mlua::Error::external)?;
+                let parsed = parser.parse(source, None).ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!("cannot parse the source of event {}", index))
+                })?;
+                let tree = parsed.with_source(source).into_lua(lua)?;
+                self.trees.insert(index, lua.create_registry_value(tree)?);
+            }
+            ReplayStep::TreeFromLua { tree } => {
+                TreeWithSource::from_lua(self.tree(*tree)?, lua)?;
+            }
+            ReplayStep::NodeFromLua { tree, path } => {
+                let node = self.node(*tree, path)?;
+                TSNode::from_lua(node, lua)?;
+            }
+            ReplayStep::CursorFromLua { tree, path } => {
+                let node = self.node(*tree, path)?;
+                let create_cursor: Function =
+                    ltreesitter::methods(lua, ltreesitter::NODE_METATABLE)?.get("create_cursor")?;
+                let cursor: Value = create_cursor.call(node)?;
+                TSTreeCursor::from_lua(cursor, lua)?;
+            }
+            ReplayStep::CachedMatches { tree, query } => {
+                let query = self.query(*tree, query)?;
+                lua.cached_matches(query, self.tree(*tree)?)?;
+            }
+            ReplayStep::RunQuery { tree, path, query } => {
+                let query = self.query(*tree, query)?;
+                let value = self.tree(*tree)?;
+                let tree = without_recording(lua, || TreeWithSource::from_lua(value, lua))?;
+                let mut node = tree.tree.root_node();
+                for index in path {
+                    node = node.child(*index).ok_or_else(missing_node)?;
+                }
+                crate::query::run_query_at(lua, query, &tree, node)?.for_each(drop);
+            }
+        }
+        Ok(())
+    }
+
+    fn tree(&self, tree: usize) -> Result<Value<'lua>, mlua::Error> {
+        let key = self.trees.get(&tree).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("event {} didn't push a tree", tree))
+        })?;
+        self.lua.registry_value(key)
+    }
+
+    fn node(&self, tree: usize, path: &[usize]) -> Result<Value<'lua>, mlua::Error> {
+        let root: Function =
+            ltreesitter::methods(self.lua, ltreesitter::TREE_METATABLE)?.get("root")?;
+        let child: Function =
+            ltreesitter::methods(self.lua, ltreesitter::NODE_METATABLE)?.get("child")?;
+        let mut node: Value = root.call(self.tree(tree)?)?;
+        for index in path {
+            node = child.call((node, *index))?;
+            if node.is_nil() {
+                return Err(missing_node());
+            }
+        }
+        Ok(node)
+    }
+
+    fn query(&mut self, tree: usize, source: &str) -> Result<Value<'lua>, mlua::Error> {
+        let key = (tree, source.to_string());
+        if let Some(query) = self.queries.get(&key) {
+            return self.lua.registry_value(query);
+        }
+        let ltreesitter_tree = ltreesitter::tree_ptr(self.lua, self.tree(tree)?)?;
+        let language = unsafe { ltreesitter::root_node(ltreesitter_tree) }.language();
+        let query = without_recording(self.lua, || {
+            TSQuery::new(language, source)
+                .map_err(|err| mlua::Error::RuntimeError(err.to_string()))?
+                .into_lua(self.lua)
+        })?;
+        self.queries
+            .insert(key, self.lua.create_registry_value(query.clone())?);
+        Ok(query)
+    }
+}
+
+fn missing_node() -> mlua::Error {
+    mlua::Error::RuntimeError("recorded node isn't in the replayed tree".to_string())
+}
+
+/// An extension trait that lets you record the calls that cross the bridge between Rust and a Lua
+/// environment.
+pub trait BridgeRecorder {
+    /// Starts recording bridge calls, discarding any events that were previously recorded.
+    fn start_recording(&self);
+
+    /// Stops recording bridge calls, and returns the events that were recorded.
+    fn finish_recording(&self) -> Recording;
+}
+
+impl BridgeRecorder for Lua {
+    fn start_recording(&self) {
+        self.set_app_data(Recorder {
+            recording: Recording::default(),
+            id: NEXT_RECORDING.fetch_add(1, Ordering::Relaxed),
+        });
+    }
+
+    fn finish_recording(&self) -> Recording {
+        self.remove_app_data::<Recorder>()
+            .map(|recorder| recorder.recording)
+            .unwrap_or_default()
+    }
+}
+
+/// A recording in progress.
+struct Recorder {
+    recording: Recording,
+    /// Identifies this recording, so that the `push_tree` events that an earlier recording stored
+    /// in a tree's attachments table aren't mistaken for events of this one.
+    id: u64,
+}
+
+static NEXT_RECORDING: AtomicU64 = AtomicU64::new(0);
+
+const RECORDING_KEY: &str = "recording";
+const PUSH_EVENT_KEY: &str = "push_event";
+
+/// Returns whether bridge calls are currently being recorded.  Callers should check this before
+/// computing the hashes for an event, since those can be expensive.
+pub(crate) fn is_recording(lua: &Lua) -> bool {
+    lua.app_data_ref::<Recorder>().is_some()
+}
+
+/// Records a bridge call, if recording is enabled.
+pub(crate) fn record(
+    lua: &Lua,
+    operation: &str,
+    input: u64,
+    output: u64,
+    replay: Option<ReplayStep>,
+) {
+    if let Some(mut recorder) = lua.app_data_mut::<Recorder>() {
+        recorder.recording.events.push(BridgeEvent {
+            operation: operation.to_string(),
+            input,
+            output,
+            replay,
+        });
+    }
+}
+
+/// Records the push of an ltreesitter tree into Lua, so that later events can refer to the tree.
+/// The index of the event is stored in the tree's attachments table, since tree-sitter can reuse
+/// the address of a tree once it has been freed.
+pub(crate) fn record_push<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    input: u64,
+    output: u64,
+    replay: Option<ReplayStep>,
+) -> Result<(), mlua::Error> {
+    let (id, index) = match lua.app_data_mut::<Recorder>() {
+        Some(mut recorder) => {
+            let index = recorder.recording.events.len();
+            recorder.recording.events.push(BridgeEvent {
+                operation: "push_tree".to_string(),
+                input,
+                output,
+                replay,
+            });
+            (recorder.id, index)
+        }
+        None => return Ok(()),
+    };
+    let attachments = trees::attachments(lua, tree)?;
+    attachments.raw_set(RECORDING_KEY, id)?;
+    attachments.raw_set(PUSH_EVENT_KEY, index)?;
+    Ok(())
+}
+
+/// Returns the index of the `push_tree` event that created an ltreesitter tree, if we recorded it
+/// in the current recording.
+pub(crate) fn tree_index<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Option<usize> {
+    let id = lua.app_data_ref::<Recorder>()?.id;
+    let attachments = trees::existing_attachments(lua, tree).ok()??;
+    match attachments.raw_get::<_, Option<u64>>(RECORDING_KEY).ok()? {
+        Some(recording) if recording == id => attachments.raw_get(PUSH_EVENT_KEY).ok()?,
+        _ => None,
+    }
+}
+
+/// Returns the name that a grammar was registered under, if it was.
+pub(crate) fn language_name(lua: &Lua, language: Language) -> Option<String> {
+    languages::linked_languages(lua)
+        .into_iter()
+        .find(|(_, linked)| *linked == language)
+        .map(|(name, _)| name)
+}
+
+/// Returns the child indices that lead from the root of a node's tree to the node.
+pub(crate) fn node_path(node: tree_sitter::Node) -> Vec<usize> {
+    let mut path = Vec::new();
+    let mut node = node;
+    while let Some(parent) = node.parent() {
+        let index = (0..parent.child_count())
+            .find(|index| parent.child(*index) == Some(node))
+            .unwrap_or_default();
+        path.push(index);
+        node = parent;
+    }
+    path.reverse();
+    path
+}
+
+/// Calls `f` without recording any of the bridge calls that it makes.
+pub(crate) fn without_recording<R>(lua: &Lua, f: impl FnOnce() -> R) -> R {
+    let recorder = lua.remove_app_data::<Recorder>();
+    let result = f();
+    if let Some(recorder) = recorder {
+        lua.set_app_data(recorder);
+    }
+    result
+}
+
+/// A 64-bit FNV-1a hash.  Unlike the standard library's hashers, this is guaranteed to produce
+/// the same results across Rust and crate versions, which is what lets recordings be compared.
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    pub(crate) fn new() -> StableHasher {
+        StableHasher(0xcbf29ce484222325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hashes the kind and location of a node.
+pub(crate) fn hash_node(node: &tree_sitter::Node) -> u64 {
+    let mut hasher = StableHasher::new();
+    write_node(&mut hasher, node);
+    hasher.finish()
+}
+
+/// Hashes the kind and location of a node, and its source code if we have it.
+pub(crate) fn hash_node_contents(node: &tree_sitter::Node, src: Option<&[u8]>) -> u64 {
+    let mut hasher = StableHasher::new();
+    write_node(&mut hasher, node);
+    match src.and_then(|src| src.get(node.byte_range())) {
+        Some(text) => {
+            hasher.write_u64(text.len() as u64);
+            hasher.write(text);
+        }
+        None => hasher.write_u64(u64::MAX),
+    }
+    hasher.finish()
+}
+
+/// Hashes a query match, given its pattern index and the name and [node hash][hash_node] of each
+/// of its captures.
+pub(crate) fn write_match(
+    hasher: &mut StableHasher,
+    pattern: i64,
+    mut captures: Vec<(Vec<u8>, u64)>,
+) {
+    hasher.write_u64(pattern as u64);
+    captures.sort();
+    for (name, node) in captures {
+        hasher.write(&name);
+        hasher.write_u64(node);
+    }
+}
+
+/// Hashes a syntax tree and its source code.
+pub(crate) fn hash_tree(root: tree_sitter::Node, src: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write_u64(src.len() as u64);
+    hasher.write(src);
+    let mut cursor = root.walk();
+    'nodes: loop {
+        write_node(&mut hasher, &cursor.node());
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'nodes;
+            }
+        }
+    }
+    hasher.finish()
+}
+
+fn write_node(hasher: &mut StableHasher, node: &tree_sitter::Node) {
+    hasher.write_u64(node.kind_id() as u64);
+    hasher.write_u64(node.start_byte() as u64);
+    hasher.write_u64(node.end_byte() as u64);
+    hasher.write_u64(node.child_count() as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+
+    fn run_workload() -> Recording {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.enable_query_cache().unwrap();
+        l.start_recording();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        let _: TSNode = l.call(r#" return parsed:root():child(0) "#);
+        let query: Value = l
+            .call(r#" return require("ltreesitter").require("python"):query("(identifier) @id") "#);
+        let tree: TreeWithSource = l.call(r#" return parsed "#);
+        let name = tree.tree.root_node().child(0).unwrap();
+        let matches = crate::query::run_query_at(&l, query.clone(), &tree, name).unwrap();
+        assert_eq!(3, matches.count());
+        l.cached_matches(query, l.globals().get("parsed").unwrap())
+            .unwrap();
+        l.finish_recording()
+    }
+
+    #[test]
+    fn can_compare_recordings() {
+        let expected = run_workload();
+        let operations = expected
+            .events
+            .iter()
+            .map(|event| event.operation.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "push_tree",
+                "node_from_lua",
+                "tree_from_lua",
+                "run_query",
+                "cached_matches"
+            ],
+            operations
+        );
+        assert!(expected.events.iter().all(|event| event.replay.is_some()));
+        assert_eq!(
+            Some(ReplayStep::NodeFromLua {
+                tree: 0,
+                path: vec![0]
+            }),
+            expected.events[1].replay
+        );
+
+        let mut saved = Vec::new();
+        expected.write(&mut saved).unwrap();
+        let loaded = Recording::read(&saved[..]).unwrap();
+        assert_eq!(expected, loaded);
+
+        let actual = run_workload();
+        assert_eq!(None, expected.first_divergence(&actual));
+
+        let mut truncated = actual.clone();
+        truncated.events.pop();
+        let divergence = expected.first_divergence(&truncated).unwrap();
+        assert_eq!(4, divergence.index);
+        assert_eq!(None, divergence.actual);
+    }
+
+    #[test]
+    fn can_replay_recordings() {
+        let expected = run_workload();
+        let python = [("python", tree_sitter_python::language())];
+        let replayed = expected.replay(&python).unwrap();
+        assert_eq!(None, expected.first_divergence(&replayed));
+
+        // Pushing different source code changes what every later call sees.
+        let mut edited = expected.clone();
+        edited.events[0].replay = Some(ReplayStep::PushTree {
+            language: "python".to_string(),
+            source: b"def triple(x): return x * 3\n".to_vec(),
+        });
+        let divergence = expected
+            .first_divergence(&edited.replay(&python).unwrap())
+            .unwrap();
+        assert_eq!(0, divergence.index);
+
+        assert!(expected.replay(&[]).is_err());
+        let mut unreplayable = expected;
+        unreplayable.events[1].replay = None;
+        assert!(unreplayable.replay(&python).is_err());
+    }
+
+    #[test]
+    fn later_recordings_dont_refer_to_earlier_pushes() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.start_recording();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.finish_recording();
+
+        l.start_recording();
+        let _: TSNode = l.call(r#" return parsed:root():child(0) "#);
+        let recording = l.finish_recording();
+        assert_eq!(1, recording.events.len());
+        assert_eq!(None, recording.events[0].replay);
+    }
+}
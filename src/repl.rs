@@ -0,0 +1,154 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! An interactive Lua prompt for exploring a parsed file.  Requires the `repl` feature.
+//!
+//! The prompt evaluates each line as an expression if it can, and as a statement otherwise.
+//! Statements that span several lines can be entered one line at a time.  Results that are trees
+//! or nodes are shown as a [pretty-printed][crate::pretty_print] outline.
+
+use std::path::PathBuf;
+
+use mlua::Function;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Value;
+use rustyline::error::ReadlineError;
+
+use crate::ltreesitter;
+use crate::TreeWithSource;
+
+/// An interactive Lua prompt with a parsed file bound to the global `tree`.
+pub struct Repl<'lua> {
+    lua: &'lua Lua,
+    history_file: Option<PathBuf>,
+}
+
+impl<'lua> Repl<'lua> {
+    /// Creates a new prompt that evaluates code in the given Lua state, which must already have
+    /// the `ltreesitter` module loaded.
+    pub fn new(lua: &'lua Lua) -> Repl<'lua> {
+        Repl {
+            lua,
+            history_file: None,
+        }
+    }
+
+    /// Loads the prompt's history from a file, and saves it back when the prompt exits.
+    pub fn history_file<P: Into<PathBuf>>(mut self, path: P) -> Repl<'lua> {
+        self.history_file = Some(path.into());
+        self
+    }
+
+    /// Binds `tree` as a global and runs the prompt until the user exits it with Ctrl-D.
+    pub fn run(self, tree: TreeWithSource) -> Result<(), mlua::Error> {
+        self.lua.globals().set("tree", tree)?;
+        let mut editor = rustyline::DefaultEditor::new().map_err(mlua::Error::external)?;
+        if let Some(history_file) = &self.history_file {
+            // There won't be a history file the first time the prompt runs.
+            let _ = editor.load_history(history_file);
+        }
+
+        let mut pending = String::new();
+        loop {
+            let prompt = if pending.is_empty() { "> " } else { ">> " };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => {
+                    pending.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(err) => return Err(mlua::Error::external(err)),
+            };
+            if !pending.is_empty() {
+                pending.push('\n');
+            }
+            pending.push_str(&line);
+            match eval(self.lua, &pending) {
+                Ok(output) => {
+                    if !output.is_empty() {
+                        println!("{}", output);
+                    }
+                }
+                Err(mlua::Error::SyntaxError {
+                    incomplete_input: true,
+                    ..
+                }) => continue,
+                Err(err) => eprintln!("{}", err),
+            }
+            let _ = editor.add_history_entry(pending.as_str());
+            pending.clear();
+        }
+
+        if let Some(history_file) = &self.history_file {
+            editor
+                .save_history(history_file)
+                .map_err(mlua::Error::external)?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluates a chunk of code, returning its formatted results.
+fn eval(lua: &Lua, code: &str) -> Result<String, mlua::Error> {
+    let chunk = match lua
+        .load(format!("return {}", code))
+        .set_name("repl")
+        .into_function()
+    {
+        Ok(chunk) => chunk,
+        Err(_) => lua.load(code).set_name("repl").into_function()?,
+    };
+    let results: MultiValue = chunk.call(())?;
+    let mut output = Vec::new();
+    for value in results {
+        output.push(format_value(lua, value)?);
+    }
+    Ok(output.join("\t"))
+}
+
+fn format_value<'lua>(lua: &'lua Lua, value: Value<'lua>) -> Result<String, mlua::Error> {
+    let is_syntax = ltreesitter::as_tree(lua, &value)?.is_some()
+        || ltreesitter::as_node(lua, &value)?.is_some();
+    if is_syntax {
+        let pretty_print: Function = crate::companion_module(lua)?.get("pretty_print")?;
+        let printed: String = pretty_print.call(value)?;
+        return Ok(printed.trim_end().to_string());
+    }
+    let tostring: Function = lua.globals().get("tostring")?;
+    tostring.call(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_evaluate_expressions_and_statements() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("tree", parsed.with_source(code)).unwrap();
+        assert_eq!("", eval(&l, "x = 1 + 1").unwrap());
+        assert_eq!("2\thello", eval(&l, "x, 'hello'").unwrap());
+        let printed = eval(&l, "tree:root():child(0)").unwrap();
+        assert!(printed.starts_with("function_definition"));
+        assert!(printed.contains("name: identifier"));
+        assert!(matches!(
+            eval(&l, "function f()"),
+            Err(mlua::Error::SyntaxError {
+                incomplete_input: true,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,552 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Runs Lua analysis scripts on a dedicated thread.
+//!
+//! A [`Lua`] state cannot be shared between threads, which makes it awkward to embed in a server
+//! that handles requests on many threads.  A [`ScriptRunner`] owns a Lua state on a thread of its
+//! own, and accepts analysis jobs from any number of other threads, running them one at a time.
+//! A [`ScriptPool`] does the same with several Lua states, so that jobs can run in parallel;
+//! trees that many jobs analyze can be stored once in the pool's [`TreeArena`].
+//!
+//! A job that panics doesn't take its Lua state down with it: the panic is reported as the job's
+//! error, and the state goes on to run other jobs.
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::thread::JoinHandle;
+
+use mlua::Function;
+use mlua::IntoLua;
+use mlua::IntoLuaMulti;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::RegistryKey;
+use mlua::Value;
+use tree_sitter::Tree;
+
+use crate::ltreesitter;
+use crate::Module;
+use crate::SharedSource;
+use crate::WithSource;
+
+pub(crate) type Job = Box<dyn FnOnce(&Lua) + Send>;
+pub(crate) type Init = Box<dyn FnOnce(&Lua) -> Result<(), mlua::Error> + Send>;
+
+/// An analysis job: a tree to analyze, the name of the global Lua function to analyze it with,
+/// and any additional arguments to pass to that function.  The function is called with the tree
+/// as its first argument, followed by `args`.
+pub struct AnalysisJob<A> {
+    pub tree: Tree,
+    pub src: Vec<u8>,
+    pub entry: String,
+    pub args: A,
+}
+
+impl AnalysisJob<()> {
+    /// Creates a new analysis job that doesn't pass any additional arguments to the entry
+    /// function.
+    pub fn new<S: Into<Vec<u8>>, E: Into<String>>(tree: Tree, src: S, entry: E) -> Self {
+        AnalysisJob {
+            tree,
+            src: src.into(),
+            entry: entry.into(),
+            args: (),
+        }
+    }
+}
+
+impl<A> AnalysisJob<A> {
+    /// Replaces the additional arguments that will be passed to the entry function.
+    pub fn with_args<B>(self, args: B) -> AnalysisJob<B> {
+        AnalysisJob {
+            tree: self.tree,
+            src: self.src,
+            entry: self.entry,
+            args,
+        }
+    }
+}
+
+/// Owns a Lua state on a dedicated thread, and runs analysis jobs on it.
+///
+/// `ScriptRunner` is `Sync`, so you can share a single runner (e.g. via an `Arc`) among all of the
+/// threads that need to run scripts.  Jobs are executed in the order that they are received.
+pub struct ScriptRunner {
+    workers: Workers,
+}
+
+impl ScriptRunner {
+    /// Spawns a new Lua state on a dedicated thread.  The `ltreesitter` module is loaded into the
+    /// state, and then `init` is called to finish setting it up — typically by loading the
+    /// scripts that define the entry functions of your analysis jobs.
+    pub fn new<F>(init: F) -> Result<ScriptRunner, mlua::Error>
+    where
+        F: FnOnce(&Lua) -> Result<(), mlua::Error> + Send + 'static,
+    {
+        let workers = Workers::spawn(vec![Box::new(init)])?;
+        Ok(ScriptRunner { workers })
+    }
+
+    /// Runs an analysis job, blocking until it completes, and returns the result of the entry
+    /// function.
+    pub fn submit<A, R>(&self, job: AnalysisJob<A>) -> Result<R, mlua::Error>
+    where
+        A: for<'lua> IntoLuaMulti<'lua> + Send + 'static,
+        R: for<'lua> mlua::FromLuaMulti<'lua> + Send + 'static,
+    {
+        self.run(move |lua| {
+            let tree = job.tree.with_source(&job.src);
+            call_entry(lua, &job.entry, tree, job.args)
+        })
+    }
+
+    /// Runs an arbitrary function on the runner's Lua state, blocking until it completes.
+    pub fn run<F, R>(&self, f: F) -> Result<R, mlua::Error>
+    where
+        F: FnOnce(&Lua) -> Result<R, mlua::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.workers.run(f)
+    }
+}
+
+/// Identifies a tree that has been added to a [`TreeArena`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TreeId(u64);
+
+/// An immutable tree, and the source code it was parsed from, that can be shared among several
+/// Lua states.
+///
+/// tree-sitter trees cannot be used on more than one thread at a time, so each Lua state gets its
+/// own shallow copy of the tree when it is pushed.  Shallow copies are cheap, and share all of
+/// their nodes with the original tree.  The source code isn't copied at all: it's a
+/// [`SharedSource`] that every Lua state reads from in place.
+pub struct SharedTree {
+    tree: Mutex<Tree>,
+    src: SharedSource,
+}
+
+impl SharedTree {
+    /// Creates a new shared tree.
+    pub fn new<S: Into<SharedSource>>(tree: Tree, src: S) -> SharedTree {
+        SharedTree {
+            tree: Mutex::new(tree),
+            src: src.into(),
+        }
+    }
+
+    /// Returns a shallow copy of the tree.
+    #[track_caller]
+    pub fn tree(&self) -> Tree {
+        crate::copies::copy_tree(&self.tree.lock().unwrap())
+    }
+
+    /// Returns the source code that the tree was parsed from.
+    pub fn src(&self) -> &[u8] {
+        &self.src
+    }
+}
+
+/// A collection of immutable trees that are shared among the Lua states of a [`ScriptPool`].
+#[derive(Default)]
+pub struct TreeArena {
+    trees: RwLock<HashMap<TreeId, Arc<SharedTree>>>,
+    next_id: AtomicU64,
+}
+
+impl TreeArena {
+    /// Adds a tree to the arena, returning the ID that you can use to refer to it in jobs.
+    pub fn insert<S: Into<SharedSource>>(&self, tree: Tree, src: S) -> TreeId {
+        let id = TreeId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let tree = Arc::new(SharedTree::new(tree, src));
+        self.trees.write().unwrap().insert(id, tree);
+        id
+    }
+
+    /// Returns a tree from the arena.
+    pub fn get(&self, id: TreeId) -> Option<Arc<SharedTree>> {
+        self.trees.read().unwrap().get(&id).cloned()
+    }
+
+    /// Removes a tree from the arena.  Jobs that are already running against the tree are not
+    /// affected.
+    pub fn remove(&self, id: TreeId) -> Option<Arc<SharedTree>> {
+        self.trees.write().unwrap().remove(&id)
+    }
+}
+
+/// Owns several Lua states, each on its own dedicated thread, and distributes analysis jobs among
+/// them so that they can run in parallel.
+///
+/// Each job runs on whichever Lua state becomes free first.  The Lua states do not share any Lua
+/// values with each other, so scripts should not rely on global state carrying over from one job
+/// to the next.
+///
+/// Each tree in the pool's arena is only pushed into each Lua state once, the first time that a
+/// job on that state analyzes it, and later jobs get the same Lua tree.  So scripts shouldn't
+/// edit the trees that they're given.  (A tree that a script closes is pushed again.)
+pub struct ScriptPool {
+    workers: Workers,
+    arena: Arc<TreeArena>,
+}
+
+impl ScriptPool {
+    /// Spawns `size` new Lua states, each on a dedicated thread.  The `ltreesitter` module is
+    /// loaded into each state, and then `init` is called to finish setting it up.
+    pub fn new<F>(size: usize, init: F) -> Result<ScriptPool, mlua::Error>
+    where
+        F: Fn(&Lua) -> Result<(), mlua::Error> + Send + Sync + 'static,
+    {
+        let init = Arc::new(init);
+        let inits = (0..size.max(1))
+            .map(|_| {
+                let init = init.clone();
+                Box::new(move |lua: &Lua| init(lua)) as Init
+            })
+            .collect();
+        Ok(ScriptPool {
+            workers: Workers::spawn(inits)?,
+            arena: Arc::default(),
+        })
+    }
+
+    /// Returns the arena of trees that jobs in this pool can analyze.
+    pub fn arena(&self) -> &Arc<TreeArena> {
+        &self.arena
+    }
+
+    /// Analyzes a tree from the pool's arena, blocking until the analysis completes.  The global
+    /// Lua function named `entry` is called with the tree as its first argument, followed by
+    /// `args`.
+    pub fn submit<A, R>(&self, tree: TreeId, entry: &str, args: A) -> Result<R, mlua::Error>
+    where
+        A: for<'lua> IntoLuaMulti<'lua> + Send + 'static,
+        R: for<'lua> mlua::FromLuaMulti<'lua> + Send + 'static,
+    {
+        let shared = self.arena.get(tree).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("no tree with ID {:?} in arena", tree))
+        })?;
+        let entry = entry.to_string();
+        self.run(move |lua| {
+            let tree = push_shared(lua, tree, &shared)?;
+            call_entry(lua, &entry, tree, args)
+        })
+    }
+
+    /// Runs an arbitrary function on one of the pool's Lua states, blocking until it completes.
+    pub fn run<F, R>(&self, f: F) -> Result<R, mlua::Error>
+    where
+        F: FnOnce(&Lua) -> Result<R, mlua::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.workers.run(f)
+    }
+}
+
+/// The trees from a [`TreeArena`] that have been pushed into a Lua state, along with the shared
+/// trees that they were pushed from.
+#[derive(Default)]
+struct PushedTrees(HashMap<TreeId, (Weak<SharedTree>, RegistryKey)>);
+
+/// Returns the Lua tree for a tree from a [`TreeArena`], pushing it into the Lua state if this is
+/// the first job on the state to analyze it.  The Lua tree reads its source from the shared tree's
+/// buffer.
+fn push_shared<'lua>(
+    lua: &'lua Lua,
+    id: TreeId,
+    shared: &Arc<SharedTree>,
+) -> Result<Value<'lua>, mlua::Error> {
+    if lua.app_data_ref::<PushedTrees>().is_none() {
+        lua.set_app_data(PushedTrees::default());
+    }
+    let cached = match lua.app_data_ref::<PushedTrees>().unwrap().0.get(&id) {
+        // The ID might have been reused for a different tree after the old one was removed.
+        Some((tree, key)) if tree.as_ptr() == Arc::as_ptr(shared) => {
+            Some(lua.registry_value::<Value>(key)?)
+        }
+        _ => None,
+    };
+    if let Some(tree) = cached {
+        let closed = match ltreesitter::as_tree(lua, &tree)? {
+            Some(ltreesitter_tree) => unsafe { (*ltreesitter_tree).tree.is_null() },
+            None => true,
+        };
+        if !closed {
+            return Ok(tree);
+        }
+    }
+
+    let tree = shared
+        .tree()
+        .with_source_store(shared.src.clone())
+        .into_lua(lua)?;
+    let key = lua.create_registry_value(tree.clone())?;
+    {
+        let mut pushed = lua.app_data_mut::<PushedTrees>().unwrap();
+        // Forget about trees that have been removed from the arena, so that they can be freed.
+        pushed.0.retain(|_, (tree, _)| tree.strong_count() > 0);
+        pushed.0.insert(id, (Arc::downgrade(shared), key));
+    }
+    lua.expire_registry_values();
+    Ok(tree)
+}
+
+/// Calls the global Lua function named `entry`, passing in a tree followed by `args`.
+pub(crate) fn call_entry<'lua, T, A, R>(
+    lua: &'lua Lua,
+    entry: &str,
+    tree: T,
+    args: A,
+) -> Result<R, mlua::Error>
+where
+    T: IntoLua<'lua>,
+    A: IntoLuaMulti<'lua>,
+    R: mlua::FromLuaMulti<'lua>,
+{
+    let entry: Function = lua.globals().get(entry)?;
+    let mut values = vec![lua.pack(tree)?];
+    values.extend(args.into_lua_multi(lua)?);
+    entry.call(MultiValue::from_vec(values))
+}
+
+/// A set of threads, each of which owns a Lua state, that pull jobs from a shared queue.
+pub(crate) struct Workers {
+    jobs: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    pub(crate) fn spawn(inits: Vec<Init>) -> Result<Workers, mlua::Error> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Workers {
+            jobs: Some(jobs),
+            threads: Vec::with_capacity(inits.len()),
+        };
+        let mut readiness = Vec::with_capacity(inits.len());
+        for init in inits {
+            let receiver = receiver.clone();
+            let (ready, is_ready) = mpsc::channel();
+            readiness.push(is_ready);
+            workers.threads.push(std::thread::spawn(move || {
+                let lua = Lua::new();
+                let result = lua.open_ltreesitter().and_then(|_| init(&lua));
+                let succeeded = result.is_ok();
+                let _ = ready.send(result);
+                if !succeeded {
+                    return;
+                }
+                loop {
+                    // Only hold the lock while waiting for the next job, so that other workers
+                    // can pick up jobs while this one is running.
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(&lua),
+                        Err(_) => break,
+                    }
+                }
+            }));
+        }
+        // If any of the workers fail to start up, dropping `workers` shuts down the others.
+        for is_ready in readiness {
+            is_ready.recv().map_err(|_| shut_down())??;
+        }
+        Ok(workers)
+    }
+
+    fn run<F, R>(&self, f: F) -> Result<R, mlua::Error>
+    where
+        F: FnOnce(&Lua) -> Result<R, mlua::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (result, receive_result) = mpsc::channel();
+        self.send(Box::new(move |lua| {
+            let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| f(lua)))
+                .unwrap_or_else(|panic| Err(panicked(panic)));
+            let _ = result.send(outcome);
+        }))?;
+        receive_result.recv().map_err(|_| shut_down())?
+    }
+
+    /// Queues a job without waiting for it to run.
+    pub(crate) fn send(&self, job: Job) -> Result<(), mlua::Error> {
+        self.jobs
+            .as_ref()
+            .ok_or_else(shut_down)?
+            .send(job)
+            .map_err(|_| shut_down())
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        // Closing the channel causes the worker threads to exit once they finish any pending
+        // jobs.
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub(crate) fn shut_down() -> mlua::Error {
+    mlua::Error::RuntimeError("script runner has shut down".to_string())
+}
+
+fn panicked(panic: Box<dyn std::any::Any + Send>) -> mlua::Error {
+    let message = if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    };
+    mlua::Error::RuntimeError(format!("analysis job panicked: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mlua::FromLua;
+
+    use super::*;
+
+    #[test]
+    fn can_run_jobs_from_multiple_threads() {
+        let runner = ScriptRunner::new(|lua| {
+            lua.load(
+                r#"
+                  function describe(parsed, label)
+                    return label .. ": " .. parsed:root():type()
+                  end
+                "#,
+            )
+            .exec()
+        })
+        .unwrap();
+        let runner = Arc::new(runner);
+
+        let threads = (0..4)
+            .map(|i| {
+                let runner = runner.clone();
+                std::thread::spawn(move || {
+                    let code = b"def double(x): return x * 2\n";
+                    let mut parser = tree_sitter::Parser::new();
+                    parser.set_language(tree_sitter_python::language()).unwrap();
+                    let parsed = parser.parse(code, None).unwrap();
+                    let job = AnalysisJob::new(parsed, &code[..], "describe")
+                        .with_args(format!("job {}", i));
+                    let result: String = runner.submit(job).unwrap();
+                    assert_eq!(format!("job {}: module", i), result);
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn can_share_trees_among_pooled_states() {
+        let pool = ScriptPool::new(3, |lua| {
+            lua.load(
+                r#"
+                  function child_type(parsed, index)
+                    return parsed:root():child(index):type()
+                  end
+                "#,
+            )
+            .exec()
+        })
+        .unwrap();
+        let code = b"def double(x): return x * 2\nx = 1\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let id = pool.arena().insert(parsed, &code[..]);
+        let pool = Arc::new(pool);
+
+        let threads = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let result: String = pool.submit(id, "child_type", i % 2).unwrap();
+                    let expected = if i % 2 == 0 {
+                        "function_definition"
+                    } else {
+                        "expression_statement"
+                    };
+                    assert_eq!(expected, result);
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // The Lua states read the source from the arena's buffer, instead of from copies.
+        let shared = pool.arena().get(id).unwrap();
+        let expected = shared.src().as_ptr() as usize;
+        let address = pool
+            .run(move |lua| {
+                let tree = push_shared(lua, id, &shared)?;
+                let tree = crate::TreeWithSource::from_lua(tree, lua)?;
+                Ok(tree.src.as_ptr() as usize)
+            })
+            .unwrap();
+        assert_eq!(expected, address);
+    }
+
+    #[test]
+    fn pushes_shared_trees_once_per_state() {
+        let pool = ScriptPool::new(1, |lua| {
+            lua.load(
+                r#"
+                  seen = {}
+                  function count_pushes(parsed)
+                    seen[parsed] = true
+                    local count = 0
+                    for _ in pairs(seen) do
+                      count = count + 1
+                    end
+                    return count
+                  end
+                "#,
+            )
+            .exec()
+        })
+        .unwrap();
+        let code = b"x = 1\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let id = pool.arena().insert(parsed, &code[..]);
+        for _ in 0..3 {
+            let count: usize = pool.submit(id, "count_pushes", ()).unwrap();
+            assert_eq!(1, count);
+        }
+
+        let result: Result<(), _> = pool.run(|_| panic!("job failed"));
+        assert!(result.unwrap_err().to_string().contains("job failed"));
+        let count: usize = pool.submit(id, "count_pushes", ()).unwrap();
+        assert_eq!(1, count);
+    }
+
+    #[test]
+    fn reports_initialization_errors() {
+        let result = ScriptRunner::new(|lua| lua.load("this is not lua").exec());
+        assert!(result.is_err());
+    }
+}
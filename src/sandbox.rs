@@ -0,0 +1,129 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A sandbox mode for running untrusted Lua code.
+//!
+//! `ltreesitter.load` and `ltreesitter.require` open arbitrary shared libraries, so they let Lua
+//! code run arbitrary native code.  In an environment opened with
+//! [`Module::open_ltreesitter_sandboxed`][crate::Module::open_ltreesitter_sandboxed],
+//! `ltreesitter.load` raises an error, and `ltreesitter.require` only returns parsers for the
+//! grammars that the host registered via
+//! [`Module::register_language`][crate::Module::register_language].  Anything else that would
+//! load or build a grammar library (like the language registry's `compile_grammar` and
+//! `load_pack`) fails too, as does anything that reads files from disk (like `read_query_file`,
+//! `load_query_file`, and the Neovim adapter's `query.get`).
+//!
+//! This only covers the bridge.  The Lua environment should also be created without the ability
+//! to load C modules (which is what [`Lua::new`] does), and without any other standard libraries
+//! that the untrusted code shouldn't have.
+
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+
+use crate::languages;
+use crate::ltreesitter;
+
+/// Marks a Lua environment as sandboxed.
+struct Sandboxed;
+
+/// Returns whether the `ltreesitter` module was opened in sandbox mode.
+pub(crate) fn is_sandboxed(lua: &Lua) -> bool {
+    lua.app_data_ref::<Sandboxed>().is_some()
+}
+
+/// Returns an error if the `ltreesitter` module was opened in sandbox mode.
+pub(crate) fn check_allowed(lua: &Lua, what: &str) -> Result<(), mlua::Error> {
+    if is_sandboxed(lua) {
+        return Err(disabled(what));
+    }
+    Ok(())
+}
+
+pub(crate) fn disabled(what: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("{} is disabled in sandboxed environments", what))
+}
+
+/// Marks a Lua environment as sandboxed, so that anything outside of the `ltreesitter` module
+/// that would load or build a grammar library fails.
+pub(crate) fn mark(lua: &Lua) {
+    lua.set_app_data(Sandboxed);
+}
+
+/// Removes the ability to load grammars from shared libraries from a copy of the `ltreesitter`
+/// module.
+pub(crate) fn restrict(lua: &Lua, module: &Table) -> Result<(), mlua::Error> {
+    module.set(
+        "load",
+        lua.create_function(|_, _: MultiValue| -> Result<(), mlua::Error> {
+            Err(disabled("ltreesitter.load"))
+        })?,
+    )?;
+    module.set(
+        "require",
+        lua.create_function(|lua, (name, _): (String, Value)| {
+            match languages::linked_language(lua, &name) {
+                Some(language) => ltreesitter::new_parser(lua, language),
+                None => Err(mlua::Error::RuntimeError(format!(
+                    "no grammar named {} is registered (loading grammar libraries is disabled in \
+                     sandboxed environments)",
+                    name
+                ))),
+            }
+        })?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::NvimCompat;
+
+    #[test]
+    fn can_sandbox_grammar_loading() {
+        let l = Lua::new();
+        l.open_ltreesitter_sandboxed().unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        assert!(is_sandboxed(&l));
+        l.check(
+            r#"
+              local ltreesitter = require("ltreesitter")
+              local parser = ltreesitter.require("python")
+              assert(parser:parse_string("x = 1"):root():type() == "module")
+              local ok, err = pcall(ltreesitter.require, "c")
+              assert(not ok and tostring(err):find("no grammar named c", 1, true))
+              ok, err = pcall(ltreesitter.load, "./libc.so", "c")
+              assert(not ok and tostring(err):find("disabled", 1, true))
+              local ltreesitter_rs = require("ltreesitter_rs")
+              local languages = ltreesitter_rs.languages
+              assert(not pcall(languages.parser, languages, "c"))
+              assert(not pcall(languages.load_pack, languages, "./pack"))
+              ok, err = pcall(ltreesitter_rs.read_query_file, "queries/python/tags.scm")
+              assert(not ok and tostring(err):find("disabled", 1, true))
+              ok, err = pcall(ltreesitter_rs.load_query_file, parser, "queries/python/tags.scm")
+              assert(not ok and tostring(err):find("disabled", 1, true))
+            "#,
+        );
+        let adapter = l.open_nvim_compat().unwrap();
+        adapter
+            .set("query_paths", l.create_sequence_from(["."]).unwrap())
+            .unwrap();
+        l.check(
+            r#"
+              local ok, err = pcall(vim.treesitter.query.get, "python", "tags")
+              assert(not ok and tostring(err):find("disabled", 1, true))
+            "#,
+        );
+
+        let unsandboxed = Lua::new();
+        unsandboxed.open_ltreesitter().unwrap();
+        assert!(!is_sandboxed(&unsandboxed));
+    }
+}
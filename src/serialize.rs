@@ -0,0 +1,218 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Serializes parse trees with serde, for debugging dumps and golden tests.
+//!
+//! With the `serde` feature, [`TSNode`] and [`TreeWithSource`] implement [`Serialize`].  Each node
+//! is serialized as a map with the same fields as the tables that
+//! [`TreeWithSource::to_lua_table`] creates: `kind`, `named`, `field` (only if the node fills a
+//! field of its parent), `start_byte`, `end_byte`, `start_point` and `end_point` (each a map with
+//! `row` and `column`), and `children`.  A tree is serialized as its root node.  To include each
+//! node's source code as `text`, serialize [`TreeWithSource::serialize_with_text`] instead.
+//!
+//! In Lua, `require("ltreesitter_rs").to_json(tree_or_node [, options])` returns the same thing as
+//! a JSON string, where `options` can have `text` and `pretty` fields.
+
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use serde::ser::SerializeMap;
+use serde::ser::SerializeSeq;
+use serde::Serialize;
+use serde::Serializer;
+use tree_sitter::Node;
+use tree_sitter::Point;
+
+use crate::limits;
+use crate::pretty;
+use crate::TSNode;
+use crate::TreeWithSource;
+
+/// A node that can be serialized, optionally along with the source code of it and its
+/// descendants.
+pub struct SerializedNode<'a> {
+    node: Node<'a>,
+    field: Option<&'static str>,
+    src: Option<&'a [u8]>,
+}
+
+impl<'a> SerializedNode<'a> {
+    /// Serializes `node` without its source code.
+    pub fn new(node: Node<'a>) -> SerializedNode<'a> {
+        SerializedNode {
+            node,
+            field: None,
+            src: None,
+        }
+    }
+
+    /// Includes each node's source code, taken from `src`.
+    pub fn with_text(mut self, src: &'a [u8]) -> SerializedNode<'a> {
+        self.src = Some(src);
+        self
+    }
+}
+
+impl Serialize for SerializedNode<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node = self.node;
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", node.kind())?;
+        map.serialize_entry("named", &node.is_named())?;
+        if let Some(field) = self.field {
+            map.serialize_entry("field", field)?;
+        }
+        map.serialize_entry("start_byte", &node.start_byte())?;
+        map.serialize_entry("end_byte", &node.end_byte())?;
+        map.serialize_entry("start_point", &SerializedPoint(node.start_position()))?;
+        map.serialize_entry("end_point", &SerializedPoint(node.end_position()))?;
+        if let Some(text) = self.src.and_then(|src| src.get(node.byte_range())) {
+            map.serialize_entry("text", &String::from_utf8_lossy(text))?;
+        }
+        map.serialize_entry("children", &SerializedChildren(self))?;
+        map.end()
+    }
+}
+
+struct SerializedChildren<'s, 'a>(&'s SerializedNode<'a>);
+
+impl Serialize for SerializedChildren<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let parent = self.0;
+        let mut seq = serializer.serialize_seq(Some(parent.node.child_count()))?;
+        let mut cursor = parent.node.walk();
+        if cursor.goto_first_child() {
+            loop {
+                seq.serialize_element(&SerializedNode {
+                    node: cursor.node(),
+                    field: cursor.field_name(),
+                    src: parent.src,
+                })?;
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+        seq.end()
+    }
+}
+
+struct SerializedPoint(Point);
+
+impl Serialize for SerializedPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("row", &self.0.row)?;
+        map.serialize_entry("column", &self.0.column)?;
+        map.end()
+    }
+}
+
+impl Serialize for TSNode<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedNode::new(self.0).serialize(serializer)
+    }
+}
+
+impl Serialize for TreeWithSource<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedNode::new(self.tree.root_node()).serialize(serializer)
+    }
+}
+
+impl TreeWithSource<'_> {
+    /// Returns the tree's root node in a form that serializes each node's source code as well.
+    pub fn serialize_with_text(&self) -> SerializedNode<'_> {
+        SerializedNode::new(self.tree.root_node()).with_text(self.src)
+    }
+}
+
+/// Adds `to_json` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let to_json = lua.create_function(|lua, (value, options): (Value, Option<Table>)| {
+        let (node, src) = pretty::node_and_source(lua, value)?;
+        limits::check_depth(lua, node)?;
+        let (text, pretty) = match options {
+            Some(options) => (
+                options.get::<_, Option<bool>>("text")?.unwrap_or(false),
+                options.get::<_, Option<bool>>("pretty")?.unwrap_or(false),
+            ),
+            None => (false, false),
+        };
+        let mut serialized = SerializedNode::new(node);
+        if let (true, Some(src)) = (text, src) {
+            serialized = serialized.with_text(src);
+        }
+        let json = if pretty {
+            serde_json::to_string_pretty(&serialized)
+        } else {
+            serde_json::to_string(&serialized)
+        };
+        json.map_err(mlua::Error::external)
+    })?;
+    crate::companion_module(lua)?.set("to_json", to_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_serialize_trees_to_json() {
+        let code = b"x = 1\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap().with_source(code);
+        let point = |row: usize, column: usize| json!({"row": row, "column": column});
+        let leaf = |kind: &str, named: bool, field: Option<&str>, start: usize, text: &str| {
+            let mut leaf = json!({
+                "kind": kind,
+                "named": named,
+                "start_byte": start,
+                "end_byte": start + text.len(),
+                "start_point": point(0, start),
+                "end_point": point(0, start + text.len()),
+                "text": text,
+                "children": [],
+            });
+            if let Some(field) = field {
+                leaf["field"] = json!(field);
+            }
+            leaf
+        };
+        let serialized = serde_json::to_value(parsed.serialize_with_text()).unwrap();
+        let assignment = &serialized["children"][0]["children"][0];
+        assert_eq!("module", serialized["kind"]);
+        assert_eq!(point(1, 0), serialized["end_point"]);
+        assert_eq!("assignment", assignment["kind"]);
+        assert_eq!(
+            json!([
+                leaf("identifier", true, Some("left"), 0, "x"),
+                leaf("=", false, None, 2, "="),
+                leaf("integer", true, Some("right"), 4, "1"),
+            ]),
+            assignment["children"]
+        );
+        let without_text = serde_json::to_value(&parsed).unwrap();
+        assert!(without_text["children"][0].get("text").is_none());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        let json: String = l.call(
+            r#"
+              return require("ltreesitter_rs").to_json(parsed:root():child(0), { text = true })
+            "#,
+        );
+        let from_lua: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(serialized["children"][0], from_lua);
+    }
+}
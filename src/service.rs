@@ -0,0 +1,190 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Runs Lua analysis scripts from async code.
+//!
+//! [`ScriptRunner::submit`][crate::ScriptRunner::submit] blocks the calling thread until the job
+//! finishes, which an event loop (like tokio or async-std) can't afford.  A [`LuaAnalysisService`]
+//! parks its Lua state on a dedicated thread in the same way, but its `submit` returns a future
+//! right away; the Lua thread wakes the future's task once the job is done.  The futures don't
+//! depend on any particular async runtime.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+use mlua::IntoLuaMulti;
+use mlua::Lua;
+
+use crate::runner;
+use crate::runner::Workers;
+use crate::AnalysisJob;
+use crate::WithSource;
+
+/// Owns a Lua state on a dedicated thread, and runs analysis jobs on it on behalf of async code.
+///
+/// `LuaAnalysisService` is `Sync`, so you can share a single service among all of your tasks.
+/// Jobs are executed in the order that they are submitted.  Dropping the service waits for any
+/// jobs that were already submitted to finish.
+pub struct LuaAnalysisService {
+    workers: Workers,
+}
+
+impl LuaAnalysisService {
+    /// Spawns a new Lua state on a dedicated thread.  The `ltreesitter` module is loaded into the
+    /// state, and then `init` is called to finish setting it up.  This blocks until `init`
+    /// finishes, so that setup errors are reported here.
+    pub fn new<F>(init: F) -> Result<LuaAnalysisService, mlua::Error>
+    where
+        F: FnOnce(&Lua) -> Result<(), mlua::Error> + Send + 'static,
+    {
+        let workers = Workers::spawn(vec![Box::new(init)])?;
+        Ok(LuaAnalysisService { workers })
+    }
+
+    /// Submits an analysis job, and returns a future that resolves to the result of the entry
+    /// function.  The job runs even if the future is dropped.
+    pub fn submit<A, R>(&self, job: AnalysisJob<A>) -> AnalysisFuture<R>
+    where
+        A: for<'lua> IntoLuaMulti<'lua> + Send + 'static,
+        R: for<'lua> mlua::FromLuaMulti<'lua> + Send + 'static,
+    {
+        self.run(move |lua| {
+            let tree = job.tree.with_source(&job.src);
+            runner::call_entry(lua, &job.entry, tree, job.args)
+        })
+    }
+
+    /// Runs an arbitrary function on the service's Lua state, and returns a future that resolves
+    /// to its result.
+    pub fn run<F, R>(&self, f: F) -> AnalysisFuture<R>
+    where
+        F: FnOnce(&Lua) -> Result<R, mlua::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (completion, future) = completion(runner::shut_down);
+        // If the service has shut down, the job is dropped without running, and the completion
+        // reports that to the future.
+        let _ = self
+            .workers
+            .send(Box::new(move |lua| completion.complete(f(lua))));
+        future
+    }
+}
+
+/// Creates a future, along with the completion that resolves it.  If the completion is dropped
+/// without being completed, the future resolves to the error that `dropped` returns.
+pub(crate) fn completion<R>(dropped: fn() -> mlua::Error) -> (Completion<R>, AnalysisFuture<R>) {
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    (
+        Completion(Some(slot.clone()), dropped),
+        AnalysisFuture { slot },
+    )
+}
+
+struct Slot<R> {
+    result: Option<Result<R, mlua::Error>>,
+    waker: Option<Waker>,
+}
+
+/// Delivers a job's result to its future.  If the job is dropped without running (because the
+/// service shut down), the future resolves to an error instead.
+pub(crate) struct Completion<R>(Option<Arc<Mutex<Slot<R>>>>, fn() -> mlua::Error);
+
+impl<R> Completion<R> {
+    pub(crate) fn complete(mut self, result: Result<R, mlua::Error>) {
+        if let Some(slot) = self.0.take() {
+            fill(&slot, result);
+        }
+    }
+}
+
+impl<R> Drop for Completion<R> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0.take() {
+            fill(&slot, Err((self.1)()));
+        }
+    }
+}
+
+fn fill<R>(slot: &Mutex<Slot<R>>, result: Result<R, mlua::Error>) {
+    let mut slot = slot.lock().unwrap();
+    slot.result = Some(result);
+    if let Some(waker) = slot.waker.take() {
+        waker.wake();
+    }
+}
+
+/// The eventual result of a job that was submitted to a [`LuaAnalysisService`], or of a
+/// [background parse][crate::parse_in_background].
+pub struct AnalysisFuture<R> {
+    slot: Arc<Mutex<Slot<R>>>,
+}
+
+impl<R> Future for AnalysisFuture<R> {
+    type Output = Result<R, mlua::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::block_on;
+
+    #[test]
+    fn can_await_analysis_jobs() {
+        let service = LuaAnalysisService::new(|lua| {
+            lua.load(
+                r#"
+                  function describe(parsed, label)
+                    return label .. ": " .. parsed:root():type()
+                  end
+                "#,
+            )
+            .exec()
+        })
+        .unwrap();
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let futures = (0..3)
+            .map(|i| {
+                let parsed = parser.parse(code, None).unwrap();
+                let job =
+                    AnalysisJob::new(parsed, &code[..], "describe").with_args(format!("job {}", i));
+                service.submit::<_, String>(job)
+            })
+            .collect::<Vec<_>>();
+        for (i, future) in futures.into_iter().enumerate().rev() {
+            assert_eq!(format!("job {}: module", i), block_on(future).unwrap());
+        }
+
+        let parsed = parser.parse(code, None).unwrap();
+        let missing = service.submit::<_, String>(AnalysisJob::new(parsed, &code[..], "missing"));
+        assert!(block_on(missing).is_err());
+        assert_eq!(
+            3,
+            block_on(service.run(|lua| lua.load("return 1 + 2").eval::<i32>())).unwrap()
+        );
+    }
+}
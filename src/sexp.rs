@@ -0,0 +1,140 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Prints syntax trees as S-expressions.
+//!
+//! Printing a big tree from Lua by calling `node:child()` recursively is very slow, since every
+//! step crosses the boundary and creates a new userdata.  These functions walk the tree in Rust
+//! instead.  [`to_sexp`] prints the same single-line S-expression as tree-sitter itself, which is
+//! the format that tree-sitter's corpus tests use.  [`to_pretty_sexp`] prints the same thing with
+//! one node per line, indented by depth:
+//!
+//! ``` text
+//! (module
+//!   (expression_statement
+//!     (assignment
+//!       left: (identifier)
+//!       right: (integer))))
+//! ```
+//!
+//! In Lua, `require("ltreesitter_rs").to_sexp(tree_or_node [, pretty])` returns either one.
+
+use std::fmt::Write;
+
+use mlua::Lua;
+use mlua::Value;
+use tree_sitter::Node;
+
+use crate::limits;
+use crate::pretty;
+
+/// Returns the S-expression of a syntax tree, starting at `node`, on a single line.
+pub fn to_sexp(node: Node) -> String {
+    node.to_sexp()
+}
+
+/// Returns the S-expression of a syntax tree, starting at `node`, with one node per line.
+pub fn to_pretty_sexp(node: Node) -> String {
+    let mut result = String::new();
+    let mut cursor = node.walk();
+    // Whether each node on the path from `node` to the cursor was printed, and so has to be
+    // closed once we're done with its children.
+    let mut open = Vec::new();
+    'nodes: loop {
+        let node = cursor.node();
+        let printed = node.is_named() || node.is_missing();
+        if printed {
+            if !result.is_empty() {
+                result.push('\n');
+            }
+            let depth = open.iter().filter(|printed| **printed).count();
+            result.push_str(&"  ".repeat(depth));
+            if let Some(field_name) = cursor.field_name() {
+                result.push_str(field_name);
+                result.push_str(": ");
+            }
+            result.push('(');
+            if node.is_missing() {
+                result.push_str("MISSING ");
+            }
+            if node.is_named() {
+                result.push_str(node.kind());
+            } else {
+                let _ = write!(result, "{:?}", node.kind());
+            }
+        }
+        open.push(printed);
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if open.pop() == Some(true) {
+                result.push(')');
+            }
+            if open.is_empty() {
+                break 'nodes;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            cursor.goto_parent();
+        }
+    }
+    result
+}
+
+/// Adds `to_sexp` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let to_sexp = lua.create_function(|lua, (value, pretty): (Value, Option<bool>)| {
+        let (node, _) = pretty::node_and_source(lua, value)?;
+        limits::check_depth(lua, node)?;
+        Ok(match pretty {
+            Some(true) => to_pretty_sexp(node),
+            _ => self::to_sexp(node),
+        })
+    })?;
+    crate::companion_module(lua)?.set("to_sexp", to_sexp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_print_sexps() {
+        let code = b"def double(x): return x * 2\nx = [1, 2]\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let root = parsed.root_node();
+        let pretty = to_pretty_sexp(root);
+        assert!(pretty.starts_with("(module\n  (function_definition\n    name: (identifier)\n"));
+        let collapsed = pretty
+            .lines()
+            .map(str::trim_start)
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(to_sexp(root), collapsed);
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("expected", pretty).unwrap();
+        l.globals().set("compact", to_sexp(root)).unwrap();
+        l.check(
+            r#"
+              local ltreesitter_rs = require("ltreesitter_rs")
+              assert(ltreesitter_rs.to_sexp(parsed, true) == expected)
+              assert(ltreesitter_rs.to_sexp(parsed) == compact)
+              local definition = ltreesitter_rs.to_sexp(parsed:root():child(0))
+              assert(definition:sub(1, 21) == "(function_definition ")
+            "#,
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Trees that can be dropped under memory pressure, and transparently reparsed when they're needed
+//! again.
+//!
+//! A [`SoftTreePool`] keeps the source code of every document that you add to it, but only keeps
+//! the parsed trees of the most recently used ones.  Each document is represented by a
+//! [`SoftTree`] handle.  Asking the handle for its tree returns the retained tree if there is one
+//! (a hit), or reparses the source if not (a miss).  Hosts can evict trees themselves when they
+//! notice memory pressure via [`SoftTreePool::release_all`], and the pool evicts the least recently
+//! used trees automatically once more than its limit are resident.
+//!
+//! Handles can be pushed into Lua, where they have `tree()`, `source()`, `is_resident()`, and
+//! `release()` methods.  `tree()` returns a new ltreesitter tree.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use mlua::IntoLua;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use tree_sitter::Language;
+use tree_sitter::Parser;
+use tree_sitter::Tree;
+
+use crate::threads::Shared;
+use crate::WithSource;
+
+/// How often a pool's trees were found resident, and how often they had to be reparsed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SoftTreeStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct Entry {
+    language: Language,
+    src: Arc<[u8]>,
+    tree: Option<Tree>,
+    last_used: u64,
+}
+
+struct PoolState {
+    max_resident: usize,
+    entries: HashMap<u64, Entry>,
+    next_id: u64,
+    clock: u64,
+    stats: SoftTreeStats,
+}
+
+impl PoolState {
+    fn resident(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.tree.is_some())
+            .count()
+    }
+
+    /// Evicts the least recently used trees until at most `max` are resident.
+    fn trim(&mut self, max: usize) {
+        let mut resident = self.resident();
+        while resident > max {
+            let oldest = self
+                .entries
+                .values_mut()
+                .filter(|entry| entry.tree.is_some())
+                .min_by_key(|entry| entry.last_used);
+            match oldest {
+                Some(entry) => entry.tree = None,
+                None => break,
+            }
+            self.stats.evictions += 1;
+            resident -= 1;
+        }
+    }
+}
+
+/// A collection of documents whose trees are retained only while they're recently used.
+#[derive(Clone)]
+pub struct SoftTreePool {
+    state: Shared<PoolState>,
+}
+
+impl SoftTreePool {
+    /// Creates a new pool that keeps at most `max_resident` parsed trees.
+    pub fn new(max_resident: usize) -> SoftTreePool {
+        SoftTreePool {
+            state: Shared::new(PoolState {
+                max_resident,
+                entries: HashMap::new(),
+                next_id: 0,
+                clock: 0,
+                stats: SoftTreeStats::default(),
+            }),
+        }
+    }
+
+    /// Adds a document that has already been parsed.
+    pub fn insert<S: Into<Arc<[u8]>>>(&self, tree: Tree, src: S) -> SoftTree {
+        let language = tree.language();
+        self.add(language, src.into(), Some(tree))
+    }
+
+    /// Adds a document that will be parsed the first time its tree is needed.
+    pub fn insert_unparsed<S: Into<Arc<[u8]>>>(&self, language: Language, src: S) -> SoftTree {
+        self.add(language, src.into(), None)
+    }
+
+    fn add(&self, language: Language, src: Arc<[u8]>, tree: Option<Tree>) -> SoftTree {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.clock += 1;
+        let last_used = state.clock;
+        state.entries.insert(
+            id,
+            Entry {
+                language,
+                src,
+                tree,
+                last_used,
+            },
+        );
+        let max_resident = state.max_resident;
+        state.trim(max_resident);
+        SoftTree {
+            state: self.state.clone(),
+            id,
+        }
+    }
+
+    /// Evicts every resident tree.  Call this when the host is under memory pressure.
+    pub fn release_all(&self) {
+        self.state.borrow_mut().trim(0);
+    }
+
+    /// Returns the number of trees that are currently resident.
+    pub fn resident(&self) -> usize {
+        self.state.borrow().resident()
+    }
+
+    /// Returns the pool's hit and miss counts.
+    pub fn stats(&self) -> SoftTreeStats {
+        self.state.borrow().stats
+    }
+}
+
+/// A handle to a document in a [`SoftTreePool`].  Dropping the handle removes the document from
+/// the pool.
+pub struct SoftTree {
+    state: Shared<PoolState>,
+    id: u64,
+}
+
+impl SoftTree {
+    /// Returns the document's tree, reparsing it if it was evicted.
+    #[track_caller]
+    pub fn tree(&self) -> Result<Tree, mlua::Error> {
+        let mut state = self.state.borrow_mut();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(&self.id).expect("missing soft tree");
+        entry.last_used = clock;
+        let hit = entry.tree.is_some();
+        if !hit {
+            let mut parser = Parser::new();
+            parser
+                .set_language(entry.language)
+                .map_err(mlua::Error::external)?;
+            let tree = parser
+                .parse(&entry.src, None)
+                .ok_or_else(|| mlua::Error::RuntimeError("cannot reparse soft tree".to_string()))?;
+            entry.tree = Some(tree);
+        }
+        let tree = crate::copies::copy_tree(entry.tree.as_ref().unwrap());
+        if hit {
+            state.stats.hits += 1;
+        } else {
+            state.stats.misses += 1;
+            let max_resident = state.max_resident;
+            state.trim(max_resident);
+        }
+        Ok(tree)
+    }
+
+    /// Returns the document's source code.
+    pub fn src(&self) -> Arc<[u8]> {
+        self.state.borrow().entries[&self.id].src.clone()
+    }
+
+    /// Returns whether the document's tree is currently retained.
+    pub fn is_resident(&self) -> bool {
+        self.state.borrow().entries[&self.id].tree.is_some()
+    }
+
+    /// Evicts the document's tree, if it's resident.
+    pub fn release(&self) {
+        let mut state = self.state.borrow_mut();
+        let entry = state.entries.get_mut(&self.id).expect("missing soft tree");
+        if entry.tree.take().is_some() {
+            state.stats.evictions += 1;
+        }
+    }
+}
+
+impl Drop for SoftTree {
+    fn drop(&mut self) {
+        self.state.borrow_mut().entries.remove(&self.id);
+    }
+}
+
+impl UserData for SoftTree {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("tree", |lua, soft, ()| {
+            let tree = soft.tree()?;
+            let src = soft.src();
+            tree.with_source(&src).into_lua(lua)
+        });
+        methods.add_method("source", |lua, soft, ()| lua.create_string(&*soft.src()));
+        methods.add_method("is_resident", |_, soft, ()| Ok(soft.is_resident()));
+        methods.add_method("release", |_, soft, ()| {
+            soft.release();
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use mlua::Lua;
+
+    #[test]
+    fn can_reparse_evicted_trees() {
+        let language = tree_sitter_python::language();
+        let pool = SoftTreePool::new(1);
+        let a = pool.insert_unparsed(language, &b"def a(): pass\n"[..]);
+        let b = pool.insert_unparsed(language, &b"def b(): pass\n"[..]);
+        assert_eq!(0, pool.resident());
+        a.tree().unwrap();
+        b.tree().unwrap();
+        assert!(!a.is_resident() && b.is_resident());
+        a.tree().unwrap();
+        a.tree().unwrap();
+        assert_eq!(
+            SoftTreeStats {
+                hits: 1,
+                misses: 3,
+                evictions: 2,
+            },
+            pool.stats()
+        );
+        pool.release_all();
+        assert_eq!(0, pool.resident());
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("soft", a).unwrap();
+        l.check(
+            r#"
+              assert(not soft:is_resident())
+              assert(soft:tree():root():type() == "module")
+              assert(soft:is_resident())
+              assert(soft:source() == "def a(): pass\n")
+            "#,
+        );
+        drop(b);
+        assert_eq!(1, pool.resident());
+    }
+}
@@ -0,0 +1,296 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Secondary sources that can be attached to a tree alongside the source that it was parsed from.
+//!
+//! This is useful for languages with preprocessors: you parse the preprocessed text, but also want
+//! to be able to see the original text that each node came from.  Each secondary source has a
+//! name and a [`SourceMap`] that translates byte offsets in the parsed source into byte offsets in
+//! the secondary source.  In Lua, `node:source(name)` returns the text of a node in the
+//! secondary source with the given name.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use mlua::AnyUserData;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+
+use crate::ltreesitter;
+use crate::trees;
+use crate::TreeWithSource;
+
+const SOURCES_KEY: &str = "sources";
+
+/// Translates byte offsets in the source that a tree was parsed from into byte offsets in a
+/// secondary source.
+///
+/// A source map consists of a list of segments, each of which maps a range of the parsed source
+/// to a range of the secondary source.  If the two ranges have the same length, offsets within
+/// the segment are mapped one-to-one; otherwise, the entire parsed range maps onto the entire
+/// secondary range.  Offsets that fall between segments are shifted by the same amount as the end
+/// of the preceding segment, so an empty source map is the identity mapping.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    segments: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl SourceMap {
+    /// Creates a new, empty source map, which represents the identity mapping.
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Adds a segment to the source map.  Segments must not overlap.
+    pub fn add(&mut self, parsed: Range<usize>, secondary: Range<usize>) {
+        let index = self
+            .segments
+            .partition_point(|(existing, _)| existing.start < parsed.start);
+        self.segments.insert(index, (parsed, secondary));
+    }
+
+    /// Maps a range of the parsed source into the corresponding range of the secondary source.
+    pub fn map_range(&self, range: Range<usize>) -> Range<usize> {
+        let start = self.map_offset(range.start, false);
+        let end = self.map_offset(range.end, true);
+        start..end.max(start)
+    }
+
+    fn map_offset(&self, offset: usize, is_end: bool) -> usize {
+        // Find the last segment that starts before the offset.
+        let index = self.segments.partition_point(|(parsed, _)| {
+            if is_end {
+                parsed.start < offset
+            } else {
+                parsed.start <= offset
+            }
+        });
+        let (parsed, secondary) = match index.checked_sub(1) {
+            Some(index) => &self.segments[index],
+            None => return offset,
+        };
+        if offset > parsed.end || (!is_end && offset == parsed.end) {
+            secondary.end + (offset - parsed.end)
+        } else if parsed.len() == secondary.len() {
+            secondary.start + (offset - parsed.start)
+        } else if is_end {
+            secondary.end
+        } else {
+            secondary.start
+        }
+    }
+}
+
+impl std::fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SourceMap(")?;
+        for (index, (parsed, secondary)) in self.segments.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:?} => {:?}", parsed, secondary)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl mlua::UserData for SourceMap {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(mlua::MetaMethod::ToString, |_, map, ()| Ok(map.to_string()));
+    }
+}
+
+/// A secondary source that is attached to a tree.
+#[derive(Clone, Debug)]
+pub struct SecondarySource<'a> {
+    pub name: String,
+    pub src: Cow<'a, [u8]>,
+    pub map: SourceMap,
+}
+
+impl SecondarySource<'_> {
+    /// Returns the text of a node in this secondary source.  Returns `None` if the node's mapped
+    /// range falls outside of the secondary source.
+    pub fn text(&self, node: &tree_sitter::Node) -> Option<&[u8]> {
+        self.src.get(self.map.map_range(node.byte_range()))
+    }
+}
+
+impl<'a> TreeWithSource<'a> {
+    /// Attaches a secondary source to this tree.  When the tree is pushed into Lua, the secondary
+    /// source will be copied into the Lua state along with the tree's main source.
+    pub fn with_secondary_source<N: Into<String>>(
+        mut self,
+        name: N,
+        src: &'a [u8],
+        map: SourceMap,
+    ) -> TreeWithSource<'a> {
+        self.secondary.push(SecondarySource {
+            name: name.into(),
+            src: Cow::Borrowed(src),
+            map,
+        });
+        self
+    }
+
+    /// Returns the secondary source with the given name, if there is one.
+    pub fn secondary_source(&self, name: &str) -> Option<&SecondarySource<'a>> {
+        self.secondary.iter().find(|source| source.name == name)
+    }
+
+    /// Returns the text of a node in the secondary source with the given name.
+    pub fn secondary_text(&self, name: &str, node: &tree_sitter::Node) -> Option<&[u8]> {
+        self.secondary_source(name)?.text(node)
+    }
+}
+
+/// Stores the secondary sources of a tree in the tree's attachments table.
+pub(crate) fn attach<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    secondary: &[SecondarySource],
+) -> Result<(), mlua::Error> {
+    if secondary.is_empty() {
+        return Ok(());
+    }
+    let sources = lua.create_table()?;
+    for source in secondary {
+        let entry = lua.create_table()?;
+        entry.set("text", lua.create_string(&source.src)?)?;
+        entry.set("map", source.map.clone())?;
+        sources.set(source.name.as_str(), entry)?;
+    }
+    trees::attachments(lua, tree)?.set(SOURCES_KEY, sources)
+}
+
+/// Loads the secondary sources of a tree from the tree's attachments table, sorted by name.
+pub(crate) fn load<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+) -> Result<Vec<SecondarySource<'static>>, mlua::Error> {
+    let sources = match trees::existing_attachments(lua, tree)? {
+        Some(attachments) => attachments.get::<_, Option<Table>>(SOURCES_KEY)?,
+        None => None,
+    };
+    let sources = match sources {
+        Some(sources) => sources,
+        None => return Ok(Vec::new()),
+    };
+    let mut result = Vec::new();
+    for pair in sources.pairs::<String, Table>() {
+        let (name, entry) = pair?;
+        let text: mlua::String = entry.get("text")?;
+        let map: AnyUserData = entry.get("map")?;
+        result.push(SecondarySource {
+            name,
+            src: Cow::Owned(text.as_bytes().to_vec()),
+            map: map.borrow::<SourceMap>()?.clone(),
+        });
+    }
+    // Lua tables don't have an order, so sort the sources to keep them stable from run to run.
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(result)
+}
+
+/// Extends ltreesitter's `node:source()` method so that it accepts an optional name of a secondary
+/// source.
+pub(crate) fn install_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    ltreesitter::wrap_method(
+        lua,
+        ltreesitter::NODE_METATABLE,
+        "source",
+        |lua, original, args| {
+            let name = match args.iter().nth(1) {
+                Some(Value::String(name)) => name.to_str()?.to_string(),
+                _ => return original.call::<_, MultiValue>(args),
+            };
+            let value = args.iter().next().cloned().unwrap_or(Value::Nil);
+
+            let ltreesitter_node = ltreesitter::node_ptr(lua, value.clone())?;
+            let node = unsafe { tree_sitter::Node::from_raw((*ltreesitter_node).node) };
+            let missing =
+                || mlua::Error::RuntimeError(format!("tree has no source named {}", name));
+            let tree = trees::owner(lua, &value)?.ok_or_else(missing)?;
+            let sources = match trees::existing_attachments(lua, &tree)? {
+                Some(attachments) => attachments.get::<_, Option<Table>>(SOURCES_KEY)?,
+                None => None,
+            };
+            let entry = match sources {
+                Some(sources) => sources.get::<_, Option<Table>>(name.as_str())?,
+                None => None,
+            };
+            let entry = entry.ok_or_else(missing)?;
+            let text: mlua::String = entry.get("text")?;
+            let map: AnyUserData = entry.get("map")?;
+            let range = map.borrow::<SourceMap>()?.map_range(node.byte_range());
+            let text = text.as_bytes().get(range).ok_or_else(|| {
+                mlua::Error::RuntimeError(format!("node is out of bounds of source {}", name))
+            })?;
+            let result = lua.create_string(text)?;
+            Ok(MultiValue::from_vec(vec![Value::String(result)]))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_map_ranges() {
+        let mut map = SourceMap::new();
+        map.add(10..20, 10..20);
+        map.add(20..25, 20..40);
+        map.add(25..30, 40..45);
+        assert_eq!(2..4, map.map_range(2..4));
+        assert_eq!(12..18, map.map_range(12..18));
+        assert_eq!(20..40, map.map_range(21..23));
+        assert_eq!(15..42, map.map_range(15..27));
+        assert_eq!(47..48, map.map_range(32..33));
+    }
+
+    #[test]
+    fn can_read_secondary_source_from_lua() {
+        let code = b"def double(x): return x * 2\n";
+        let original = b"def DOUBLE(x): return TWICE(x)\n";
+        let mut map = SourceMap::new();
+        map.add(22..27, 22..30);
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let parsed = parsed
+            .with_source(code)
+            .with_secondary_source("original", original, map);
+        let node = parsed.tree.root_node().child(0).unwrap();
+        assert_eq!(
+            Some(&original[..30]),
+            parsed.secondary_text("original", &node)
+        );
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        l.check(
+            r#"
+              local def = parsed:root():child(0)
+              local name = def:child_by_field_name("name")
+              assert(name:source() == "double", "expected parsed source")
+              assert(name:source("original") == "DOUBLE", "expected original source")
+              local body = def:child_by_field_name("body")
+              assert(body:source("original") == "return TWICE(x)", "expected original body")
+            "#,
+        );
+
+        let tws: TreeWithSource = l.call(r#" return parsed "#);
+        let node = tws.tree.root_node().child(0).unwrap();
+        assert_eq!(Some(&original[..30]), tws.secondary_text("original", &node));
+    }
+}
@@ -0,0 +1,216 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Merges overlapping capture ranges into a flat list of spans that a highlighter can render.
+//!
+//! [`merge_spans`] splits the input ranges at every boundary, and assigns each piece to exactly
+//! one of the ranges that covers it: the one with the highest priority, or if there's a tie, the
+//! innermost (shortest) one, or if there's still a tie, the one that appears last in the input.
+//! Adjacent pieces with the same name and priority are joined back together.  The result is
+//! sorted, and no two spans overlap.
+//!
+//! In Lua, `require("ltreesitter_rs").merge_spans(spans)` does the same.  Each input span is a
+//! table with a `name`, an optional `priority` (which defaults to 0), and either `start_byte` and
+//! `end_byte` fields or a `node` field.  The results have `start_byte`, `end_byte`, `name`, and
+//! `priority` fields.
+
+use mlua::FromLua;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::Value;
+
+use crate::offsets::Offset;
+use crate::LuaInterning;
+use crate::ResolvedCapture;
+use crate::TSNode;
+
+/// A named byte range of a source file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HighlightSpan {
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub name: String,
+    pub priority: i64,
+}
+
+impl HighlightSpan {
+    /// Creates a new span.
+    pub fn new<S: Into<String>>(start_byte: usize, end_byte: usize, name: S) -> HighlightSpan {
+        HighlightSpan {
+            start_byte,
+            end_byte,
+            name: name.into(),
+            priority: 0,
+        }
+    }
+
+    /// Sets the priority of the span.
+    pub fn with_priority(mut self, priority: i64) -> HighlightSpan {
+        self.priority = priority;
+        self
+    }
+
+    fn len(&self) -> usize {
+        self.end_byte - self.start_byte
+    }
+}
+
+impl From<&ResolvedCapture<'_>> for HighlightSpan {
+    fn from(capture: &ResolvedCapture) -> HighlightSpan {
+        HighlightSpan::new(
+            capture.node.start_byte(),
+            capture.node.end_byte(),
+            capture.name.as_str(),
+        )
+        .with_priority(capture.priority)
+    }
+}
+
+impl<'lua> FromLua<'lua> for HighlightSpan {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self, mlua::Error> {
+        let table = match value {
+            Value::Table(table) => table,
+            value => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "HighlightSpan",
+                    message: None,
+                })
+            }
+        };
+        let (start_byte, end_byte) = match table.get::<_, Value>("node")? {
+            Value::Nil => (
+                table.get::<_, Offset>("start_byte")?.0,
+                table.get::<_, Offset>("end_byte")?.0,
+            ),
+            node => {
+                let node = TSNode::from_lua(node, lua)?;
+                (node.start_byte(), node.end_byte())
+            }
+        };
+        Ok(HighlightSpan {
+            start_byte,
+            end_byte,
+            name: table.get("name")?,
+            priority: table.get::<_, Option<i64>>("priority")?.unwrap_or(0),
+        })
+    }
+}
+
+impl<'lua> IntoLua<'lua> for HighlightSpan {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let table = lua.create_table()?;
+        table.set("start_byte", Offset(self.start_byte))?;
+        table.set("end_byte", Offset(self.end_byte))?;
+        table.set("name", lua.interned_string(&self.name)?)?;
+        table.set("priority", self.priority)?;
+        Ok(Value::Table(table))
+    }
+}
+
+/// Merges overlapping spans into a sorted list of non-overlapping spans, resolving each overlap
+/// in favor of the highest-priority, innermost span.  Empty spans are ignored.
+pub fn merge_spans(spans: &[HighlightSpan]) -> Vec<HighlightSpan> {
+    let mut boundaries = spans
+        .iter()
+        .filter(|span| span.start_byte < span.end_byte)
+        .flat_map(|span| [span.start_byte, span.end_byte])
+        .collect::<Vec<_>>();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut order = (0..spans.len())
+        .filter(|index| spans[*index].start_byte < spans[*index].end_byte)
+        .collect::<Vec<_>>();
+    order.sort_by_key(|index| spans[*index].start_byte);
+    let mut pending = order.into_iter().peekable();
+    let mut active: Vec<usize> = Vec::new();
+
+    let mut result: Vec<HighlightSpan> = Vec::new();
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        active.retain(|index| spans[*index].end_byte > start);
+        while let Some(index) = pending.next_if(|index| spans[*index].start_byte <= start) {
+            active.push(index);
+        }
+        let winner = active.iter().copied().max_by(|a, b| {
+            let (a_span, b_span) = (&spans[*a], &spans[*b]);
+            a_span
+                .priority
+                .cmp(&b_span.priority)
+                .then_with(|| b_span.len().cmp(&a_span.len()))
+                .then_with(|| a.cmp(b))
+        });
+        let winner = match winner {
+            Some(winner) => &spans[winner],
+            None => continue,
+        };
+        if let Some(last) = result.last_mut() {
+            if last.end_byte == start
+                && last.name == winner.name
+                && last.priority == winner.priority
+            {
+                last.end_byte = end;
+                continue;
+            }
+        }
+        result.push(HighlightSpan {
+            start_byte: start,
+            end_byte: end,
+            name: winner.name.clone(),
+            priority: winner.priority,
+        });
+    }
+    result
+}
+
+/// Adds `merge_spans` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let merge = lua.create_function(|_, spans: Vec<HighlightSpan>| Ok(merge_spans(&spans)))?;
+    crate::companion_module(lua)?.set("merge_spans", merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+
+    #[test]
+    fn can_merge_overlapping_spans() {
+        let merged = merge_spans(&[
+            HighlightSpan::new(0, 20, "function"),
+            HighlightSpan::new(4, 10, "name"),
+            HighlightSpan::new(8, 14, "keyword").with_priority(1),
+            HighlightSpan::new(14, 20, "function"),
+            HighlightSpan::new(30, 30, "empty"),
+        ]);
+        assert_eq!(
+            vec![
+                HighlightSpan::new(0, 4, "function"),
+                HighlightSpan::new(4, 8, "name"),
+                HighlightSpan::new(8, 14, "keyword").with_priority(1),
+                HighlightSpan::new(14, 20, "function"),
+            ],
+            merged
+        );
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.check(
+            r#"
+              local merged = require("ltreesitter_rs").merge_spans({
+                { start_byte = 0, end_byte = 10, name = "string" },
+                { start_byte = 2, end_byte = 4, name = "escape" },
+              })
+              assert(#merged == 3)
+              assert(merged[1].name == "string" and merged[1].end_byte == 2)
+              assert(merged[2].name == "escape" and merged[2].start_byte == 2)
+              assert(merged[3].name == "string" and merged[3].start_byte == 4)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,588 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Pluggable storage for the source code of trees that are pushed into Lua.
+//!
+//! By default, pushing a [`TreeWithSource`] into Lua copies its source code into the Lua state,
+//! which owns that copy for as long as the tree is live.  A tree can instead be pushed with a
+//! [`SourceStore`], which is read from whenever Lua code calls `node:source()`.  This crate
+//! provides stores that hold the source in memory (a [`SharedSource`], an [`Arc<[u8]>`][Arc], or a
+//! `Vec<u8>`), and that fetch text on demand via a callback ([`FetchSource`]).  A `SharedSource`
+//! can be shared by the host and any number of Lua states without ever being copied.
+//!
+//! ltreesitter's C code reads a tree's source directly, to evaluate query predicates like `#eq?`,
+//! and it expects the source to be laid out as a [`SourceText`].  A store that holds its source
+//! that way ([`SourceStore::source_text`]) is read in place.  A store that holds its source in
+//! memory some other way is replaced by a copy that is laid out that way when the tree is pushed,
+//! so the Lua state still only holds one copy of the source.  For a store that doesn't hold its
+//! source in memory, like a [`FetchSource`], ltreesitter only sees an empty source, so running a
+//! query over the tree with `query:match`, `query:capture`, or `query:exec` raises an error.
+//!
+//! A tree can also be pushed without any source at all, as a [`TreeWithoutSource`].  Then
+//! `node:source()` raises an error, unless the host provides a callback that fetches the text.
+//!
+//! When a store-backed tree is converted back into a [`TreeWithSource`], `src` borrows the store's
+//! contents if the store holds them in memory ([`SourceStore::as_bytes`]).  Otherwise, the
+//! conversion fails.
+
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::ops::Range;
+use std::sync::Arc;
+
+use mlua::AnyUserData;
+use mlua::IntoLua;
+use mlua::Lua;
+use mlua::MaybeSend;
+use mlua::MultiValue;
+use mlua::UserData;
+use mlua::Value;
+use tree_sitter::Tree;
+
+use crate::ltreesitter;
+use crate::ltreesitter::SourceBuffer;
+use crate::metrics;
+use crate::trees;
+use crate::SourceText;
+use crate::WithSource;
+
+const STORE_KEY: &str = "source_store";
+
+/// A place where the source code of a tree is stored.
+pub trait SourceStore: MaybeSend + 'static {
+    /// Returns the length of the source code.
+    fn len(&self) -> usize;
+
+    /// Returns whether the source code is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a range of the source code.
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error>;
+
+    /// Returns all of the source code, if the store holds it in memory.
+    fn as_bytes(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Returns all of the source code laid out the way that ltreesitter's C code reads it, if the
+    /// store holds it that way.  Then a pushed tree reads its source from the store in place,
+    /// instead of from a copy.
+    fn source_text(&self) -> Option<&SourceText> {
+        None
+    }
+
+    /// Returns an error if the store's contents are no longer valid, like a mapped file that has
+    /// changed since it was mapped.  This is checked when a tree is pushed into Lua with the store,
+    /// and before each read that Lua code makes via `node:source()`.
+    fn validate(&self) -> Result<(), mlua::Error> {
+        Ok(())
+    }
+}
+
+fn out_of_bounds(range: &Range<usize>, len: usize) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "range {}..{} is out of bounds of source of length {}",
+        range.start, range.end, len
+    ))
+}
+
+impl SourceStore for Arc<[u8]> {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error> {
+        self.get(range.clone())
+            .map(Cow::Borrowed)
+            .ok_or_else(|| out_of_bounds(&range, <[u8]>::len(self)))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        Some(&self[..])
+    }
+}
+
+impl SourceStore for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error> {
+        self.get(range.clone())
+            .map(Cow::Borrowed)
+            .ok_or_else(|| out_of_bounds(&range, Vec::len(self)))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        Some(self.as_slice())
+    }
+}
+
+/// A source store that fetches text on demand from a callback, for sources that live somewhere
+/// else entirely, like a host's virtual file system.
+pub struct FetchSource<F> {
+    len: usize,
+    fetch: F,
+}
+
+impl<F> FetchSource<F>
+where
+    F: Fn(Range<usize>) -> Result<Vec<u8>, mlua::Error> + MaybeSend + 'static,
+{
+    /// Creates a new source store for a source of length `len`, whose text is fetched by calling
+    /// `fetch`.
+    pub fn new(len: usize, fetch: F) -> FetchSource<F> {
+        FetchSource { len, fetch }
+    }
+}
+
+impl<F> SourceStore for FetchSource<F>
+where
+    F: Fn(Range<usize>) -> Result<Vec<u8>, mlua::Error> + MaybeSend + 'static,
+{
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error> {
+        if range.start > range.end || range.end > self.len {
+            return Err(out_of_bounds(&range, self.len));
+        }
+        (self.fetch)(range).map(Cow::Owned)
+    }
+}
+
+/// Source code that the host can share with any number of Lua states without copying it.
+///
+/// Like an [`Arc<[u8]>`][Arc], a `SharedSource` is reference-counted, so it's cheap to clone, and
+/// it can be sent to other threads.  Unlike an `Arc<[u8]>`, it lays out its contents the way that
+/// ltreesitter's C code reads them, so a tree that's pushed into Lua with one as its source store
+/// reads its source in place.  The Lua tree holds a reference to the `SharedSource` until the tree
+/// is closed or garbage-collected.
+#[derive(Clone)]
+pub struct SharedSource(Arc<SourceBuffer>);
+
+impl SharedSource {
+    /// Creates a shared copy of `src`.
+    pub fn new(src: &[u8]) -> SharedSource {
+        SharedSource(Arc::new(SourceBuffer::new(src)))
+    }
+}
+
+impl std::fmt::Debug for SharedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SharedSource")
+            .field(&String::from_utf8_lossy(self))
+            .finish()
+    }
+}
+
+impl Deref for SharedSource {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.0.text().bytes()
+    }
+}
+
+impl AsRef<[u8]> for SharedSource {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<&[u8]> for SharedSource {
+    fn from(src: &[u8]) -> SharedSource {
+        SharedSource::new(src)
+    }
+}
+
+impl From<&str> for SharedSource {
+    fn from(src: &str) -> SharedSource {
+        SharedSource::new(src.as_bytes())
+    }
+}
+
+impl From<Vec<u8>> for SharedSource {
+    fn from(src: Vec<u8>) -> SharedSource {
+        SharedSource::new(&src)
+    }
+}
+
+impl From<String> for SharedSource {
+    fn from(src: String) -> SharedSource {
+        SharedSource::new(src.as_bytes())
+    }
+}
+
+impl From<Arc<[u8]>> for SharedSource {
+    fn from(src: Arc<[u8]>) -> SharedSource {
+        SharedSource::new(&src)
+    }
+}
+
+impl SourceStore for SharedSource {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error> {
+        self.get(range.clone())
+            .map(Cow::Borrowed)
+            .ok_or_else(|| out_of_bounds(&range, <[u8]>::len(self)))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        Some(&self[..])
+    }
+
+    fn source_text(&self) -> Option<&SourceText> {
+        Some(self.0.text())
+    }
+}
+
+/// A source store for a tree whose source isn't available.  Every read fails.
+struct NoSource {
+    len: usize,
+}
+
+impl SourceStore for NoSource {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, _range: Range<usize>) -> Result<Cow<'_, [u8]>, mlua::Error> {
+        Err(mlua::Error::RuntimeError(
+            "tree was pushed into Lua without its source".to_string(),
+        ))
+    }
+}
+
+#[cfg(not(feature = "send"))]
+type TextCallback = Box<dyn Fn(Range<usize>) -> Result<Vec<u8>, mlua::Error>>;
+#[cfg(feature = "send")]
+type TextCallback = Box<dyn Fn(Range<usize>) -> Result<Vec<u8>, mlua::Error> + Send>;
+
+/// A [`tree_sitter::Tree`] that is pushed into Lua without its source code, so that the Lua state
+/// never holds a copy of it.  This type implements the [`mlua::IntoLua`] trait.
+///
+/// Lua code can walk the tree as usual, but `node:source()` raises an error, unless you provide a
+/// [callback][TreeWithoutSource::with_text_callback] that fetches the text of a byte range.
+pub struct TreeWithoutSource {
+    tree: Tree,
+    text: Option<TextCallback>,
+}
+
+impl TreeWithoutSource {
+    /// Wraps a tree, so that it can be pushed into Lua without its source code.
+    pub fn new(tree: Tree) -> TreeWithoutSource {
+        TreeWithoutSource { tree, text: None }
+    }
+
+    /// Sets a callback that fetches the text of a byte range of the tree's source, which services
+    /// `node:source()` calls from Lua.
+    pub fn with_text_callback<F>(mut self, text: F) -> TreeWithoutSource
+    where
+        F: Fn(Range<usize>) -> Result<Vec<u8>, mlua::Error> + MaybeSend + 'static,
+    {
+        self.text = Some(Box::new(text));
+        self
+    }
+}
+
+impl<'lua> IntoLua<'lua> for TreeWithoutSource {
+    fn into_lua(self, lua: &'lua Lua) -> Result<Value<'lua>, mlua::Error> {
+        let len = self.tree.root_node().end_byte();
+        match self.text {
+            Some(text) => self
+                .tree
+                .with_source_store(FetchSource::new(len, text))
+                .into_lua(lua),
+            None => self.tree.with_source_store(NoSource { len }).into_lua(lua),
+        }
+    }
+}
+
+/// A tree's source store, along with the number of live trees that read from it.  (A copy of a
+/// tree, from `tree:copy()`, reads from the same store as the original.)
+struct StoredSource {
+    store: Box<dyn SourceStore>,
+    trees: usize,
+}
+
+impl UserData for StoredSource {}
+
+/// Stores the source store of a tree in the tree's attachments table, and points ltreesitter's C
+/// code at the store's contents.  A store that holds its contents in memory, but not laid out the
+/// way that ltreesitter reads them, is replaced by a copy that is.
+pub(crate) fn attach<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    store: Box<dyn SourceStore>,
+) -> Result<(), mlua::Error> {
+    store.validate()?;
+    let store: Box<dyn SourceStore> = match (store.source_text(), store.as_bytes()) {
+        (None, Some(bytes)) => {
+            metrics::record_source_copy(lua, bytes.len());
+            Box::new(SharedSource::new(bytes))
+        }
+        _ => store,
+    };
+    // The store lives in the tree's attachments table, which keeps it alive for as long as the
+    // tree, and its contents don't move when the box does.  A store that doesn't hold its contents
+    // in memory gets an empty source, since the query methods that would read it refuse to run
+    // over the tree.
+    let text = match store.source_text() {
+        Some(text) => text as *const SourceText,
+        None => &ltreesitter::EMPTY_SOURCE,
+    };
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+    unsafe { (*ltreesitter_tree).source = text };
+    trees::attachments(lua, tree)?.set(STORE_KEY, StoredSource { store, trees: 1 })
+}
+
+/// Lets a copy of a tree read from the same source store as the original.
+pub(crate) fn share<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    copy: &Value<'lua>,
+) -> Result<(), mlua::Error> {
+    if let Some(store) = stored_source(lua, tree)? {
+        store.borrow_mut::<StoredSource>()?.trees += 1;
+        trees::attachments(lua, copy)?.set(STORE_KEY, store)?;
+    }
+    Ok(())
+}
+
+fn stored_source<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+) -> Result<Option<AnyUserData<'lua>>, mlua::Error> {
+    match trees::existing_attachments(lua, tree)? {
+        Some(attachments) => attachments.get(STORE_KEY),
+        None => Ok(None),
+    }
+}
+
+/// Returns the source code of an ltreesitter tree, or `None` if the tree has a source store that
+/// doesn't hold its source in memory.  The result is only valid for as long as the tree is.
+pub(crate) fn source<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+) -> Result<Option<&'lua [u8]>, mlua::Error> {
+    if stored_source(lua, tree)?.is_some() {
+        return contents(lua, tree);
+    }
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+    Ok(Some(unsafe { ltreesitter::source(ltreesitter_tree) }))
+}
+
+/// The error for converting a tree whose source isn't in memory into a Rust type that borrows it.
+pub(crate) fn not_in_memory(from: &'static str, to: &'static str) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from,
+        to,
+        message: Some("the tree's source store doesn't hold its source in memory".to_string()),
+    }
+}
+
+/// Stops a tree from reading from its source store, if it has one, and frees the store if no other
+/// tree reads from it.
+pub(crate) fn release<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Result<(), mlua::Error> {
+    let store = match stored_source(lua, tree)? {
+        Some(store) => store,
+        None => return Ok(()),
+    };
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+    unsafe { (*ltreesitter_tree).source = &ltreesitter::EMPTY_SOURCE };
+    let remaining = {
+        let mut stored = store.borrow_mut::<StoredSource>()?;
+        stored.trees -= 1;
+        stored.trees
+    };
+    if remaining == 0 {
+        drop(store.take::<StoredSource>()?);
+    }
+    Ok(())
+}
+
+/// Returns the contents of a tree's source store, if it has one that holds its source in memory.
+fn contents<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Result<Option<&'lua [u8]>, mlua::Error> {
+    let store = match stored_source(lua, tree)? {
+        Some(store) => store,
+        None => return Ok(None),
+    };
+    let store = store.borrow::<StoredSource>()?;
+    let bytes = store.store.as_bytes().map(|bytes| bytes as *const [u8]);
+    // The store lives in the tree's attachments table, which lives as long as the tree, and the
+    // store's contents never move.  The caller must keep the tree alive for as long as it uses
+    // the result.
+    Ok(bytes.map(|bytes| unsafe { &*bytes }))
+}
+
+/// Extends ltreesitter's `node:source()` method so that it reads from the tree's source store, if
+/// the tree has one, and makes ltreesitter's query methods refuse to run over trees whose source
+/// they can't see.
+pub(crate) fn install_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    for name in ["match", "capture", "exec"] {
+        ltreesitter::wrap_method(
+            lua,
+            ltreesitter::QUERY_METATABLE,
+            name,
+            |lua, original, args| {
+                let node = args.iter().nth(1).cloned().unwrap_or(Value::Nil);
+                if let Some(tree) = trees::owner(lua, &node)? {
+                    if source(lua, &tree)?.is_none() {
+                        return Err(mlua::Error::RuntimeError(
+                            "cannot run a query over a tree whose source isn't in memory"
+                                .to_string(),
+                        ));
+                    }
+                }
+                original.call::<_, MultiValue>(args)
+            },
+        )?;
+    }
+    ltreesitter::wrap_method(
+        lua,
+        ltreesitter::NODE_METATABLE,
+        "source",
+        |lua, original, args| {
+            let named = matches!(args.iter().nth(1), Some(Value::String(_)));
+            let node = args.iter().next().cloned().unwrap_or(Value::Nil);
+            let ltreesitter_node = ltreesitter::node_ptr(lua, node.clone())?;
+            let tree = match trees::owner(lua, &node)? {
+                Some(tree) if !named => tree,
+                _ => return original.call::<_, MultiValue>(args),
+            };
+            let store = match stored_source(lua, &tree)? {
+                Some(store) => store,
+                None => return original.call::<_, MultiValue>(args),
+            };
+            let node = unsafe { tree_sitter::Node::from_raw((*ltreesitter_node).node) };
+            let store = store.borrow::<StoredSource>()?;
+            store.store.validate()?;
+            let text = store.store.read(node.byte_range())?;
+            Ok(MultiValue::from_vec(vec![Value::String(
+                lua.create_string(&text)?,
+            )]))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::TreeWithSource;
+    use crate::WithSource;
+    use mlua::FromLua;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn can_serve_sources_from_stores() {
+        let code: Arc<[u8]> = Arc::from(&b"def double(x): return x * 2\n"[..]);
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(&code, None).unwrap();
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("shared", parsed.clone().with_source_store(code.clone()))
+            .unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetched = code.clone();
+        let counter = fetches.clone();
+        let store = FetchSource::new(code.len(), move |range| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(fetched[range].to_vec())
+        });
+        l.globals()
+            .set("fetched", parsed.with_source_store(store))
+            .unwrap();
+        l.register_language("python", tree_sitter_python::language())
+            .unwrap();
+        l.check(
+            r#"
+              local name = shared:root():child(0):child_by_field_name("name")
+              assert(name:source() == "double")
+              local body = fetched:root():child(0):child_by_field_name("body")
+              assert(body:source() == "return x * 2")
+
+              local query = require("ltreesitter")
+                .require("python")
+                :query([[((identifier) @id (#eq? @id "double"))]])
+              local count = 0
+              for _ in query:capture(shared:root()) do
+                count = count + 1
+              end
+              assert(count == 1)
+              assert(not pcall(query.capture, query, fetched:root()))
+            "#,
+        );
+        assert_eq!(1, fetches.load(Ordering::Relaxed));
+
+        let tws: TreeWithSource = l.call(r#" return shared "#);
+        assert_eq!(&code[..], tws.src);
+        // ltreesitter reads the same copy of the source that the store holds.
+        let shared: Value = l.globals().get("shared").unwrap();
+        let ltreesitter_tree = ltreesitter::tree_ptr(&l, shared).unwrap();
+        let ltreesitter_src = unsafe { ltreesitter::source(ltreesitter_tree) };
+        assert_eq!(tws.src.as_ptr(), ltreesitter_src.as_ptr());
+        let fetched: Value = l.globals().get("fetched").unwrap();
+        assert!(TreeWithSource::from_lua(fetched, &l).is_err());
+
+        drop(tws);
+        l.check(
+            r#"
+              local copy = shared:copy()
+              shared:close()
+              local name = copy:root():child(0):child_by_field_name("name")
+              assert(name:source() == "double")
+            "#,
+        );
+    }
+
+    #[test]
+    fn can_push_trees_without_sources() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals()
+            .set("bare", parsed.clone().without_source())
+            .unwrap();
+        l.globals()
+            .set(
+                "served",
+                parsed
+                    .without_source()
+                    .with_text_callback(|range| Ok(code[range].to_vec())),
+            )
+            .unwrap();
+        l.check(
+            r#"
+              local name = bare:root():child(0):child_by_field_name("name")
+              assert(name:type() == "identifier")
+              local ok, err = pcall(name.source, name)
+              assert(not ok and tostring(err):find("without its source"))
+              name = served:root():child(0):child_by_field_name("name")
+              assert(name:source() == "double")
+            "#,
+        );
+        // The Lua state doesn't hold anything as large as the source.
+        let bare: Value = l.globals().get("bare").unwrap();
+        let ltreesitter_tree = ltreesitter::tree_ptr(&l, bare).unwrap();
+        assert!(unsafe { ltreesitter::source(ltreesitter_tree) }.is_empty());
+    }
+}
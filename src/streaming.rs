@@ -0,0 +1,390 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Streams the files in a directory into Lua, parsing them on worker threads.
+//!
+//! A [`DirectoryStream`] walks a directory, and asks a host-provided filter which grammar (if
+//! any) to parse each file with.  Worker threads read and parse the files, and the thread that
+//! owns the Lua state pushes each tree into Lua and passes it to a callback, in whatever order the
+//! workers finish.
+//!
+//! The stream applies backpressure: before reading a file, a worker reserves room for it in a
+//! budget of in-flight bytes, and the room is only released once the Lua callback has finished
+//! with the file's tree.  If Lua falls behind, the workers wait, instead of piling up parsed trees
+//! in memory.  (A file that is larger than the whole budget is still processed, but only once
+//! nothing else is in flight.)  The callback can return `false` to stop the stream early, and a
+//! [`CancellationToken`] can cancel it from anywhere, including from Lua.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use mlua::Function;
+use mlua::Value;
+use tree_sitter::Language;
+use tree_sitter::Parser;
+use tree_sitter::Tree;
+
+use crate::CancellationToken;
+use crate::WithSource;
+
+/// How often the stream checks whether it has been cancelled while it waits for trees.
+const CANCELLATION_POLL: Duration = Duration::from_millis(10);
+
+type Filter = dyn Fn(&Path) -> Option<Language> + Send + Sync;
+
+/// Walks a directory, parsing files on worker threads and feeding the trees to a Lua callback.
+pub struct DirectoryStream {
+    root: PathBuf,
+    filter: Arc<Filter>,
+    workers: usize,
+    max_in_flight_bytes: usize,
+    cancellation: CancellationToken,
+}
+
+/// What happened while streaming a directory into Lua.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StreamStats {
+    /// The number of trees that were passed to the callback.
+    pub delivered: usize,
+    /// The files that couldn't be read or parsed, and why.
+    pub failed: Vec<(PathBuf, String)>,
+    /// The largest number of bytes of source that were in flight at once.
+    pub peak_in_flight_bytes: usize,
+    /// Whether the callback stopped the stream early.
+    pub stopped: bool,
+    /// Whether the stream's cancellation token stopped it early.
+    pub cancelled: bool,
+}
+
+impl DirectoryStream {
+    /// Creates a new stream over the files under `root`.  `filter` returns the grammar to parse
+    /// each file with, or `None` to skip it.
+    pub fn new<P, F>(root: P, filter: F) -> DirectoryStream
+    where
+        P: Into<PathBuf>,
+        F: Fn(&Path) -> Option<Language> + Send + Sync + 'static,
+    {
+        DirectoryStream {
+            root: root.into(),
+            filter: Arc::new(filter),
+            workers: 4,
+            max_in_flight_bytes: 64 * 1024 * 1024,
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    /// Sets the number of worker threads that read and parse files.  (The default is 4.)
+    pub fn with_workers(mut self, workers: usize) -> DirectoryStream {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Sets the number of bytes of source that can be in flight at once.  (The default is 64MiB.)
+    pub fn with_max_in_flight_bytes(mut self, max: usize) -> DirectoryStream {
+        self.max_in_flight_bytes = max;
+        self
+    }
+
+    /// Sets a token that stops the stream once it's cancelled.  The trees that were delivered
+    /// before then are still counted in the [`StreamStats`].
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> DirectoryStream {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Streams the directory into Lua.  `callback` is called with the path of each file and its
+    /// tree, on the current thread.  If it returns `false`, the stream stops.
+    pub fn run(&self, callback: Function) -> Result<StreamStats, mlua::Error> {
+        let in_flight = Arc::new(InFlight::new(self.max_in_flight_bytes));
+        let (path_tx, path_rx) = mpsc::sync_channel::<(PathBuf, Language)>(self.workers * 4);
+        let path_rx = Arc::new(Mutex::new(path_rx));
+        let (tree_tx, tree_rx) = mpsc::channel::<Parsed>();
+
+        let walker = {
+            let root = self.root.clone();
+            let filter = self.filter.clone();
+            let in_flight = in_flight.clone();
+            let tree_tx = tree_tx.clone();
+            std::thread::spawn(move || walk(&root, &*filter, &in_flight, &path_tx, &tree_tx))
+        };
+        let workers = (0..self.workers)
+            .map(|_| {
+                let path_rx = path_rx.clone();
+                let in_flight = in_flight.clone();
+                let tree_tx = tree_tx.clone();
+                std::thread::spawn(move || parse_files(&path_rx, &in_flight, &tree_tx))
+            })
+            .collect::<Vec<_>>();
+        // Only the workers should hold these, so that the walker and workers notice when the
+        // other side has finished.
+        drop(path_rx);
+        drop(tree_tx);
+
+        let mut stats = StreamStats::default();
+        let mut result = Ok(());
+        loop {
+            if self.cancellation.is_cancelled() {
+                stats.cancelled = true;
+                break;
+            }
+            let parsed = match tree_rx.recv_timeout(CANCELLATION_POLL) {
+                Ok(parsed) => parsed,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            match parsed {
+                Parsed::Tree(path, tree, src) => {
+                    let delivered = callback.call::<_, Value>((
+                        path.to_string_lossy().into_owned(),
+                        tree.with_source(&src),
+                    ));
+                    in_flight.release(src.len());
+                    stats.delivered += 1;
+                    match delivered {
+                        Ok(Value::Boolean(false)) => {
+                            stats.stopped = true;
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    }
+                }
+                Parsed::Failed(path, reserved, err) => {
+                    in_flight.release(reserved);
+                    stats.failed.push((path, err));
+                }
+            }
+        }
+
+        // Wake up any workers that are waiting for room, and make sure that they don't start on
+        // anything new.  Dropping the receiver makes any further sends fail.
+        in_flight.stop();
+        drop(tree_rx);
+        let _ = walker.join();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        stats.peak_in_flight_bytes = in_flight.peak();
+        result.map(|_| stats)
+    }
+}
+
+enum Parsed {
+    Tree(PathBuf, Tree, Vec<u8>),
+    Failed(PathBuf, usize, String),
+}
+
+/// A budget of in-flight bytes, which workers wait on when it's full.
+struct InFlight {
+    state: Mutex<(usize, usize)>,
+    released: Condvar,
+    max: usize,
+    stopped: AtomicBool,
+}
+
+impl InFlight {
+    fn new(max: usize) -> InFlight {
+        InFlight {
+            state: Mutex::new((0, 0)),
+            released: Condvar::new(),
+            max,
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits until there's room for `bytes` more bytes, and reserves it.  Returns `false` if the
+    /// stream was stopped while waiting.
+    fn acquire(&self, bytes: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+            let (current, _) = *state;
+            if current == 0 || current + bytes <= self.max {
+                break;
+            }
+            state = self.released.wait(state).unwrap();
+        }
+        state.0 += bytes;
+        state.1 = state.1.max(state.0);
+        true
+    }
+
+    /// Reserves room for `bytes` more bytes, without waiting.
+    fn acquire_unchecked(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += bytes;
+        state.1 = state.1.max(state.0);
+    }
+
+    fn release(&self, bytes: usize) {
+        self.state.lock().unwrap().0 -= bytes;
+        self.released.notify_all();
+    }
+
+    fn stop(&self) {
+        let _state = self.state.lock().unwrap();
+        self.stopped.store(true, Ordering::SeqCst);
+        self.released.notify_all();
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn peak(&self) -> usize {
+        self.state.lock().unwrap().1
+    }
+}
+
+fn walk(
+    root: &Path,
+    filter: &Filter,
+    in_flight: &InFlight,
+    path_tx: &mpsc::SyncSender<(PathBuf, Language)>,
+    tree_tx: &mpsc::Sender<Parsed>,
+) {
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                let _ = tree_tx.send(Parsed::Failed(dir, 0, err.to_string()));
+                continue;
+            }
+        };
+        let mut paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths.into_iter().rev() {
+            if in_flight.is_stopped() {
+                return;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if let Some(language) = filter(&path) {
+                if path_tx.send((path, language)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn parse_files(
+    path_rx: &Mutex<mpsc::Receiver<(PathBuf, Language)>>,
+    in_flight: &InFlight,
+    tree_tx: &mpsc::Sender<Parsed>,
+) {
+    let mut parser = Parser::new();
+    loop {
+        let next = path_rx.lock().unwrap().recv();
+        let (path, language) = match next {
+            Ok(next) => next,
+            Err(_) => return,
+        };
+        let size = std::fs::metadata(&path)
+            .map(|metadata| metadata.len() as usize)
+            .unwrap_or(0);
+        if !in_flight.acquire(size) {
+            return;
+        }
+        let parsed = match parse_file(&mut parser, &path, language) {
+            Ok((tree, src)) => {
+                // The file might have changed size since we looked; keep the reservation honest.
+                let actual = src.len();
+                if actual > size {
+                    in_flight.acquire_unchecked(actual - size);
+                } else {
+                    in_flight.release(size - actual);
+                }
+                Parsed::Tree(path, tree, src)
+            }
+            Err(err) => Parsed::Failed(path, size, err),
+        };
+        if tree_tx.send(parsed).is_err() {
+            return;
+        }
+    }
+}
+
+fn parse_file(
+    parser: &mut Parser,
+    path: &Path,
+    language: Language,
+) -> Result<(Tree, Vec<u8>), String> {
+    let src = std::fs::read(path).map_err(|err| err.to_string())?;
+    parser
+        .set_language(language)
+        .map_err(|err| err.to_string())?;
+    let tree = parser
+        .parse(&src, None)
+        .ok_or_else(|| "cannot parse file".to_string())?;
+    Ok((tree, src))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use mlua::Lua;
+
+    #[test]
+    fn can_stream_a_directory_into_lua() {
+        let dir =
+            std::env::temp_dir().join(format!("mlua-tree-sitter-stream-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("pkg")).unwrap();
+        std::fs::write(dir.join("a.py"), "def a(): pass\n").unwrap();
+        std::fs::write(dir.join("pkg/b.py"), "def b(): pass\n").unwrap();
+        std::fs::write(dir.join("pkg/c.py"), "def c(): pass\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not python\n").unwrap();
+        let python = |path: &Path| (path.extension()? == "py").then(tree_sitter_python::language);
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let callback: Function = l
+            .load(
+                r#"
+                  seen = {}
+                  return function(path, tree)
+                    table.insert(seen, tree:root():child(0):child_by_field_name("name"):source())
+                  end
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let stream = DirectoryStream::new(&dir, python)
+            .with_workers(2)
+            .with_max_in_flight_bytes(1);
+        let stats = stream.run(callback).unwrap();
+        assert_eq!(3, stats.delivered);
+        assert!(stats.failed.is_empty());
+        assert!(!stats.stopped);
+        // With a tiny budget, only one file can be in flight at a time.
+        assert_eq!(14, stats.peak_in_flight_bytes);
+        let mut seen: Vec<String> = l.globals().get("seen").unwrap();
+        seen.sort();
+        assert_eq!(vec!["a", "b", "c"], seen);
+
+        let stop: Function = l.load("return function() return false end").eval().unwrap();
+        let stats = DirectoryStream::new(&dir, python).run(stop).unwrap();
+        assert_eq!(1, stats.delivered);
+        assert!(stats.stopped);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
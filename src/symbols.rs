@@ -0,0 +1,446 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A project-wide symbol index, for cross-file navigation in Lua scripts.
+//!
+//! A [`SymbolIndex`] runs a "symbols" query for each language over the files in a workspace, and
+//! keeps the results in memory, indexed by file and by symbol name.  Symbols queries follow the
+//! conventions of tree-sitter's `tags.scm` queries: each pattern captures the symbol's name as
+//! `@name`, and the whole definition as `@definition.<kind>`:
+//!
+//! ``` scheme
+//! (function_definition name: (identifier) @name) @definition.function
+//! (class_definition name: (identifier) @name) @definition.class
+//! ```
+//!
+//! An index can be saved to a file and loaded again later, so that a host doesn't have to reindex
+//! a workspace that hasn't changed.
+//!
+//! Indexes can be pushed into Lua, where they have the following methods:
+//!
+//! - `index:lookup(name)` returns a list of the symbols with the given name, in any file.
+//! - `index:symbols_in(path)` returns a list of the symbols in a file, in document order.
+//! - `index:index_file(path, src)` (re)indexes a file, reading it from disk if `src` is `nil`, and
+//!   returns the number of symbols that it contains.
+//! - `index:remove_file(path)` removes a file from the index.
+//! - `index:files()` returns a list of the files in the index.
+//!
+//! Each symbol is a table with `name`, `kind`, `path`, `start_byte`, `end_byte`, and `start_point`
+//! fields.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+
+use mlua::Lua;
+use mlua::Table;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use tree_sitter::Language;
+use tree_sitter::Parser;
+use tree_sitter::Point;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+use tree_sitter::QueryError;
+
+use crate::offsets::Offset;
+use crate::ranges::range_table;
+use crate::LanguagePack;
+
+/// A symbol that was found by a symbols query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// The kind of symbol, from the `@definition.<kind>` capture, or empty if there wasn't one.
+    pub kind: String,
+    pub path: PathBuf,
+    /// The range of the whole definition.
+    pub range: Range<usize>,
+    pub start_point: Point,
+}
+
+struct IndexedLanguage {
+    pack: LanguagePack,
+    language: Language,
+    query: Query,
+}
+
+/// An in-memory index of the symbols in a workspace.
+#[derive(Default)]
+pub struct SymbolIndex {
+    languages: Vec<IndexedLanguage>,
+    files: BTreeMap<PathBuf, Vec<Symbol>>,
+    by_name: BTreeMap<String, BTreeSet<PathBuf>>,
+}
+
+impl SymbolIndex {
+    /// Creates a new, empty index.
+    pub fn new() -> SymbolIndex {
+        SymbolIndex::default()
+    }
+
+    /// Adds a language to the index.  Files that `pack` applies to will be parsed with `language`,
+    /// and their symbols found with the symbols query `query`.
+    pub fn add_language(
+        &mut self,
+        pack: &LanguagePack,
+        language: Language,
+        query: &str,
+    ) -> Result<(), QueryError> {
+        let query = Query::new(language, query)?;
+        self.languages.push(IndexedLanguage {
+            pack: pack.clone(),
+            language,
+            query,
+        });
+        Ok(())
+    }
+
+    /// Indexes a file, replacing any symbols that were previously found in it, and returns the
+    /// number of symbols that it contains.  Files that none of the index's languages apply to are
+    /// skipped.
+    pub fn index_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        src: &[u8],
+    ) -> Result<usize, mlua::Error> {
+        let path = path.as_ref();
+        let indexed = match self
+            .languages
+            .iter()
+            .find(|indexed| indexed.pack.matches_path(path))
+        {
+            Some(indexed) => indexed,
+            None => return Ok(0),
+        };
+        let mut parser = Parser::new();
+        parser
+            .set_language(indexed.language)
+            .map_err(mlua::Error::external)?;
+        let tree = parser
+            .parse(src, None)
+            .ok_or_else(|| mlua::Error::RuntimeError(format!("cannot parse {}", path.display())))?;
+        let name_index = indexed.query.capture_index_for_name("name");
+        let capture_names = indexed.query.capture_names();
+        let mut symbols = Vec::new();
+        let mut cursor = QueryCursor::new();
+        for query_match in cursor.matches(&indexed.query, tree.root_node(), src) {
+            let name = match query_match
+                .captures
+                .iter()
+                .find(|capture| Some(capture.index) == name_index)
+            {
+                Some(name) => name.node,
+                None => continue,
+            };
+            let definition = query_match
+                .captures
+                .iter()
+                .find(|capture| Some(capture.index) != name_index);
+            let (kind, node) = match definition {
+                Some(definition) => {
+                    let capture_name = &capture_names[definition.index as usize];
+                    let kind = capture_name
+                        .strip_prefix("definition.")
+                        .unwrap_or(capture_name.as_str());
+                    (kind.to_string(), definition.node)
+                }
+                None => (String::new(), name),
+            };
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(&src[name.byte_range()]).into_owned(),
+                kind,
+                path: path.to_path_buf(),
+                range: node.byte_range(),
+                start_point: node.start_position(),
+            });
+        }
+        symbols.sort_by_key(|symbol| (symbol.range.start, std::cmp::Reverse(symbol.range.end)));
+        let count = symbols.len();
+        self.insert_file(path.to_path_buf(), symbols);
+        Ok(count)
+    }
+
+    /// Indexes every file under a directory that one of the index's languages applies to, and
+    /// returns the total number of symbols found.
+    pub fn index_directory<P: AsRef<Path>>(&mut self, root: P) -> Result<usize, mlua::Error> {
+        let mut count = 0;
+        let mut pending = vec![root.as_ref().to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries = std::fs::read_dir(&dir).map_err(|err| {
+                mlua::Error::RuntimeError(format!("cannot read {}: {}", dir.display(), err))
+            })?;
+            let mut paths = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect::<Vec<_>>();
+            paths.sort();
+            for path in paths {
+                if path.is_dir() {
+                    pending.push(path);
+                } else if self.languages.iter().any(|l| l.pack.matches_path(&path)) {
+                    let src = read_file(&path)?;
+                    count += self.index_file(&path, &src)?;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Removes a file from the index.
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) {
+        if let Some(symbols) = self.files.remove(path.as_ref()) {
+            for symbol in symbols {
+                if let Some(paths) = self.by_name.get_mut(&symbol.name) {
+                    paths.remove(&symbol.path);
+                    if paths.is_empty() {
+                        self.by_name.remove(&symbol.name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the symbols with the given name, in any file.
+    pub fn lookup(&self, name: &str) -> Vec<&Symbol> {
+        let paths = match self.by_name.get(name) {
+            Some(paths) => paths,
+            None => return Vec::new(),
+        };
+        paths
+            .iter()
+            .flat_map(|path| self.symbols_in(path))
+            .filter(|symbol| symbol.name == name)
+            .collect()
+    }
+
+    /// Returns the symbols in a file, in document order.
+    pub fn symbols_in<P: AsRef<Path>>(&self, path: P) -> &[Symbol] {
+        self.files
+            .get(path.as_ref())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns the files in the index.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+
+    /// Saves the symbols in the index to a file.  (The index's languages aren't saved.)
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), mlua::Error> {
+        let mut contents = String::new();
+        for symbol in self.files.values().flatten() {
+            let fields = [
+                escape(&symbol.path.to_string_lossy()),
+                escape(&symbol.name),
+                escape(&symbol.kind),
+                symbol.range.start.to_string(),
+                symbol.range.end.to_string(),
+                symbol.start_point.row.to_string(),
+                symbol.start_point.column.to_string(),
+            ];
+            contents.push_str(&fields.join("\t"));
+            contents.push('\n');
+        }
+        let path = path.as_ref();
+        std::fs::write(path, contents).map_err(|err| {
+            mlua::Error::RuntimeError(format!("cannot write {}: {}", path.display(), err))
+        })
+    }
+
+    /// Loads symbols that were saved with [`save`][SymbolIndex::save], replacing whatever the
+    /// index already holds for the files that they belong to.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), mlua::Error> {
+        let path = path.as_ref();
+        let contents = String::from_utf8(read_file(path)?).map_err(mlua::Error::external)?;
+        let invalid =
+            || mlua::Error::RuntimeError(format!("invalid symbol index {}", path.display()));
+        let mut files: BTreeMap<PathBuf, Vec<Symbol>> = BTreeMap::new();
+        for line in contents.lines() {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() != 7 {
+                return Err(invalid());
+            }
+            let number = |field: &str| field.parse::<usize>().map_err(|_| invalid());
+            let symbol = Symbol {
+                path: PathBuf::from(unescape(fields[0])),
+                name: unescape(fields[1]),
+                kind: unescape(fields[2]),
+                range: number(fields[3])?..number(fields[4])?,
+                start_point: Point::new(number(fields[5])?, number(fields[6])?),
+            };
+            files.entry(symbol.path.clone()).or_default().push(symbol);
+        }
+        for (path, symbols) in files {
+            self.insert_file(path, symbols);
+        }
+        Ok(())
+    }
+
+    fn insert_file(&mut self, path: PathBuf, symbols: Vec<Symbol>) {
+        self.remove_file(&path);
+        for symbol in &symbols {
+            self.by_name
+                .entry(symbol.name.clone())
+                .or_default()
+                .insert(path.clone());
+        }
+        self.files.insert(path, symbols);
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, mlua::Error> {
+    std::fs::read(path).map_err(|err| {
+        mlua::Error::RuntimeError(format!("cannot read {}: {}", path.display(), err))
+    })
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(c) => result.push(c),
+            None => {}
+        }
+    }
+    result
+}
+
+fn symbol_table<'lua>(lua: &'lua Lua, symbol: &Symbol) -> Result<Table<'lua>, mlua::Error> {
+    let table = range_table(lua, symbol.range.clone())?;
+    table.set("name", symbol.name.as_str())?;
+    table.set("kind", symbol.kind.as_str())?;
+    table.set("path", symbol.path.to_string_lossy().into_owned())?;
+    let start_point = lua.create_table()?;
+    start_point.set("row", Offset(symbol.start_point.row))?;
+    start_point.set("column", Offset(symbol.start_point.column))?;
+    table.set("start_point", start_point)?;
+    Ok(table)
+}
+
+fn symbol_list<'lua, 'a>(
+    lua: &'lua Lua,
+    symbols: impl IntoIterator<Item = &'a Symbol>,
+) -> Result<Table<'lua>, mlua::Error> {
+    let list = lua.create_table()?;
+    for symbol in symbols {
+        list.push(symbol_table(lua, symbol)?)?;
+    }
+    Ok(list)
+}
+
+impl UserData for SymbolIndex {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("lookup", |lua, index, name: String| {
+            symbol_list(lua, index.lookup(&name))
+        });
+        methods.add_method("symbols_in", |lua, index, path: String| {
+            symbol_list(lua, index.symbols_in(path))
+        });
+        methods.add_method_mut(
+            "index_file",
+            |_, index, (path, src): (String, Option<mlua::String>)| match src {
+                Some(src) => index.index_file(&path, src.as_bytes()),
+                None => {
+                    let src = read_file(Path::new(&path))?;
+                    index.index_file(&path, &src)
+                }
+            },
+        );
+        methods.add_method_mut("remove_file", |_, index, path: String| {
+            index.remove_file(path);
+            Ok(())
+        });
+        methods.add_method("files", |_, index, ()| {
+            Ok(index
+                .files()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+
+    const SYMBOLS: &str = r#"
+        (function_definition name: (identifier) @name) @definition.function
+        (class_definition name: (identifier) @name) @definition.class
+    "#;
+
+    #[test]
+    fn can_index_and_look_up_symbols() {
+        let mut index = SymbolIndex::new();
+        let python = LanguagePack::new("python").with_file_type("py");
+        index
+            .add_language(&python, tree_sitter_python::language(), SYMBOLS)
+            .unwrap();
+        let a = b"class A:\n    def f(self):\n        pass\n";
+        let b = b"def f():\n    pass\n\ndef g():\n    pass\n";
+        assert_eq!(2, index.index_file("a.py", a).unwrap());
+        assert_eq!(2, index.index_file("b.py", b).unwrap());
+        assert_eq!(0, index.index_file("c.rs", b"fn f() {}").unwrap());
+
+        let fs = index.lookup("f");
+        assert_eq!(2, fs.len());
+        assert_eq!(Path::new("a.py"), fs[0].path);
+        assert_eq!(Point::new(1, 4), fs[0].start_point);
+        let kinds = index
+            .symbols_in("a.py")
+            .iter()
+            .map(|symbol| (symbol.name.as_str(), symbol.kind.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![("A", "class"), ("f", "function")], kinds);
+
+        index.index_file("b.py", b"def h():\n    pass\n").unwrap();
+        assert_eq!(1, index.lookup("f").len());
+        assert!(index.lookup("g").is_empty());
+
+        let saved = std::env::temp_dir().join(format!(
+            "mlua-tree-sitter-symbols-{}.tsv",
+            std::process::id()
+        ));
+        index.save(&saved).unwrap();
+        let mut loaded = SymbolIndex::new();
+        loaded.load(&saved).unwrap();
+        std::fs::remove_file(&saved).unwrap();
+        assert_eq!(index.symbols_in("a.py"), loaded.symbols_in("a.py"));
+        assert_eq!(1, loaded.lookup("h").len());
+
+        let l = Lua::new();
+        l.globals().set("index", index).unwrap();
+        l.check(
+            r#"
+              local h = index:lookup("h")[1]
+              assert(h.path == "b.py" and h.kind == "function" and h.start_byte == 0)
+              local symbols = index:symbols_in("a.py")
+              assert(#symbols == 2 and symbols[2].start_point.row == 1)
+              assert(index:index_file("b.py", "class H:\n    pass\n") == 1)
+              assert(index:lookup("H")[1].kind == "class")
+              index:remove_file("a.py")
+              assert(#index:lookup("A") == 0 and #index:files() == 1)
+            "#,
+        );
+    }
+}
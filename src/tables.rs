@@ -0,0 +1,230 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Materializes a tree as nested plain Lua tables.
+//!
+//! Some sandboxed environments don't let scripts touch userdata at all, and plain tables are much
+//! easier to inspect (and compare) in Lua test code than ltreesitter nodes.
+//! [`TreeWithSource::to_lua_table`] turns a tree into a table for its root node, where each node's
+//! table has these fields:
+//!
+//! - `kind`, `named`, and (if the node fills a field of its parent) `field`
+//! - `start_byte`, `end_byte`, `start_point`, and `end_point`, in the same shape as a
+//!   [`TSRange`][crate::TSRange]
+//! - `text`, the node's source code, if [`TableOptions::with_text`] is set
+//! - `children`, a list of the tables of the node's children
+//! - `truncated`, set to `true` if the node has children that were left out because of
+//!   [`TableOptions::with_max_depth`]
+//!
+//! Nodes that are rejected by [`TableOptions::with_filter`] are left out, along with all of their
+//! descendants.  The root node is always included.
+//!
+//! In Lua, `require("ltreesitter_rs").to_table(tree_or_node [, options])` does the same, where
+//! `options` can have `max_depth`, `text`, and `named` (to only include named nodes) fields.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use tree_sitter::Node;
+
+use crate::limits;
+use crate::offsets::Offset;
+use crate::pretty;
+use crate::TSPoint;
+use crate::TreeWithSource;
+
+/// Controls how [`TreeWithSource::to_lua_table`] materializes a tree.
+#[derive(Default)]
+pub struct TableOptions {
+    /// The deepest level of nodes to include, where the root node is at depth 0.  Defaults to no
+    /// limit.
+    pub max_depth: Option<usize>,
+    /// Whether each node's table includes its source code.  Defaults to `false`.
+    pub text: bool,
+    filter: Option<Box<dyn Fn(Node) -> bool>>,
+}
+
+impl TableOptions {
+    pub fn new() -> TableOptions {
+        TableOptions::default()
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> TableOptions {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_text(mut self, text: bool) -> TableOptions {
+        self.text = text;
+        self
+    }
+
+    /// Only includes the nodes for which `filter` returns `true` (and their descendants that it
+    /// also accepts).
+    pub fn with_filter<F>(mut self, filter: F) -> TableOptions
+    where
+        F: Fn(Node) -> bool + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    fn includes(&self, node: Node) -> bool {
+        self.filter.as_ref().map_or(true, |filter| filter(node))
+    }
+}
+
+impl TreeWithSource<'_> {
+    /// Materializes the tree as nested plain Lua tables.
+    pub fn to_lua_table<'lua>(
+        &self,
+        lua: &'lua Lua,
+        options: &TableOptions,
+    ) -> Result<Table<'lua>, mlua::Error> {
+        let text = |range: Range<usize>| -> Result<Option<Cow<[u8]>>, mlua::Error> {
+            if let Some(text) = self.src.get(range.clone()) {
+                return Ok(Some(Cow::Borrowed(text)));
+            }
+            match &self.store {
+                Some(store) => store.read(range).map(Some),
+                None => Ok(None),
+            }
+        };
+        to_table(lua, self.tree.root_node(), &text, options)
+    }
+}
+
+type Text<'a> = dyn Fn(Range<usize>) -> Result<Option<Cow<'a, [u8]>>, mlua::Error> + 'a;
+
+fn to_table<'lua>(
+    lua: &'lua Lua,
+    root: Node,
+    text: &Text,
+    options: &TableOptions,
+) -> Result<Table<'lua>, mlua::Error> {
+    // With a depth limit, the recursion below is bounded no matter how deep the tree is.
+    if options.max_depth.is_none() {
+        limits::check_depth(lua, root)?;
+    }
+    node_table(lua, root, None, 0, text, options)
+}
+
+fn node_table<'lua>(
+    lua: &'lua Lua,
+    node: Node,
+    field: Option<&str>,
+    depth: usize,
+    text: &Text,
+    options: &TableOptions,
+) -> Result<Table<'lua>, mlua::Error> {
+    let table = lua.create_table()?;
+    table.set("kind", node.kind())?;
+    table.set("named", node.is_named())?;
+    table.set("field", field)?;
+    table.set("start_byte", Offset(node.start_byte()))?;
+    table.set("end_byte", Offset(node.end_byte()))?;
+    table.set("start_point", TSPoint(node.start_position()))?;
+    table.set("end_point", TSPoint(node.end_position()))?;
+    if options.text {
+        if let Some(text) = text(node.byte_range())? {
+            table.set("text", lua.create_string(&text)?)?;
+        }
+    }
+    let children = lua.create_table()?;
+    if options
+        .max_depth
+        .map_or(false, |max_depth| depth >= max_depth)
+    {
+        if node.child_count() > 0 {
+            table.set("truncated", true)?;
+        }
+    } else {
+        let mut cursor = node.walk();
+        if cursor.goto_first_child() {
+            loop {
+                let child = cursor.node();
+                if options.includes(child) {
+                    let child =
+                        node_table(lua, child, cursor.field_name(), depth + 1, text, options)?;
+                    children.raw_push(child)?;
+                }
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+    }
+    table.set("children", children)?;
+    Ok(table)
+}
+
+/// Adds `to_table` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let to_lua_table = lua.create_function(|lua, (value, options): (Value, Option<Table>)| {
+        let (node, src) = pretty::node_and_source(lua, value)?;
+        let mut table_options = TableOptions::new();
+        if let Some(options) = options {
+            table_options.max_depth = options.get("max_depth")?;
+            table_options.text = options.get::<_, Option<bool>>("text")?.unwrap_or(false);
+            if options.get::<_, Option<bool>>("named")?.unwrap_or(false) {
+                table_options = table_options.with_filter(|node| node.is_named());
+            }
+        }
+        let text = |range: Range<usize>| -> Result<Option<Cow<[u8]>>, mlua::Error> {
+            Ok(src.and_then(|src| src.get(range)).map(Cow::Borrowed))
+        };
+        to_table(lua, node, &text, &table_options)
+    })?;
+    crate::companion_module(lua)?.set("to_table", to_lua_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_materialize_trees_as_tables() {
+        let code = b"def double(x): return x * 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        let parsed = parsed.with_source(code);
+        let table = parsed
+            .to_lua_table(&l, &TableOptions::new().with_max_depth(2).with_text(true))
+            .unwrap();
+        l.globals().set("materialized", table).unwrap();
+        l.globals().set("parsed", parsed).unwrap();
+        l.check(
+            r#"
+              assert(materialized.kind == "module" and materialized.start_byte == 0)
+              local definition = materialized.children[1]
+              assert(definition.kind == "function_definition")
+              assert(definition.text == "def double(x): return x * 2")
+              local name = definition.children[2]
+              assert(name.kind == "identifier" and name.field == "name" and name.text == "double")
+              local body = definition.children[#definition.children]
+              assert(body.field == "body" and body.truncated and #body.children == 0)
+              assert(name.truncated == nil)
+
+              local named = require("ltreesitter_rs").to_table(parsed, { named = true })
+              local definition = named.children[1]
+              assert(#definition.children == 3)
+              assert(definition.children[1].kind == "identifier")
+              assert(definition.children[2].kind == "parameters")
+              assert(definition.children[2].end_point.column == 13)
+              assert(definition.text == nil)
+            "#,
+        );
+    }
+}
@@ -0,0 +1,192 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Evaluates the standard text predicates of a query when it's run from Rust.
+//!
+//! Editors like Neovim give queries a common set of predicates that compare the text of captured
+//! nodes: `#eq?`, `#match?` (with a regular expression), and `#any-of?`, along with their `#not-`
+//! variants.  tree-sitter's Rust bindings leave any predicate that they don't evaluate themselves
+//! to the caller, so [`TextPredicates`] evaluates whichever of these they left behind, with the
+//! same meaning that they have in nvim-treesitter.  [`run_query`][crate::run_query] uses it, so
+//! Lua-compiled queries behave the same way whether they're run from Lua or from Rust.  Other
+//! predicates are ignored.
+
+use regex::bytes::Regex;
+use tree_sitter::Query;
+use tree_sitter::QueryMatch;
+use tree_sitter::QueryPredicate;
+use tree_sitter::QueryPredicateArg;
+
+enum Operand {
+    Capture(u32),
+    Text(Box<str>),
+}
+
+enum Check {
+    Eq(u32, Operand),
+    Match(u32, Regex),
+    AnyOf(u32, Vec<Box<str>>),
+}
+
+/// The text predicates of every pattern in a query, ready to be evaluated against its matches.
+pub struct TextPredicates {
+    patterns: Vec<Vec<(Check, bool)>>,
+}
+
+impl TextPredicates {
+    /// Compiles the text predicates of a query.  Returns an error if one of them has the wrong
+    /// arguments, or has a regular expression that isn't valid.
+    pub fn new(query: &Query) -> Result<TextPredicates, mlua::Error> {
+        let mut patterns = Vec::with_capacity(query.pattern_count());
+        for pattern_index in 0..query.pattern_count() {
+            let mut checks = Vec::new();
+            for predicate in query.general_predicates(pattern_index) {
+                if let Some(check) = compile(predicate).map_err(|message| {
+                    mlua::Error::RuntimeError(format!(
+                        "invalid #{} predicate in pattern {}: {}",
+                        predicate.operator, pattern_index, message
+                    ))
+                })? {
+                    checks.push(check);
+                }
+            }
+            patterns.push(checks);
+        }
+        Ok(TextPredicates { patterns })
+    }
+
+    /// Returns whether a match satisfies the text predicates of its pattern.  `src` is the source
+    /// of the tree that the match came from.
+    pub fn satisfied(&self, m: &QueryMatch, src: &[u8]) -> bool {
+        let checks = match self.patterns.get(m.pattern_index) {
+            Some(checks) => checks,
+            None => return true,
+        };
+        checks.iter().all(|(check, positive)| {
+            m.nodes_for_capture_index(check.capture())
+                .all(|node| check.holds(m, src, node_text(node, src)) == *positive)
+        })
+    }
+}
+
+impl Check {
+    /// The capture whose nodes the check applies to.
+    fn capture(&self) -> u32 {
+        match self {
+            Check::Eq(capture, _) | Check::Match(capture, _) | Check::AnyOf(capture, _) => *capture,
+        }
+    }
+
+    fn holds(&self, m: &QueryMatch, src: &[u8], text: &[u8]) -> bool {
+        match self {
+            Check::Eq(_, Operand::Text(expected)) => text == expected.as_bytes(),
+            Check::Eq(_, Operand::Capture(other)) => m
+                .nodes_for_capture_index(*other)
+                .next()
+                .map_or(false, |other| text == node_text(other, src)),
+            Check::Match(_, regex) => regex.is_match(text),
+            Check::AnyOf(_, values) => values.iter().any(|value| text == value.as_bytes()),
+        }
+    }
+}
+
+fn node_text<'a>(node: tree_sitter::Node, src: &'a [u8]) -> &'a [u8] {
+    src.get(node.byte_range()).unwrap_or_default()
+}
+
+/// Compiles one predicate.  Returns `None` if it isn't one of the text predicates.
+fn compile(predicate: &QueryPredicate) -> Result<Option<(Check, bool)>, String> {
+    let (operator, positive) = match predicate.operator.strip_prefix("not-") {
+        Some(operator) => (operator, false),
+        None => (&*predicate.operator, true),
+    };
+    if !matches!(operator, "eq?" | "match?" | "any-of?") {
+        return Ok(None);
+    }
+    let capture = match predicate.args.first() {
+        Some(QueryPredicateArg::Capture(capture)) => *capture,
+        _ => return Err("first argument must be a capture".to_string()),
+    };
+    let rest = &predicate.args[1..];
+    let check = match (operator, rest) {
+        ("eq?", [QueryPredicateArg::Capture(other)]) => {
+            Check::Eq(capture, Operand::Capture(*other))
+        }
+        ("eq?", [QueryPredicateArg::String(text)]) => {
+            Check::Eq(capture, Operand::Text(text.clone()))
+        }
+        ("match?", [QueryPredicateArg::String(pattern)]) => {
+            Check::Match(capture, Regex::new(pattern).map_err(|err| err.to_string())?)
+        }
+        ("any-of?", values) => Check::AnyOf(
+            capture,
+            values
+                .iter()
+                .map(|value| match value {
+                    QueryPredicateArg::String(value) => Ok(value.clone()),
+                    QueryPredicateArg::Capture(_) => Err("arguments must be strings".to_string()),
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        _ => return Err("expected two arguments".to_string()),
+    };
+    Ok(Some((check, positive)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree_sitter::QueryCursor;
+
+    fn matching(query: &str, code: &[u8]) -> Vec<String> {
+        let python = tree_sitter_python::language();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(python).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let query = Query::new(python, query).unwrap();
+        let predicates = TextPredicates::new(&query).unwrap();
+        let mut cursor = QueryCursor::new();
+        let matches = cursor.matches(&query, parsed.root_node(), code);
+        matches
+            .filter(|m| predicates.satisfied(m, code))
+            .map(|m| m.captures[0].node.utf8_text(code).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn can_evaluate_text_predicates() {
+        let code = b"x = 1\nfoo = x\nfab = 2\n";
+        assert_eq!(
+            vec!["x", "foo", "x"],
+            matching(r#"((identifier) @id (#any-of? @id "x" "foo"))"#, code),
+        );
+        assert_eq!(
+            vec!["fab"],
+            matching(r#"((identifier) @id (#not-any-of? @id "x" "foo"))"#, code),
+        );
+        assert_eq!(
+            vec!["foo", "fab"],
+            matching(r#"((identifier) @id (#match? @id "^f"))"#, code),
+        );
+        assert_eq!(
+            vec!["x", "x"],
+            matching(r#"((identifier) @id (#eq? @id "x"))"#, code),
+        );
+        assert_eq!(
+            vec!["foo"],
+            matching(
+                r#"((assignment left: (identifier) @a right: (identifier) @b) (#not-eq? @a @b))"#,
+                code,
+            ),
+        );
+
+        let python = tree_sitter_python::language();
+        // Newer versions of tree-sitter check #any-of? themselves when compiling the query.
+        if let Ok(query) = Query::new(python, r#"((identifier) @id (#any-of? @id @id))"#) {
+            assert!(TextPredicates::new(&query).is_err());
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! A textobjects engine, like the ones that editors drive with `textobjects.scm` queries.
+//!
+//! A textobjects query uses captures like `@function.outer` and `@parameter.inner` to mark the
+//! text objects in a file.  If a match captures several nodes with the same name (for instance,
+//! via a quantifier), the text object covers all of them, from the start of the first to the end
+//! of the last.  [`TextObjects`] can then:
+//!
+//! - [`select`][TextObjects::select] the smallest text object that contains a position;
+//! - [`move_next`][TextObjects::move_next] to the next text object that starts after a position;
+//! - [`move_prev`][TextObjects::move_prev] to the previous text object that starts before it.
+//!
+//! Each operation only runs the query over the part of the tree that can affect the result.
+//! (Editor-specific predicates like `#make-range!` aren't supported.)
+//!
+//! In Lua, `require("ltreesitter_rs").textobjects(tree, query)` compiles a textobjects query for
+//! the tree's grammar.  The result has `select(tree, name, byte)`, `move_next(tree, name, byte)`,
+//! and `move_prev(tree, name, byte)` methods, which return range tables with `start_byte` and
+//! `end_byte` fields, or `nil`.
+
+use std::ops::Range;
+
+use mlua::Lua;
+use mlua::UserData;
+use mlua::UserDataMethods;
+use tree_sitter::Language;
+use tree_sitter::Node;
+use tree_sitter::Query;
+use tree_sitter::QueryCursor;
+use tree_sitter::QueryError;
+
+use crate::ranges::range_table;
+use crate::TreeWithSource;
+
+/// A compiled textobjects query.
+pub struct TextObjects {
+    query: Query,
+}
+
+impl TextObjects {
+    /// Compiles a textobjects query.
+    pub fn new(language: Language, source: &str) -> Result<TextObjects, QueryError> {
+        Ok(TextObjects::from_query(Query::new(language, source)?))
+    }
+
+    /// Wraps a query that has already been compiled.
+    pub fn from_query(query: Query) -> TextObjects {
+        TextObjects { query }
+    }
+
+    /// Returns the ranges of the text objects named `name` within `bytes`.
+    fn objects(
+        &self,
+        root: Node,
+        src: &[u8],
+        name: &str,
+        bytes: Range<usize>,
+    ) -> Vec<Range<usize>> {
+        let index = match self.query.capture_index_for_name(name) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(bytes);
+        cursor
+            .matches(&self.query, root, src)
+            .filter_map(|query_match| {
+                let mut nodes = query_match.nodes_for_capture_index(index);
+                let first = nodes.next()?;
+                let range = nodes.fold(first.byte_range(), |range, node| {
+                    range.start.min(node.start_byte())..range.end.max(node.end_byte())
+                });
+                Some(range)
+            })
+            .collect()
+    }
+
+    /// Returns the smallest text object named `name` that contains `byte`.
+    pub fn select(&self, root: Node, src: &[u8], name: &str, byte: usize) -> Option<Range<usize>> {
+        self.objects(root, src, name, byte..byte + 1)
+            .into_iter()
+            .filter(|range| range.start <= byte && byte < range.end)
+            .min_by_key(|range| range.end - range.start)
+    }
+
+    /// Returns the first text object named `name` that starts after `byte`.  If several start at
+    /// the same place, returns the largest.
+    pub fn move_next(
+        &self,
+        root: Node,
+        src: &[u8],
+        name: &str,
+        byte: usize,
+    ) -> Option<Range<usize>> {
+        if byte + 1 >= root.end_byte() {
+            return None;
+        }
+        self.objects(root, src, name, byte + 1..root.end_byte())
+            .into_iter()
+            .filter(|range| range.start > byte)
+            .min_by_key(|range| (range.start, std::cmp::Reverse(range.end)))
+    }
+
+    /// Returns the last text object named `name` that starts before `byte`.  If several start at
+    /// the same place, returns the largest.
+    pub fn move_prev(
+        &self,
+        root: Node,
+        src: &[u8],
+        name: &str,
+        byte: usize,
+    ) -> Option<Range<usize>> {
+        self.objects(root, src, name, root.start_byte()..byte)
+            .into_iter()
+            .filter(|range| range.start < byte)
+            .max_by_key(|range| (range.start, range.end))
+    }
+}
+
+impl UserData for TextObjects {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        type Args<'lua> = (TreeWithSource<'lua>, String, usize);
+        methods.add_method("select", |lua, objects, (tree, name, byte): Args| {
+            objects
+                .select(tree.tree.root_node(), tree.src, &name, byte)
+                .map(|range| range_table(lua, range))
+                .transpose()
+        });
+        methods.add_method("move_next", |lua, objects, (tree, name, byte): Args| {
+            objects
+                .move_next(tree.tree.root_node(), tree.src, &name, byte)
+                .map(|range| range_table(lua, range))
+                .transpose()
+        });
+        methods.add_method("move_prev", |lua, objects, (tree, name, byte): Args| {
+            objects
+                .move_prev(tree.tree.root_node(), tree.src, &name, byte)
+                .map(|range| range_table(lua, range))
+                .transpose()
+        });
+    }
+}
+
+/// Adds `textobjects` to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let textobjects = lua.create_function(|_, (tree, source): (TreeWithSource, String)| {
+        TextObjects::new(tree.tree.language(), &source).map_err(mlua::Error::external)
+    })?;
+    crate::companion_module(lua)?.set("textobjects", textobjects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    const QUERY: &str = r#"
+        (function_definition) @function.outer
+        (function_definition body: (block) @function.inner)
+        (parameters (identifier) @parameter.list "," (identifier) @parameter.list)
+    "#;
+
+    #[test]
+    fn can_select_and_move_between_text_objects() {
+        let code = b"def f(a, b):\n    return a\n\ndef g():\n    return 2\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let root = parsed.root_node();
+        let objects = TextObjects::new(tree_sitter_python::language(), QUERY).unwrap();
+        let text = |range: Option<Range<usize>>| range.map(|range| &code[range]);
+
+        assert_eq!(
+            Some(&b"return a"[..]),
+            text(objects.select(root, code, "function.inner", 20))
+        );
+        assert_eq!(
+            Some(&b"a, b"[..]),
+            text(objects.select(root, code, "parameter.list", 6))
+        );
+        assert!(objects.select(root, code, "function.outer", 26).is_none());
+        assert!(objects.select(root, code, "class.outer", 0).is_none());
+        let g = objects.move_next(root, code, "function.outer", 0);
+        assert_eq!(Some(&b"def g():\n    return 2"[..]), text(g));
+        assert!(objects
+            .move_next(root, code, "function.outer", 27)
+            .is_none());
+        let f = objects.move_prev(root, code, "function.outer", 27);
+        assert_eq!(Some(0), f.map(|range| range.start));
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.globals().set("query", QUERY).unwrap();
+        l.check(
+            r#"
+              local objects = require("ltreesitter_rs").textobjects(parsed, query)
+              local outer = objects:select(parsed, "function.outer", 30)
+              assert(outer.start_byte == 27 and outer.end_byte == 48)
+              assert(objects:move_prev(parsed, "function.outer", 27).start_byte == 0)
+              assert(objects:move_next(parsed, "function.outer", 27) == nil)
+            "#,
+        );
+    }
+}
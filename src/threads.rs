@@ -0,0 +1,123 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Which of this crate's types can cross threads.
+//!
+//! When mlua is built with its `send` feature, a [`Lua`][mlua::Lua] state can be moved to another
+//! thread, which means that everything that the state holds onto has to be `Send`, too.  This
+//! crate's `send` feature turns on mlua's, and makes the crate's own types follow suit:
+//!
+//! - [`SourceStore`][crate::SourceStore]s, and the callbacks that you pass to
+//!   [`FetchSource`][crate::FetchSource], [`HostFunctions`][crate::HostFunctions],
+//!   [`QueryPredicates`][crate::QueryPredicates], and
+//!   [`EmitChannels`][crate::EmitChannels], must be `Send` (via [`mlua::MaybeSend`], which only
+//!   requires `Send` when the feature is on).
+//! - [`SoftTreePool`][crate::SoftTreePool], [`SoftTree`][crate::SoftTree], and
+//!   [`Budget`][crate::Budget] share their state through a mutex instead of a `RefCell`, so that
+//!   they're `Send`.
+//!
+//! These are always `Send`, with or without the feature: [`TreeWithOwnedSource`] (with a `Send`
+//! buffer), [`CancellationToken`][crate::CancellationToken],
+//! [`AnalysisContext`][crate::AnalysisContext], [`MatchBuffer`][crate::MatchBuffer], and the
+//! other values that don't refer to a Lua state.
+//!
+//! A [`TreeWithSource`][crate::TreeWithSource] or [`TSNode`][crate::TSNode] never is, since it
+//! can borrow from the Lua state that it came from.  To move a tree between threads, or to hand it
+//! to an async executor, convert it into a [`TreeWithOwnedSource`] first.
+//!
+//! [`TreeWithOwnedSource`]: crate::TreeWithOwnedSource
+
+#[cfg(not(feature = "send"))]
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::ops::DerefMut;
+#[cfg(not(feature = "send"))]
+use std::rc::Rc;
+#[cfg(feature = "send")]
+use std::sync::Arc;
+#[cfg(feature = "send")]
+use std::sync::Mutex;
+
+/// Mutable state that's shared by several handles.  This is an `Rc<RefCell<T>>`, unless the
+/// `send` feature is on, in which case it's an `Arc<Mutex<T>>`.
+pub(crate) struct Shared<T> {
+    #[cfg(not(feature = "send"))]
+    inner: Rc<RefCell<T>>,
+    #[cfg(feature = "send")]
+    inner: Arc<Mutex<T>>,
+}
+
+impl<T> Shared<T> {
+    pub(crate) fn new(value: T) -> Shared<T> {
+        Shared {
+            #[cfg(not(feature = "send"))]
+            inner: Rc::new(RefCell::new(value)),
+            #[cfg(feature = "send")]
+            inner: Arc::new(Mutex::new(value)),
+        }
+    }
+
+    #[cfg(not(feature = "send"))]
+    pub(crate) fn borrow(&self) -> impl Deref<Target = T> + '_ {
+        self.inner.borrow()
+    }
+
+    #[cfg(feature = "send")]
+    pub(crate) fn borrow(&self) -> impl Deref<Target = T> + '_ {
+        self.borrow_mut()
+    }
+
+    #[cfg(not(feature = "send"))]
+    pub(crate) fn borrow_mut(&self) -> impl DerefMut<Target = T> + '_ {
+        self.inner.borrow_mut()
+    }
+
+    #[cfg(feature = "send")]
+    pub(crate) fn borrow_mut(&self) -> impl DerefMut<Target = T> + '_ {
+        // A panic while the state is borrowed can't leave it half-updated in a way that matters to
+        // the other handles, so we ignore poisoning, like a RefCell would.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Shared<T> {
+        Shared {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::AnalysisContext;
+    use crate::CancellationToken;
+    use crate::MatchBuffer;
+    use crate::TreeWithOwnedSource;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn thread_safe_types_are_send() {
+        assert_send::<TreeWithOwnedSource>();
+        assert_send::<TreeWithOwnedSource<Arc<[u8]>>>();
+        assert_send::<TreeWithOwnedSource<Vec<u8>>>();
+        assert_send::<CancellationToken>();
+        assert_send::<AnalysisContext>();
+        assert_send::<MatchBuffer>();
+        #[cfg(feature = "send")]
+        {
+            assert_send::<crate::SoftTreePool>();
+            assert_send::<crate::SoftTree>();
+            assert_send::<crate::Budget>();
+            assert_send::<mlua::Lua>();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Classifies leaf tokens as keywords, operators, punctuation, and so on, so that formatters and
+//! highlighters don't need a hand-written token list for each language.
+//!
+//! Grammars don't record this directly, so [`token_class`] uses the same heuristics for every
+//! language:
+//!
+//! - An anonymous token that looks like a word (`def`, `return`, `in`) is a keyword.
+//! - An anonymous token made of brackets, commas, semicolons, colons, or periods is punctuation.
+//! - Any other anonymous token (`+`, `==`, `->`) is an operator.
+//! - Named leaves are comments, identifiers, or literals, based on their kind.
+//!
+//! In Lua, ltreesitter nodes gain a `token_class()` method, which returns the class's name (like
+//! `"keyword"`), or `nil` for nodes that aren't leaves.
+
+use std::fmt::Display;
+
+use mlua::Lua;
+use tree_sitter::Node;
+
+use crate::ltreesitter;
+use crate::TSNode;
+
+/// The classes of leaf tokens.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum TokenClass {
+    Keyword,
+    Operator,
+    Punctuation,
+    Comment,
+    Identifier,
+    Literal,
+}
+
+impl TokenClass {
+    /// Returns the name of the class, as Lua code sees it.
+    pub fn name(self) -> &'static str {
+        match self {
+            TokenClass::Keyword => "keyword",
+            TokenClass::Operator => "operator",
+            TokenClass::Punctuation => "punctuation",
+            TokenClass::Comment => "comment",
+            TokenClass::Identifier => "identifier",
+            TokenClass::Literal => "literal",
+        }
+    }
+}
+
+impl Display for TokenClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Classifies a leaf token.  Returns `None` if the node has any children.
+pub fn token_class(node: Node) -> Option<TokenClass> {
+    if node.child_count() > 0 {
+        return None;
+    }
+    let kind = node.kind();
+    if node.is_named() {
+        return Some(if kind.contains("comment") {
+            TokenClass::Comment
+        } else if kind.contains("identifier") || kind == "name" {
+            TokenClass::Identifier
+        } else {
+            TokenClass::Literal
+        });
+    }
+    let word = kind
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_alphabetic() || c == '_')
+        && kind.chars().all(|c| c.is_alphanumeric() || c == '_');
+    Some(if word {
+        TokenClass::Keyword
+    } else if !kind.is_empty() && kind.chars().all(|c| "()[]{},;:.".contains(c)) {
+        TokenClass::Punctuation
+    } else {
+        TokenClass::Operator
+    })
+}
+
+/// Adds `token_class` to ltreesitter nodes.
+pub(crate) fn install_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    let methods = ltreesitter::methods(lua, ltreesitter::NODE_METATABLE)?;
+    methods.set(
+        "token_class",
+        lua.create_function(|_, node: TSNode| Ok(token_class(*node).map(TokenClass::name)))?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_classify_tokens() {
+        let code = b"def f(x): return x + 1  # done\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let root = parsed.root_node();
+        let class_at =
+            |start, end| token_class(root.descendant_for_byte_range(start, end).unwrap());
+        assert_eq!(Some(TokenClass::Keyword), class_at(0, 3));
+        assert_eq!(Some(TokenClass::Identifier), class_at(4, 5));
+        assert_eq!(Some(TokenClass::Punctuation), class_at(5, 6));
+        assert_eq!(Some(TokenClass::Operator), class_at(19, 20));
+        assert_eq!(Some(TokenClass::Literal), class_at(21, 22));
+        assert_eq!(Some(TokenClass::Comment), class_at(24, 30));
+        assert_eq!(None, token_class(root));
+
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r#"
+              local definition = parsed:root():child(0)
+              assert(definition:token_class() == nil)
+              assert(definition:child(0):token_class() == "keyword")
+              assert(definition:child_by_field_name("name"):token_class() == "identifier")
+            "#,
+        );
+    }
+}
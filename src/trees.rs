@@ -0,0 +1,578 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Keeps track of the ltreesitter trees that this crate has pushed into Lua.
+//!
+//! ltreesitter nodes don't give us any way to get back to the Lua object of the tree that they
+//! belong to.  To work around that, we maintain an index that maps each underlying tree-sitter
+//! tree to its ltreesitter wrapper.  The index holds the wrappers weakly, so it doesn't keep them
+//! from being garbage-collected.  We also maintain a table of arbitrary "attachments" for each
+//! tree, which other parts of the crate use to store extra data alongside a tree.
+//!
+//! Trees can also be closed explicitly, which frees the underlying tree-sitter tree right away
+//! instead of waiting for the garbage collector.  A closed tree's userdata keeps a null tree
+//! pointer, and the tree's methods check for it, so that any further use of the tree raises an
+//! error instead of touching freed memory.  Nodes and cursors only hold the address of their
+//! tree-sitter tree, which tree-sitter can reuse once the tree is freed, so we record which
+//! ltreesitter tree each node and cursor came from, as the methods that create them return them.
+//! When a tree is closed, its nodes and cursors get a metatable whose methods all raise an error.
+//! That way, we don't have to check anything in the methods that only use a node, like
+//! `node:type()`, which are the ones that Lua code calls most often.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+
+use mlua::AnyUserData;
+use mlua::Function;
+use mlua::LightUserData;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Value;
+
+use crate::ltreesitter;
+use crate::ltreesitter::Object;
+use crate::ltreesitter::SourceBuffer;
+use crate::query_cache;
+use crate::stores;
+use crate::Error;
+use crate::StaleNode;
+
+const INDEX_KEY: &str = "mlua_tree_sitter.tree_index";
+const ATTACHMENTS_KEY: &str = "mlua_tree_sitter.tree_attachments";
+const OWNERS_KEY: &str = "mlua_tree_sitter.tree_owners";
+const GUARD_ITERATOR_KEY: &str = "mlua_tree_sitter.guard_iterator";
+const CLOSED_NODE_METATABLE: &str = "mlua_tree_sitter.ClosedNode";
+const CLOSED_TREE_CURSOR_METATABLE: &str = "mlua_tree_sitter.ClosedTreeCursor";
+const SOURCE_BUFFER_KEY: &str = "source_buffer";
+const DOCUMENT_KEY: &str = "document";
+const GENERATION_KEY: &str = "generation";
+
+fn index(lua: &Lua) -> Result<Table, mlua::Error> {
+    if let Some(index) = lua.named_registry_value::<Option<Table>>(INDEX_KEY)? {
+        return Ok(index);
+    }
+    let index = crate::weak_table(lua, "v")?;
+    lua.set_named_registry_value(INDEX_KEY, index.clone())?;
+    Ok(index)
+}
+
+fn all_attachments(lua: &Lua) -> Result<Table, mlua::Error> {
+    if let Some(attachments) = lua.named_registry_value::<Option<Table>>(ATTACHMENTS_KEY)? {
+        return Ok(attachments);
+    }
+    let attachments = crate::weak_table(lua, "k")?;
+    lua.set_named_registry_value(ATTACHMENTS_KEY, attachments.clone())?;
+    Ok(attachments)
+}
+
+fn owners(lua: &Lua) -> Result<Table, mlua::Error> {
+    if let Some(owners) = lua.named_registry_value::<Option<Table>>(OWNERS_KEY)? {
+        return Ok(owners);
+    }
+    let owners = crate::weak_table(lua, "k")?;
+    lua.set_named_registry_value(OWNERS_KEY, owners.clone())?;
+    Ok(owners)
+}
+
+/// Adds an ltreesitter tree to the index.
+pub(crate) fn register_tree<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Result<(), mlua::Error> {
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+    let ts_tree = unsafe { (*ltreesitter_tree).tree };
+    index(lua)?.raw_set(LightUserData(ts_tree as *mut c_void), tree.clone())
+}
+
+/// Returns the ltreesitter tree that wraps a tree-sitter tree, if that tree has been added to the
+/// index and has not been garbage-collected.
+fn lookup_tree(
+    lua: &Lua,
+    ts_tree: *const tree_sitter::ffi::TSTree,
+) -> Result<Option<Value>, mlua::Error> {
+    let tree: Value = index(lua)?.raw_get(LightUserData(ts_tree as *mut c_void))?;
+    Ok((!tree.is_nil()).then_some(tree))
+}
+
+/// Returns the ltreesitter tree that an ltreesitter tree, node, or cursor belongs to, if we know
+/// it.  A tree belongs to itself.
+pub(crate) fn owner<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<Value<'lua>>, mlua::Error> {
+    if !matches!(value, Value::UserData(_)) {
+        return Ok(None);
+    }
+    if let Some(owner) = owners(lua)?.raw_get::<_, Option<Value>>(value.clone())? {
+        return Ok(Some(owner));
+    }
+    if ltreesitter::as_tree(lua, value)?.is_some() {
+        return Ok(Some(value.clone()));
+    }
+    // This node or cursor wasn't returned by one of ltreesitter's methods, so fall back on the
+    // address of its tree-sitter tree.
+    let ts_tree = if let Some(node) = ltreesitter::as_node(lua, value)? {
+        unsafe { (*node).node.tree }
+    } else if let Some(cursor) = ltreesitter::as_tree_cursor(lua, value)? {
+        unsafe { (*cursor).cursor.tree as *const tree_sitter::ffi::TSTree }
+    } else {
+        return Ok(None);
+    };
+    lookup_tree(lua, ts_tree)
+}
+
+/// Returns the ltreesitter tree that an ltreesitter tree, node, or cursor belongs to, if we know
+/// it.  Returns an [`Error::ClosedTree`] if that tree has been closed.
+pub(crate) fn check_open<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<Option<Value<'lua>>, mlua::Error> {
+    let owner = match owner(lua, value)? {
+        Some(owner) => owner,
+        None => return Ok(None),
+    };
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, owner.clone())?;
+    if unsafe { (*ltreesitter_tree).tree.is_null() } {
+        return Err(closed_error());
+    }
+    Ok(Some(owner))
+}
+
+/// Replaces the source code of an ltreesitter tree with a buffer that we own, so that we can free
+/// it when the tree is closed.
+pub(crate) fn set_source<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    buffer: SourceBuffer,
+) -> Result<(), mlua::Error> {
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+    let text = buffer.as_ptr();
+    // The attachments table keeps the buffer alive for as long as the tree.  ltreesitter's own
+    // copy is a userdata that the tree keeps alive, so the garbage collector still frees it.
+    attachments(lua, tree)?.raw_set(SOURCE_BUFFER_KEY, buffer)?;
+    unsafe { (*ltreesitter_tree).source = text };
+    Ok(())
+}
+
+/// Returns the attachments table for an ltreesitter tree, creating it if necessary.
+pub(crate) fn attachments<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+) -> Result<Table<'lua>, mlua::Error> {
+    let all_attachments = all_attachments(lua)?;
+    if let Some(attachments) = all_attachments.raw_get::<_, Option<Table>>(tree.clone())? {
+        return Ok(attachments);
+    }
+    let attachments = lua.create_table()?;
+    all_attachments.raw_set(tree.clone(), attachments.clone())?;
+    Ok(attachments)
+}
+
+/// Returns the attachments table for an ltreesitter tree, if it has one.
+pub(crate) fn existing_attachments<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+) -> Result<Option<Table<'lua>>, mlua::Error> {
+    all_attachments(lua)?.raw_get(tree.clone())
+}
+
+/// Tracks whether any tree has been closed, and which trees are in use by Rust code and so cannot
+/// be closed.
+/// Also tracks each [`Document`][crate::Document]'s current generation.  (The generation that
+/// each of a document's trees belongs to is stored in the tree's attachments table.)
+#[derive(Default)]
+struct TreeStates {
+    any_closed: bool,
+    pins: HashMap<usize, usize>,
+    current_generations: HashMap<u64, u64>,
+}
+
+fn with_states<R>(lua: &Lua, f: impl FnOnce(&mut TreeStates) -> R) -> R {
+    if lua.app_data_ref::<TreeStates>().is_none() {
+        lua.set_app_data(TreeStates::default());
+    }
+    f(&mut lua.app_data_mut::<TreeStates>().unwrap())
+}
+
+/// Records that a Rust value borrows from a tree, preventing it from being closed.
+pub(crate) fn pin(lua: &Lua, ts_tree: *const tree_sitter::ffi::TSTree) {
+    with_states(lua, |states| {
+        *states.pins.entry(ts_tree as usize).or_default() += 1
+    });
+}
+
+/// Records that a Rust value no longer borrows from a tree.
+pub(crate) fn unpin(lua: &Lua, ts_tree: *const tree_sitter::ffi::TSTree) {
+    with_states(lua, |states| {
+        if let Some(count) = states.pins.get_mut(&(ts_tree as usize)) {
+            *count -= 1;
+            if *count == 0 {
+                states.pins.remove(&(ts_tree as usize));
+            }
+        }
+    });
+}
+
+pub(crate) fn closed_error() -> mlua::Error {
+    Error::ClosedTree.into()
+}
+
+/// Records that an ltreesitter tree is the given generation of a document, and that it is that
+/// document's current generation.
+pub(crate) fn set_generation<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    document: u64,
+    generation: u64,
+) -> Result<(), mlua::Error> {
+    let attachments = attachments(lua, tree)?;
+    attachments.raw_set(DOCUMENT_KEY, document)?;
+    attachments.raw_set(GENERATION_KEY, generation)?;
+    with_states(lua, |states| {
+        states.current_generations.insert(document, generation);
+    });
+    Ok(())
+}
+
+/// Returns a [`StaleNode`] error if an ltreesitter tree, or the tree of an ltreesitter node or
+/// cursor, belongs to an old generation of a document.
+pub(crate) fn check_generation<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<(), mlua::Error> {
+    let any_documents = match lua.app_data_ref::<TreeStates>() {
+        Some(states) => !states.current_generations.is_empty(),
+        None => false,
+    };
+    if !any_documents {
+        return Ok(());
+    }
+    let attachments = match owner(lua, value)? {
+        Some(tree) => existing_attachments(lua, &tree)?,
+        None => None,
+    };
+    let (document, generation) = match attachments {
+        Some(attachments) => (
+            attachments.raw_get::<_, Option<u64>>(DOCUMENT_KEY)?,
+            attachments.raw_get::<_, Option<u64>>(GENERATION_KEY)?,
+        ),
+        None => return Ok(()),
+    };
+    let (document, generation) = match (document, generation) {
+        (Some(document), Some(generation)) => (document, generation),
+        _ => return Ok(()),
+    };
+    let current_generation = with_states(lua, |states| {
+        states.current_generations.get(&document).copied()
+    });
+    match current_generation {
+        Some(current_generation) if current_generation != generation => {
+            Err(mlua::Error::external(StaleNode {
+                document,
+                generation,
+                current_generation,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Frees the tree-sitter tree of an ltreesitter tree, along with its source code if we own it.
+/// Closing a tree that is already closed does nothing.
+///
+/// The source code of a tree that was parsed in Lua belongs to ltreesitter, and is only freed when
+/// the tree is garbage-collected.
+pub(crate) fn close_tree<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Result<(), mlua::Error> {
+    let ltreesitter_tree = ltreesitter::tree_ptr(lua, tree.clone())?;
+    let ts_tree = unsafe { (*ltreesitter_tree).tree };
+    if ts_tree.is_null() {
+        return Ok(());
+    }
+    let pinned = with_states(lua, |states| states.pins.contains_key(&(ts_tree as usize)));
+    if pinned {
+        return Err(Error::PinnedTree.into());
+    }
+
+    // We leave the tree in the index, so that any nodes of it that didn't come from one of
+    // ltreesitter's methods can still find it, and see that it's closed.
+    query_cache::forget_tree(lua, tree)?;
+    if let Some(attachments) = existing_attachments(lua, tree)? {
+        if let Some(buffer) = attachments.raw_get::<_, Option<AnyUserData>>(SOURCE_BUFFER_KEY)? {
+            unsafe { (*ltreesitter_tree).source = &ltreesitter::EMPTY_SOURCE };
+            drop(buffer.take::<SourceBuffer>()?);
+        }
+        stores::release(lua, tree)?;
+    }
+    all_attachments(lua)?.raw_set(tree.clone(), Value::Nil)?;
+    close_objects(lua, tree)?;
+    with_states(lua, |states| states.any_closed = true);
+    // ltreesitter's finalizer passes the (now null) tree to ts_tree_delete, which ignores nulls.
+    unsafe {
+        tree_sitter::ffi::ts_tree_delete(ts_tree);
+        (*ltreesitter_tree).tree = std::ptr::null_mut();
+    }
+    Ok(())
+}
+
+/// Gives every node and cursor of a tree that is being closed a metatable whose methods all raise
+/// an error, since they would otherwise read the tree's freed memory.
+fn close_objects<'lua>(lua: &'lua Lua, tree: &Value<'lua>) -> Result<(), mlua::Error> {
+    let mut objects = Vec::new();
+    for pair in owners(lua)?.pairs::<Value, Value>() {
+        let (object, owner) = pair?;
+        if owner == *tree {
+            objects.push(object);
+        }
+    }
+    for object in objects {
+        let metatable = match ltreesitter::object_kind(lua, &object)? {
+            Some(Object::Node) => closed_metatable(lua, CLOSED_NODE_METATABLE, None)?,
+            Some(Object::TreeCursor) => closed_metatable(
+                lua,
+                CLOSED_TREE_CURSOR_METATABLE,
+                Some(closed_cursor_gc as mlua::ffi::lua_CFunction),
+            )?,
+            _ => continue,
+        };
+        ltreesitter::set_metatable(lua, &object, metatable)?;
+    }
+    Ok(())
+}
+
+/// Returns the metatable that we give to the nodes or cursors of closed trees, creating it if
+/// necessary.  Every method of an object with this metatable raises an [`Error::ClosedTree`].
+fn closed_metatable<'lua>(
+    lua: &'lua Lua,
+    name: &str,
+    gc: Option<mlua::ffi::lua_CFunction>,
+) -> Result<Table<'lua>, mlua::Error> {
+    if let Some(metatable) = lua.named_registry_value::<Option<Table>>(name)? {
+        return Ok(metatable);
+    }
+    let metatable = lua.create_table()?;
+    metatable.raw_set("__name", name)?;
+    let index = lua.create_function(|lua, _: MultiValue| {
+        lua.create_function(|_, _: MultiValue| Err::<(), _>(closed_error()))
+    })?;
+    metatable.raw_set("__index", index)?;
+    if let Some(gc) = gc {
+        metatable.raw_set("__gc", unsafe { lua.create_c_function(gc) }?)?;
+    }
+    lua.set_named_registry_value(name, metatable.clone())?;
+    Ok(metatable)
+}
+
+/// Frees a cursor of a closed tree.  ltreesitter's own finalizer would refuse the cursor, since it
+/// no longer has ltreesitter's metatable.  A cursor doesn't read its tree's memory when it's freed.
+unsafe extern "C-unwind" fn closed_cursor_gc(l: *mut mlua::lua_State) -> i32 {
+    let cursor = mlua::ffi::lua_touserdata(l, 1) as *mut ltreesitter::TreeCursor;
+    if !cursor.is_null() {
+        tree_sitter::ffi::ts_tree_cursor_delete(&mut (*cursor).cursor);
+    }
+    0
+}
+
+/// Returns whether a Lua value is a node or cursor of a closed tree.
+pub(crate) fn is_closed_object<'lua>(
+    lua: &'lua Lua,
+    value: &Value<'lua>,
+) -> Result<bool, mlua::Error> {
+    Ok(
+        ltreesitter::has_metatable(lua, value, CLOSED_NODE_METATABLE)?
+            || ltreesitter::has_metatable(lua, value, CLOSED_TREE_CURSOR_METATABLE)?,
+    )
+}
+
+/// The methods of ltreesitter's nodes that return new nodes or cursors, or iterators over them.
+/// These are the only methods of a node that we wrap.
+const NODE_METHODS: &[&str] = &[
+    "child",
+    "child_by_field_name",
+    "children",
+    "create_cursor",
+    "named_child",
+    "named_children",
+    "next_named_sibling",
+    "next_sibling",
+    "parent",
+    "prev_named_sibling",
+    "prev_sibling",
+];
+
+/// The methods of ltreesitter's tree cursors that return new nodes or cursors.
+const TREE_CURSOR_METHODS: &[&str] = &["copy", "current_node"];
+
+/// The methods of ltreesitter's queries that return iterators over the nodes of a tree.
+const QUERY_METHODS: &[&str] = &["capture", "exec", "match"];
+
+/// The deepest that we look into the tables that ltreesitter's methods return, which is enough to
+/// reach the nodes in the captures of a query match.
+const MAX_RESULT_DEPTH: usize = 3;
+
+/// Wraps one of ltreesitter's methods.  Raises an error if any of the arguments is a closed tree,
+/// or a node or cursor of a closed tree.  Otherwise, calls the method, and records that any nodes
+/// and cursors that it returns belong to the same tree as its arguments.
+fn guarded<'lua>(
+    lua: &'lua Lua,
+    original: Function<'lua>,
+    args: MultiValue<'lua>,
+) -> Result<MultiValue<'lua>, mlua::Error> {
+    let mut tree = None;
+    for arg in args.iter() {
+        let owner = check_arg(lua, arg)?;
+        if tree.is_none() {
+            tree = owner;
+        }
+    }
+    let results = original.call::<_, MultiValue>(args)?;
+    match tree {
+        Some(tree) => adopt_all(lua, &tree, results),
+        None => Ok(results),
+    }
+}
+
+fn check_arg<'lua>(lua: &'lua Lua, arg: &Value<'lua>) -> Result<Option<Value<'lua>>, mlua::Error> {
+    let any_closed = match lua.app_data_ref::<TreeStates>() {
+        Some(states) => states.any_closed,
+        None => false,
+    };
+    if any_closed {
+        check_open(lua, arg)
+    } else {
+        owner(lua, arg)
+    }
+}
+
+fn adopt_all<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    results: MultiValue<'lua>,
+) -> Result<MultiValue<'lua>, mlua::Error> {
+    results
+        .into_iter()
+        .map(|result| adopt(lua, tree, result, 0))
+        .collect()
+}
+
+/// Records that a value that one of ltreesitter's methods returned belongs to `tree`.  Nodes and
+/// cursors are added to the owners table.  Functions, like the iterators that `query:match` and
+/// `node:children` return, are wrapped so that they are guarded too.
+fn adopt<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    value: Value<'lua>,
+    depth: usize,
+) -> Result<Value<'lua>, mlua::Error> {
+    match &value {
+        Value::UserData(_) => {
+            match ltreesitter::object_kind(lua, &value)? {
+                Some(Object::Tree) if value != *tree => {
+                    let copy = ltreesitter::tree_ptr(lua, value.clone())?;
+                    share_source(lua, tree, &value, copy)?;
+                }
+                Some(Object::Node) | Some(Object::TreeCursor) => {
+                    owners(lua)?.raw_set(value.clone(), tree.clone())?;
+                }
+                _ => {}
+            }
+            Ok(value)
+        }
+        Value::Table(table) if depth < MAX_RESULT_DEPTH => {
+            for pair in table.clone().pairs::<Value, Value>() {
+                let (_, element) = pair?;
+                adopt(lua, tree, element, depth + 1)?;
+            }
+            Ok(value)
+        }
+        Value::Function(function) => guard_iterator(lua)?.call((tree.clone(), function.clone())),
+        _ => Ok(value),
+    }
+}
+
+/// Returns a Lua function that wraps an iterator that one of ltreesitter's methods returned, so
+/// that it raises an error once its tree is closed, and adopts the values that it produces.  The
+/// wrappers are Lua closures, so creating one doesn't need any registry values.
+fn guard_iterator(lua: &Lua) -> Result<Function, mlua::Error> {
+    if let Some(guard) = lua.named_registry_value::<Option<Function>>(GUARD_ITERATOR_KEY)? {
+        return Ok(guard);
+    }
+    let check = lua.create_function(|lua, tree: Value| check_open(lua, &tree).map(|_| ()))?;
+    let adopt = lua.create_function(|lua, (tree, results): (Value, MultiValue)| {
+        adopt_all(lua, &tree, results)
+    })?;
+    let guard: Function = lua
+        .load(GUARD_ITERATOR)
+        .set_name("guard_iterator")
+        .call((check, adopt))?;
+    lua.set_named_registry_value(GUARD_ITERATOR_KEY, guard.clone())?;
+    Ok(guard)
+}
+
+const GUARD_ITERATOR: &str = r#"
+  local check, adopt = ...
+  return function(tree, iterator)
+    return function(...)
+      check(tree)
+      return adopt(tree, iterator(...))
+    end
+  end
+"#;
+
+/// Gives a tree that one of ltreesitter's methods returned (like `tree:copy()`) its own copy of its
+/// source code, if it shares the buffer of the tree that it came from, so that it doesn't lose its
+/// source when that tree is closed.  If the source is in a source store, the copy reads from the
+/// same store instead, which stays alive until both trees are done with it.
+fn share_source<'lua>(
+    lua: &'lua Lua,
+    tree: &Value<'lua>,
+    copy: &Value<'lua>,
+    ltreesitter_copy: *mut ltreesitter::Tree,
+) -> Result<(), mlua::Error> {
+    let ltreesitter_tree = match ltreesitter::as_tree(lua, tree)? {
+        Some(ltreesitter_tree) => ltreesitter_tree,
+        None => return Ok(()),
+    };
+    let has_buffer = match existing_attachments(lua, tree)? {
+        Some(attachments) => attachments.contains_key(SOURCE_BUFFER_KEY)?,
+        None => false,
+    };
+    if unsafe { (*ltreesitter_copy).source != (*ltreesitter_tree).source } {
+        return Ok(());
+    }
+    if has_buffer {
+        let src = unsafe { ltreesitter::source(ltreesitter_copy) };
+        set_source(lua, copy, SourceBuffer::new(src))?;
+    }
+    stores::share(lua, tree, copy)
+}
+
+/// Adds a `close` method to ltreesitter's trees, and makes sure that ltreesitter's methods raise an
+/// error if they're called on a closed tree.  Every method of a tree is guarded, since they all
+/// read the tree's memory, but only the methods of nodes, cursors, and queries that create new
+/// nodes or cursors are.  This must be called after all other tree methods have been installed,
+/// so that they are guarded too.
+pub(crate) fn install_close(lua: &Lua) -> Result<(), mlua::Error> {
+    if lua
+        .named_registry_value::<Option<Table>>(ltreesitter::TREE_METATABLE)?
+        .is_some()
+    {
+        ltreesitter::guard_methods(lua, ltreesitter::TREE_METATABLE, guarded)?;
+    }
+    for (metatable, names) in [
+        (ltreesitter::NODE_METATABLE, NODE_METHODS),
+        (ltreesitter::TREE_CURSOR_METATABLE, TREE_CURSOR_METHODS),
+        (ltreesitter::QUERY_METATABLE, QUERY_METHODS),
+    ] {
+        if lua
+            .named_registry_value::<Option<Table>>(metatable)?
+            .is_some()
+        {
+            for name in names {
+                ltreesitter::wrap_method(lua, metatable, name, guarded)?;
+            }
+        }
+    }
+    let close = lua.create_function(|lua, tree: Value| close_tree(lua, &tree))?;
+    ltreesitter::methods(lua, ltreesitter::TREE_METATABLE)?.raw_set("close", close)
+}
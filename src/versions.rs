@@ -0,0 +1,137 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Versioned views of the `ltreesitter_rs` module, so that Lua plugins can pin the API that they
+//! were written against.
+//!
+//! `require("ltreesitter_rs")` always provides the newest API.  `require("ltreesitter_rs").v1` (or
+//! `require("ltreesitter_rs.v1")`) only provides the functions that are part of version 1, with
+//! the behavior that they had in version 1.  Where a function has since changed, the versioned
+//! table holds a shim that maps the old calling convention onto the newer implementation:
+//!
+//! - `v1.packed_matches(tree, query)` only returns the match buffer, and not whether it's
+//!   complete.
+//!
+//! Helpers that the host registers via [`HostFunctions`][crate::HostFunctions] aren't part of
+//! any version, so they are only available from the unversioned module.  Lua code can check
+//! `require("ltreesitter_rs").api_version` to find out which version is the newest.
+
+use mlua::Lua;
+
+/// The newest version of the Lua API.
+pub const LUA_API_VERSION: u32 = 1;
+
+/// The names that are part of version 1 of the Lua API.
+const V1: &[&str] = &[
+    "affected_patterns",
+    "cached_matches",
+    "closest_ancestor_of_kind",
+    "conformance",
+    "emit",
+    "err",
+    "expand_kinds",
+    "explain",
+    "highlight",
+    "is_node",
+    "is_query",
+    "is_tree",
+    "is_tree_cursor",
+    "languages",
+    "load_query_file",
+    "mark",
+    "match_objects",
+    "measure",
+    "merge_spans",
+    "next_node_of_kind_after",
+    "ok",
+    "packed_matches",
+    "playground_json",
+    "pretty_print",
+    "previous_node_of_kind_before",
+    "ranges",
+    "read_query_file",
+    "render_matches",
+    "reparse",
+    "run_query",
+    "set_match_class",
+    "textobjects",
+];
+
+const VERSIONED: &str = r#"
+    local latest, version, names, shims = ...
+    local stable = {}
+    for _, name in ipairs(names) do stable[name] = true end
+    -- Look names up lazily, so that functions that are installed later (like cached_matches,
+    -- once the query cache is enabled) show up too.
+    local versioned = setmetatable({}, {
+      __index = function(_, name)
+        if shims[name] ~= nil then return shims[name] end
+        if stable[name] then return latest[name] end
+        return nil
+      end,
+      __newindex = function()
+        error("ltreesitter_rs." .. version .. " is read-only", 2)
+      end,
+    })
+    latest[version] = versioned
+    package.loaded["ltreesitter_rs." .. version] = versioned
+"#;
+
+const V1_SHIMS: &str = r#"
+    local latest = ...
+    local shims = {}
+    function shims.packed_matches(tree, query)
+      local buffer = latest.packed_matches(tree, query)
+      return buffer
+    end
+    return shims
+"#;
+
+/// Adds the versioned API tables to the `ltreesitter_rs` module.
+pub(crate) fn install(lua: &Lua) -> Result<(), mlua::Error> {
+    let module = crate::companion_module(lua)?;
+    module.set("api_version", LUA_API_VERSION)?;
+    let shims: mlua::Table = lua
+        .load(V1_SHIMS)
+        .set_name("ltreesitter_rs v1 shims")
+        .call(module.clone())?;
+    lua.load(VERSIONED)
+        .set_name("ltreesitter_rs versions")
+        .call((module, "v1", V1.to_vec(), shims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CheckLua;
+    use crate::HostFunctions;
+    use crate::Module;
+    use crate::WithSource;
+
+    #[test]
+    fn can_pin_api_versions() {
+        let code = b"x = 1\n";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language()).unwrap();
+        let parsed = parser.parse(code, None).unwrap();
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_function("host_only", |_, ()| Ok(true)).unwrap();
+        l.globals().set("parsed", parsed.with_source(code)).unwrap();
+        l.check(
+            r##"
+              local latest = require("ltreesitter_rs")
+              local v1 = require("ltreesitter_rs.v1")
+              assert(latest.v1 == v1 and latest.api_version == 1)
+              assert(v1.merge_spans == latest.merge_spans)
+              assert(v1.host_only == nil and latest.host_only ~= nil)
+              assert(select("#", v1.packed_matches(parsed, "(integer) @n")) == 1)
+              assert(select("#", latest.packed_matches(parsed, "(integer) @n")) == 2)
+              assert(not pcall(function() v1.merge_spans = nil end))
+            "##,
+        );
+    }
+}
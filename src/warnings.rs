@@ -0,0 +1,201 @@
+// -*- coding: utf-8 -*-
+// ------------------------------------------------------------------------------------------------
+// Copyright © 2023, Douglas Creager.
+// Please see the LICENSE file in this distribution for license details.
+// ------------------------------------------------------------------------------------------------
+
+//! Structured warnings about deprecated functions and missing capabilities, which are delivered to
+//! the host instead of being printed.
+//!
+//! Once the host has registered a [warning channel][ScriptWarnings::warning_channel], the bridge
+//! sends a [`ScriptWarning`] whenever a script calls a deprecated `ltreesitter_rs` function, or
+//! asks for something that the host can't provide (like an injected language whose grammar isn't
+//! linked in), or runs a query over a tree that was parsed with a different grammar.  Each warning
+//! includes the script and line that triggered it.  A warning is only
+//! sent the first time that it's triggered in a Lua state, so a deprecated function that is
+//! called in a loop produces one warning, not thousands.  Without a channel, warnings are dropped.
+
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::sync::mpsc;
+
+use mlua::Function;
+use mlua::Lua;
+use mlua::MultiValue;
+
+/// The kinds of warnings that the bridge reports.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum WarningKind {
+    /// A script called a function that is deprecated.
+    Deprecated,
+    /// A script asked for something that the host doesn't support.
+    Unsupported,
+    /// A script ran a query over a tree that was parsed with a different grammar.
+    GrammarMismatch,
+}
+
+/// A warning about something that a script did.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScriptWarning {
+    pub kind: WarningKind,
+    /// The function or capability that the warning is about.
+    pub subject: String,
+    pub message: String,
+    /// The script that triggered the warning, if it was triggered from Lua code.
+    pub script: Option<String>,
+    /// The line of the script that triggered the warning.
+    pub line: Option<usize>,
+}
+
+impl Display for ScriptWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let (Some(script), Some(line)) = (&self.script, self.line) {
+            write!(f, "{}:{}: ", script, line)?;
+        }
+        match self.kind {
+            WarningKind::Deprecated => {
+                write!(f, "{} is deprecated: {}", self.subject, self.message)
+            }
+            WarningKind::Unsupported => {
+                write!(f, "{} is not supported: {}", self.subject, self.message)
+            }
+            WarningKind::GrammarMismatch => write!(f, "{}: {}", self.subject, self.message),
+        }
+    }
+}
+
+#[derive(Default)]
+struct WarningState {
+    sender: Option<mpsc::Sender<ScriptWarning>>,
+    seen: BTreeSet<(WarningKind, String)>,
+}
+
+/// An extension trait that lets you receive the warnings that scripts trigger.
+pub trait ScriptWarnings {
+    /// Registers the channel that warnings are sent to, replacing any existing channel.
+    fn warning_channel(&self) -> mpsc::Receiver<ScriptWarning>;
+
+    /// Marks a function in the `ltreesitter_rs` module as deprecated, so that calling it sends a
+    /// warning with the given message.  Does nothing if there is no function with that name.
+    fn deprecate_function(&self, name: &str, message: &str) -> Result<(), mlua::Error>;
+}
+
+impl ScriptWarnings for Lua {
+    fn warning_channel(&self) -> mpsc::Receiver<ScriptWarning> {
+        let (sender, receiver) = mpsc::channel();
+        match self.app_data_mut::<WarningState>() {
+            Some(mut state) => state.sender = Some(sender),
+            None => {
+                self.set_app_data(WarningState {
+                    sender: Some(sender),
+                    ..WarningState::default()
+                });
+            }
+        }
+        receiver
+    }
+
+    fn deprecate_function(&self, name: &str, message: &str) -> Result<(), mlua::Error> {
+        let module = crate::companion_module(self)?;
+        let original: Option<Function> = module.get(name)?;
+        let original = match original {
+            Some(original) => self.create_registry_value(original)?,
+            None => return Ok(()),
+        };
+        let (subject, message) = (format!("ltreesitter_rs.{}", name), message.to_string());
+        let deprecated = self.create_function(move |lua, args: MultiValue| {
+            warn(lua, WarningKind::Deprecated, &subject, &message);
+            let original: Function = lua.registry_value(&original)?;
+            original.call::<_, MultiValue>(args)
+        })?;
+        module.set(name, deprecated)
+    }
+}
+
+/// Sends a warning to the host's warning channel, unless a warning of the same kind about the
+/// same subject has already been sent.
+pub(crate) fn warn(lua: &Lua, kind: WarningKind, subject: &str, message: &str) {
+    {
+        let mut state = match lua.app_data_mut::<WarningState>() {
+            Some(state) => state,
+            None => return,
+        };
+        if state.sender.is_none() || !state.seen.insert((kind, subject.to_string())) {
+            return;
+        }
+    }
+    send(lua, kind, subject, message);
+}
+
+/// Sends a warning to the host's warning channel, even if the same warning has already been sent.
+/// This is for callers that decide for themselves how often a warning should be sent.
+pub(crate) fn send(lua: &Lua, kind: WarningKind, subject: &str, message: &str) {
+    let state = match lua.app_data_ref::<WarningState>() {
+        Some(state) => state,
+        None => return,
+    };
+    let (script, line) = match lua_location(lua) {
+        Some((script, line)) => (Some(script), Some(line)),
+        None => (None, None),
+    };
+    let warning = ScriptWarning {
+        kind,
+        subject: subject.to_string(),
+        message: message.to_string(),
+        script,
+        line,
+    };
+    if let Some(sender) = &state.sender {
+        // The host might not be listening any more, which is fine.
+        let _ = sender.send(warning);
+    }
+}
+
+/// Returns the innermost Lua script and line on the call stack.
+fn lua_location(lua: &Lua) -> Option<(String, usize)> {
+    let mut level = 0;
+    while let Some(debug) = lua.inspect_stack(level) {
+        let line = debug.curr_line();
+        if line > 0 {
+            let source = debug.source();
+            let short_src = source.short_src.as_deref().unwrap_or("?");
+            return Some((short_src.to_string(), line as usize));
+        }
+        level += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HostFunctions;
+    use crate::Module;
+
+    #[test]
+    fn can_warn_about_deprecated_functions() {
+        let l = Lua::new();
+        l.open_ltreesitter().unwrap();
+        l.register_function("old_helper", |_, x: i64| Ok(x + 1))
+            .unwrap();
+        l.deprecate_function("old_helper", "use new_helper instead")
+            .unwrap();
+        let warnings = l.warning_channel();
+        l.load(
+            r#"
+              local old_helper = require("ltreesitter_rs").old_helper
+              for i = 1, 3 do assert(old_helper(i) == i + 1) end
+            "#,
+        )
+        .set_name("@plugin.lua")
+        .exec()
+        .unwrap();
+        let warnings = warnings.try_iter().collect::<Vec<_>>();
+        assert_eq!(1, warnings.len());
+        assert_eq!(WarningKind::Deprecated, warnings[0].kind);
+        assert_eq!(
+            "plugin.lua:3: ltreesitter_rs.old_helper is deprecated: use new_helper instead",
+            warnings[0].to_string()
+        );
+    }
+}